@@ -0,0 +1,29 @@
+//! Capture information about the build, so a running node can report exactly what it is.
+
+use std::process::Command;
+
+fn main() {
+    // Building from a source tarball, or without git installed, is not an error.
+    let commit = command_output("git", &["rev-parse", "--short", "HEAD"])
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=MYCELIUM_GIT_COMMIT={commit}");
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=MYCELIUM_RUSTC_VERSION={rustc_version}");
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+/// Run a command and get its trimmed stdout, if it succeeded.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    let output = output.trim();
+    (!output.is_empty()).then(|| output.to_string())
+}
@@ -0,0 +1,153 @@
+//! Thresholds on counters of the node, and evaluation of which thresholds are currently
+//! breached, so monitoring can poll for actionable alerts instead of interpreting raw counters.
+
+use serde::{Deserialize, Serialize};
+
+/// Window over which rates are computed, in seconds.
+pub const ALERT_WINDOW_SECS: u64 = 60;
+
+/// Factor of a threshold at or above which a breach is critical instead of a warning.
+const CRITICAL_FACTOR: usize = 2;
+
+/// Thresholds which raise an alert once they are reached. Thresholds which are not set never
+/// raise an alert.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertThresholds {
+    /// Amount of lost peer connections in the last minute.
+    #[serde(default)]
+    pub disconnects_per_minute: Option<usize>,
+    /// Amount of dropped messages and rejected routes in the last minute.
+    #[serde(default)]
+    pub drops_per_minute: Option<usize>,
+    /// Amount of routes in the routing table.
+    #[serde(default)]
+    pub route_table_size: Option<usize>,
+}
+
+/// Current values of the counters alerts are raised for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlertCounters {
+    /// Amount of lost peer connections in the last minute.
+    pub disconnects_per_minute: usize,
+    /// Amount of dropped messages and rejected routes in the last minute.
+    pub drops_per_minute: usize,
+    /// Amount of routes in the routing table.
+    pub route_table_size: usize,
+}
+
+/// The counter an [`Alert`] is raised for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AlertKind {
+    /// Too many peer connections have been lost recently.
+    Disconnects,
+    /// Too many messages or routes have been dropped recently.
+    Drops,
+    /// The routing table holds too many routes.
+    RouteTableSize,
+}
+
+/// How severe a breached threshold is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    /// The threshold is reached.
+    Warning,
+    /// The counter is at least twice the threshold.
+    Critical,
+}
+
+/// A currently breached threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    /// The counter which breached its threshold.
+    pub kind: AlertKind,
+    /// How severe the breach is.
+    pub severity: Severity,
+    /// Current value of the counter.
+    pub value: usize,
+    /// The configured threshold.
+    pub threshold: usize,
+}
+
+impl AlertThresholds {
+    /// Get an [`Alert`] for every threshold reached by the given counters.
+    pub fn evaluate(&self, counters: &AlertCounters) -> Vec<Alert> {
+        [
+            (
+                AlertKind::Disconnects,
+                self.disconnects_per_minute,
+                counters.disconnects_per_minute,
+            ),
+            (
+                AlertKind::Drops,
+                self.drops_per_minute,
+                counters.drops_per_minute,
+            ),
+            (
+                AlertKind::RouteTableSize,
+                self.route_table_size,
+                counters.route_table_size,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(kind, threshold, value)| {
+            let threshold = threshold?;
+            if value < threshold {
+                return None;
+            }
+            Some(Alert {
+                kind,
+                severity: if value >= threshold.saturating_mul(CRITICAL_FACTOR) {
+                    Severity::Critical
+                } else {
+                    Severity::Warning
+                },
+                value,
+                threshold,
+            })
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AlertCounters, AlertKind, AlertThresholds, Severity};
+
+    #[test]
+    fn unset_thresholds_never_alert() {
+        let counters = AlertCounters {
+            disconnects_per_minute: 100,
+            drops_per_minute: 100,
+            route_table_size: 100_000,
+        };
+
+        assert!(AlertThresholds::default().evaluate(&counters).is_empty());
+    }
+
+    #[test]
+    fn breached_thresholds_alert_with_severity() {
+        let thresholds = AlertThresholds {
+            disconnects_per_minute: Some(5),
+            drops_per_minute: Some(10),
+            route_table_size: Some(1000),
+        };
+        let counters = AlertCounters {
+            disconnects_per_minute: 5,
+            drops_per_minute: 9,
+            route_table_size: 2000,
+        };
+
+        let alerts = thresholds.evaluate(&counters);
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].kind, AlertKind::Disconnects);
+        assert_eq!(alerts[0].severity, Severity::Warning);
+        assert_eq!(alerts[1].kind, AlertKind::RouteTableSize);
+        assert_eq!(alerts[1].severity, Severity::Critical);
+        assert_eq!(alerts[1].value, 2000);
+        assert_eq!(alerts[1].threshold, 1000);
+    }
+}
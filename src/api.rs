@@ -1,29 +1,90 @@
 use std::{
-    net::SocketAddr,
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    routing::{delete, get},
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        ConnectInfo, FromRequest, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Json, Router,
 };
-use log::{debug, error};
-use serde::{Deserialize, Serialize};
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use log::{debug, error, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
 
 #[cfg(feature = "message")]
 use crate::message::MessageStack;
 use crate::{
+    alerts::{Alert, AlertCounters, AlertThresholds, ALERT_WINDOW_SECS},
+    crypto::PublicKey,
     endpoint::Endpoint,
-    peer_manager::{PeerExists, PeerManager, PeerNotFound, PeerStats},
+    events::{DropEvent, PacketDropReason, PeerEvent, SampleWindow},
+    filters::PrefixLists,
+    peer_manager::{
+        ConnectionState, DeletePeerError, PeerChange, PeerDiscoveryConfig, PeerManager,
+        PeerNotFound, PeerStats, PeerSummary, PeerType,
+    },
+    reconnect::ReconnectPolicy,
+    router::RouterStatus,
+    routing_table::RouteEntry,
+    subnet::Subnet,
 };
 
+mod error;
 #[cfg(feature = "message")]
 mod message;
+mod metrics;
+pub use error::{ApiError, ErrorCode};
 #[cfg(feature = "message")]
-pub use message::{MessageDestination, MessageReceiveInfo, MessageSendInfo, PushMessageResponse};
+pub use message::{
+    DestinationValidation, MessageConsumers, MessageDestination, MessageReceiveInfo,
+    MessageSendInfo, PushMessageResponse, TopicAggregation, ValidateDestinationInfo,
+};
+pub use metrics::{check_metric_labels, InvalidMetricLabel, MetricLabel};
+
+/// Maximum amount of peers which are probed at the same time.
+const PEER_PROBE_CONCURRENCY: usize = 8;
+
+/// Maximum amount of client errors logged per [`CLIENT_LOG_WINDOW`]. Further reports are
+/// rejected until the window passes.
+const CLIENT_LOG_RATE: u32 = 30;
+
+/// Window over which the amount of logged client errors is limited.
+const CLIENT_LOG_WINDOW: Duration = Duration::from_secs(60);
+
+/// Maximum size of the message of a client error report. Longer messages are truncated.
+const MAX_CLIENT_LOG_MESSAGE_SIZE: usize = 4096;
+
+/// Maximum size of the source and location of a client error report. Longer values are
+/// truncated.
+const MAX_CLIENT_LOG_FIELD_SIZE: usize = 256;
+
+/// Content type of request and response bodies encoded as CBOR.
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Path the API is served under, unless an additional base path is configured.
+pub const API_PATH: &str = "/api/v1";
+
+/// Get the path the API is served under, with an optional base path in front of [`API_PATH`].
+/// Leading and trailing slashes of the base path are optional.
+pub fn api_prefix(base_path: Option<&str>) -> String {
+    match base_path.map(|base_path| base_path.trim_matches('/')) {
+        Some(base_path) if !base_path.is_empty() => format!("/{base_path}{API_PATH}"),
+        _ => API_PATH.to_string(),
+    }
+}
 
 /// Http API server handle. The server is spawned in a background task. If this handle is dropped,
 /// the server is terminated.
@@ -43,40 +104,158 @@ struct HttpServerState {
     #[cfg(feature = "message")]
     /// Access to messages.
     message_stack: MessageStack,
+    /// Thresholds for which alerts are raised.
+    alert_thresholds: Arc<Mutex<AlertThresholds>>,
+    #[cfg(feature = "message")]
+    /// Amount of time to try and send a message if it is not explicitly specified.
+    message_try_duration: message::MessageTryDuration,
+    /// Maximum size in bytes of the payload of a pushed message.
+    #[cfg(feature = "message")]
+    max_payload_size: usize,
+    /// Cancelled when the server shuts down, so long lived connections like websockets can be
+    /// closed.
+    shutdown: CancellationToken,
+    /// Limit on the amount of client errors which are logged.
+    client_log_limit: Arc<Mutex<SampleWindow>>,
+    /// Uptime and readiness of the router, which can be checked without locking the router.
+    router_status: RouterStatus,
+    /// Labels added to every sample served by the metrics endpoint.
+    metric_labels: Arc<[MetricLabel]>,
 }
 
 impl Http {
-    /// Spawns a new HTTP API server on the provided listening address.
+    /// Spawns a new HTTP API server on the provided listening address. Requests which take longer
+    /// than `request_timeout` are aborted, except for the long polling message endpoints. Alerts
+    /// are raised for the given `alert_thresholds`, which can be changed through the API.
+    ///
+    /// If `allowed_sources` is not empty, only connections from an address in one of these
+    /// subnets are served, other connections get a `403 Forbidden` response.
+    ///
+    /// The API is served under [`API_PATH`], prefixed by `base_path` if it is set, so the API can
+    /// be exposed on a subpath by a reverse proxy without rewriting URLs.
+    ///
+    /// If `listen_addr` is an IPv6 address, `v6_only` controls if IPv4 connections are accepted
+    /// on it as well, see [`bind_listener`].
+    ///
+    /// If `client_log` is set, clients can report their own errors to be logged by the node.
+    ///
+    /// Messages with a payload larger than `max_payload_size` bytes are rejected.
+    ///
+    /// The `metric_labels` are added to every sample served by the metrics endpoint.
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         router: crate::router::Router,
         peer_manager: PeerManager,
         #[cfg(feature = "message")] message_stack: MessageStack,
+        #[cfg(feature = "message")] max_payload_size: usize,
         listen_addr: SocketAddr,
+        v6_only: bool,
+        request_timeout: Duration,
+        alert_thresholds: AlertThresholds,
+        allowed_sources: Vec<Subnet>,
+        base_path: Option<String>,
+        client_log: bool,
+        metric_labels: Vec<MetricLabel>,
     ) -> Self {
+        let router_status = router.status();
         let server_state = HttpServerState {
             router: Arc::new(Mutex::new(router)),
             peer_manager,
             #[cfg(feature = "message")]
             message_stack,
+            alert_thresholds: Arc::new(Mutex::new(alert_thresholds)),
+            #[cfg(feature = "message")]
+            message_try_duration: message::MessageTryDuration::default(),
+            #[cfg(feature = "message")]
+            max_payload_size,
+            shutdown: CancellationToken::new(),
+            client_log_limit: Arc::new(Mutex::new(SampleWindow::new(
+                CLIENT_LOG_WINDOW,
+                CLIENT_LOG_RATE,
+                Instant::now(),
+            ))),
+            router_status,
+            metric_labels: metric_labels.into(),
         };
-        let admin_routes = Router::new()
+        let shutdown = server_state.shutdown.clone();
+        let mut admin_routes = Router::new()
             .route("/admin", get(get_info))
+            .route("/admin/health", get(get_health))
+            .route("/admin/build", get(get_build))
+            .route("/admin/dashboard", get(get_dashboard))
+            .route("/admin/router-id", get(get_router_id))
+            .route("/admin/config", get(get_config))
             .route("/admin/peers", get(get_peers).post(add_peer))
+            .route("/admin/peers/summary", get(get_peer_summary))
+            .route("/admin/peers/ws", get(get_peers_feed))
+            .route("/admin/peers/probe-all", post(probe_all_peers))
             .route("/admin/peers/:endpoint", delete(delete_peer))
+            .route(
+                "/admin/peers/:endpoint/policy",
+                get(get_import_policy).put(set_import_policy),
+            )
+            .route("/admin/peers/:endpoint/log", get(get_peer_events))
+            .route("/admin/neighbours/keys", get(get_neighbour_keys))
+            .route("/admin/neighbours/:pubkey", get(get_neighbour))
+            .route("/admin/routes", get(get_routes))
             .route("/admin/routes/selected", get(get_selected_routes))
+            .route("/admin/routes/selected/:subnet", get(get_selected_route))
             .route("/admin/routes/fallback", get(get_fallback_routes))
+            .route("/admin/routes/stats", get(get_route_stats))
+            .route("/admin/routes/reselect", post(reselect_routes))
+            .route("/admin/routes/simulate", post(simulate_routes))
+            .route("/admin/events/drops", get(get_drop_events))
+            .route("/admin/alerts", get(get_alerts))
+            .route(
+                "/admin/alerts/config",
+                get(get_alert_thresholds).put(set_alert_thresholds),
+            );
+        // Reporting client errors writes to the logs of the node, so it must be enabled
+        // explicitly.
+        if client_log {
+            admin_routes = admin_routes.route("/admin/client-log", post(report_client_error));
+        }
+        let admin_routes = admin_routes
+            .layer(middleware::from_fn_with_state(
+                request_timeout,
+                timeout_request,
+            ))
+            .with_state(server_state.clone());
+        let prefix = api_prefix(base_path.as_deref());
+        // Metrics are served next to the API instead of under it, where scrapers expect them.
+        let metrics_path = format!(
+            "{}/metrics",
+            prefix
+                .strip_suffix(API_PATH)
+                .expect("API prefix always ends with the API path; qed")
+        );
+        let mut app = Router::new()
+            .route(&metrics_path, get(metrics::get_metrics))
             .with_state(server_state.clone());
-        let mut app = Router::new();
-        app = app.nest("/api/v1", admin_routes);
+        // Probes are kept out of the admin routes, so they stay cheap and are easy to expose
+        // separately.
+        let probe_routes = Router::new()
+            .route("/health", get(get_liveness))
+            .route("/ready", get(get_readiness))
+            .with_state(server_state.clone());
+        app = app.nest(&prefix, probe_routes);
+        app = app.nest(&prefix, admin_routes);
         #[cfg(feature = "message")]
         {
-            app = app.nest("/api/v1", message::message_router_v1(server_state));
+            app = app.nest(
+                &prefix,
+                message::message_router_v1(server_state, request_timeout),
+            );
         }
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(allowed_sources),
+            check_source,
+        ));
 
         let (_cancel_tx, cancel_rx) = tokio::sync::oneshot::channel();
 
         tokio::spawn(async move {
-            let listener = match tokio::net::TcpListener::bind(listen_addr).await {
+            let listener = match bind_listener(listen_addr, v6_only) {
                 Ok(listener) => listener,
                 Err(e) => {
                     error!("Failed to bind listener for Http Api server: {e}");
@@ -85,10 +264,14 @@ impl Http {
                 }
             };
 
-            let server =
-                axum::serve(listener, app.into_make_service()).with_graceful_shutdown(async {
-                    cancel_rx.await.ok();
-                });
+            let server = axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(async move {
+                cancel_rx.await.ok();
+                shutdown.cancel();
+            });
 
             if let Err(e) = server.await {
                 error!("Http API server error: {e}");
@@ -98,59 +281,562 @@ impl Http {
     }
 }
 
-/// Get the stats of the current known peers
-async fn get_peers(State(state): State<HttpServerState>) -> Json<Vec<PeerStats>> {
+/// Bind a listener for the API on the given address.
+///
+/// If the address is an IPv6 address, `v6_only` sets whether only IPv6 connections are accepted.
+/// Otherwise, IPv4 connections are accepted on the socket as well, as IPv4-mapped IPv6
+/// addresses. This is set explicitly, since the default varies between operating systems. For
+/// IPv4 addresses, `v6_only` is ignored.
+fn bind_listener(addr: SocketAddr, v6_only: bool) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // Same as tokio, this allows the API to be rebound right after the node restarts.
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Middleware which aborts a request if the handler does not finish within the given timeout, and
+/// returns a `504 Gateway Timeout` instead.
+async fn timeout_request(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("Http API request {method} {uri} timed out after {timeout:?}");
+            StatusCode::GATEWAY_TIMEOUT.into_response()
+        }
+    }
+}
+
+/// Encoding of the body of a request or response. Endpoints which support CBOR negotiate the
+/// encoding with the `Content-Type` and `Accept` headers, and use JSON unless CBOR is requested.
+/// Binary fields are raw bytes in CBOR, instead of base64 encoded strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFormat {
+    Json,
+    Cbor,
+}
+
+impl BodyFormat {
+    /// The format of the body of a request with the given headers.
+    pub fn of_request(headers: &HeaderMap) -> Self {
+        match headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(value) if is_media_type(value, CBOR_CONTENT_TYPE) => Self::Cbor,
+            _ => Self::Json,
+        }
+    }
+
+    /// The format the client accepts for the response, according to the `Accept` header of its
+    /// request.
+    pub fn accepted(headers: &HeaderMap) -> Self {
+        let accepts_cbor = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|range| is_media_type(range, CBOR_CONTENT_TYPE));
+        if accepts_cbor {
+            Self::Cbor
+        } else {
+            Self::Json
+        }
+    }
+
+    /// Create a response with the given status, with `value` encoded as body in this format.
+    pub fn respond<T: Serialize>(self, status: StatusCode, value: T) -> Response {
+        match self {
+            Self::Json => (status, Json(value)).into_response(),
+            Self::Cbor => {
+                let mut body = Vec::new();
+                if let Err(e) = ciborium::into_writer(&value, &mut body) {
+                    error!("Failed to encode response as CBOR: {e}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                (status, [(header::CONTENT_TYPE, CBOR_CONTENT_TYPE)], body).into_response()
+            }
+        }
+    }
+}
+
+/// Check if the value of a `Content-Type` header, or a single media range of an `Accept` header,
+/// is the given media type. Parameters are ignored.
+fn is_media_type(value: &str, media_type: &str) -> bool {
+    value
+        .split(';')
+        .next()
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case(media_type))
+}
+
+/// Extractor of a request body, which is decoded according to the [`BodyFormat`] of the request.
+/// JSON bodies are extracted with [`JsonBody`], so they are rejected the same way.
+pub struct Negotiated<T>(pub T);
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S> for Negotiated<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match BodyFormat::of_request(request.headers()) {
+            BodyFormat::Json => {
+                let JsonBody(value) = JsonBody::<T>::from_request(request, state).await?;
+                Ok(Self(value))
+            }
+            BodyFormat::Cbor => {
+                let body = bytes::Bytes::from_request(request, state).await?;
+                ciborium::from_reader(&body[..]).map(Self).map_err(|e| {
+                    ApiError::new(
+                        ErrorCode::InvalidBody,
+                        format!("Failed to parse the request body as CBOR: {e}"),
+                    )
+                })
+            }
+        }
+    }
+}
+
+/// Extractor of a JSON request body, like [`Json`], but bodies which can't be decoded, or exceed
+/// the body limit, are rejected with an [`ApiError`].
+pub struct JsonBody<T>(pub T);
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// Middleware which rejects requests with a `403 Forbidden` if the remote address of the
+/// connection is not in one of the allowed subnets.
+async fn check_source(
+    State(allowed_sources): State<Arc<Vec<Subnet>>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let remote = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    if !source_allowed(&allowed_sources, remote) {
+        warn!(
+            "Rejecting Http API request {} {} from disallowed address {}",
+            request.method(),
+            request.uri(),
+            remote.map(|ip| ip.to_string()).unwrap_or_default()
+        );
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+/// Checks if a connection from the given remote address is allowed. Every address is allowed if
+/// no subnets are configured. Connections without a remote IP, e.g. over a unix socket, are
+/// always allowed.
+fn source_allowed(allowed_sources: &[Subnet], remote: Option<IpAddr>) -> bool {
+    let Some(ip) = remote else {
+        return true;
+    };
+    // Listening on a dual stack socket reports IPv4 clients as IPv4-mapped IPv6 addresses.
+    let ip = match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    };
+    allowed_sources.is_empty() || allowed_sources.iter().any(|subnet| subnet.contains_ip(ip))
+}
+
+/// Query parameter to include the full public key of peers instead of only the fingerprint.
+#[derive(Deserialize)]
+struct PeersQuery {
+    #[serde(default)]
+    full_key: bool,
+}
+
+/// Query parameters to filter and paginate the list of peers. Without parameters, all peers are
+/// listed.
+#[derive(Deserialize)]
+struct PeerListParams {
+    /// Include the full public key of peers instead of only the fingerprint.
+    #[serde(default)]
+    full_key: bool,
+    /// Only list peers of this type.
+    #[serde(rename = "type")]
+    peer_type: Option<PeerType>,
+    /// Only list peers in this connection state.
+    state: Option<ConnectionState>,
+    /// Amount of matching peers to skip.
+    #[serde(default)]
+    offset: usize,
+    /// Maximum amount of peers to list.
+    limit: Option<usize>,
+}
+
+/// Response header holding the amount of peers matching the filters, before pagination.
+const TOTAL_COUNT_HEADER: HeaderName = HeaderName::from_static("x-total-count");
+
+/// Get the stats of the current known peers, ordered by id. The total amount of peers matching the
+/// filters is returned in the [`TOTAL_COUNT_HEADER`], so clients can paginate.
+async fn get_peers(
+    State(state): State<HttpServerState>,
+    Query(params): Query<PeerListParams>,
+) -> impl IntoResponse {
     debug!("Fetching peer stats");
-    Json(state.peer_manager.peers())
+    let (peers, total) = select_peers(load_peers(&state.peer_manager, params.full_key), &params);
+    ([(TOTAL_COUNT_HEADER, total.to_string())], Json(peers))
+}
+
+/// Filter the peers on the type and state in the `params`, and select the requested page, in
+/// order of id. The total amount of peers matching the filter is returned as well.
+fn select_peers(mut peers: Vec<PeerStats>, params: &PeerListParams) -> (Vec<PeerStats>, usize) {
+    peers.retain(|peer| {
+        params.peer_type.as_ref().map_or(true, |pt| &peer.pt == pt)
+            && params
+                .state
+                .map_or(true, |state| peer.connection_state == state)
+    });
+    peers.sort_by_key(|peer| peer.id);
+    let total = peers.len();
+    let page = peers
+        .into_iter()
+        .skip(params.offset)
+        .take(params.limit.unwrap_or(usize::MAX))
+        .collect();
+    (page, total)
+}
+
+/// Load the stats of the current known peers. The full public key of the peers is only included
+/// if `full_key` is set.
+fn load_peers(peer_manager: &PeerManager, full_key: bool) -> Vec<PeerStats> {
+    let mut peers = peer_manager.peers();
+    if !full_key {
+        for peer in &mut peers {
+            peer.public_key = None;
+        }
+    }
+    peers
+}
+
+/// Message sent over the live peers feed.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum PeersFeedMessage {
+    /// All known peers, sent once when the feed is opened.
+    Snapshot { peers: Vec<PeerStats> },
+    /// A peer which is new, or of which the connection state changed.
+    Update { peer: PeerStats },
+    /// A peer which is no longer known.
+    Removed { endpoint: Endpoint },
+}
+
+/// Open a websocket which streams the known peers. All peers are sent once the websocket is
+/// opened, afterwards only peers which are added, have a connection event, or are removed are
+/// sent.
+async fn get_peers_feed(
+    State(state): State<HttpServerState>,
+    Query(query): Query<PeersQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    debug!("Opening live peers feed");
+    ws.on_upgrade(move |socket| {
+        stream_peers(state.peer_manager, query.full_key, state.shutdown, socket)
+    })
+}
+
+/// Stream the known peers over a websocket, until it is closed or the server shuts down.
+async fn stream_peers(
+    peer_manager: PeerManager,
+    full_key: bool,
+    shutdown: CancellationToken,
+    socket: WebSocket,
+) {
+    let (mut sender, mut receiver) = socket.split();
+
+    // Subscribe before loading the snapshot, so no change is missed.
+    let mut changes = peer_manager.subscribe_changes();
+    let peers = load_peers(&peer_manager, full_key);
+    if send_feed_message(&mut sender, &PeersFeedMessage::Snapshot { peers })
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(WsMessage::Close(_)) | Err(_)) | None => {
+                    debug!("Live peers feed closed");
+                    return;
+                }
+                // Nothing is expected from the client, other messages are ignored.
+                Some(Ok(_)) => {}
+            },
+            change = changes.recv() => {
+                let msg = match change {
+                    Ok(change) => match feed_message(change, load_peers(&peer_manager, full_key)) {
+                        Some(msg) => msg,
+                        None => continue,
+                    },
+                    // Changes were missed, so send the current state of all peers again.
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("Live peers feed missed {missed} changes, sending snapshot");
+                        PeersFeedMessage::Snapshot { peers: load_peers(&peer_manager, full_key) }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if send_feed_message(&mut sender, &msg).await.is_err() {
+                    return;
+                }
+            }
+            _ = shutdown.cancelled() => {
+                debug!("Closing live peers feed, server is shutting down");
+                let _ = sender.send(WsMessage::Close(None)).await;
+                return;
+            }
+        }
+    }
+}
+
+/// Send a message over the live peers feed.
+async fn send_feed_message(
+    sender: &mut SplitSink<WebSocket, WsMessage>,
+    msg: &PeersFeedMessage,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(msg).expect("Peers feed messages can be serialized; qed");
+    sender.send(WsMessage::Text(text)).await
+}
+
+/// The message to send over the live peers feed for a change of the peers. The stats of an
+/// updated peer are taken from the current `peers`. If the peer is not known anymore, nothing is
+/// sent, as its removal follows.
+fn feed_message(change: PeerChange, peers: Vec<PeerStats>) -> Option<PeersFeedMessage> {
+    match change {
+        PeerChange::Updated(endpoint) => peers
+            .into_iter()
+            .find(|peer| peer.endpoint == endpoint)
+            .map(|peer| PeersFeedMessage::Update { peer }),
+        PeerChange::Removed(endpoint) => Some(PeersFeedMessage::Removed { endpoint }),
+    }
+}
+
+/// Get aggregated stats of all known peers
+async fn get_peer_summary(State(state): State<HttpServerState>) -> Json<PeerSummary> {
+    debug!("Fetching peer summary");
+    Json(state.peer_manager.peer_summary())
+}
+
+/// Result of probing the connection to a single peer.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerProbeResult {
+    /// Average round trip time of the probes which were answered, in milliseconds. This is not
+    /// set if no probe was answered.
+    pub rtt_ms: Option<f64>,
+    /// Fraction of the probes which were not answered, as a value between 0 and 1.
+    pub loss: f64,
+}
+
+/// Actively probe every connected peer, and get the round trip time and loss of the connection
+/// to each of them, keyed by the connection identifier of the peer. At most
+/// [`PEER_PROBE_CONCURRENCY`] peers are probed at the same time.
+async fn probe_all_peers(
+    State(state): State<HttpServerState>,
+) -> Json<BTreeMap<String, PeerProbeResult>> {
+    debug!("Probing all connected peers");
+    let peers = state.router.lock().unwrap().peer_interfaces();
+
+    let results = futures::stream::iter(peers)
+        .map(|peer| async move {
+            let probe = crate::router::Router::probe_peer(&peer).await;
+            (
+                peer.connection_identifier().clone(),
+                PeerProbeResult {
+                    rtt_ms: probe.rtt.map(|rtt| rtt.as_secs_f64() * 1000.),
+                    loss: probe.loss,
+                },
+            )
+        })
+        .buffer_unordered(PEER_PROBE_CONCURRENCY)
+        .collect()
+        .await;
+
+    Json(results)
 }
 
 /// Payload of an add_peer request
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct AddPeer {
     /// The endpoint used to connect to the peer
     pub endpoint: String,
+    /// Optional cap on the outbound bandwidth to the peer, in bytes per second
+    #[serde(default)]
+    pub bandwidth_cap: Option<u64>,
+    /// Optional policy for reconnecting to the peer, the default policy is used if this is not
+    /// set
+    #[serde(default)]
+    pub reconnect: Option<ReconnectPolicy>,
 }
 
-/// Add a new peer to the system
+/// Response of a successful add_peer request
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddedPeer {
+    /// The endpoint of the new peer, as parsed from the request
+    pub endpoint: String,
+}
+
+/// Add a new static peer to the system
 async fn add_peer(
     State(state): State<HttpServerState>,
-    Json(payload): Json<AddPeer>,
-) -> Result<StatusCode, (StatusCode, String)> {
+    JsonBody(payload): JsonBody<AddPeer>,
+) -> Result<(StatusCode, Json<AddedPeer>), ApiError> {
     debug!("Attempting to add peer {} to  the system", payload.endpoint);
-    let endpoint = match Endpoint::from_str(&payload.endpoint) {
-        Ok(endpoint) => endpoint,
-        Err(e) => return Err((StatusCode::BAD_REQUEST, e.to_string())),
+    let endpoint = Endpoint::from_str(&payload.endpoint)
+        .map_err(|e| ApiError::new(ErrorCode::InvalidEndpoint, e))?;
+
+    let reconnect_policy = payload.reconnect.unwrap_or_default();
+    reconnect_policy
+        .validate()
+        .map_err(|e| ApiError::new(ErrorCode::InvalidReconnectPolicy, e))?;
+
+    let added = AddedPeer {
+        endpoint: endpoint.to_string(),
     };
+    state
+        .peer_manager
+        .add_peer(endpoint, payload.bandwidth_cap, reconnect_policy)?;
+    Ok((StatusCode::CREATED, Json(added)))
+}
 
-    match state.peer_manager.add_peer(endpoint) {
-        Ok(()) => Ok(StatusCode::NO_CONTENT),
-        Err(PeerExists) => Err((
-            StatusCode::CONFLICT,
-            "A peer identified by that endpoint already exists".to_string(),
-        )),
+/// Resolve the peer referred to in a request path, either by its short id or its full endpoint.
+fn resolve_peer(state: &HttpServerState, peer: &str) -> Result<Endpoint, ApiError> {
+    // An endpoint always contains a port, so it is never a valid id.
+    if let Ok(id) = peer.parse::<u64>() {
+        return state.peer_manager.endpoint_by_id(id).ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::PeerNotFound,
+                "A peer identified by that id does not exist",
+            )
+        });
     }
+    Endpoint::from_str(peer).map_err(|e| ApiError::new(ErrorCode::InvalidEndpoint, e))
 }
 
-/// remove an existing peer from the system
+/// remove an existing static peer from the system
 async fn delete_peer(
     State(state): State<HttpServerState>,
     Path(endpoint): Path<String>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> Result<StatusCode, ApiError> {
     debug!("Attempting to remove peer {} to  the system", endpoint);
-    let endpoint = match Endpoint::from_str(&endpoint) {
-        Ok(endpoint) => endpoint,
-        Err(e) => return Err((StatusCode::BAD_REQUEST, e.to_string())),
-    };
+    let endpoint = resolve_peer(&state, &endpoint)?;
 
     match state.peer_manager.delete_peer(&endpoint) {
         Ok(()) => Ok(StatusCode::NO_CONTENT),
-        Err(PeerNotFound) => Err((
-            StatusCode::NOT_FOUND,
-            "A peer identified by that endpoint does not exist".to_string(),
+        Err(DeletePeerError::NotFound) => Err(PeerNotFound.into()),
+        Err(DeletePeerError::NotStatic(pt)) => Err(ApiError::new(
+            ErrorCode::PeerNotStatic,
+            format!("Only static peers can be removed, this peer is {pt:?}"),
         )),
     }
 }
 
+/// Get the recent connection events of a peer, oldest first.
+async fn get_peer_events(
+    State(state): State<HttpServerState>,
+    Path(endpoint): Path<String>,
+) -> Result<Json<Vec<PeerEvent>>, ApiError> {
+    debug!("Loading connection events of peer {endpoint}");
+    let endpoint = resolve_peer(&state, &endpoint)?;
+
+    Ok(Json(state.peer_manager.peer_events(&endpoint)?))
+}
+
+/// The route import policy of a peer. Subnets are represented as strings in CIDR notation.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportPolicyInfo {
+    /// Prefixes which routes are accepted for. If this is empty, all prefixes are accepted.
+    #[serde(default)]
+    pub allowed: Vec<String>,
+    /// Prefixes which routes are never accepted for.
+    #[serde(default)]
+    pub denied: Vec<String>,
+    /// Amount of route updates from the peer which have been rejected by the policy. This is
+    /// ignored when setting the policy.
+    #[serde(default)]
+    pub rejected_updates: u64,
+}
+
+/// Get the route import policy of a peer
+async fn get_import_policy(
+    State(state): State<HttpServerState>,
+    Path(endpoint): Path<String>,
+) -> Result<Json<ImportPolicyInfo>, ApiError> {
+    debug!("Loading import policy of peer {endpoint}");
+    let endpoint = resolve_peer(&state, &endpoint)?;
+
+    let policy = state.peer_manager.import_policy(&endpoint)?;
+    let prefixes = policy.prefixes();
+    Ok(Json(ImportPolicyInfo {
+        allowed: prefixes.allowed.iter().map(ToString::to_string).collect(),
+        denied: prefixes.denied.iter().map(ToString::to_string).collect(),
+        rejected_updates: policy.rejected(),
+    }))
+}
+
+/// Replace the route import policy of a peer. This only applies to updates received afterwards,
+/// routes which are already installed are kept.
+async fn set_import_policy(
+    State(state): State<HttpServerState>,
+    Path(endpoint): Path<String>,
+    JsonBody(payload): JsonBody<ImportPolicyInfo>,
+) -> Result<StatusCode, ApiError> {
+    debug!("Setting import policy of peer {endpoint}");
+    let endpoint = resolve_peer(&state, &endpoint)?;
+    let parse = |subnets: &[String]| {
+        subnets
+            .iter()
+            .map(|subnet| {
+                subnet
+                    .parse::<Subnet>()
+                    .map_err(|e| ApiError::new(ErrorCode::InvalidSubnet, format!("{subnet}: {e}")))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let prefixes = PrefixLists {
+        allowed: parse(&payload.allowed)?,
+        denied: parse(&payload.denied)?,
+    };
+
+    state.peer_manager.set_import_policy(&endpoint, prefixes)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 /// Alias to a [`Metric`](crate::metric::Metric) for serialization in the API.
 pub enum Metric {
     /// Finite metric
@@ -159,6 +845,101 @@ pub enum Metric {
     Infinite,
 }
 
+/// A [`Metric`] serialized as an object, i.e. `{"value": 42}` or `{"infinite": true}`. This is
+/// easier to handle for strongly typed clients than the number or string union of [`Metric`].
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DetailedMetric {
+    /// Finite metric
+    Value(u16),
+    /// Infinite metric, always true
+    Infinite(bool),
+}
+
+/// Representation of a metric in API responses, selected by the client with the `metric_detail`
+/// query parameter.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum MetricRepr {
+    /// A number, or the string `infinite`. This is the default.
+    Union(Metric),
+    /// An object, see [`DetailedMetric`].
+    Detailed(DetailedMetric),
+}
+
+impl From<Metric> for DetailedMetric {
+    fn from(metric: Metric) -> Self {
+        match metric {
+            Metric::Value(v) => DetailedMetric::Value(v),
+            Metric::Infinite => DetailedMetric::Infinite(true),
+        }
+    }
+}
+
+impl MetricRepr {
+    /// Use the detailed representation if `detail` is set.
+    fn with_detail(self, detail: bool) -> Self {
+        match self {
+            MetricRepr::Union(metric) if detail => MetricRepr::Detailed(metric.into()),
+            repr => repr,
+        }
+    }
+}
+
+/// Identity of a directly connected neighbour.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NeighbourKey {
+    /// The public key of the neighbour, in lowercase hex.
+    pub public_key: PublicKey,
+    /// The overlay address of the neighbour, derived from its public key.
+    pub address: IpAddr,
+}
+
+/// Get the public key and overlay address of all directly connected neighbours, keyed by the
+/// connection identifier of the neighbour.
+async fn get_neighbour_keys(
+    State(state): State<HttpServerState>,
+) -> Json<BTreeMap<String, NeighbourKey>> {
+    debug!("Loading neighbour keys");
+    let keys = state
+        .router
+        .lock()
+        .unwrap()
+        .neighbour_keys()
+        .into_iter()
+        .map(|(connection_identifier, public_key)| {
+            (
+                connection_identifier,
+                NeighbourKey {
+                    public_key,
+                    address: public_key.address().into(),
+                },
+            )
+        })
+        .collect();
+
+    Json(keys)
+}
+
+/// Get the connection details of the directly connected neighbour with the given public key.
+async fn get_neighbour(
+    State(state): State<HttpServerState>,
+    Path(public_key): Path<PublicKey>,
+) -> Result<Json<PeerStats>, ApiError> {
+    debug!("Looking up neighbour {public_key}");
+    state
+        .peer_manager
+        .neighbour(&public_key)
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::NeighbourNotFound,
+                "No directly connected neighbour has that public key",
+            )
+        })
+}
+
 /// Info about a route. This uses base types only to avoid having to introduce too many Serialize
 /// bounds in the core types.
 #[derive(Serialize)]
@@ -170,59 +951,430 @@ pub struct Route {
     /// Next hop of the route, in the underlay.
     pub next_hop: String,
     /// Computed metric of the route.
-    pub metric: Metric,
+    pub metric: MetricRepr,
     /// Sequence number of the route.
     pub seqno: u16,
+    /// Amount of times the selected next hop for the subnet changed since a route to the subnet
+    /// first appeared.
+    pub reselect_count: u64,
+}
+
+impl Route {
+    /// Convert a [`RouteEntry`] of the router to a `Route`, including the reselect count of its
+    /// subnet. The metric is serialized as an object if `metric_detail` is set.
+    fn load(router: &crate::router::Router, re: RouteEntry, metric_detail: bool) -> Self {
+        let reselect_count = router.reselect_count(re.source().subnet());
+        Route {
+            reselect_count,
+            ..Route::from(re)
+        }
+        .with_metric_detail(metric_detail)
+    }
+
+    /// Serialize the metric of the route as an object if `detail` is set.
+    fn with_metric_detail(self, detail: bool) -> Self {
+        Route {
+            metric: self.metric.with_detail(detail),
+            ..self
+        }
+    }
+}
+
+impl From<RouteEntry> for Route {
+    fn from(re: RouteEntry) -> Self {
+        Route {
+            subnet: re.source().subnet().to_string(),
+            next_hop: re.neighbour().connection_identifier().clone(),
+            metric: MetricRepr::Union(if re.metric().is_infinite() {
+                Metric::Infinite
+            } else {
+                Metric::Value(re.metric().into())
+            }),
+            seqno: re.seqno().into(),
+            reselect_count: 0,
+        }
+    }
+}
+
+/// Whether a route is currently selected, or kept as fallback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RouteKind {
+    /// The route is selected and used to forward packets.
+    Selected,
+    /// The route is not selected, but available in case the selected route fails.
+    Fallback,
+}
+
+/// Info about a route, and whether it is selected or a fallback route.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteWithKind {
+    /// The kind of route.
+    pub kind: RouteKind,
+    /// The route itself.
+    #[serde(flatten)]
+    pub route: Route,
+}
+
+/// Query parameters of the requests which list routes. The kind only applies when listing all
+/// routes, since the other lists only hold routes of a single kind.
+#[derive(Deserialize)]
+struct RoutesQuery {
+    /// Only list routes of this kind, if set.
+    kind: Option<RouteKind>,
+    /// Serialize metrics as objects instead of a number or string.
+    #[serde(default)]
+    metric_detail: bool,
+    /// Only list routes with at least this metric, if set.
+    #[serde(default, deserialize_with = "deserialize_metric_bound")]
+    min_metric: Option<crate::metric::Metric>,
+    /// Only list routes with at most this metric, if set.
+    #[serde(default, deserialize_with = "deserialize_metric_bound")]
+    max_metric: Option<crate::metric::Metric>,
+}
+
+/// Inclusive bounds on the metric of listed routes. The infinite metric is higher than any finite
+/// metric, so infinite routes are only listed if there is no upper bound, or the upper bound is
+/// infinite.
+#[derive(Debug, Clone, Copy, Default)]
+struct MetricBand {
+    min: Option<crate::metric::Metric>,
+    max: Option<crate::metric::Metric>,
+}
+
+impl MetricBand {
+    /// Check if the given metric is within the band.
+    fn contains(&self, metric: crate::metric::Metric) -> bool {
+        self.min.map_or(true, |min| metric >= min) && self.max.map_or(true, |max| metric <= max)
+    }
+}
+
+impl RoutesQuery {
+    /// The metric band of routes to list.
+    fn band(&self) -> MetricBand {
+        MetricBand {
+            min: self.min_metric,
+            max: self.max_metric,
+        }
+    }
+}
+
+/// Deserialize a bound on a metric from a query parameter, which is either a number or
+/// `infinite`.
+fn deserialize_metric_bound<'de, D>(
+    deserializer: D,
+) -> Result<Option<crate::metric::Metric>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let bound = String::deserialize(deserializer)?;
+    if bound.eq_ignore_ascii_case("infinite") {
+        return Ok(Some(crate::metric::Metric::infinite()));
+    }
+    bound
+        .parse::<u16>()
+        .map(|value| Some(crate::metric::Metric::new(value)))
+        .map_err(|_| {
+            serde::de::Error::custom(format!(
+                "invalid metric {bound}, expected a number or \"infinite\""
+            ))
+        })
+}
+
+/// List all routes, both selected and fallback, optionally only those of a specific kind.
+async fn get_routes(
+    State(state): State<HttpServerState>,
+    Query(query): Query<RoutesQuery>,
+) -> Json<Vec<RouteWithKind>> {
+    debug!("Loading routes, kind {:?}", query.kind);
+    let band = query.band();
+    let router = state.router.lock().unwrap();
+    let mut routes = Vec::new();
+    if matches!(query.kind, None | Some(RouteKind::Selected)) {
+        routes.extend(
+            router
+                .load_selected_routes()
+                .into_iter()
+                .filter(|re| band.contains(re.metric()))
+                .map(|re| RouteWithKind {
+                    kind: RouteKind::Selected,
+                    route: Route::load(&router, re, query.metric_detail),
+                }),
+        );
+    }
+    if matches!(query.kind, None | Some(RouteKind::Fallback)) {
+        routes.extend(
+            router
+                .load_fallback_routes()
+                .into_iter()
+                .filter(|re| band.contains(re.metric()))
+                .map(|re| RouteWithKind {
+                    kind: RouteKind::Fallback,
+                    route: Route::load(&router, re, query.metric_detail),
+                }),
+        );
+    }
+
+    Json(routes)
 }
 
 /// List all currently selected routes.
-async fn get_selected_routes(State(state): State<HttpServerState>) -> Json<Vec<Route>> {
+async fn get_selected_routes(
+    State(state): State<HttpServerState>,
+    Query(query): Query<RoutesQuery>,
+) -> Json<Vec<Route>> {
     debug!("Loading selected routes");
-    let routes = state
-        .router
-        .lock()
-        .unwrap()
+    let band = query.band();
+    let router = state.router.lock().unwrap();
+    let routes = router
         .load_selected_routes()
         .into_iter()
-        .map(|sr| Route {
-            subnet: sr.source().subnet().to_string(),
-            next_hop: sr.neighbour().connection_identifier().clone(),
-            metric: if sr.metric().is_infinite() {
-                Metric::Infinite
-            } else {
-                Metric::Value(sr.metric().into())
-            },
-            seqno: sr.seqno().into(),
-        })
+        .filter(|re| band.contains(re.metric()))
+        .map(|re| Route::load(&router, re, query.metric_detail))
         .collect();
 
     Json(routes)
 }
 
+/// Query parameters of the get selected route request.
+#[derive(Deserialize)]
+struct RouteQuery {
+    /// Serialize the metric as an object instead of a number or string.
+    #[serde(default)]
+    metric_detail: bool,
+}
+
+/// Get the selected route for exactly the given subnet.
+async fn get_selected_route(
+    State(state): State<HttpServerState>,
+    Path(subnet): Path<String>,
+    Query(query): Query<RouteQuery>,
+) -> Result<Json<Route>, ApiError> {
+    debug!("Loading selected route for {subnet}");
+    let subnet = subnet
+        .parse::<Subnet>()
+        .map_err(|e| ApiError::new(ErrorCode::InvalidSubnet, format!("{subnet}: {e}")))?;
+
+    let router = state.router.lock().unwrap();
+    let re = router.load_selected_route(subnet).ok_or_else(|| {
+        ApiError::new(
+            ErrorCode::RouteNotFound,
+            "No route is selected for that subnet",
+        )
+    })?;
+
+    Ok(Json(Route::load(&router, re, query.metric_detail)))
+}
+
 /// List all active fallback routes.
-async fn get_fallback_routes(State(state): State<HttpServerState>) -> Json<Vec<Route>> {
+async fn get_fallback_routes(
+    State(state): State<HttpServerState>,
+    Query(query): Query<RoutesQuery>,
+) -> Json<Vec<Route>> {
     debug!("Loading fallback routes");
-    let routes = state
-        .router
-        .lock()
-        .unwrap()
+    let band = query.band();
+    let router = state.router.lock().unwrap();
+    let routes = router
         .load_fallback_routes()
         .into_iter()
-        .map(|sr| Route {
-            subnet: sr.source().subnet().to_string(),
-            next_hop: sr.neighbour().connection_identifier().clone(),
-            metric: if sr.metric().is_infinite() {
-                Metric::Infinite
-            } else {
-                Metric::Value(sr.metric().into())
-            },
-            seqno: sr.seqno().into(),
-        })
+        .filter(|re| band.contains(re.metric()))
+        .map(|re| Route::load(&router, re, query.metric_detail))
         .collect();
 
     Json(routes)
 }
 
+/// Result of forcing route selection.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReselectResult {
+    /// Amount of subnets for which a different route is selected.
+    pub changed: usize,
+}
+
+/// Force route selection for all subnets in the routing table.
+async fn reselect_routes(State(state): State<HttpServerState>) -> Json<ReselectResult> {
+    debug!("Forcing route selection");
+    Json(ReselectResult {
+        changed: state.router.lock().unwrap().reselect_routes(),
+    })
+}
+
+/// A hypothetical change to simulate the effect of on the selected routes.
+#[derive(Deserialize)]
+#[serde(tag = "change", rename_all = "camelCase")]
+pub enum RouteChange {
+    /// A peer goes down, identified by its short id or its endpoint.
+    #[serde(rename_all = "camelCase")]
+    RemovePeer {
+        /// The peer which goes down.
+        peer: String,
+    },
+}
+
+/// Get the selected routes as they would be after the given change, without applying it.
+async fn simulate_routes(
+    State(state): State<HttpServerState>,
+    Query(query): Query<RoutesQuery>,
+    JsonBody(change): JsonBody<RouteChange>,
+) -> Result<Json<Vec<Route>>, ApiError> {
+    let RouteChange::RemovePeer { peer } = change;
+    debug!("Simulating removal of peer {peer}");
+    let endpoint = resolve_peer(&state, &peer)?;
+    let peer = state.peer_manager.connected_peer(&endpoint)?;
+
+    let router = state.router.lock().unwrap();
+    // A peer which is not connected has no routes, so removing it changes nothing.
+    let routes = match peer {
+        Some(peer) => router.simulate_peer_removal(&peer),
+        None => router.load_selected_routes(),
+    };
+
+    let band = query.band();
+    Ok(Json(
+        routes
+            .into_iter()
+            .filter(|re| band.contains(re.metric()))
+            .map(|re| Route::load(&router, re, query.metric_detail))
+            .collect(),
+    ))
+}
+
+/// Statistics about the routing table.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteStats {
+    /// Total amount of routes in the routing table.
+    pub total: usize,
+    /// Amount of selected routes.
+    pub selected: usize,
+    /// Amount of fallback routes.
+    pub fallback: usize,
+    /// The maximum amount of routes kept in the routing table, if limited.
+    pub max_routes: Option<usize>,
+    /// Amount of fallback routes evicted to make room for new routes.
+    pub evictions: u64,
+}
+
+/// Get statistics about the routing table.
+async fn get_route_stats(State(state): State<HttpServerState>) -> Json<RouteStats> {
+    debug!("Loading route stats");
+    let stats = state.router.lock().unwrap().route_stats();
+
+    Json(RouteStats {
+        total: stats.total,
+        selected: stats.selected,
+        fallback: stats.fallback,
+        max_routes: stats.max_routes,
+        evictions: stats.evictions,
+    })
+}
+
+/// List recent events of things which have been dropped or rejected, oldest first.
+async fn get_drop_events(State(state): State<HttpServerState>) -> Json<Vec<DropEvent>> {
+    debug!("Loading drop events");
+    Json(state.router.lock().unwrap().drop_events().recent())
+}
+
+/// List all alert thresholds which are currently breached.
+async fn get_alerts(State(state): State<HttpServerState>) -> Json<Vec<Alert>> {
+    debug!("Evaluating alerts");
+    let window = Duration::from_secs(ALERT_WINDOW_SECS);
+    let counters = {
+        let router = state.router.lock().unwrap();
+        AlertCounters {
+            disconnects_per_minute: state.peer_manager.disconnects_within(window),
+            drops_per_minute: router.drop_events().count_within(window),
+            route_table_size: router.route_stats().total,
+        }
+    };
+    Json(state.alert_thresholds.lock().unwrap().evaluate(&counters))
+}
+
+/// Get the currently configured alert thresholds.
+async fn get_alert_thresholds(State(state): State<HttpServerState>) -> Json<AlertThresholds> {
+    debug!("Loading alert thresholds");
+    Json(*state.alert_thresholds.lock().unwrap())
+}
+
+/// Replace the alert thresholds. Thresholds which are not set are disabled.
+async fn set_alert_thresholds(
+    State(state): State<HttpServerState>,
+    JsonBody(thresholds): JsonBody<AlertThresholds>,
+) -> StatusCode {
+    debug!("Setting alert thresholds to {thresholds:?}");
+    *state.alert_thresholds.lock().unwrap() = thresholds;
+    StatusCode::NO_CONTENT
+}
+
+/// An error which happened in a client of the API, e.g. a failed request or a crash of the UI.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientError {
+    /// Description of the error.
+    pub message: String,
+    /// Optional part of the client where the error happened, e.g. `fetch` or `render`.
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Optional location in the client where the error happened, e.g. the page which was open.
+    #[serde(default)]
+    pub location: Option<String>,
+}
+
+/// Truncate a field of a client error report to at most `max_size` bytes, and escape it, so a
+/// client can't forge log lines or inject terminal escape sequences.
+fn sanitize_client_field(field: &str, max_size: usize) -> String {
+    let mut end = field.len().min(max_size);
+    while !field.is_char_boundary(end) {
+        end -= 1;
+    }
+    field[..end].escape_debug().to_string()
+}
+
+/// Log an error reported by a client at warn level. Reports beyond the rate limit are rejected
+/// with a `429 Too Many Requests`.
+async fn report_client_error(
+    State(state): State<HttpServerState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    JsonBody(report): JsonBody<ClientError>,
+) -> StatusCode {
+    debug!("Client reported an error");
+    let Some(rejected) = state
+        .client_log_limit
+        .lock()
+        .unwrap()
+        .sample(Instant::now())
+    else {
+        return StatusCode::TOO_MANY_REQUESTS;
+    };
+
+    let remote = connect_info
+        .map(|ConnectInfo(addr)| addr.to_string())
+        .unwrap_or_default();
+    warn!(
+        "[client {remote}] {}{}: {}{}",
+        report
+            .source
+            .map(|source| sanitize_client_field(&source, MAX_CLIENT_LOG_FIELD_SIZE))
+            .unwrap_or_else(|| "error".to_string()),
+        report
+            .location
+            .map(|location| format!(
+                " at {}",
+                sanitize_client_field(&location, MAX_CLIENT_LOG_FIELD_SIZE)
+            ))
+            .unwrap_or_default(),
+        sanitize_client_field(&report.message, MAX_CLIENT_LOG_MESSAGE_SIZE),
+        if rejected > 0 {
+            format!(" ({rejected} earlier reports not logged)")
+        } else {
+            String::new()
+        },
+    );
+    StatusCode::NO_CONTENT
+}
+
 /// General info about a node.
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -238,6 +1390,234 @@ async fn get_info(State(state): State<HttpServerState>) -> Json<Info> {
     })
 }
 
+/// Summary of the state of a node, for an at a glance overview. Every section is optional, so
+/// clients must handle sections which are left out.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dashboard {
+    /// The overlay subnet in use by the node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_subnet: Option<String>,
+    /// The public key of the node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_pubkey: Option<PublicKey>,
+    /// Aggregated statistics of all known peers, including the total traffic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peers: Option<PeerSummary>,
+    /// Statistics about the routing table.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routes: Option<RouteStats>,
+    /// Amount of messages waiting to be sent or read.
+    #[cfg(feature = "message")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub messages: Option<MessageQueueDepth>,
+    /// Amount of data packets for this node which could not be decrypted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decryption_failures: Option<u64>,
+    /// Amount of seconds since the node started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+}
+
+/// Amount of messages waiting to be sent or read.
+#[cfg(feature = "message")]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageQueueDepth {
+    /// Amount of outbound messages which are still being sent.
+    pub outbound: usize,
+    /// Amount of inbound messages waiting to be read.
+    pub inbound: usize,
+}
+
+/// Get a summary of the state of the node in a single response.
+async fn get_dashboard(State(state): State<HttpServerState>) -> Json<Dashboard> {
+    debug!("Loading dashboard");
+    let router = state.router.lock().unwrap();
+    let stats = router.route_stats();
+
+    Json(Dashboard {
+        node_subnet: Some(router.node_tun_subnet().to_string()),
+        node_pubkey: Some(router.node_public_key()),
+        peers: Some(state.peer_manager.peer_summary()),
+        routes: Some(RouteStats {
+            total: stats.total,
+            selected: stats.selected,
+            fallback: stats.fallback,
+            max_routes: stats.max_routes,
+            evictions: stats.evictions,
+        }),
+        #[cfg(feature = "message")]
+        messages: Some(MessageQueueDepth {
+            outbound: state.message_stack.outbound_pending(),
+            inbound: state.message_stack.inbound_queued(),
+        }),
+        decryption_failures: Some(
+            router
+                .packet_drop_log()
+                .count(PacketDropReason::DecryptionFailure),
+        ),
+        uptime_secs: Some(router.uptime().as_secs()),
+    })
+}
+
+/// The identity of the router of a node.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouterIdInfo {
+    /// The hex encoded router id, exactly as it is advertised in route updates.
+    pub router_id: String,
+    /// The public key of the node, which is the first part of the router id.
+    pub public_key: PublicKey,
+}
+
+/// Get the router id the node advertises its routes with.
+async fn get_router_id(State(state): State<HttpServerState>) -> Json<RouterIdInfo> {
+    debug!("Loading router id");
+    let router_id = state.router.lock().unwrap().router_id();
+    Json(RouterIdInfo {
+        router_id: router_id.as_hex(),
+        public_key: router_id.to_pubkey(),
+    })
+}
+
+/// Information about the build of the running binary.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    /// Version of the crate.
+    pub version: &'static str,
+    /// Short hash of the git commit the binary was built from, or "unknown".
+    pub git_commit: &'static str,
+    /// Version of the compiler the binary was built with, or "unknown".
+    pub rustc_version: &'static str,
+    /// The cargo features enabled in the build.
+    pub features: Vec<&'static str>,
+}
+
+/// Get information about the build of the running binary.
+async fn get_build() -> Json<BuildInfo> {
+    debug!("Fetching build info");
+    Json(BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("MYCELIUM_GIT_COMMIT"),
+        rustc_version: env!("MYCELIUM_RUSTC_VERSION"),
+        features: [
+            ("message", cfg!(feature = "message")),
+            ("http-api", cfg!(feature = "http-api")),
+            (
+                "deterministic-message-ids",
+                cfg!(feature = "deterministic-message-ids"),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(feature, enabled)| enabled.then_some(feature))
+        .collect(),
+    })
+}
+
+/// Health of a node.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    /// Whether the node finished its initial route convergence. This is distinct from liveness,
+    /// which is indicated by the endpoint responding at all.
+    pub ready: bool,
+}
+
+/// Get the health of the node.
+async fn get_health(State(state): State<HttpServerState>) -> Json<Health> {
+    debug!("Checking node health");
+    Json(Health {
+        ready: state.router.lock().unwrap().ready(),
+    })
+}
+
+/// Liveness of a node.
+#[derive(Serialize)]
+pub struct Liveness {
+    /// Always "ok", the node is alive if it responds at all.
+    pub status: &'static str,
+    /// Amount of seconds since the node started.
+    pub uptime_secs: u64,
+}
+
+/// Check if the node is alive. This does not lock the router, so a busy router does not make the
+/// node look dead.
+async fn get_liveness(State(state): State<HttpServerState>) -> Json<Liveness> {
+    debug!("Checking node liveness");
+    Json(Liveness {
+        status: "ok",
+        uptime_secs: state.router_status.uptime().as_secs(),
+    })
+}
+
+/// Readiness of a node to carry traffic.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    /// Whether the node finished its initial route convergence.
+    pub ready: bool,
+}
+
+/// Check if the node can carry traffic, i.e. it selected the minimum amount of routes required
+/// for readiness, see [`RouterStatus::ready`]. Returns `503 Service Unavailable` if it can't. This
+/// does not lock the router.
+async fn get_readiness(State(state): State<HttpServerState>) -> impl IntoResponse {
+    debug!("Checking node readiness");
+    let ready = state.router_status.ready();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(Readiness { ready }))
+}
+
+/// Effective configuration of the node.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeConfig {
+    /// Configuration of the link local peer discovery.
+    pub peer_discovery: PeerDiscoveryConfig,
+    /// The maximum amount of routes kept in the routing table, if limited.
+    pub max_routes: Option<usize>,
+    /// The maximum metric of a route which can be selected, if limited.
+    pub max_metric: Option<u16>,
+    /// Maximum amount of data packets queued to be sent to a single peer.
+    pub peer_data_queue_size: usize,
+    /// Maximum amount of outbound connection attempts to peers running at the same time.
+    pub max_concurrent_dials: usize,
+    /// Amount of seconds after which inbound peers which don't exchange data are disconnected,
+    /// if set.
+    pub inbound_idle_timeout_secs: Option<u64>,
+    /// Percentage by which the intervals of periodic control packets are randomly shortened.
+    pub control_jitter_percent: u8,
+    #[cfg(feature = "message")]
+    /// Amount of seconds to try and send a message if it is not explicitly specified.
+    pub message_try_duration_secs: u64,
+}
+
+/// Get the effective configuration of the node.
+async fn get_config(State(state): State<HttpServerState>) -> Json<NodeConfig> {
+    debug!("Loading node config");
+    let router = state.router.lock().unwrap();
+    Json(NodeConfig {
+        peer_discovery: state.peer_manager.discovery_config(),
+        max_routes: router.max_routes(),
+        max_metric: router.max_metric().map(Into::into),
+        peer_data_queue_size: state.peer_manager.data_queue_size().get(),
+        max_concurrent_dials: state.peer_manager.max_concurrent_dials().get(),
+        inbound_idle_timeout_secs: state
+            .peer_manager
+            .inbound_idle_timeout()
+            .map(|timeout| timeout.as_secs()),
+        control_jitter_percent: router.control_jitter(),
+        #[cfg(feature = "message")]
+        message_try_duration_secs: state.message_try_duration.get().as_secs(),
+    })
+}
+
 impl Serialize for Metric {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -252,6 +1632,209 @@ impl Serialize for Metric {
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use axum::{
+        body::Body,
+        extract::{FromRequest, Request},
+        http::{header, HeaderMap, HeaderValue},
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use super::{BodyFormat, ErrorCode, HttpServerState, JsonBody};
+    use crate::{events::SampleWindow, testing};
+
+    /// Create the state of the API of a fresh node, which is not connected to any peer. Messages
+    /// can have a payload of at most 1 KiB.
+    pub(super) fn server_state() -> HttpServerState {
+        let (router, _, _) = testing::router(None);
+        HttpServerState {
+            router_status: router.status(),
+            peer_manager: testing::peer_manager(router.clone()),
+            router: Arc::new(Mutex::new(router)),
+            #[cfg(feature = "message")]
+            message_stack: crate::message::MessageStack::new(
+                testing::data_plane(),
+                tokio_stream::pending(),
+                false,
+            ),
+            alert_thresholds: Arc::default(),
+            #[cfg(feature = "message")]
+            message_try_duration: super::message::MessageTryDuration::default(),
+            #[cfg(feature = "message")]
+            max_payload_size: 1024,
+            shutdown: CancellationToken::new(),
+            client_log_limit: Arc::new(Mutex::new(SampleWindow::new(
+                super::CLIENT_LOG_WINDOW,
+                super::CLIENT_LOG_RATE,
+                Instant::now(),
+            ))),
+            metric_labels: Arc::new([]),
+        }
+    }
+
+    #[tokio::test]
+    async fn peers_are_resolved_by_id_or_endpoint() {
+        let state = server_state();
+        let endpoint = "tcp://[2001:db8::1]:9651"
+            .parse()
+            .expect("Valid endpoint; qed");
+        state
+            .peer_manager
+            .add_peer(endpoint, None, Default::default())
+            .expect("Peer is new");
+        let id = state
+            .peer_manager
+            .peers()
+            .first()
+            .expect("Peer is known")
+            .id;
+
+        assert_eq!(
+            super::resolve_peer(&state, &id.to_string()).expect("Id is known"),
+            endpoint
+        );
+        assert_eq!(
+            super::resolve_peer(&state, "tcp://[2001:db8::1]:9651").expect("Valid endpoint"),
+            endpoint
+        );
+        assert_eq!(
+            super::resolve_peer(&state, &(id + 1).to_string())
+                .expect_err("Id is not known")
+                .code(),
+            ErrorCode::PeerNotFound
+        );
+        assert_eq!(
+            super::resolve_peer(&state, "not a peer")
+                .expect_err("Invalid endpoint")
+                .code(),
+            ErrorCode::InvalidEndpoint
+        );
+    }
+
+    #[test]
+    fn api_prefix_includes_base_path() {
+        assert_eq!(super::api_prefix(None), "/api/v1");
+        assert_eq!(super::api_prefix(Some("/")), "/api/v1");
+        assert_eq!(super::api_prefix(Some("/mycelium")), "/mycelium/api/v1");
+        assert_eq!(super::api_prefix(Some("mycelium/")), "/mycelium/api/v1");
+        assert_eq!(super::api_prefix(Some("/a/b")), "/a/b/api/v1");
+    }
+
+    #[test]
+    fn body_format_is_negotiated() {
+        let headers = |name, value| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert_eq!(BodyFormat::of_request(&HeaderMap::new()), BodyFormat::Json);
+        assert_eq!(
+            BodyFormat::of_request(&headers(header::CONTENT_TYPE, "application/json")),
+            BodyFormat::Json
+        );
+        assert_eq!(
+            BodyFormat::of_request(&headers(header::CONTENT_TYPE, "Application/CBOR")),
+            BodyFormat::Cbor
+        );
+
+        assert_eq!(BodyFormat::accepted(&HeaderMap::new()), BodyFormat::Json);
+        assert_eq!(
+            BodyFormat::accepted(&headers(header::ACCEPT, "*/*")),
+            BodyFormat::Json
+        );
+        assert_eq!(
+            BodyFormat::accepted(&headers(
+                header::ACCEPT,
+                "application/json;q=0.5, application/cbor"
+            )),
+            BodyFormat::Cbor
+        );
+    }
+
+    #[tokio::test]
+    async fn json_body_is_rejected_with_api_error() {
+        let request = |body: Vec<u8>| {
+            Request::builder()
+                .method("POST")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .expect("Valid request; qed")
+        };
+
+        let JsonBody(value) = JsonBody::<Vec<u8>>::from_request(request(b"[1,2]".to_vec()), &())
+            .await
+            .expect("valid body is extracted");
+        assert_eq!(value, [1, 2]);
+
+        let err = JsonBody::<Vec<u8>>::from_request(request(b"[1,".to_vec()), &())
+            .await
+            .err()
+            .expect("invalid body is rejected");
+        assert_eq!(err.code(), ErrorCode::InvalidBody);
+
+        // Without a body limit layer, axum limits bodies to 2 MiB.
+        let err = JsonBody::<Vec<u8>>::from_request(request(vec![b' '; 2 * 1024 * 1024 + 1]), &())
+            .await
+            .err()
+            .expect("too large body is rejected");
+        assert_eq!(err.code(), ErrorCode::PayloadTooLarge);
+    }
+
+    #[test]
+    fn client_errors_are_rate_limited() {
+        let start = std::time::Instant::now();
+        let mut limit = crate::events::SampleWindow::new(
+            super::CLIENT_LOG_WINDOW,
+            super::CLIENT_LOG_RATE,
+            start,
+        );
+
+        for _ in 0..super::CLIENT_LOG_RATE {
+            assert_eq!(limit.sample(start), Some(0));
+        }
+        assert_eq!(limit.sample(start), None);
+        assert_eq!(limit.sample(start + super::CLIENT_LOG_WINDOW / 2), None);
+
+        // A new window admits errors again, and reports how many were rejected.
+        let next = start + super::CLIENT_LOG_WINDOW;
+        assert_eq!(limit.sample(next), Some(2));
+        assert_eq!(limit.sample(next), Some(0));
+    }
+
+    #[test]
+    fn client_error_fields_are_escaped_and_truncated() {
+        assert_eq!(
+            super::sanitize_client_field("line\n[client 1.2.3.4] forged\u{1b}[2J", 1024),
+            "line\\n[client 1.2.3.4] forged\\u{1b}[2J"
+        );
+        assert_eq!(super::sanitize_client_field("abcdef", 4), "abcd");
+        // Truncation does not split a character.
+        assert_eq!(super::sanitize_client_field("aé", 2), "a");
+    }
+
+    #[tokio::test]
+    async fn listener_binds_wildcard_with_family_control() {
+        for v6_only in [true, false] {
+            // This needs IPv6 support on the host.
+            let listener = super::bind_listener("[::]:0".parse().unwrap(), v6_only)
+                .expect("Can bind IPv6 wildcard address");
+            let addr = listener.local_addr().expect("Listener has a local address");
+
+            assert!(addr.is_ipv6());
+            assert!(addr.ip().is_unspecified());
+            assert_ne!(addr.port(), 0);
+            assert_eq!(
+                socket2::SockRef::from(&listener).only_v6().unwrap(),
+                v6_only
+            );
+        }
+    }
+
     #[test]
     fn finite_metric_serialization() {
         let metric = super::Metric::Value(10);
@@ -267,4 +1850,213 @@ mod tests {
 
         assert_eq!("\"infinite\"", s);
     }
+
+    #[test]
+    fn metric_repr_defaults_to_union() {
+        let finite = super::MetricRepr::Union(super::Metric::Value(42)).with_detail(false);
+        let infinite = super::MetricRepr::Union(super::Metric::Infinite).with_detail(false);
+
+        assert_eq!("42", serde_json::to_string(&finite).unwrap());
+        assert_eq!("\"infinite\"", serde_json::to_string(&infinite).unwrap());
+    }
+
+    #[test]
+    fn detailed_metric_serialization() {
+        let finite = super::MetricRepr::Union(super::Metric::Value(42)).with_detail(true);
+        let infinite = super::MetricRepr::Union(super::Metric::Infinite).with_detail(true);
+
+        assert_eq!(
+            r#"{"value":42}"#,
+            serde_json::to_string(&finite).expect("can encode detailed finite metric")
+        );
+        assert_eq!(
+            r#"{"infinite":true}"#,
+            serde_json::to_string(&infinite).expect("can encode detailed infinite metric")
+        );
+    }
+
+    #[test]
+    fn sources_are_checked_against_allowed_subnets() {
+        use crate::subnet::Subnet;
+
+        let allowed: Vec<Subnet> = vec!["10.0.0.0/8".parse().unwrap(), "400::/7".parse().unwrap()];
+
+        assert!(super::source_allowed(
+            &allowed,
+            Some("10.1.2.3".parse().unwrap())
+        ));
+        assert!(super::source_allowed(
+            &allowed,
+            Some("::ffff:10.1.2.3".parse().unwrap())
+        ));
+        assert!(super::source_allowed(
+            &allowed,
+            Some("4aa::1".parse().unwrap())
+        ));
+        assert!(!super::source_allowed(
+            &allowed,
+            Some("192.168.1.1".parse().unwrap())
+        ));
+        assert!(!super::source_allowed(
+            &allowed,
+            Some("2001:db8::1".parse().unwrap())
+        ));
+    }
+
+    #[test]
+    fn sources_are_allowed_without_subnets_or_remote() {
+        use crate::subnet::Subnet;
+
+        let allowed: Vec<Subnet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        assert!(super::source_allowed(
+            &[],
+            Some("192.168.1.1".parse().unwrap())
+        ));
+        assert!(super::source_allowed(&allowed, None));
+    }
+
+    #[test]
+    fn metric_band_boundaries_are_inclusive() {
+        use crate::metric::Metric;
+
+        let band = super::MetricBand {
+            min: Some(Metric::new(10)),
+            max: Some(Metric::new(20)),
+        };
+
+        assert!(!band.contains(Metric::new(9)));
+        assert!(band.contains(Metric::new(10)));
+        assert!(band.contains(Metric::new(15)));
+        assert!(band.contains(Metric::new(20)));
+        assert!(!band.contains(Metric::new(21)));
+        assert!(!band.contains(Metric::infinite()));
+    }
+
+    #[test]
+    fn infinite_metric_only_included_without_finite_max() {
+        use crate::metric::Metric;
+
+        let unbounded = super::MetricBand::default();
+        let min_only = super::MetricBand {
+            min: Some(Metric::new(100)),
+            max: None,
+        };
+        let infinite_max = super::MetricBand {
+            min: None,
+            max: Some(Metric::infinite()),
+        };
+        let finite_max = super::MetricBand {
+            min: None,
+            max: Some(Metric::new(u16::MAX - 1)),
+        };
+
+        assert!(unbounded.contains(Metric::new(0)));
+        assert!(unbounded.contains(Metric::infinite()));
+        assert!(!min_only.contains(Metric::new(99)));
+        assert!(min_only.contains(Metric::infinite()));
+        assert!(infinite_max.contains(Metric::infinite()));
+        assert!(finite_max.contains(Metric::new(u16::MAX - 1)));
+        assert!(!finite_max.contains(Metric::infinite()));
+    }
+
+    #[test]
+    fn metric_bounds_are_parsed_from_query() {
+        use axum::{extract::Query, http::Uri};
+
+        use crate::metric::Metric;
+
+        let uri: Uri = "/api/v1/admin/routes?min_metric=5&max_metric=infinite"
+            .parse()
+            .unwrap();
+        let query = Query::<super::RoutesQuery>::try_from_uri(&uri).expect("valid query");
+        assert_eq!(query.min_metric, Some(Metric::new(5)));
+        assert_eq!(query.max_metric, Some(Metric::infinite()));
+
+        let uri: Uri = "/api/v1/admin/routes/selected".parse().unwrap();
+        let query = Query::<super::RoutesQuery>::try_from_uri(&uri).expect("valid query");
+        assert_eq!(query.min_metric, None);
+        assert_eq!(query.max_metric, None);
+
+        let uri: Uri = "/api/v1/admin/routes?max_metric=lots".parse().unwrap();
+        assert!(Query::<super::RoutesQuery>::try_from_uri(&uri).is_err());
+    }
+
+    #[test]
+    fn peers_are_filtered_and_paginated() {
+        use crate::peer_manager::{ConnectionState, PeerStats, PeerType};
+
+        use super::PeerListParams;
+
+        let peer = testing::peer_stats;
+        let peers = || {
+            vec![
+                peer(3, PeerType::Inbound, ConnectionState::Alive),
+                peer(1, PeerType::Static, ConnectionState::Alive),
+                peer(4, PeerType::Static, ConnectionState::Dead),
+                peer(2, PeerType::Static, ConnectionState::Alive),
+            ]
+        };
+        let ids = |peers: Vec<PeerStats>| peers.into_iter().map(|p| p.id).collect::<Vec<_>>();
+        let params = |peer_type, state, offset, limit| PeerListParams {
+            full_key: false,
+            peer_type,
+            state,
+            offset,
+            limit,
+        };
+
+        // Without parameters, every peer is listed.
+        let (page, total) = super::select_peers(peers(), &params(None, None, 0, None));
+        assert_eq!(ids(page), [1, 2, 3, 4]);
+        assert_eq!(total, 4);
+
+        let (page, total) = super::select_peers(
+            peers(),
+            &params(
+                Some(PeerType::Static),
+                Some(ConnectionState::Alive),
+                0,
+                None,
+            ),
+        );
+        assert_eq!(ids(page), [1, 2]);
+        assert_eq!(total, 2);
+
+        // The total is counted before pagination.
+        let (page, total) =
+            super::select_peers(peers(), &params(Some(PeerType::Static), None, 1, Some(1)));
+        assert_eq!(ids(page), [2]);
+        assert_eq!(total, 3);
+
+        let (page, total) = super::select_peers(peers(), &params(None, None, 10, Some(5)));
+        assert!(page.is_empty());
+        assert_eq!(total, 4);
+    }
+
+    #[test]
+    fn peer_changes_are_sent_on_the_feed() {
+        use crate::peer_manager::{ConnectionState, PeerChange, PeerType};
+
+        use super::PeersFeedMessage;
+
+        let peers = || {
+            vec![
+                testing::peer_stats(1, PeerType::Static, ConnectionState::Alive),
+                testing::peer_stats(2, PeerType::Inbound, ConnectionState::Dead),
+            ]
+        };
+        let endpoint = |id: u8| format!("tcp://10.0.0.{id}:9651").parse().unwrap();
+
+        assert!(matches!(
+            super::feed_message(PeerChange::Updated(endpoint(2)), peers()),
+            Some(PeersFeedMessage::Update { peer }) if peer.id == 2
+        ));
+        // The peer is already gone, its removal follows.
+        assert!(super::feed_message(PeerChange::Updated(endpoint(3)), peers()).is_none());
+        assert!(matches!(
+            super::feed_message(PeerChange::Removed(endpoint(3)), peers()),
+            Some(PeersFeedMessage::Removed { endpoint: removed }) if removed == endpoint(3)
+        ));
+    }
 }
@@ -0,0 +1,196 @@
+//! Errors returned by handlers of the API. Every error is returned as a JSON body holding a
+//! description of the error and a machine readable code, so clients can tell why a request failed.
+
+use std::fmt;
+
+use axum::{
+    extract::rejection::{BytesRejection, JsonRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::peer_manager::{PeerExists, PeerNotFound};
+
+/// Machine readable kind of an [`ApiError`]. The code determines the status of the response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The body of the request could not be decoded.
+    InvalidBody,
+    /// An endpoint in the request is not valid.
+    InvalidEndpoint,
+    /// A subnet in the request is not valid.
+    InvalidSubnet,
+    /// The requested reconnect policy of a peer is not valid.
+    InvalidReconnectPolicy,
+    /// A peer with the same endpoint already exists.
+    PeerExists,
+    /// The requested peer does not exist.
+    PeerNotFound,
+    /// The requested peer is not a static peer.
+    PeerNotStatic,
+    /// No directly connected neighbour has the requested public key.
+    NeighbourNotFound,
+    /// No route is selected for the requested subnet.
+    RouteNotFound,
+    /// The destination of a message is not an address in the overlay.
+    InvalidDestination,
+    /// A topic in the request is not valid base64.
+    InvalidTopic,
+    /// A topic in the request is too large.
+    TopicTooLarge,
+    /// A correlation id in the request is too large.
+    CorrelationIdTooLarge,
+    /// A content type in the request is too large.
+    ContentTypeTooLarge,
+    /// The payload of a message is too large.
+    PayloadTooLarge,
+    /// The requested try duration of a message is out of bounds.
+    InvalidTryDuration,
+    /// The requested retry schedule of a message is not valid.
+    InvalidRetrySchedule,
+    /// A batch holds too many messages.
+    BatchTooLarge,
+    /// The requested message is not known, or no longer being sent.
+    MessageNotFound,
+    /// The requested aggregation settings are not valid.
+    InvalidAggregation,
+    /// Messages on the requested topic are not aggregated.
+    AggregationNotFound,
+    /// A topic in the request is aggregated, which the request does not support.
+    AggregatedTopic,
+}
+
+impl ErrorCode {
+    /// The status of a response for an error with this code.
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::InvalidBody
+            | Self::InvalidEndpoint
+            | Self::InvalidSubnet
+            | Self::InvalidReconnectPolicy
+            | Self::InvalidDestination
+            | Self::InvalidTopic
+            | Self::TopicTooLarge
+            | Self::CorrelationIdTooLarge
+            | Self::ContentTypeTooLarge
+            | Self::InvalidTryDuration
+            | Self::InvalidRetrySchedule
+            | Self::BatchTooLarge
+            | Self::InvalidAggregation
+            | Self::AggregatedTopic => StatusCode::BAD_REQUEST,
+            Self::PeerNotStatic => StatusCode::FORBIDDEN,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::PeerNotFound
+            | Self::NeighbourNotFound
+            | Self::RouteNotFound
+            | Self::MessageNotFound
+            | Self::AggregationNotFound => StatusCode::NOT_FOUND,
+            Self::PeerExists => StatusCode::CONFLICT,
+        }
+    }
+}
+
+/// An error returned by a handler, serialized as `{"error": "...", "code": "..."}`.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    /// Description of the error, for humans.
+    #[serde(rename = "error")]
+    message: String,
+    /// Kind of the error, for clients.
+    code: ErrorCode,
+}
+
+impl ApiError {
+    /// Create a new `ApiError` with the given code and description.
+    pub fn new(code: ErrorCode, message: impl fmt::Display) -> Self {
+        Self {
+            message: message.to_string(),
+            code,
+        }
+    }
+
+    /// The kind of the error.
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// The description of the error.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.code.status(), Json(self)).into_response()
+    }
+}
+
+impl From<PeerExists> for ApiError {
+    fn from(_: PeerExists) -> Self {
+        Self::new(
+            ErrorCode::PeerExists,
+            "A peer identified by that endpoint already exists",
+        )
+    }
+}
+
+impl From<PeerNotFound> for ApiError {
+    fn from(_: PeerNotFound) -> Self {
+        Self::new(
+            ErrorCode::PeerNotFound,
+            "A peer identified by that endpoint does not exist",
+        )
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(body_error_code(rejection.status()), rejection.body_text())
+    }
+}
+
+impl From<BytesRejection> for ApiError {
+    fn from(rejection: BytesRejection) -> Self {
+        Self::new(body_error_code(rejection.status()), rejection.body_text())
+    }
+}
+
+/// The code of an error for a request body which was rejected with the given status. Bodies
+/// which exceed the body limit are too large, every other rejection means the body is not valid.
+fn body_error_code(status: StatusCode) -> ErrorCode {
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        ErrorCode::PayloadTooLarge
+    } else {
+        ErrorCode::InvalidBody
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use super::{ApiError, ErrorCode};
+
+    #[test]
+    fn error_is_serialized_with_code() {
+        let err = ApiError::new(ErrorCode::PeerNotFound, "no such peer");
+
+        assert_eq!(
+            serde_json::to_string(&err).unwrap(),
+            r#"{"error":"no such peer","code":"peerNotFound"}"#
+        );
+        assert_eq!(err.into_response().status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,33 +1,136 @@
-use std::{net::IpAddr, ops::Deref, time::Duration};
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
-    routing::{get, post},
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, Path, Query, State,
+    },
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Json, Router,
 };
-use log::debug;
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     crypto::PublicKey,
-    message::{MessageId, MessageInfo},
+    message::{
+        check_message_fields, Aggregation, InvalidRetrySchedule, MessageId, MessageInfo,
+        MessageNotPending, MessageStack, PushMessageError, ReceivedMessage, RetrySchedule,
+        TopicFilter, MAX_CONTENT_TYPE_SIZE, MAX_CORRELATION_ID_SIZE, MAX_TOPIC_SIZE,
+    },
+    subnet::Subnet,
+    GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN,
 };
 
-use super::HttpServerState;
+use super::{
+    timeout_request, ApiError, BodyFormat, ErrorCode, HttpServerState, JsonBody, Metric, Negotiated,
+};
 
+/// Value of the `Cache-Control` header of the message limits. The limits are fixed, except for
+/// the default try duration, which rarely changes.
+const MESSAGE_LIMITS_CACHE_CONTROL: &str = "max-age=60";
 /// Default amount of time to try and send a message if it is not explicitly specified.
 const DEFAULT_MESSAGE_TRY_DURATION: Duration = Duration::from_secs(60 * 5);
+/// Maximum amount of time the default try duration of messages can be set to.
+const MAX_MESSAGE_TRY_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+/// Maximum amount of time to wait for the message sent to ourselves during a self test.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Payload of the message sent to ourselves during a self test.
+const SELF_TEST_PAYLOAD: &[u8] = b"mycelium self test";
+/// Maximum amount of messages which can be pushed in a single batch.
+const MAX_MESSAGE_BATCH_SIZE: usize = 1000;
+/// Minimum limit on the size of request bodies of the message endpoints, which is the default
+/// limit of axum. Batches of small messages are not limited by the maximum payload size.
+const MIN_MESSAGE_BODY_LIMIT: usize = 2 * 1024 * 1024;
+/// Room in the request body of a message for the fields besides the payload.
+const MESSAGE_BODY_OVERHEAD: usize = 64 * 1024;
 
-/// Return a router which has message endpoints and their handlers mounted.
-pub fn message_router_v1(server_state: HttpServerState) -> Router {
+/// Return a router which has message endpoints and their handlers mounted. Requests which take
+/// longer than `request_timeout` are aborted, except for the long polling endpoints.
+pub fn message_router_v1(server_state: HttpServerState, request_timeout: Duration) -> Router {
+    // The body must fit a base64 encoded payload of the maximum size, so the payload size check
+    // is reached instead of the body being rejected.
+    let body_limit = server_state
+        .max_payload_size
+        .div_ceil(3)
+        .saturating_mul(4)
+        .saturating_add(MESSAGE_BODY_OVERHEAD)
+        .max(MIN_MESSAGE_BODY_LIMIT);
     Router::new()
-        .route("/messages", get(get_message).post(push_message))
         .route("/messages/status/:id", get(message_status))
+        .route("/messages/outbox", get(get_outbox))
+        .route("/messages/:id", delete(cancel_message))
         .route("/messages/reply/:id", post(reply_message))
+        .route("/messages/validate", post(validate_destination))
+        .route("/messages/consumers", get(get_consumers))
+        .route("/messages/topics", get(get_topics))
+        .route("/messages/limits", get(get_limits))
+        .route("/messages/broadcast", post(broadcast_message))
+        .route("/messages/batch", post(push_message_batch))
+        .route("/admin/selftest", post(self_test))
+        .route(
+            "/admin/config/message-try-duration",
+            get(get_message_try_duration).put(set_message_try_duration),
+        )
+        .route(
+            "/messages/aggregations",
+            get(get_aggregations)
+                .put(set_aggregation)
+                .delete(delete_aggregation),
+        )
+        .layer(middleware::from_fn_with_state(
+            request_timeout,
+            timeout_request,
+        ))
+        // Routes below are not subject to the request timeout. Getting a message long polls for
+        // the requested amount of time, pushing a message can wait for a reply, and the message
+        // feed stays open until it is closed.
+        .route("/messages", get(get_message).post(push_message))
+        .route("/messages/ws", get(get_messages_feed))
+        .layer(DefaultBodyLimit::max(body_limit))
         .with_state(server_state)
 }
 
+/// The amount of time to try and send a message if it is not explicitly specified. This can be
+/// changed at runtime. Clones share the same value.
+#[derive(Clone)]
+pub struct MessageTryDuration(Arc<Mutex<Duration>>);
+
+impl MessageTryDuration {
+    /// The current default try duration.
+    pub fn get(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+
+    /// Set a new default try duration. The duration is clamped to [`MAX_MESSAGE_TRY_DURATION`],
+    /// the effective duration is returned. A duration of 0 is rejected, as no message could be
+    /// sent with it.
+    pub fn set(&self, try_duration: Duration) -> Result<Duration, InvalidTryDuration> {
+        if try_duration.is_zero() {
+            return Err(InvalidTryDuration);
+        }
+        let try_duration = try_duration.min(MAX_MESSAGE_TRY_DURATION);
+        *self.0.lock().unwrap() = try_duration;
+        Ok(try_duration)
+    }
+}
+
+impl Default for MessageTryDuration {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(DEFAULT_MESSAGE_TRY_DURATION)))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageSendInfo {
@@ -36,6 +139,21 @@ pub struct MessageSendInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(with = "base64::optional_binary")]
     pub topic: Option<Vec<u8>>,
+    /// Opaque id set by the sender, which is passed to the receiver as is.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "base64::optional_binary")]
+    pub correlation_id: Option<Vec<u8>>,
+    /// Content type of the payload, e.g. `application/json`, which is passed to the receiver as
+    /// is.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Schedule of the retransmissions of the message, the default schedule is used if this is
+    /// not set.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetrySchedule>,
     #[serde(with = "base64::binary")]
     pub payload: Vec<u8>,
 }
@@ -43,7 +161,7 @@ pub struct MessageSendInfo {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum MessageDestination {
-    Ip(IpAddr),
+    Ip(#[serde(with = "ip_text")] IpAddr),
     Pk(PublicKey),
 }
 
@@ -51,24 +169,186 @@ pub enum MessageDestination {
 #[serde(rename_all = "camelCase")]
 pub struct MessageReceiveInfo {
     pub id: MessageId,
+    #[serde(with = "ip_text")]
     pub src_ip: IpAddr,
     pub src_pk: PublicKey,
+    #[serde(with = "ip_text")]
     pub dst_ip: IpAddr,
     pub dst_pk: PublicKey,
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(with = "base64::optional_binary")]
     pub topic: Option<Vec<u8>>,
+    /// Opaque id set by the sender of the message, if any.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "base64::optional_binary")]
+    pub correlation_id: Option<Vec<u8>>,
+    /// Content type of the payload set by the sender of the message, if any.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
     #[serde(with = "base64::binary")]
     pub payload: Vec<u8>,
 }
 
+impl From<ReceivedMessage> for MessageReceiveInfo {
+    fn from(m: ReceivedMessage) -> Self {
+        MessageReceiveInfo {
+            id: m.id,
+            src_ip: m.src_ip,
+            src_pk: m.src_pk,
+            dst_ip: m.dst_ip,
+            dst_pk: m.dst_pk,
+            topic: if m.topic.is_empty() {
+                None
+            } else {
+                Some(m.topic)
+            },
+            correlation_id: if m.correlation_id.is_empty() {
+                None
+            } else {
+                Some(m.correlation_id)
+            },
+            content_type: if m.content_type.is_empty() {
+                None
+            } else {
+                Some(m.content_type)
+            },
+            payload: m.data,
+        }
+    }
+}
+
+/// Error returned when a message destination is not an address in the overlay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidDestination;
+
+impl std::fmt::Display for InvalidDestination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("destination is not an IPv6 address in the overlay subnet")
+    }
+}
+
+impl std::error::Error for InvalidDestination {}
+
+/// Error returned when a topic in a request is larger than [`MAX_TOPIC_SIZE`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopicTooLarge;
+
+impl std::fmt::Display for TopicTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "topic is too large, topics are limited to {MAX_TOPIC_SIZE} bytes"
+        )
+    }
+}
+
+impl std::error::Error for TopicTooLarge {}
+
+/// Error returned when the try duration of a message is zero, or larger than
+/// [`MAX_MESSAGE_TRY_DURATION`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTryDuration;
+
+impl std::fmt::Display for InvalidTryDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "try duration must be between 1 and {} seconds",
+            MAX_MESSAGE_TRY_DURATION.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for InvalidTryDuration {}
+
+/// Error returned when the payload of a message is larger than the configured maximum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadTooLarge {
+    /// The maximum size of a payload in bytes.
+    pub max_payload_size: usize,
+}
+
+impl std::fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "payload is too large, payloads are limited to {} bytes",
+            self.max_payload_size
+        )
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// Check that a payload is at most `max_payload_size` bytes.
+fn check_payload_size(payload: &[u8], max_payload_size: usize) -> Result<(), PayloadTooLarge> {
+    if payload.len() > max_payload_size {
+        Err(PayloadTooLarge { max_payload_size })
+    } else {
+        Ok(())
+    }
+}
+
+impl From<PayloadTooLarge> for ApiError {
+    fn from(e: PayloadTooLarge) -> Self {
+        ApiError::new(ErrorCode::PayloadTooLarge, e)
+    }
+}
+
+impl From<InvalidDestination> for ApiError {
+    fn from(e: InvalidDestination) -> Self {
+        ApiError::new(ErrorCode::InvalidDestination, e)
+    }
+}
+
+impl From<TopicTooLarge> for ApiError {
+    fn from(e: TopicTooLarge) -> Self {
+        ApiError::new(ErrorCode::TopicTooLarge, e)
+    }
+}
+
+impl From<InvalidTryDuration> for ApiError {
+    fn from(e: InvalidTryDuration) -> Self {
+        ApiError::new(ErrorCode::InvalidTryDuration, e)
+    }
+}
+
+impl From<InvalidRetrySchedule> for ApiError {
+    fn from(e: InvalidRetrySchedule) -> Self {
+        ApiError::new(ErrorCode::InvalidRetrySchedule, e)
+    }
+}
+
+impl From<PushMessageError> for ApiError {
+    fn from(e: PushMessageError) -> Self {
+        let code = match e {
+            PushMessageError::TopicTooLarge => ErrorCode::TopicTooLarge,
+            PushMessageError::CorrelationIdTooLarge => ErrorCode::CorrelationIdTooLarge,
+            PushMessageError::ContentTypeTooLarge => ErrorCode::ContentTypeTooLarge,
+        };
+        ApiError::new(code, e)
+    }
+}
+
 impl MessageDestination {
-    /// Get the IP address of the destination.
-    fn ip(self) -> IpAddr {
+    /// Get the IP address of the destination. An IP destination must be an IPv6 address in the
+    /// global overlay subnet, otherwise it can never be reached and [`InvalidDestination`] is
+    /// returned.
+    fn ip(self) -> Result<IpAddr, InvalidDestination> {
         match self {
-            MessageDestination::Ip(ip) => ip,
-            MessageDestination::Pk(pk) => IpAddr::V6(pk.address()),
+            MessageDestination::Ip(ip) => {
+                let global_subnet = Subnet::new(GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN)
+                    .expect("Global subnet is properly defined; qed");
+                if global_subnet.contains_ip(ip) {
+                    Ok(ip)
+                } else {
+                    Err(InvalidDestination)
+                }
+            }
+            MessageDestination::Pk(pk) => Ok(IpAddr::V6(pk.address())),
         }
     }
 }
@@ -77,11 +357,20 @@ impl MessageDestination {
 struct GetMessageQuery {
     peek: Option<bool>,
     timeout: Option<u64>,
-    /// Optional filter for start of the message, base64 encoded.
+    // The `topic` parameter can be repeated, which can't be expressed here. It is loaded with
+    // `query_topics` instead.
+    /// Optional filter on the start of the topic of the message, base64 encoded. Ignored if
+    /// `topic` is set.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "base64::optional_binary")]
+    topic_prefix: Option<Vec<u8>>,
+    /// Optional filter on the correlation id of the message, base64 encoded. This is combined
+    /// with the topic filter, if any, so a message must match both.
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(with = "base64::optional_binary")]
-    topic: Option<Vec<u8>>,
+    correlation_id: Option<Vec<u8>>,
 }
 
 impl GetMessageQuery {
@@ -94,42 +383,234 @@ impl GetMessageQuery {
     fn timeout_secs(&self) -> u64 {
         self.timeout.unwrap_or(0)
     }
+
+    /// The filter on the topic of the message, if any, given the values of the `topic`
+    /// parameter. Multiple topics match a message on any of them. Exact topics take precedence
+    /// over a topic prefix.
+    fn topic_filter(&self, topics: Vec<Vec<u8>>) -> Option<TopicFilter> {
+        topic_filter(topics, self.topic_prefix.as_ref())
+    }
+
+    /// Check that none of the given values of the `topic` parameter, nor the topic prefix, are
+    /// larger than [`MAX_TOPIC_SIZE`]. Such topics can never match a message.
+    fn check_topic_sizes(&self, topics: &[Vec<u8>]) -> Result<(), TopicTooLarge> {
+        check_topic_sizes(topics, self.topic_prefix.as_ref())
+    }
+}
+
+/// Build the filter on the topic of a message from the values of the `topic` parameter and the
+/// topic prefix. Multiple topics match a message on any of them. Exact topics take precedence
+/// over a topic prefix.
+fn topic_filter(mut topics: Vec<Vec<u8>>, topic_prefix: Option<&Vec<u8>>) -> Option<TopicFilter> {
+    match topics.len() {
+        0 => topic_prefix.cloned().map(TopicFilter::Prefix),
+        1 => topics.pop().map(TopicFilter::Exact),
+        _ => Some(TopicFilter::AnyOf(topics)),
+    }
 }
 
+/// Check that none of the values of the `topic` parameter, nor the topic prefix, are larger than
+/// [`MAX_TOPIC_SIZE`].
+fn check_topic_sizes(
+    topics: &[Vec<u8>],
+    topic_prefix: Option<&Vec<u8>>,
+) -> Result<(), TopicTooLarge> {
+    if topics
+        .iter()
+        .chain(topic_prefix)
+        .any(|topic| topic.len() > MAX_TOPIC_SIZE)
+    {
+        Err(TopicTooLarge)
+    } else {
+        Ok(())
+    }
+}
+
+/// Decode all values of the repeatable `topic` query parameter, in the order they are given.
+fn query_topics(params: &[(String, String)]) -> Result<Vec<Vec<u8>>, ::base64::DecodeError> {
+    params
+        .iter()
+        .filter(|(key, _)| key == "topic")
+        .map(|(_, value)| base64::decode(value))
+        .collect()
+}
+
+/// Response header holding the amount of messages matching the query which are still waiting to
+/// be read after the returned message(s).
+const REMAINING_MESSAGES_HEADER: HeaderName = HeaderName::from_static("x-remaining-messages");
+
+/// Get a message from the inbox. If a single topic is requested and it is aggregated, a batch of
+/// messages is returned as a list instead. The message is encoded as CBOR if the client accepts
+/// it.
 async fn get_message(
     State(state): State<HttpServerState>,
     Query(query): Query<GetMessageQuery>,
-) -> Result<Json<MessageReceiveInfo>, StatusCode> {
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let format = BodyFormat::accepted(&headers);
     debug!(
         "Attempt to get message, peek {}, timeout {} seconds",
         query.peek(),
         query.timeout_secs()
     );
 
+    let topics = query_topics(&params).map_err(|e| {
+        debug!("Invalid topic in message query: {e}");
+        ApiError::new(ErrorCode::InvalidTopic, e)
+    })?;
+    query.check_topic_sizes(&topics).map_err(|e| {
+        debug!("Rejecting message query: {e}");
+        ApiError::from(e)
+    })?;
+    if matches!(&query.correlation_id, Some(id) if id.len() > MAX_CORRELATION_ID_SIZE) {
+        debug!("Rejecting message query: correlation id too large");
+        return Err(ApiError::new(
+            ErrorCode::CorrelationIdTooLarge,
+            format!(
+                "correlation id is too large, correlation ids are limited to {MAX_CORRELATION_ID_SIZE} bytes"
+            ),
+        ));
+    }
+    // Messages on aggregated topics are only delivered in batches, which can't be filtered on
+    // correlation id.
+    if query.correlation_id.is_some()
+        && topics
+            .iter()
+            .any(|topic| state.message_stack.aggregation(topic).is_some())
+    {
+        debug!("Rejecting message query: correlation id on aggregated topic");
+        return Err(ApiError::new(
+            ErrorCode::AggregatedTopic,
+            "messages on aggregated topics can't be filtered on correlation id",
+        ));
+    }
+    let timeout = Duration::from_secs(query.timeout_secs());
+
+    if let [topic] = &topics[..] {
+        if state.message_stack.aggregation(topic).is_some() {
+            let Ok((batch, remaining)) = tokio::time::timeout(
+                timeout,
+                state
+                    .message_stack
+                    .message_batch_with_remaining(!query.peek(), topic.clone()),
+            )
+            .await
+            else {
+                return Ok(StatusCode::NO_CONTENT.into_response());
+            };
+            return Ok((
+                [(REMAINING_MESSAGES_HEADER, remaining.to_string())],
+                format.respond(
+                    StatusCode::OK,
+                    batch
+                        .into_iter()
+                        .map(MessageReceiveInfo::from)
+                        .collect::<Vec<_>>(),
+                ),
+            )
+                .into_response());
+        }
+    }
+
     // A timeout of 0 seconds essentially means get a message if there is one, and return
     // immediatly if there isn't. This is the result of the implementation of Timeout, which does a
     // poll of the internal future first, before polling the delay.
-    tokio::time::timeout(
-        Duration::from_secs(query.timeout_secs()),
-        state.message_stack.message(!query.peek(), query.topic),
+    let Ok((m, remaining)) = tokio::time::timeout(
+        timeout,
+        state.message_stack.message_with_remaining(
+            !query.peek(),
+            query.topic_filter(topics),
+            query.correlation_id.clone(),
+        ),
     )
     .await
-    .or(Err(StatusCode::NO_CONTENT))
-    .map(|m| {
-        Json(MessageReceiveInfo {
-            id: m.id,
-            src_ip: m.src_ip,
-            src_pk: m.src_pk,
-            dst_ip: m.dst_ip,
-            dst_pk: m.dst_pk,
-            topic: if m.topic.is_empty() {
-                None
-            } else {
-                Some(m.topic)
+    else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
+    Ok((
+        [(REMAINING_MESSAGES_HEADER, remaining.to_string())],
+        format.respond(StatusCode::OK, MessageReceiveInfo::from(m)),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct MessageFeedQuery {
+    // The `topic` parameter can be repeated, which can't be expressed here. It is loaded with
+    // `query_topics` instead.
+    /// Optional filter on the start of the topic of the messages, base64 encoded. Ignored if
+    /// `topic` is set.
+    #[serde(default)]
+    #[serde(with = "base64::optional_binary")]
+    topic_prefix: Option<Vec<u8>>,
+}
+
+/// Open a websocket which streams received messages. Every message is sent as soon as it is
+/// received, and is removed from the inbox once it is sent. The topics of the streamed messages can be filtered
+/// the same way as when getting a single message.
+async fn get_messages_feed(
+    State(state): State<HttpServerState>,
+    Query(query): Query<MessageFeedQuery>,
+    Query(params): Query<Vec<(String, String)>>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    debug!("Opening live message feed");
+
+    let topics = query_topics(&params).map_err(|e| {
+        debug!("Invalid topic in message feed query: {e}");
+        ApiError::new(ErrorCode::InvalidTopic, e)
+    })?;
+    check_topic_sizes(&topics, query.topic_prefix.as_ref()).map_err(|e| {
+        debug!("Rejecting message feed query: {e}");
+        ApiError::from(e)
+    })?;
+    let filter = topic_filter(topics, query.topic_prefix.as_ref());
+
+    Ok(ws.on_upgrade(move |socket| {
+        stream_messages(state.message_stack, filter, state.shutdown, socket)
+    }))
+}
+
+/// Stream received messages matching the filter over a websocket, until it is closed or the
+/// server shuts down.
+async fn stream_messages(
+    message_stack: MessageStack,
+    filter: Option<TopicFilter>,
+    shutdown: CancellationToken,
+    socket: WebSocket,
+) {
+    let (mut sender, mut receiver) = socket.split();
+
+    loop {
+        tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(WsMessage::Close(_)) | Err(_)) | None => {
+                    debug!("Live message feed closed");
+                    return;
+                }
+                // Nothing is expected from the client, other messages are ignored.
+                Some(Ok(_)) => {}
             },
-            payload: m.data,
-        })
-    })
+            // Waiting for a message does not remove anything from the inbox until it resolves,
+            // so no message is lost if another branch completes first.
+            msg = message_stack.message_matching(true, filter.clone()) => {
+                let text = serde_json::to_string(&MessageReceiveInfo::from(msg.clone()))
+                    .expect("Received messages can be serialized; qed");
+                if sender.send(WsMessage::Text(text)).await.is_err() {
+                    // The message did not reach the client, keep it for the next reader.
+                    debug!("Live message feed closed while sending a message");
+                    message_stack.requeue_message(msg);
+                    return;
+                }
+            }
+            _ = shutdown.cancelled() => {
+                debug!("Closing live message feed, server is shutting down");
+                let _ = sender.send(WsMessage::Close(None)).await;
+                return;
+            }
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -149,6 +630,8 @@ pub enum PushMessageResponse {
 #[derive(Deserialize)]
 struct PushMessageQuery {
     reply_timeout: Option<u64>,
+    /// Amount of seconds to try and send the message, instead of the default try duration.
+    try_duration_secs: Option<u64>,
 }
 
 impl PushMessageQuery {
@@ -161,109 +644,1491 @@ impl PushMessageQuery {
     fn timeout(&self) -> u64 {
         self.reply_timeout.unwrap_or(0)
     }
+
+    /// Amount of time to try and send the message. This is the requested try duration, or
+    /// `default` if none is requested.
+    fn try_duration(&self, default: Duration) -> Result<Duration, InvalidTryDuration> {
+        match self.try_duration_secs {
+            None => Ok(default),
+            Some(secs) => {
+                let try_duration = Duration::from_secs(secs);
+                if try_duration.is_zero() || try_duration > MAX_MESSAGE_TRY_DURATION {
+                    Err(InvalidTryDuration)
+                } else {
+                    Ok(try_duration)
+                }
+            }
+        }
+    }
 }
 
+/// Push a new message. The message can be sent as JSON or CBOR, and the response is encoded as
+/// CBOR if the client accepts it.
 async fn push_message(
     State(state): State<HttpServerState>,
     Query(query): Query<PushMessageQuery>,
-    Json(message_info): Json<MessageSendInfo>,
-) -> Result<(StatusCode, Json<PushMessageResponse>), StatusCode> {
-    let dst = message_info.dst.ip();
+    headers: HeaderMap,
+    Negotiated(message_info): Negotiated<MessageSendInfo>,
+) -> Result<Response, ApiError> {
+    let format = BodyFormat::accepted(&headers);
+    let dst = message_info.dst.ip().map_err(|e| {
+        debug!("Rejecting message: {e}");
+        ApiError::from(e)
+    })?;
+    check_payload_size(&message_info.payload, state.max_payload_size).map_err(|e| {
+        debug!("Rejecting message: {e}");
+        ApiError::from(e)
+    })?;
     debug!(
         "Pushing new message of {} bytes to message stack for target {dst}",
         message_info.payload.len(),
     );
 
-    let (id, sub) = match state.message_stack.new_message(
-        dst,
-        message_info.payload,
-        if let Some(topic) = message_info.topic {
-            topic
-        } else {
-            vec![]
-        },
-        DEFAULT_MESSAGE_TRY_DURATION,
-        query.await_reply(),
-    ) {
-        Ok((id, sub)) => (id, sub),
-        Err(_) => {
-            return Err(StatusCode::BAD_REQUEST);
-        }
-    };
+    let topic = message_info.topic.unwrap_or_default();
+    let correlation_id = message_info.correlation_id.unwrap_or_default();
+    let content_type = message_info.content_type.unwrap_or_default();
+    let try_duration = query
+        .try_duration(state.message_try_duration.get())
+        .map_err(|e| {
+            debug!("Rejecting message: {e}");
+            ApiError::from(e)
+        })?;
+    let retry_schedule = message_info.retry.unwrap_or_default();
+    retry_schedule.validate(try_duration).map_err(|e| {
+        debug!("Rejecting message: {e}");
+        ApiError::from(e)
+    })?;
 
     if !query.await_reply() {
         // If we don't wait for the reply just return here.
-        return Ok((
+        let id = state.message_stack.new_message(
+            dst,
+            message_info.payload,
+            topic,
+            correlation_id,
+            content_type,
+            try_duration,
+            retry_schedule,
+        )?;
+
+        return Ok(format.respond(
             StatusCode::CREATED,
-            Json(PushMessageResponse::Id(MessageIdReply { id })),
+            PushMessageResponse::Id(MessageIdReply { id }),
         ));
     }
 
-    let mut sub = sub.unwrap();
+    let (id, mut sub) = state.message_stack.new_message_with_reply(
+        dst,
+        message_info.payload,
+        topic,
+        correlation_id,
+        content_type,
+        try_duration,
+        retry_schedule,
+    )?;
+
     tokio::select! {
-        sub_res = sub.changed() => {
-            match sub_res {
-                Ok(_) => {
-                    if let Some(m) = sub.borrow().deref()  {
-                        Ok((StatusCode::OK, Json(PushMessageResponse::Reply(MessageReceiveInfo {
-                            id: m.id,
-                            src_ip: m.src_ip,
-                            src_pk: m.src_pk,
-                            dst_ip: m.dst_ip,
-                            dst_pk: m.dst_pk,
-                            topic: if m.topic.is_empty() { None } else { Some(m.topic.clone()) },
-                            payload: m.data.clone(),
-                        }))))
-                    } else {
-                        // This happens if a none value is send, which should not happen.
-                        Err(StatusCode::INTERNAL_SERVER_ERROR)
-                    }
-                }
-                Err(_)  => {
-                    // This happens if the sender drops, which should not happen.
-                    Err(StatusCode::INTERNAL_SERVER_ERROR)
-                }
-            }
+        m = sub.reply() => {
+            Ok(format.respond(StatusCode::OK, PushMessageResponse::Reply(m.into())))
         },
         _ = tokio::time::sleep(Duration::from_secs(query.timeout())) => {
             // Timeout expired while waiting for reply
-            Ok((StatusCode::REQUEST_TIMEOUT, Json(PushMessageResponse::Id(MessageIdReply { id  }))))
+            Ok(format.respond(StatusCode::REQUEST_TIMEOUT, PushMessageResponse::Id(MessageIdReply { id  })))
         }
     }
 }
 
+/// Result of pushing a single message of a batch.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchMessageResult {
+    /// Status of the message, as if it was pushed on its own.
+    pub status: u16,
+    /// Id of the message, if it was pushed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<MessageId>,
+    /// Reason the message was rejected, if it was not pushed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Kind of the error, if the message was not pushed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+}
+
+/// Push multiple messages in a single request. Every message is pushed on its own, so a rejected
+/// message does not fail the batch. The result of every message is returned in the same order as
+/// the messages, with a `207 Multi-Status`.
+async fn push_message_batch(
+    State(state): State<HttpServerState>,
+    JsonBody(messages): JsonBody<Vec<MessageSendInfo>>,
+) -> Result<(StatusCode, Json<Vec<BatchMessageResult>>), ApiError> {
+    if messages.len() > MAX_MESSAGE_BATCH_SIZE {
+        debug!("Rejecting batch of {} messages", messages.len());
+        return Err(ApiError::new(
+            ErrorCode::BatchTooLarge,
+            format!("batch is too large, batches are limited to {MAX_MESSAGE_BATCH_SIZE} messages"),
+        ));
+    }
+    debug!("Pushing batch of {} messages", messages.len());
+
+    let try_duration = state.message_try_duration.get();
+    Ok((
+        StatusCode::MULTI_STATUS,
+        Json(push_messages(
+            &state.message_stack,
+            try_duration,
+            state.max_payload_size,
+            messages,
+        )),
+    ))
+}
+
+/// Push every message on the message stack, and collect the result of every push.
+fn push_messages(
+    message_stack: &MessageStack,
+    try_duration: Duration,
+    max_payload_size: usize,
+    messages: Vec<MessageSendInfo>,
+) -> Vec<BatchMessageResult> {
+    messages
+        .into_iter()
+        .map(|message_info| {
+            match push_batch_message(message_stack, try_duration, max_payload_size, message_info) {
+                Ok(id) => BatchMessageResult {
+                    status: StatusCode::CREATED.as_u16(),
+                    id: Some(id),
+                    error: None,
+                    code: None,
+                },
+                Err(e) => {
+                    debug!("Rejecting message in batch: {e}");
+                    BatchMessageResult {
+                        status: e.code().status().as_u16(),
+                        id: None,
+                        error: Some(e.message().to_string()),
+                        code: Some(e.code()),
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+/// Push a single message of a batch, with the same checks as a message pushed on its own.
+fn push_batch_message(
+    message_stack: &MessageStack,
+    try_duration: Duration,
+    max_payload_size: usize,
+    message_info: MessageSendInfo,
+) -> Result<MessageId, ApiError> {
+    let dst = message_info.dst.ip()?;
+    check_payload_size(&message_info.payload, max_payload_size)?;
+    let retry_schedule = message_info.retry.unwrap_or_default();
+    retry_schedule.validate(try_duration)?;
+
+    Ok(message_stack.new_message(
+        dst,
+        message_info.payload,
+        message_info.topic.unwrap_or_default(),
+        message_info.correlation_id.unwrap_or_default(),
+        message_info.content_type.unwrap_or_default(),
+        try_duration,
+        retry_schedule,
+    )?)
+}
+
+/// Payload of a message broadcast to all directly connected neighbours.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastMessageInfo {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "base64::optional_binary")]
+    pub topic: Option<Vec<u8>>,
+    /// Content type of the payload, which is passed to the receivers as is.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(with = "base64::binary")]
+    pub payload: Vec<u8>,
+}
+
+/// Result of a message broadcast.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastResult {
+    /// Amount of neighbours the message was pushed to.
+    pub reached: usize,
+}
+
+/// Push a message to every directly connected neighbour. This only reaches neighbours one hop
+/// away, the message is never forwarded any further, so broadcasts can't flood the network.
+async fn broadcast_message(
+    State(state): State<HttpServerState>,
+    JsonBody(message_info): JsonBody<BroadcastMessageInfo>,
+) -> Result<Json<BroadcastResult>, ApiError> {
+    debug!(
+        "Broadcasting message of {} bytes to all neighbours",
+        message_info.payload.len()
+    );
+    check_payload_size(&message_info.payload, state.max_payload_size).map_err(|e| {
+        debug!("Rejecting broadcast: {e}");
+        ApiError::from(e)
+    })?;
+
+    let topic = message_info.topic.unwrap_or_default();
+    let content_type = message_info.content_type.unwrap_or_default();
+    // Check the message once, instead of failing to push it to every neighbour.
+    check_message_fields(&topic, &[], &content_type).map_err(|e| {
+        debug!("Rejecting broadcast: {e}");
+        ApiError::from(e)
+    })?;
+    let try_duration = state.message_try_duration.get();
+
+    // A neighbour can be connected over multiple peers, but should only get the message once.
+    let neighbours = state
+        .router
+        .lock()
+        .unwrap()
+        .neighbour_keys()
+        .into_iter()
+        .map(|(_, public_key)| public_key)
+        .collect::<HashSet<_>>();
+
+    let mut reached = 0;
+    for neighbour in neighbours {
+        let dst = IpAddr::V6(neighbour.address());
+        match state.message_stack.new_message(
+            dst,
+            message_info.payload.clone(),
+            topic.clone(),
+            vec![],
+            content_type.clone(),
+            try_duration,
+            RetrySchedule::default(),
+        ) {
+            Ok(_) => reached += 1,
+            Err(e) => warn!("Could not push broadcast message to neighbour {dst}: {e}"),
+        }
+    }
+
+    Ok(Json(BroadcastResult { reached }))
+}
+
 async fn reply_message(
     State(state): State<HttpServerState>,
     Path(id): Path<MessageId>,
-    Json(message_info): Json<MessageSendInfo>,
-) -> StatusCode {
-    let dst = message_info.dst.ip();
+    JsonBody(message_info): JsonBody<MessageSendInfo>,
+) -> Result<StatusCode, ApiError> {
+    let dst = message_info.dst.ip().map_err(|e| {
+        debug!("Rejecting reply: {e}");
+        ApiError::from(e)
+    })?;
+    check_payload_size(&message_info.payload, state.max_payload_size).map_err(|e| {
+        debug!("Rejecting reply: {e}");
+        ApiError::from(e)
+    })?;
     debug!(
         "Pushing new reply to {} of {} bytes to message stack for target {dst}",
         id.as_hex(),
         message_info.payload.len(),
     );
 
-    state
-        .message_stack
-        .reply_message(id, dst, message_info.payload, DEFAULT_MESSAGE_TRY_DURATION);
+    let try_duration = state.message_try_duration.get();
+    let retry_schedule = message_info.retry.unwrap_or_default();
+    retry_schedule.validate(try_duration).map_err(|e| {
+        debug!("Rejecting reply: {e}");
+        ApiError::from(e)
+    })?;
+    state.message_stack.reply_message(
+        id,
+        dst,
+        message_info.payload,
+        message_info.correlation_id.unwrap_or_default(),
+        message_info.content_type.unwrap_or_default(),
+        try_duration,
+        retry_schedule,
+    )?;
 
-    StatusCode::NO_CONTENT
+    Ok(StatusCode::NO_CONTENT)
 }
 
 async fn message_status(
     State(state): State<HttpServerState>,
     Path(id): Path<MessageId>,
-) -> Result<Json<MessageInfo>, StatusCode> {
+) -> Result<Json<MessageInfo>, ApiError> {
     debug!("Fetching message status for message {}", id.as_hex());
 
     state
         .message_stack
         .message_info(id)
-        .ok_or(StatusCode::NOT_FOUND)
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::MessageNotFound,
+                "A message identified by that id is not known",
+            )
+        })
         .map(Json)
 }
 
+/// Cancel sending an outbound message. The receiver is informed that the message is aborted.
+async fn cancel_message(
+    State(state): State<HttpServerState>,
+    Path(id): Path<MessageId>,
+) -> Result<StatusCode, ApiError> {
+    debug!("Cancelling message {}", id.as_hex());
+
+    match state.message_stack.cancel_message(id) {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(MessageNotPending) => Err(ApiError::new(
+            ErrorCode::MessageNotFound,
+            "A message identified by that id is not being sent",
+        )),
+    }
+}
+
+/// Query parameters of the outbox listing.
+#[derive(Deserialize)]
+struct OutboxQuery {
+    /// Include the payload of the messages.
+    #[serde(default)]
+    payload: bool,
+}
+
+/// An outbound message which is still being sent.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxMessage {
+    /// Id of the message.
+    pub id: MessageId,
+    /// The receiver of the message.
+    pub dst: IpAddr,
+    /// The topic of the message, if it has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "base64::optional_binary")]
+    pub topic: Option<Vec<u8>>,
+    /// Amount of times packets of the message have been sent, the first transmission included.
+    pub attempts: u64,
+    /// Time the message was created, in seconds since the UNIX epoch.
+    pub created: i64,
+    /// Time at which sending the message is given up, in seconds since the UNIX epoch.
+    pub deadline: i64,
+    /// Size of the payload in bytes.
+    pub payload_len: usize,
+    /// Hex encoded blake3 hash of the payload.
+    pub payload_hash: String,
+    /// The payload of the message, only included if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "base64::optional_binary")]
+    pub payload: Option<Vec<u8>>,
+}
+
+/// List the outbound messages which are still being sent, oldest first.
+async fn get_outbox(
+    State(state): State<HttpServerState>,
+    Query(query): Query<OutboxQuery>,
+) -> Json<Vec<OutboxMessage>> {
+    debug!("Listing outbound messages which are still being sent");
+
+    Json(
+        state
+            .message_stack
+            .pending_messages(query.payload)
+            .into_iter()
+            .map(|pm| OutboxMessage {
+                id: pm.id,
+                dst: pm.dst,
+                topic: (!pm.topic.is_empty()).then_some(pm.topic),
+                attempts: pm.attempts,
+                created: pm.created,
+                deadline: pm.deadline,
+                payload_len: pm.payload_len,
+                payload_hash: pm.payload_hash,
+                payload: pm.payload,
+            })
+            .collect(),
+    )
+}
+
+/// Payload of a destination validation request.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidateDestinationInfo {
+    pub dst: MessageDestination,
+}
+
+/// Result of a destination validation. If the destination is reachable, the next hop and metric
+/// of the selected route are included.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DestinationValidation {
+    /// Whether a usable route to the destination is currently selected.
+    pub reachable: bool,
+    /// Next hop of the selected route, in the underlay.
+    pub next_hop: Option<String>,
+    /// Metric of the selected route.
+    pub metric: Option<Metric>,
+}
+
+/// Check if a message destination currently resolves to a selected route, without actually
+/// sending anything. Destinations in the subnet of the node itself are always reachable, without
+/// a next hop.
+async fn validate_destination(
+    State(state): State<HttpServerState>,
+    JsonBody(info): JsonBody<ValidateDestinationInfo>,
+) -> Result<Json<DestinationValidation>, ApiError> {
+    let dst = info.dst.ip().map_err(|e| {
+        debug!("Rejecting destination validation: {e}");
+        ApiError::from(e)
+    })?;
+    debug!("Validating message destination {dst}");
+
+    let router = state.router.lock().unwrap();
+    // Messages to the node itself are delivered locally, there is no route for its own subnet.
+    if router.node_tun_subnet().contains_ip(dst) {
+        return Ok(Json(DestinationValidation {
+            reachable: true,
+            next_hop: None,
+            metric: Some(Metric::Value(0)),
+        }));
+    }
+    let route = router.select_best_route(dst);
+
+    Ok(Json(match route {
+        Some(route) => DestinationValidation {
+            reachable: true,
+            next_hop: Some(route.neighbour().connection_identifier().clone()),
+            metric: Some(Metric::Value(route.metric().into())),
+        },
+        None => DestinationValidation {
+            reachable: false,
+            next_hop: None,
+            metric: None,
+        },
+    }))
+}
+
+/// Callers which are currently waiting for a message with the same topic filter.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageConsumers {
+    /// Topic the callers wait for, if they wait for an exact topic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "base64::optional_binary")]
+    pub topic: Option<Vec<u8>>,
+    /// Topic prefix the callers wait for, if they wait for any topic with this prefix.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(with = "base64::optional_binary")]
+    pub topic_prefix: Option<Vec<u8>>,
+    /// Amount of callers currently waiting.
+    pub waiting: usize,
+    /// Amount of seconds the longest waiting caller has been waiting.
+    pub oldest_wait_secs: u64,
+}
+
+/// List the callers which are currently waiting for a message, grouped by topic filter.
+async fn get_consumers(State(state): State<HttpServerState>) -> Json<Vec<MessageConsumers>> {
+    debug!("Fetching waiting message consumers");
+
+    Json(
+        state
+            .message_stack
+            .consumers()
+            .into_iter()
+            .map(|ci| MessageConsumers {
+                topic: ci.topic,
+                topic_prefix: ci.topic_prefix,
+                waiting: ci.waiting,
+                oldest_wait_secs: ci.oldest_wait.as_secs(),
+            })
+            .collect(),
+    )
+}
+
+/// A topic on which messages have been received recently.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageTopic {
+    /// The topic.
+    #[serde(with = "base64::binary")]
+    pub topic: Vec<u8>,
+    /// Amount of messages received on the topic.
+    pub count: u64,
+    /// Amount of seconds since the last message on the topic was received.
+    pub last_seen_secs: u64,
+}
+
+/// List the distinct topics of recently received messages, with the amount of messages received
+/// on each of them.
+async fn get_topics(State(state): State<HttpServerState>) -> Json<Vec<MessageTopic>> {
+    debug!("Fetching topics of received messages");
+
+    Json(
+        state
+            .message_stack
+            .seen_topics()
+            .into_iter()
+            .map(|st| MessageTopic {
+                topic: st.topic,
+                count: st.messages,
+                last_seen_secs: st.last_seen.as_secs(),
+            })
+            .collect(),
+    )
+}
+
+/// Limits which apply to messages sent through this node.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageLimits {
+    /// The maximum size of a message payload in bytes.
+    pub max_payload_size: usize,
+    /// The maximum size of a topic in bytes.
+    pub max_topic_size: usize,
+    /// The maximum size of a correlation id in bytes.
+    pub max_correlation_id_size: usize,
+    /// The maximum size of a content type in bytes.
+    pub max_content_type_size: usize,
+    /// The amount of seconds to try and send a message if it is not explicitly specified.
+    pub default_try_duration_secs: u64,
+    /// The maximum amount of seconds the default try duration can be set to.
+    pub max_try_duration_secs: u64,
+    /// Whether received messages can be replied to.
+    pub replies_supported: bool,
+}
+
+/// Get the limits which apply to messages, so clients can configure themselves.
+async fn get_limits(State(state): State<HttpServerState>) -> Response {
+    debug!("Fetching message limits");
+
+    (
+        [(header::CACHE_CONTROL, MESSAGE_LIMITS_CACHE_CONTROL)],
+        Json(MessageLimits {
+            max_payload_size: state.max_payload_size,
+            max_topic_size: MAX_TOPIC_SIZE,
+            max_correlation_id_size: MAX_CORRELATION_ID_SIZE,
+            max_content_type_size: MAX_CONTENT_TYPE_SIZE,
+            default_try_duration_secs: state.message_try_duration.get().as_secs(),
+            max_try_duration_secs: MAX_MESSAGE_TRY_DURATION.as_secs(),
+            replies_supported: true,
+        }),
+    )
+        .into_response()
+}
+
+/// Amount of time to try and send a message.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TryDuration {
+    /// The amount of seconds to try and send a message.
+    pub seconds: u64,
+}
+
+/// Get the amount of time to try and send a message if it is not explicitly specified.
+async fn get_message_try_duration(State(state): State<HttpServerState>) -> Json<TryDuration> {
+    debug!("Loading default message try duration");
+    Json(TryDuration {
+        seconds: state.message_try_duration.get().as_secs(),
+    })
+}
+
+/// Set the amount of time to try and send a message if it is not explicitly specified. This
+/// applies to messages pushed afterwards. The duration is clamped to a maximum, the effective
+/// duration is returned.
+async fn set_message_try_duration(
+    State(state): State<HttpServerState>,
+    JsonBody(try_duration): JsonBody<TryDuration>,
+) -> Result<Json<TryDuration>, ApiError> {
+    debug!(
+        "Setting default message try duration to {} seconds",
+        try_duration.seconds
+    );
+    let try_duration = state
+        .message_try_duration
+        .set(Duration::from_secs(try_duration.seconds))
+        .map_err(|e| {
+            debug!("Rejecting message try duration: {e}");
+            ApiError::from(e)
+        })?;
+    Ok(Json(TryDuration {
+        seconds: try_duration.as_secs(),
+    }))
+}
+
+/// Aggregation settings of a topic.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopicAggregation {
+    /// The aggregated topic.
+    #[serde(with = "base64::binary")]
+    pub topic: Vec<u8>,
+    /// The maximum amount of messages in a batch.
+    pub max_messages: usize,
+    /// The maximum amount of milliseconds a message waits for a batch to fill up.
+    pub max_delay_ms: u64,
+}
+
+/// Query to select an aggregated topic.
+#[derive(Deserialize)]
+struct AggregationQuery {
+    /// The aggregated topic, base64 encoded.
+    #[serde(with = "base64::binary")]
+    topic: Vec<u8>,
+}
+
+/// List all aggregated topics and their settings.
+async fn get_aggregations(State(state): State<HttpServerState>) -> Json<Vec<TopicAggregation>> {
+    debug!("Fetching message aggregations");
+
+    Json(
+        state
+            .message_stack
+            .aggregations()
+            .into_iter()
+            .map(|(topic, aggregation)| TopicAggregation {
+                topic,
+                max_messages: aggregation.max_messages,
+                max_delay_ms: aggregation.max_delay.as_millis() as u64,
+            })
+            .collect(),
+    )
+}
+
+/// Aggregate inbound messages on a topic, or change the settings of an aggregated topic.
+async fn set_aggregation(
+    State(state): State<HttpServerState>,
+    JsonBody(aggregation): JsonBody<TopicAggregation>,
+) -> Result<StatusCode, ApiError> {
+    debug!("Setting message aggregation");
+
+    if aggregation.max_messages == 0 {
+        return Err(ApiError::new(
+            ErrorCode::InvalidAggregation,
+            "maxMessages must be at least 1",
+        ));
+    }
+
+    state.message_stack.set_aggregation(
+        aggregation.topic,
+        Some(Aggregation {
+            max_messages: aggregation.max_messages,
+            max_delay: Duration::from_millis(aggregation.max_delay_ms),
+        }),
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stop aggregating inbound messages on a topic.
+async fn delete_aggregation(
+    State(state): State<HttpServerState>,
+    Query(query): Query<AggregationQuery>,
+) -> Result<StatusCode, ApiError> {
+    debug!("Removing message aggregation");
+
+    if state.message_stack.aggregation(&query.topic).is_none() {
+        return Err(ApiError::new(
+            ErrorCode::AggregationNotFound,
+            "Messages on that topic are not aggregated",
+        ));
+    }
+    state.message_stack.set_aggregation(query.topic, None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Result of a self test.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfTestResult {
+    /// Whether the message sent to ourselves was received back.
+    pub success: bool,
+    /// Time between sending the message and receiving it, in milliseconds. Only set on success.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// The error encountered, if the self test failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Send a message to our own overlay address, and wait for it to be received back. This exercises
+/// the full local message pipeline: sending, routing and receiving.
+async fn self_test(State(state): State<HttpServerState>) -> Json<SelfTestResult> {
+    debug!("Running self test");
+
+    let own_ip: IpAddr = state
+        .router
+        .lock()
+        .unwrap()
+        .node_public_key()
+        .address()
+        .into();
+    let start = std::time::Instant::now();
+
+    // The message we send has the id we subscribe to, so once it arrives it is delivered to the
+    // subscription, and never ends up in the inbox.
+    let (_, mut sub) = match state.message_stack.new_message_with_reply(
+        own_ip,
+        SELF_TEST_PAYLOAD.to_vec(),
+        vec![],
+        vec![],
+        String::new(),
+        SELF_TEST_TIMEOUT,
+        RetrySchedule::default(),
+    ) {
+        Ok(res) => res,
+        Err(e) => {
+            return Json(SelfTestResult {
+                success: false,
+                latency_ms: None,
+                error: Some(format!("Failed to send message: {e}")),
+            })
+        }
+    };
+
+    let result = match tokio::time::timeout(SELF_TEST_TIMEOUT, sub.reply()).await {
+        Ok(m) if m.data == SELF_TEST_PAYLOAD => SelfTestResult {
+            success: true,
+            latency_ms: Some(start.elapsed().as_millis() as u64),
+            error: None,
+        },
+        Ok(_) => SelfTestResult {
+            success: false,
+            latency_ms: None,
+            error: Some("Received message has a different payload than sent".to_string()),
+        },
+        Err(_) => SelfTestResult {
+            success: false,
+            latency_ms: None,
+            error: Some(format!(
+                "Message was not received within {} seconds",
+                SELF_TEST_TIMEOUT.as_secs()
+            )),
+        },
+    };
+
+    if let Some(ref e) = result.error {
+        warn!("Self test failed: {e}");
+    }
+
+    Json(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::Body,
+        extract::{FromRequest, Query, Request, State},
+        http::{header, HeaderMap, HeaderValue, StatusCode},
+        response::IntoResponse,
+        Json,
+    };
+
+    use crate::api::{ErrorCode, JsonBody, Metric, Negotiated, CBOR_CONTENT_TYPE};
+    use crate::crypto::{PublicKey, SecretKey};
+    use crate::message::{
+        MessageStack, RetrySchedule, TopicFilter, TransmissionProgress, MAX_CONTENT_TYPE_SIZE,
+        MAX_TOPIC_SIZE,
+    };
+    use crate::testing;
+
+    use super::{
+        broadcast_message, check_payload_size, get_limits, get_message, push_message,
+        push_messages, query_topics, set_message_try_duration, validate_destination,
+        BroadcastMessageInfo, GetMessageQuery, InvalidDestination, InvalidTryDuration,
+        MessageDestination, MessageIdReply, MessageReceiveInfo, MessageSendInfo,
+        MessageTryDuration, PayloadTooLarge, PushMessageQuery, TopicTooLarge, TryDuration,
+        ValidateDestinationInfo, DEFAULT_MESSAGE_TRY_DURATION, MAX_MESSAGE_TRY_DURATION,
+    };
+
+    /// Create a new [`MessageStack`] which is not connected to any peer.
+    fn message_stack() -> MessageStack {
+        MessageStack::new(testing::data_plane(), tokio_stream::pending(), false)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn message_is_abandoned_after_changed_try_duration() {
+        let ms = message_stack();
+        let try_duration = MessageTryDuration::default();
+        assert_eq!(try_duration.get(), DEFAULT_MESSAGE_TRY_DURATION);
+        assert_eq!(
+            try_duration.set(Duration::from_secs(30)),
+            Ok(Duration::from_secs(30))
+        );
+
+        let results = push_messages(
+            &ms,
+            try_duration.get(),
+            1024,
+            vec![MessageSendInfo {
+                dst: MessageDestination::Ip("400::1".parse().unwrap()),
+                topic: None,
+                correlation_id: None,
+                content_type: None,
+                retry: None,
+                payload: b"ping".to_vec(),
+            }],
+        );
+        let id = results[0].id.expect("Message is pushed");
+
+        // There is no route to the destination, so the message is still being sent right before
+        // its try duration expires, and abandoned right after.
+        tokio::time::sleep(Duration::from_secs(29)).await;
+        let info = ms.message_info(id).expect("Pushed message is tracked");
+        assert!(!matches!(info.state, TransmissionProgress::Aborted));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let info = ms.message_info(id).expect("Abandoned message is tracked");
+        assert!(matches!(info.state, TransmissionProgress::Aborted));
+    }
+
+    #[tokio::test]
+    async fn batch_rejects_messages_individually() {
+        let ms = message_stack();
+        let message = |dst: &str| MessageSendInfo {
+            dst: MessageDestination::Ip(dst.parse().unwrap()),
+            topic: None,
+            correlation_id: None,
+            content_type: None,
+            retry: None,
+            payload: b"ping".to_vec(),
+        };
+
+        let results = push_messages(
+            &ms,
+            DEFAULT_MESSAGE_TRY_DURATION,
+            1024,
+            vec![message("400::1"), message("10.0.0.1"), message("400::2")],
+        );
+
+        assert_eq!(
+            results.iter().map(|r| r.status).collect::<Vec<_>>(),
+            [201, 400, 201]
+        );
+        assert!(results[0].id.is_some() && results[0].error.is_none());
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some(InvalidDestination.to_string().as_str())
+        );
+        assert!(ms.message_info(results[2].id.unwrap()).is_some());
+    }
+
+    #[test]
+    fn payload_size_is_limited() {
+        let max_payload_size = 1024;
+
+        assert_eq!(
+            check_payload_size(&vec![0; max_payload_size - 1], max_payload_size),
+            Ok(())
+        );
+        assert_eq!(
+            check_payload_size(&vec![0; max_payload_size], max_payload_size),
+            Ok(())
+        );
+        assert_eq!(
+            check_payload_size(&vec![0; max_payload_size + 1], max_payload_size),
+            Err(PayloadTooLarge { max_payload_size })
+        );
+    }
+
+    #[test]
+    fn message_is_encoded_as_cbor_with_raw_bytes() {
+        let info = MessageSendInfo {
+            dst: MessageDestination::Ip("400::1".parse().unwrap()),
+            topic: Some(b"topic".to_vec()),
+            correlation_id: None,
+            content_type: None,
+            retry: None,
+            payload: vec![0, 1, 2, 255],
+        };
+
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&info, &mut cbor).unwrap();
+        // The payload is a byte string of 4 bytes, and the destination is still text.
+        assert!(cbor.windows(5).any(|w| w == [0x44, 0, 1, 2, 255]));
+        assert!(cbor.windows(6).any(|w| w == b"400::1"));
+
+        let decoded: MessageSendInfo = ciborium::from_reader(&cbor[..]).unwrap();
+        assert!(
+            matches!(decoded.dst, MessageDestination::Ip(ip) if ip == "400::1".parse::<std::net::IpAddr>().unwrap())
+        );
+        assert_eq!(decoded.topic, Some(b"topic".to_vec()));
+        assert_eq!(decoded.payload, vec![0, 1, 2, 255]);
+
+        // JSON still encodes binary fields as base64.
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["payload"], "AAEC/w==");
+        assert_eq!(json["dst"]["ip"], "400::1");
+    }
+
+    #[test]
+    fn requested_try_duration_is_validated() {
+        let query = |try_duration_secs| PushMessageQuery {
+            reply_timeout: None,
+            try_duration_secs,
+        };
+
+        assert_eq!(
+            query(None).try_duration(DEFAULT_MESSAGE_TRY_DURATION),
+            Ok(DEFAULT_MESSAGE_TRY_DURATION)
+        );
+        assert_eq!(
+            query(Some(30)).try_duration(DEFAULT_MESSAGE_TRY_DURATION),
+            Ok(Duration::from_secs(30))
+        );
+        assert_eq!(
+            query(Some(MAX_MESSAGE_TRY_DURATION.as_secs()))
+                .try_duration(DEFAULT_MESSAGE_TRY_DURATION),
+            Ok(MAX_MESSAGE_TRY_DURATION)
+        );
+        assert_eq!(
+            query(Some(0)).try_duration(DEFAULT_MESSAGE_TRY_DURATION),
+            Err(InvalidTryDuration)
+        );
+        assert_eq!(
+            query(Some(MAX_MESSAGE_TRY_DURATION.as_secs() + 1))
+                .try_duration(DEFAULT_MESSAGE_TRY_DURATION),
+            Err(InvalidTryDuration)
+        );
+    }
+
+    #[test]
+    fn try_duration_is_clamped() {
+        let try_duration = MessageTryDuration::default();
+
+        assert_eq!(
+            try_duration.set(MAX_MESSAGE_TRY_DURATION * 2),
+            Ok(MAX_MESSAGE_TRY_DURATION)
+        );
+        assert_eq!(try_duration.get(), MAX_MESSAGE_TRY_DURATION);
+
+        // A duration of 0 is rejected, and the previous duration is kept.
+        assert_eq!(try_duration.set(Duration::ZERO), Err(InvalidTryDuration));
+        assert_eq!(try_duration.get(), MAX_MESSAGE_TRY_DURATION);
+    }
+
+    #[tokio::test]
+    async fn zero_default_try_duration_is_rejected() {
+        let state = crate::api::tests::server_state();
+
+        let err =
+            set_message_try_duration(State(state.clone()), JsonBody(TryDuration { seconds: 0 }))
+                .await
+                .expect_err("0 seconds is rejected");
+        assert_eq!(err.code(), ErrorCode::InvalidTryDuration);
+        assert_eq!(err.code().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            state.message_try_duration.get(),
+            DEFAULT_MESSAGE_TRY_DURATION
+        );
+    }
+
+    #[test]
+    fn repeated_topics_are_loaded_in_order() {
+        let params = [
+            ("topic", "Zmlyc3Q="),
+            ("timeout", "60"),
+            ("topic", "c2Vjb25k"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+
+        let topics = query_topics(&params).expect("Topics are valid base64");
+        assert_eq!(topics, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn invalid_topic_is_rejected() {
+        let params = [("topic".to_string(), "not base64!".to_string())];
+
+        assert!(query_topics(&params).is_err());
+    }
+
+    #[test]
+    fn oversized_topic_is_rejected() {
+        let query = GetMessageQuery {
+            peek: None,
+            timeout: None,
+            topic_prefix: None,
+            correlation_id: None,
+        };
+
+        assert_eq!(
+            query.check_topic_sizes(&[vec![0; MAX_TOPIC_SIZE], b"small".to_vec()]),
+            Ok(())
+        );
+        assert_eq!(
+            query.check_topic_sizes(&[b"small".to_vec(), vec![0; MAX_TOPIC_SIZE + 1]]),
+            Err(TopicTooLarge)
+        );
+
+        let query = GetMessageQuery {
+            peek: None,
+            timeout: None,
+            topic_prefix: Some(vec![0; MAX_TOPIC_SIZE + 1]),
+            correlation_id: None,
+        };
+        assert_eq!(query.check_topic_sizes(&[]), Err(TopicTooLarge));
+    }
+
+    #[tokio::test]
+    async fn messages_are_filtered_on_topic_and_correlation_id() {
+        let mut state = crate::api::tests::server_state();
+        let (message_stack, own_ip) = testing::loopback_message_stack();
+        state.message_stack = message_stack;
+        for (topic, correlation_id) in [
+            ("rpc", "other"),
+            ("events", "request-1"),
+            ("rpc", "request-1"),
+        ] {
+            state
+                .message_stack
+                .new_message(
+                    own_ip,
+                    b"ping".to_vec(),
+                    topic.into(),
+                    correlation_id.into(),
+                    String::new(),
+                    DEFAULT_MESSAGE_TRY_DURATION,
+                    RetrySchedule::default(),
+                )
+                .expect("Message is pushed");
+        }
+        let get = |topic: Option<&str>, correlation_id: &str, timeout| {
+            get_message(
+                State(state.clone()),
+                Query(GetMessageQuery {
+                    peek: None,
+                    timeout: Some(timeout),
+                    topic_prefix: None,
+                    correlation_id: Some(correlation_id.as_bytes().to_vec()),
+                }),
+                Query(
+                    topic
+                        .map(|topic| vec![("topic".to_string(), topic.to_string())])
+                        .unwrap_or_default(),
+                ),
+                HeaderMap::new(),
+            )
+        };
+        let received = |response: axum::response::Response| async move {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<MessageReceiveInfo>(&body).expect("Message is returned")
+        };
+
+        // The topic and the correlation id must both match, "cnBj" is "rpc" in base64.
+        let msg = received(
+            get(Some("cnBj"), "request-1", 5)
+                .await
+                .expect("Valid query"),
+        )
+        .await;
+        assert_eq!(msg.topic.as_deref(), Some(b"rpc".as_slice()));
+        assert_eq!(msg.correlation_id.as_deref(), Some(b"request-1".as_slice()));
+
+        // Without a topic, any topic matches.
+        let msg = received(get(None, "request-1", 5).await.expect("Valid query")).await;
+        assert_eq!(msg.topic.as_deref(), Some(b"events".as_slice()));
+
+        // The only message left has a different correlation id.
+        let response = get(None, "request-1", 0).await.expect("Valid query");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn multiple_topics_match_any_of_them() {
+        let query = GetMessageQuery {
+            peek: None,
+            timeout: None,
+            topic_prefix: Some(b"app/".to_vec()),
+            correlation_id: None,
+        };
+
+        assert_eq!(
+            query.topic_filter(vec![]),
+            Some(TopicFilter::Prefix(b"app/".to_vec()))
+        );
+        assert_eq!(
+            query.topic_filter(vec![b"first".to_vec()]),
+            Some(TopicFilter::Exact(b"first".to_vec()))
+        );
+        assert_eq!(
+            query.topic_filter(vec![b"first".to_vec(), b"second".to_vec()]),
+            Some(TopicFilter::AnyOf(vec![
+                b"first".to_vec(),
+                b"second".to_vec()
+            ]))
+        );
+    }
+
+    #[test]
+    fn overlay_ip_destination_is_valid() {
+        let ip = "4a3:bc:e7d:10:aa:bb:cc:dd".parse().unwrap();
+        assert_eq!(MessageDestination::Ip(ip).ip(), Ok(ip));
+
+        let pk = PublicKey::from(&SecretKey::new());
+        assert_eq!(
+            MessageDestination::Pk(pk).ip(),
+            Ok(std::net::IpAddr::V6(pk.address()))
+        );
+    }
+
+    #[test]
+    fn public_ipv6_destination_is_invalid() {
+        assert_eq!(
+            MessageDestination::Ip("2001:db8::1".parse().unwrap()).ip(),
+            Err(InvalidDestination)
+        );
+    }
+
+    #[test]
+    fn ipv4_destination_is_invalid() {
+        assert_eq!(
+            MessageDestination::Ip("10.0.0.1".parse().unwrap()).ip(),
+            Err(InvalidDestination)
+        );
+    }
+
+    #[test]
+    fn correlation_id_round_trip() {
+        let msi = MessageSendInfo {
+            dst: MessageDestination::Ip("400::1".parse().unwrap()),
+            topic: None,
+            correlation_id: Some(vec![0, 1, 254, 255]),
+            content_type: None,
+            retry: None,
+            payload: b"ping".to_vec(),
+        };
+
+        let s = serde_json::to_string(&msi).expect("can encode message send info");
+        assert!(s.contains("\"correlationId\":\"AAH+/w==\""));
+
+        let decoded: MessageSendInfo =
+            serde_json::from_str(&s).expect("can decode message send info");
+        assert_eq!(decoded.correlation_id, Some(vec![0, 1, 254, 255]));
+    }
+
+    #[test]
+    fn correlation_id_is_optional() {
+        let decoded: MessageSendInfo =
+            serde_json::from_str(r#"{"dst":{"ip":"400::1"},"payload":"cGluZw=="}"#)
+                .expect("can decode message send info without correlation id");
+
+        assert_eq!(decoded.correlation_id, None);
+    }
+
+    #[test]
+    fn empty_payload_round_trip() {
+        let msi = MessageSendInfo {
+            dst: MessageDestination::Ip("400::1".parse().unwrap()),
+            topic: Some(b"signal".to_vec()),
+            correlation_id: None,
+            content_type: None,
+            retry: None,
+            payload: vec![],
+        };
+
+        let s = serde_json::to_string(&msi).expect("can encode message send info");
+        assert!(s.contains("\"payload\":\"\""));
+
+        let decoded: MessageSendInfo =
+            serde_json::from_str(&s).expect("can decode message send info");
+        assert!(decoded.payload.is_empty());
+        assert_eq!(decoded.topic, Some(b"signal".to_vec()));
+    }
+
+    #[test]
+    fn content_type_round_trip() {
+        let msi = MessageSendInfo {
+            dst: MessageDestination::Ip("400::1".parse().unwrap()),
+            topic: None,
+            correlation_id: None,
+            content_type: Some("application/json".to_string()),
+            retry: None,
+            payload: b"{}".to_vec(),
+        };
+
+        let s = serde_json::to_string(&msi).expect("can encode message send info");
+        assert!(s.contains("\"contentType\":\"application/json\""));
+
+        let decoded: MessageSendInfo =
+            serde_json::from_str(&s).expect("can decode message send info");
+        assert_eq!(decoded.content_type.as_deref(), Some("application/json"));
+
+        let decoded: MessageSendInfo =
+            serde_json::from_str(r#"{"dst":{"ip":"400::1"},"payload":"cGluZw=="}"#)
+                .expect("can decode message send info without content type");
+        assert_eq!(decoded.content_type, None);
+    }
+
+    #[test]
+    fn retry_schedule_round_trip() {
+        let msi = MessageSendInfo {
+            dst: MessageDestination::Ip("400::1".parse().unwrap()),
+            topic: None,
+            correlation_id: None,
+            content_type: None,
+            retry: Some(RetrySchedule {
+                initial_interval: Duration::from_millis(200),
+                multiplier: 2.,
+                max_interval: Duration::from_secs(10),
+            }),
+            payload: b"ping".to_vec(),
+        };
+
+        let s = serde_json::to_string(&msi).expect("can encode message send info");
+        assert!(s.contains(
+            "\"retry\":{\"initialIntervalMs\":200,\"multiplier\":2.0,\"maxIntervalMs\":10000}"
+        ));
+
+        let decoded: MessageSendInfo =
+            serde_json::from_str(&s).expect("can decode message send info");
+        assert_eq!(decoded.retry, msi.retry);
+
+        let decoded: MessageSendInfo =
+            serde_json::from_str(r#"{"dst":{"ip":"400::1"},"payload":"cGluZw=="}"#)
+                .expect("can decode message send info without retry schedule");
+        assert_eq!(decoded.retry, None);
+    }
+
+    /// Create a request with the given body, encoded as CBOR.
+    fn cbor_request(body: Vec<u8>) -> Request {
+        Request::builder()
+            .method("POST")
+            .header(header::CONTENT_TYPE, CBOR_CONTENT_TYPE)
+            .body(Body::from(body))
+            .expect("Valid request; qed")
+    }
+
+    #[tokio::test]
+    async fn broadcast_reaches_every_neighbour_once() {
+        let state = crate::api::tests::server_state();
+        let router = state.router.lock().unwrap().clone();
+        let message = || {
+            JsonBody(BroadcastMessageInfo {
+                topic: None,
+                content_type: None,
+                payload: b"hello".to_vec(),
+            })
+        };
+
+        let Json(result) = broadcast_message(State(state.clone()), message())
+            .await
+            .expect("Broadcast without neighbours succeeds");
+        assert_eq!(result.reached, 0);
+
+        // The same neighbour is connected twice.
+        let (neighbour, _, _) = testing::router(None);
+        for _ in 0..2 {
+            let (stream, remote_stream) = testing::tcp_pair().await;
+            router.add_peer_interface(testing::router_peer(&router, stream));
+            neighbour.add_peer_interface(testing::router_peer(&neighbour, remote_stream));
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while router.neighbour_keys().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Neighbour announces its subnet over both connections");
+
+        let Json(result) = broadcast_message(State(state), message())
+            .await
+            .expect("Broadcast succeeds");
+        assert_eq!(result.reached, 1);
+    }
+
+    #[tokio::test]
+    async fn broadcast_with_oversized_fields_is_rejected() {
+        let state = crate::api::tests::server_state();
+        let broadcast = |topic, content_type| {
+            broadcast_message(
+                State(state.clone()),
+                JsonBody(BroadcastMessageInfo {
+                    topic: Some(topic),
+                    content_type: Some(content_type),
+                    payload: b"hello".to_vec(),
+                }),
+            )
+        };
+
+        let err = broadcast(vec![0; MAX_TOPIC_SIZE + 1], String::new())
+            .await
+            .err()
+            .expect("oversized topic is rejected");
+        assert_eq!(err.code(), ErrorCode::TopicTooLarge);
+        assert_eq!(err.code().status(), StatusCode::BAD_REQUEST);
+
+        let err = broadcast(b"topic".to_vec(), "a".repeat(MAX_CONTENT_TYPE_SIZE + 1))
+            .await
+            .err()
+            .expect("oversized content type is rejected");
+        assert_eq!(err.code(), ErrorCode::ContentTypeTooLarge);
+        assert_eq!(err.code().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn message_is_pushed_as_cbor() {
+        let state = crate::api::tests::server_state();
+        let mut body = Vec::new();
+        ciborium::into_writer(
+            &MessageSendInfo {
+                dst: MessageDestination::Ip("400::1".parse().unwrap()),
+                topic: Some(b"topic".to_vec()),
+                correlation_id: None,
+                content_type: None,
+                retry: None,
+                payload: b"ping".to_vec(),
+            },
+            &mut body,
+        )
+        .unwrap();
+        let message = Negotiated::<MessageSendInfo>::from_request(cbor_request(body), &())
+            .await
+            .expect("can decode CBOR message");
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(CBOR_CONTENT_TYPE));
+
+        let response = push_message(
+            State(state.clone()),
+            Query(PushMessageQuery {
+                reply_timeout: None,
+                try_duration_secs: None,
+            }),
+            headers,
+            message,
+        )
+        .await
+        .expect("message is pushed");
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE),
+            Some(&HeaderValue::from_static(CBOR_CONTENT_TYPE))
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let reply: MessageIdReply =
+            ciborium::from_reader(&body[..]).expect("response is encoded as CBOR");
+        let info = state
+            .message_stack
+            .message_info(reply.id)
+            .expect("message is pushed to the message stack");
+        assert_eq!(info.dst, "400::1".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(info.msg_len, 4);
+    }
+
+    #[tokio::test]
+    async fn push_rejects_payload_over_limit() {
+        // The payload of messages is limited to 1 KiB.
+        let state = crate::api::tests::server_state();
+        let push = |size| {
+            push_message(
+                State(state.clone()),
+                Query(PushMessageQuery {
+                    reply_timeout: None,
+                    try_duration_secs: None,
+                }),
+                HeaderMap::new(),
+                Negotiated(MessageSendInfo {
+                    dst: MessageDestination::Ip("400::1".parse().unwrap()),
+                    topic: None,
+                    correlation_id: None,
+                    content_type: None,
+                    retry: None,
+                    payload: vec![0; size],
+                }),
+            )
+        };
+
+        for size in [1023, 1024] {
+            let response = push(size).await.expect("payload within limit is accepted");
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        let err = push(1025)
+            .await
+            .expect_err("payload over limit is rejected");
+        assert_eq!(err.code(), ErrorCode::PayloadTooLarge);
+        assert_eq!(err.into_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn limits_report_the_payload_size() {
+        let response = get_limits(State(crate::api::tests::server_state())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let limits: serde_json::Value = serde_json::from_slice(&body).expect("limits are JSON");
+        assert_eq!(limits["maxPayloadSize"], 1024);
+    }
+
+    #[tokio::test]
+    async fn own_subnet_is_reachable_without_next_hop() {
+        let state = crate::api::tests::server_state();
+        let own_ip: std::net::IpAddr = state
+            .router
+            .lock()
+            .unwrap()
+            .node_public_key()
+            .address()
+            .into();
+        let validate = |dst| {
+            validate_destination(
+                State(state.clone()),
+                JsonBody(ValidateDestinationInfo {
+                    dst: MessageDestination::Ip(dst),
+                }),
+            )
+        };
+
+        let Json(validation) = validate(own_ip).await.expect("own address is valid");
+        assert!(validation.reachable);
+        assert_eq!(validation.next_hop, None);
+        assert!(matches!(validation.metric, Some(Metric::Value(0))));
+
+        let Json(validation) = validate("400::1".parse().unwrap())
+            .await
+            .expect("overlay address is valid");
+        assert!(!validation.reachable);
+        assert_eq!(validation.next_hop, None);
+        assert!(validation.metric.is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_cbor_is_rejected_with_api_error() {
+        let err = Negotiated::<MessageSendInfo>::from_request(cbor_request(vec![0xff]), &())
+            .await
+            .err()
+            .expect("invalid CBOR is rejected");
+
+        assert_eq!(err.code(), ErrorCode::InvalidBody);
+        assert_eq!(err.code().status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+/// Module to encode IP addresses as text, also in formats which are not human readable like CBOR.
+/// Serde encodes them as raw octets in those formats, which would change the shape of a message.
+mod ip_text {
+    use std::net::IpAddr;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(ip: &IpAddr, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(ip)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<IpAddr, D::Error> {
+        let ip = String::deserialize(d)?;
+        ip.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 /// Module to implement base64 decoding and encoding
 /// Sourced from https://users.rust-lang.org/t/serialize-a-vec-u8-to-json-as-base64/57781, with some
 /// addaptions to work with the new version of the base64 crate
@@ -276,18 +2141,67 @@ mod base64 {
         GeneralPurposeConfig::new(),
     );
 
+    /// Decode a single base64 encoded value.
+    pub fn decode(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+        use base64::Engine;
+        B64ENGINE.decode(value.as_bytes())
+    }
+
+    /// Binary value in a format which is not human readable, like CBOR. These formats can hold
+    /// raw bytes, so values are not base64 encoded.
+    struct RawBytes(Vec<u8>);
+
+    struct RawBytesVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for RawBytesVisitor {
+        type Value = RawBytes;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("A byte string")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(RawBytes(v.to_vec()))
+        }
+
+        fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(RawBytes(v))
+        }
+    }
+
+    impl<'de> serde::Deserialize<'de> for RawBytes {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_byte_buf(RawBytesVisitor)
+        }
+    }
+
     pub mod binary {
-        use super::B64ENGINE;
+        use super::{RawBytes, B64ENGINE};
         use base64::Engine;
         use serde::{Deserialize, Serialize};
         use serde::{Deserializer, Serializer};
 
         pub fn serialize<S: Serializer>(v: &Vec<u8>, s: S) -> Result<S::Ok, S::Error> {
+            if !s.is_human_readable() {
+                return s.serialize_bytes(v);
+            }
             let base64 = B64ENGINE.encode(v);
             String::serialize(&base64, s)
         }
 
         pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+            if !d.is_human_readable() {
+                return RawBytes::deserialize(d).map(|raw| raw.0);
+            }
             let base64 = String::deserialize(d)?;
             B64ENGINE
                 .decode(base64.as_bytes())
@@ -296,21 +2210,26 @@ mod base64 {
     }
 
     pub mod optional_binary {
-        use super::B64ENGINE;
+        use super::{RawBytes, B64ENGINE};
         use base64::Engine;
         use serde::{Deserialize, Serialize};
         use serde::{Deserializer, Serializer};
 
         pub fn serialize<S: Serializer>(v: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
-            if let Some(v) = v {
-                let base64 = B64ENGINE.encode(v);
-                String::serialize(&base64, s)
-            } else {
-                <Option<String>>::serialize(&None, s)
+            match v {
+                Some(v) if !s.is_human_readable() => s.serialize_bytes(v),
+                Some(v) => {
+                    let base64 = B64ENGINE.encode(v);
+                    String::serialize(&base64, s)
+                }
+                None => <Option<String>>::serialize(&None, s),
             }
         }
 
         pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+            if !d.is_human_readable() {
+                return Ok(<Option<RawBytes>>::deserialize(d)?.map(|raw| raw.0));
+            }
             if let Some(base64) = <Option<String>>::deserialize(d)? {
                 B64ENGINE
                     .decode(base64.as_bytes())
@@ -0,0 +1,476 @@
+//! Metrics of the node in the Prometheus text exposition format, so the node can be scraped
+//! without a separate exporter.
+
+use std::{
+    fmt::{self, Display, Write},
+    str::FromStr,
+};
+
+use axum::{extract::State, http::header, response::IntoResponse};
+use log::debug;
+
+use crate::{
+    events::PacketDropReason,
+    peer_manager::{ConnectionState, PeerStats, PeerType},
+};
+
+use super::HttpServerState;
+
+/// Content type of the Prometheus text exposition format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Names of the labels the node sets on its own metrics. These can't be used as static label, as
+/// a sample with the same label twice is invalid.
+const OWN_LABEL_NAMES: [&str; 5] = ["endpoint", "type", "state", "kind", "direction"];
+
+/// A label which is added to every sample, set when the node starts. This allows telling apart
+/// nodes which are scraped by the same Prometheus server, e.g. by region or deployment.
+///
+/// A label is parsed from `name=value`. The name must be a valid Prometheus label name, i.e. match
+/// `[a-zA-Z_][a-zA-Z0-9_]*`. Names starting with `__` are reserved by Prometheus, and the names of
+/// the labels the node sets itself can't be used either. The value can be any string, but must
+/// not be empty, since Prometheus treats an empty label the same as a missing one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricLabel {
+    name: String,
+    value: String,
+}
+
+/// Error returned for an invalid [`MetricLabel`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidMetricLabel {
+    /// The label is not in the `name=value` form.
+    MissingValue,
+    /// The name of the label is not a valid Prometheus label name.
+    InvalidName(String),
+    /// The name of the label is reserved by Prometheus, or set by the node itself.
+    ReservedName(String),
+    /// The value of the label is empty.
+    EmptyValue(String),
+    /// The same label name is set more than once.
+    DuplicateName(String),
+}
+
+impl MetricLabel {
+    /// Get the name of the label.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the value of the label.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+impl FromStr for MetricLabel {
+    type Err = InvalidMetricLabel;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=').ok_or(InvalidMetricLabel::MissingValue)?;
+        let mut chars = name.chars();
+        let valid_name = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(InvalidMetricLabel::InvalidName(name.to_string()));
+        }
+        if name.starts_with("__") || OWN_LABEL_NAMES.contains(&name) {
+            return Err(InvalidMetricLabel::ReservedName(name.to_string()));
+        }
+        if value.is_empty() {
+            return Err(InvalidMetricLabel::EmptyValue(name.to_string()));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for MetricLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+impl fmt::Display for InvalidMetricLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingValue => f.write_str("metric label must be in the form name=value"),
+            Self::InvalidName(name) => write!(
+                f,
+                "metric label name {name:?} must match [a-zA-Z_][a-zA-Z0-9_]*"
+            ),
+            Self::ReservedName(name) => write!(f, "metric label name {name} is reserved"),
+            Self::EmptyValue(name) => write!(f, "value of metric label {name} is empty"),
+            Self::DuplicateName(name) => write!(f, "metric label {name} is set more than once"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidMetricLabel {}
+
+/// Check that the static metric labels can be used together, i.e. that no label name is used
+/// more than once. Every label on its own is already validated when it is parsed.
+pub fn check_metric_labels(labels: &[MetricLabel]) -> Result<(), InvalidMetricLabel> {
+    for (i, label) in labels.iter().enumerate() {
+        if labels[..i].iter().any(|other| other.name == label.name) {
+            return Err(InvalidMetricLabel::DuplicateName(label.name.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Type of a metric family.
+#[derive(Debug, Clone, Copy)]
+pub enum MetricType {
+    /// A value which only goes up, unless the node restarts.
+    Counter,
+    /// A value which can go up and down.
+    Gauge,
+}
+
+impl MetricType {
+    /// Name of the type in the text format.
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+/// Encoder of metrics in the Prometheus text exposition format.
+#[derive(Default)]
+pub struct MetricsEncoder<'a> {
+    out: String,
+    /// Labels added to every sample, after the labels of the sample itself.
+    static_labels: &'a [MetricLabel],
+}
+
+impl<'a> MetricsEncoder<'a> {
+    /// Create a new, empty `MetricsEncoder`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new, empty `MetricsEncoder`, which adds the given static labels to every sample.
+    pub fn with_labels(static_labels: &'a [MetricLabel]) -> Self {
+        Self {
+            out: String::new(),
+            static_labels,
+        }
+    }
+
+    /// Start a new metric family. Samples of the family must be added before the next family is
+    /// started.
+    pub fn family(&mut self, name: &str, metric_type: MetricType, help: &str) -> &mut Self {
+        let _ = writeln!(self.out, "# HELP {name} {}", escape_help(help));
+        let _ = writeln!(self.out, "# TYPE {name} {}", metric_type.as_str());
+        self
+    }
+
+    /// Add a sample of the current metric family, with the given labels. The static labels of
+    /// the encoder are added as well.
+    pub fn sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: impl Display,
+    ) -> &mut Self {
+        self.out.push_str(name);
+        if !labels.is_empty() || !self.static_labels.is_empty() {
+            self.out.push('{');
+            let static_labels = self
+                .static_labels
+                .iter()
+                .map(|label| (label.name.as_str(), label.value.as_str()));
+            for (i, (label, label_value)) in labels.iter().copied().chain(static_labels).enumerate()
+            {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{label}=\"{}\"", escape_label_value(label_value));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {value}");
+        self
+    }
+
+    /// Get the encoded metrics.
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// Escape a help text, in which backslashes and line feeds must be escaped.
+fn escape_help(help: &str) -> String {
+    help.replace('\\', r"\\").replace('\n', r"\n")
+}
+
+/// Escape a label value, in which backslashes, double quotes and line feeds must be escaped.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Value of the type label of a peer, the same as in the JSON API.
+fn peer_type_label(pt: &PeerType) -> &'static str {
+    match pt {
+        PeerType::Static => "static",
+        PeerType::LinkLocalDiscovery => "linkLocalDiscovery",
+        PeerType::Inbound => "inbound",
+    }
+}
+
+/// Encode the metrics of the known peers.
+fn encode_peers(encoder: &mut MetricsEncoder<'_>, peers: &[PeerStats]) {
+    let labels = |peer: &PeerStats| (peer.endpoint.to_string(), peer_type_label(&peer.pt));
+
+    encoder.family(
+        "mycelium_peer_tx_bytes_total",
+        MetricType::Counter,
+        "Amount of bytes transmitted to a peer.",
+    );
+    for peer in peers {
+        let (endpoint, pt) = labels(peer);
+        encoder.sample(
+            "mycelium_peer_tx_bytes_total",
+            &[("endpoint", &endpoint), ("type", pt)],
+            peer.tx_bytes,
+        );
+    }
+
+    encoder.family(
+        "mycelium_peer_rx_bytes_total",
+        MetricType::Counter,
+        "Amount of bytes received from a peer.",
+    );
+    for peer in peers {
+        let (endpoint, pt) = labels(peer);
+        encoder.sample(
+            "mycelium_peer_rx_bytes_total",
+            &[("endpoint", &endpoint), ("type", pt)],
+            peer.rx_bytes,
+        );
+    }
+
+    encoder.family(
+        "mycelium_peer_decryption_failures_total",
+        MetricType::Counter,
+        "Amount of data packets received from a peer which could not be decrypted.",
+    );
+    for peer in peers {
+        let (endpoint, pt) = labels(peer);
+        encoder.sample(
+            "mycelium_peer_decryption_failures_total",
+            &[("endpoint", &endpoint), ("type", pt)],
+            peer.decryption_failures,
+        );
+    }
+
+    let (mut alive, mut connecting, mut dead) = (0, 0, 0);
+    for peer in peers {
+        match peer.connection_state {
+            ConnectionState::Alive => alive += 1,
+            ConnectionState::Connecting => connecting += 1,
+            ConnectionState::Dead => dead += 1,
+        }
+    }
+    encoder
+        .family(
+            "mycelium_peers",
+            MetricType::Gauge,
+            "Amount of known peers, by connection state.",
+        )
+        .sample("mycelium_peers", &[("state", "alive")], alive)
+        .sample("mycelium_peers", &[("state", "connecting")], connecting)
+        .sample("mycelium_peers", &[("state", "dead")], dead);
+}
+
+/// Get the metrics of the node in the Prometheus text exposition format.
+pub async fn get_metrics(State(state): State<HttpServerState>) -> impl IntoResponse {
+    debug!("Loading metrics");
+    let peers = state.peer_manager.peers();
+    // Only hold the router for as long as it takes to load the counters.
+    let (route_stats, decryption_failures) = {
+        let router = state.router.lock().unwrap();
+        (
+            router.route_stats(),
+            router
+                .packet_drop_log()
+                .count(PacketDropReason::DecryptionFailure),
+        )
+    };
+
+    let mut encoder = MetricsEncoder::with_labels(&state.metric_labels);
+    encode_peers(&mut encoder, &peers);
+    encoder
+        .family(
+            "mycelium_routes",
+            MetricType::Gauge,
+            "Amount of routes in the routing table, by kind.",
+        )
+        .sample(
+            "mycelium_routes",
+            &[("kind", "selected")],
+            route_stats.selected,
+        )
+        .sample(
+            "mycelium_routes",
+            &[("kind", "fallback")],
+            route_stats.fallback,
+        );
+    encoder
+        .family(
+            "mycelium_decryption_failures_total",
+            MetricType::Counter,
+            "Amount of data packets for this node which could not be decrypted.",
+        )
+        .sample(
+            "mycelium_decryption_failures_total",
+            &[],
+            decryption_failures,
+        );
+    #[cfg(feature = "message")]
+    {
+        encoder
+            .family(
+                "mycelium_messages_pending",
+                MetricType::Gauge,
+                "Amount of messages waiting to be sent or read, by direction.",
+            )
+            .sample(
+                "mycelium_messages_pending",
+                &[("direction", "outbound")],
+                state.message_stack.outbound_pending(),
+            )
+            .sample(
+                "mycelium_messages_pending",
+                &[("direction", "inbound")],
+                state.message_stack.inbound_queued(),
+            );
+    }
+
+    (
+        [(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)],
+        encoder.finish(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_metric_labels, InvalidMetricLabel, MetricLabel, MetricType, MetricsEncoder};
+
+    #[test]
+    fn metrics_are_encoded_in_text_format() {
+        let mut encoder = MetricsEncoder::new();
+        encoder
+            .family("mycelium_peers", MetricType::Gauge, "Amount of peers.")
+            .sample("mycelium_peers", &[("state", "alive")], 2)
+            .sample("mycelium_peers", &[("state", "dead")], 0);
+        encoder
+            .family("mycelium_total", MetricType::Counter, "A total.")
+            .sample("mycelium_total", &[], 10);
+
+        assert_eq!(
+            encoder.finish(),
+            "# HELP mycelium_peers Amount of peers.\n\
+             # TYPE mycelium_peers gauge\n\
+             mycelium_peers{state=\"alive\"} 2\n\
+             mycelium_peers{state=\"dead\"} 0\n\
+             # HELP mycelium_total A total.\n\
+             # TYPE mycelium_total counter\n\
+             mycelium_total 10\n"
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let mut encoder = MetricsEncoder::new();
+        encoder.sample(
+            "mycelium_peer_tx_bytes_total",
+            &[("endpoint", "a\"b\\c\nd"), ("type", "static")],
+            1,
+        );
+
+        assert_eq!(
+            encoder.finish(),
+            "mycelium_peer_tx_bytes_total{endpoint=\"a\\\"b\\\\c\\nd\",type=\"static\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn static_labels_are_added_to_every_sample() {
+        let labels = ["region=eu-west".parse(), "node=a\"b".parse()]
+            .into_iter()
+            .collect::<Result<Vec<MetricLabel>, _>>()
+            .expect("Valid labels; qed");
+        let mut encoder = MetricsEncoder::with_labels(&labels);
+        encoder
+            .family("mycelium_peers", MetricType::Gauge, "Amount of peers.")
+            .sample("mycelium_peers", &[("state", "alive")], 2);
+        encoder
+            .family("mycelium_total", MetricType::Counter, "A total.")
+            .sample("mycelium_total", &[], 10);
+
+        assert_eq!(
+            encoder.finish(),
+            "# HELP mycelium_peers Amount of peers.\n\
+             # TYPE mycelium_peers gauge\n\
+             mycelium_peers{state=\"alive\",region=\"eu-west\",node=\"a\\\"b\"} 2\n\
+             # HELP mycelium_total A total.\n\
+             # TYPE mycelium_total counter\n\
+             mycelium_total{region=\"eu-west\",node=\"a\\\"b\"} 10\n"
+        );
+    }
+
+    #[test]
+    fn static_labels_are_validated() {
+        let label = "_region1=eu=west"
+            .parse::<MetricLabel>()
+            .expect("Valid label; qed");
+        assert_eq!(label.name(), "_region1");
+        assert_eq!(label.value(), "eu=west");
+
+        for (input, err) in [
+            ("region", InvalidMetricLabel::MissingValue),
+            ("=eu", InvalidMetricLabel::InvalidName(String::new())),
+            (
+                "1region=eu",
+                InvalidMetricLabel::InvalidName("1region".into()),
+            ),
+            (
+                "re-gion=eu",
+                InvalidMetricLabel::InvalidName("re-gion".into()),
+            ),
+            (
+                "__name__=eu",
+                InvalidMetricLabel::ReservedName("__name__".into()),
+            ),
+            (
+                "endpoint=eu",
+                InvalidMetricLabel::ReservedName("endpoint".into()),
+            ),
+            ("region=", InvalidMetricLabel::EmptyValue("region".into())),
+        ] {
+            assert_eq!(input.parse::<MetricLabel>(), Err(err), "{input}");
+        }
+
+        let labels = vec![
+            "region=eu".parse().expect("Valid label; qed"),
+            "node=a".parse().expect("Valid label; qed"),
+            "region=us".parse().expect("Valid label; qed"),
+        ];
+        assert_eq!(check_metric_labels(&labels[..2]), Ok(()),);
+        assert_eq!(
+            check_metric_labels(&labels),
+            Err(InvalidMetricLabel::DuplicateName("region".into()))
+        );
+    }
+}
@@ -12,7 +12,9 @@ use base64::{
 };
 use log::{debug, error};
 use mycelium::{
-    api::{MessageDestination, MessageReceiveInfo, MessageSendInfo, PushMessageResponse},
+    api::{
+        api_prefix, MessageDestination, MessageReceiveInfo, MessageSendInfo, PushMessageResponse,
+    },
     crypto::PublicKey,
     message::MessageId,
     subnet::Subnet,
@@ -69,6 +71,7 @@ pub async fn send_msg(
     topic: Option<String>,
     msg_path: Option<PathBuf>,
     server_addr: SocketAddr,
+    api_base_path: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     if reply_to.is_some() && wait {
         error!("Can't wait on a reply for a reply, either use --reply-to or --wait");
@@ -140,7 +143,10 @@ pub async fn send_msg(
         .into());
     };
 
-    let mut url = format!("http://{server_addr}/api/v1/messages");
+    let mut url = format!(
+        "http://{server_addr}{}/messages",
+        api_prefix(api_base_path.as_deref())
+    );
     if let Some(reply_to) = reply_to {
         url.push_str(&format!("/reply/{reply_to}"));
     }
@@ -155,6 +161,9 @@ pub async fn send_msg(
         .json(&MessageSendInfo {
             dst: destination,
             topic: topic.map(String::into_bytes),
+            correlation_id: None,
+            content_type: None,
+            retry: None,
             payload: msg,
         })
         .send()
@@ -221,10 +230,14 @@ pub async fn recv_msg(
     msg_path: Option<PathBuf>,
     raw: bool,
     server_addr: SocketAddr,
+    api_base_path: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // One year timeout should be sufficient
     let timeout = timeout.unwrap_or(60 * 60 * 24 * 365);
-    let mut url = format!("http://{server_addr}/api/v1/messages?timeout={timeout}");
+    let mut url = format!(
+        "http://{server_addr}{}/messages?timeout={timeout}",
+        api_prefix(api_base_path.as_deref())
+    );
     if let Some(ref topic) = topic {
         if topic.len() > 255 {
             error!("{topic} is longer than the maximum allowed topic length of 255");
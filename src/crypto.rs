@@ -13,6 +13,9 @@ use blake2::{Blake2b, Digest};
 use digest::consts::U16;
 use serde::{de::Visitor, Deserialize, Serialize};
 
+/// Amount of bytes of the hash of a [`PublicKey`] used as its fingerprint.
+const FINGERPRINT_SIZE: usize = 8;
+
 /// Default MTU for a packet. Ideally this would not be needed and the [`PacketBuffer`] takes a
 /// const generic argument which is then expanded with the needed extra space for the buffer,
 /// however as it stands const generics can only be used standalone and not in a constant
@@ -122,6 +125,12 @@ impl PublicKey {
         Ipv6Addr::from(<[u8; 16]>::from(buf))
     }
 
+    /// A short fingerprint of this `PublicKey`, to compare keys out of band. This is the hex
+    /// encoding of the first bytes of the hash of the key.
+    pub fn fingerprint(&self) -> String {
+        faster_hex::hex_string(&blake3::hash(self.as_bytes()).as_bytes()[..FINGERPRINT_SIZE])
+    }
+
     /// Convert this `PublicKey` to a byte array.
     pub fn to_bytes(self) -> [u8; 32] {
         self.0.to_bytes()
@@ -368,7 +377,20 @@ impl fmt::Debug for PacketBuffer {
 
 #[cfg(test)]
 mod tests {
-    use super::{PacketBuffer, SecretKey, AES_NONCE_SIZE, AES_TAG_SIZE, DATA_HEADER_SIZE};
+    use super::{
+        PacketBuffer, PublicKey, SecretKey, AES_NONCE_SIZE, AES_TAG_SIZE, DATA_HEADER_SIZE,
+        FINGERPRINT_SIZE,
+    };
+
+    #[test]
+    fn fingerprint_is_short_and_stable() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let other = PublicKey::from(&SecretKey::new());
+
+        assert_eq!(pk.fingerprint().len(), FINGERPRINT_SIZE * 2);
+        assert_eq!(pk.fingerprint(), pk.fingerprint());
+        assert_ne!(pk.fingerprint(), other.fingerprint());
+    }
 
     #[test]
     /// Test if encryption works in general. We just create some random value and encrypt it.
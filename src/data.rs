@@ -5,7 +5,9 @@ use futures::{Sink, SinkExt, Stream, StreamExt};
 use log::{debug, error, trace, warn};
 use tokio::sync::mpsc::UnboundedReceiver;
 
-use crate::{crypto::PacketBuffer, packet::DataPacket, router::Router};
+use crate::{
+    crypto::PacketBuffer, events::PacketDropReason, packet::DataPacket, peer::Peer, router::Router,
+};
 
 /// Current version of the user data header.
 const USER_DATA_VERSION: u8 = 1;
@@ -58,12 +60,14 @@ impl DataPlane {
     ///
     /// `l3_packet_stream` is a stream of l3 packets from the host, usually read from a TUN interface.
     /// `l3_packet_sink` is a sink for l3 packets received from a romte, usually send to a TUN interface,
+    /// `host_packet_source` yields the data packets for this node, with the peer they were
+    /// received from.
     pub fn new<S, T, U>(
         router: Router,
         l3_packet_stream: S,
         l3_packet_sink: T,
         message_packet_sink: U,
-        host_packet_source: UnboundedReceiver<DataPacket>,
+        host_packet_source: UnboundedReceiver<(DataPacket, Option<Peer>)>,
     ) -> Self
     where
         S: Stream<Item = Result<PacketBuffer, std::io::Error>> + Send + Unpin + 'static,
@@ -175,7 +179,9 @@ impl DataPlane {
             header[0] = USER_DATA_VERSION;
             header[1] = USER_DATA_L3_TYPE;
 
-            if let Some(icmp) = self.encrypt_and_route_packet(src_ip, dst_ip, hop_limit, packet) {
+            if let Some(icmp) =
+                self.encrypt_and_route_packet(src_ip, dst_ip, hop_limit, packet, false)
+            {
                 if let Err(e) = l3_packet_sink.send(icmp).await {
                     error!("Could not forward icmp packet back to TUN interface {e}");
                 }
@@ -196,7 +202,7 @@ impl DataPlane {
         header[0] = USER_DATA_VERSION;
         header[1] = USER_DATA_MESSAGE_TYPE;
 
-        self.encrypt_and_route_packet(src_ip, dst_ip, MESSAGE_HOP_LIMIT, packet);
+        self.encrypt_and_route_packet(src_ip, dst_ip, MESSAGE_HOP_LIMIT, packet, true);
     }
 
     /// Encrypt the content of a packet based on the destination key, and then inject the packet
@@ -205,21 +211,24 @@ impl DataPlane {
     /// If no key exists for the destination, the content can'be encrypted, the packet is not injected
     /// into the router, and a packet is returned containing an ICMP packet. Note that a return
     /// value of [`Option::None`] does not mean the packet was successfully forwarded;
+    ///
+    /// If `is_message` is set, the packet is routed as a message packet originating from this
+    /// node, see [`Router::route_message_packet`].
     fn encrypt_and_route_packet(
         &self,
         src_ip: Ipv6Addr,
         dst_ip: Ipv6Addr,
         hop_limit: u8,
         packet: PacketBuffer,
+        is_message: bool,
     ) -> Option<PacketBuffer> {
         // Get shared secret from node and dest address
         let shared_secret = match self.router.get_shared_secret_from_dest(dst_ip.into()) {
             Some(ss) => ss,
             None => {
-                debug!(
-                    "No entry found for destination address {}, dropping packet",
-                    dst_ip
-                );
+                self.router
+                    .packet_drop_log()
+                    .record(PacketDropReason::NoRoute, dst_ip);
 
                 let mut pb = PacketBuffer::new();
                 // From self to self
@@ -242,12 +251,17 @@ impl DataPlane {
             }
         };
 
-        self.router.route_packet(DataPacket {
+        let data_packet = DataPacket {
             dst_ip,
             src_ip,
             hop_limit,
             raw_data: shared_secret.encrypt(packet),
-        });
+        };
+        if is_message {
+            self.router.route_message_packet(data_packet);
+        } else {
+            self.router.route_packet(data_packet);
+        }
 
         None
     }
@@ -256,14 +270,14 @@ impl DataPlane {
         self,
         mut l3_packet_sink: T,
         mut message_packet_sink: U,
-        mut host_packet_source: UnboundedReceiver<DataPacket>,
+        mut host_packet_source: UnboundedReceiver<(DataPacket, Option<Peer>)>,
     ) where
         T: Sink<PacketBuffer> + Send + Unpin + 'static,
         T::Error: std::fmt::Display,
         U: Sink<(PacketBuffer, IpAddr, IpAddr)> + Send + Unpin + 'static,
         U::Error: std::fmt::Display,
     {
-        while let Some(data_packet) = host_packet_source.recv().await {
+        while let Some((data_packet, from)) = host_packet_source.recv().await {
             // decrypt & send to TUN interface
             let shared_secret = if let Some(ss) = self
                 .router
@@ -277,7 +291,15 @@ impl DataPlane {
             let mut decrypted_packet = match shared_secret.decrypt(data_packet.raw_data) {
                 Ok(data) => data,
                 Err(_) => {
-                    log::debug!("Dropping data packet with invalid encrypted content");
+                    // A packet which can't be decrypted is corrupted, or sent with a different
+                    // key, so keep track of the peer it was received from.
+                    debug!("Failed to decrypt data packet from {}", data_packet.src_ip);
+                    if let Some(peer) = &from {
+                        peer.decryption_failed();
+                    }
+                    self.router
+                        .packet_drop_log()
+                        .record(PacketDropReason::DecryptionFailure, data_packet.dst_ip);
                     continue;
                 }
             };
@@ -328,7 +350,9 @@ impl DataPlane {
                         continue;
                     }
                     if real_packet.len() > MIN_IPV6_MTU + 16 {
-                        debug!("Discarding ICMP packet which is too large");
+                        self.router
+                            .packet_drop_log()
+                            .record(PacketDropReason::Oversized, data_packet.dst_ip);
                         continue;
                     }
 
@@ -410,3 +434,95 @@ impl DataPlane {
         warn!("Extract loop from router to host ended");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use futures::StreamExt;
+
+    use crate::{crypto::PacketBuffer, events::PacketDropReason, packet::DataPacket, testing};
+
+    use super::DataPlane;
+
+    #[tokio::test]
+    async fn message_to_own_subnet_is_looped_back() {
+        let (router, _, tun_rx) = testing::router(None);
+        let own_ip = router.node_public_key().address();
+        let (message_tx, mut message_rx) = futures::channel::mpsc::unbounded();
+        let data_plane = DataPlane::new(
+            router,
+            tokio_stream::pending(),
+            futures::sink::drain(),
+            message_tx,
+            tun_rx,
+        );
+
+        // There is no route to ourselves, the packet is encrypted with our own key and handed
+        // back to the data plane without ever reaching a peer.
+        let mut packet = PacketBuffer::new();
+        packet.set_size(4);
+        packet.buffer_mut()[..4].copy_from_slice(b"ping");
+        data_plane.inject_message_packet(own_ip, own_ip, packet);
+
+        let (packet, src, dst) = tokio::time::timeout(Duration::from_secs(1), message_rx.next())
+            .await
+            .expect("Message packet is looped back")
+            .expect("Data plane is running");
+        assert!(packet.buffer().starts_with(b"ping"));
+        assert_eq!(src, IpAddr::from(own_ip));
+        assert_eq!(dst, IpAddr::from(own_ip));
+    }
+
+    #[tokio::test]
+    async fn corrupt_packets_are_counted() {
+        let (router, tun_tx, tun_rx) = testing::router(None);
+        let pk = router.node_public_key();
+        let _data_plane = DataPlane::new(
+            router.clone(),
+            tokio_stream::pending(),
+            futures::sink::drain(),
+            futures::sink::drain(),
+            tun_rx,
+        );
+
+        let (stream, _remote) = testing::tcp_pair().await;
+        let peer = testing::router_peer(&router, stream);
+        let packet = |raw_data| DataPacket {
+            raw_data,
+            hop_limit: 64,
+            src_ip: pk.address(),
+            dst_ip: pk.address(),
+        };
+
+        // Packets from ourselves are decrypted with our own key, so the sender is known. One
+        // packet is too short to hold a nonce, the other one has a garbage nonce and tag. Both
+        // are received from the peer.
+        for raw_data in [vec![1, 2, 3], vec![0xAA; 100]] {
+            router
+                .router_data_tx()
+                .send((packet(raw_data), peer.clone()))
+                .await
+                .expect("Router is running");
+        }
+        // A packet which did not come from a peer is only counted in total.
+        tun_tx
+            .send((packet(vec![0xAA; 100]), None))
+            .expect("Data plane is running");
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while router
+                .packet_drop_log()
+                .count(PacketDropReason::DecryptionFailure)
+                < 3
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Corrupt packets are counted");
+        assert_eq!(peer.decryption_failures(), 2);
+        // The data plane keeps running after a failure.
+        assert!(!tun_tx.is_closed());
+    }
+}
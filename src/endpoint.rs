@@ -1,20 +1,21 @@
 use std::{
     fmt,
-    net::{AddrParseError, SocketAddr},
+    net::{AddrParseError, SocketAddr, SocketAddrV6},
     str::FromStr,
 };
 
+use network_interface::NetworkInterfaceConfig;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 /// Error generated while processing improperly formatted endpoints.
 pub enum EndpointParseError {
-    /// An address was specified without leading protocol information.
-    MissingProtocol,
     /// An endpoint was specified using a protocol we (currently) do not understand.
     UnknownProtocol,
     /// Error while parsing the specific address.
     Address(AddrParseError),
+    /// The zone of a scoped IPv6 address names a network interface which does not exist.
+    UnknownInterface(String),
 }
 
 /// Protocol used by an endpoint.
@@ -28,16 +29,45 @@ pub enum Protocol {
 }
 
 /// An endpoint defines a address and a protocol to use when communicating with it.
+///
+/// Endpoints are always kept in a canonical form, so equivalent endpoints compare equal:
+///
+///  - An IPv4-mapped IPv6 address is converted to the plain IPv4 address.
+///  - The flow info of an IPv6 address is cleared. The scope id is kept, since it is needed to
+///    connect to link local addresses.
+///  - A zone given as interface name, e.g. `[fe80::1%eth0]:9651`, is converted to the index of
+///    that interface, e.g. `[fe80::1%2]:9651`.
+///
+/// The canonical string representation is `<protocol>://<address>`, with the protocol in lower
+/// case, e.g. `tcp://1.2.3.4:9651` or `quic://[2001:db8::1]:9651`. When parsing, the protocol is
+/// case insensitive, and defaults to TCP if it is omitted. Deserialized endpoints are converted to
+/// the canonical form as well.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", try_from = "RawEndpoint")]
 pub struct Endpoint {
     proto: Protocol,
     socket_addr: SocketAddr,
 }
 
+/// An [`Endpoint`] as it is deserialized, before it is converted to the canonical form.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawEndpoint {
+    proto: Protocol,
+    socket_addr: SocketAddr,
+}
+
 impl Endpoint {
-    /// Create a new `Endpoint` with given [`Protocol`] and address.
+    /// Create a new `Endpoint` with given [`Protocol`] and address. The address is converted to
+    /// its canonical form.
     pub fn new(proto: Protocol, socket_addr: SocketAddr) -> Self {
+        let socket_addr = match socket_addr {
+            SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+                None => SocketAddrV6::new(*addr.ip(), addr.port(), 0, addr.scope_id()).into(),
+            },
+            SocketAddr::V4(_) => socket_addr,
+        };
         Self { proto, socket_addr }
     }
 
@@ -52,28 +82,70 @@ impl Endpoint {
     }
 }
 
+impl From<RawEndpoint> for Endpoint {
+    fn from(raw: RawEndpoint) -> Self {
+        Endpoint::new(raw.proto, raw.socket_addr)
+    }
+}
+
 impl FromStr for Endpoint {
     type Err = EndpointParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.split_once("://") {
-            None => Err(EndpointParseError::MissingProtocol),
-            Some((proto, socket)) => {
-                let proto = match proto.to_lowercase().as_str() {
+        let (proto, socket) = match s.split_once("://") {
+            None => (Protocol::Tcp, s),
+            Some((proto, socket)) => (
+                match proto.to_lowercase().as_str() {
                     "tcp" => Protocol::Tcp,
                     "quic" => Protocol::Quic,
                     _ => return Err(EndpointParseError::UnknownProtocol),
-                };
-                let socket_addr = SocketAddr::from_str(socket)?;
-                Ok(Endpoint { proto, socket_addr })
+                },
+                socket,
+            ),
+        };
+        let socket_addr = match interface_zone(socket) {
+            Some((start, name)) => {
+                let index = interface_index(name)
+                    .ok_or_else(|| EndpointParseError::UnknownInterface(name.to_string()))?;
+                let mut socket = socket.to_string();
+                socket.replace_range(start..start + name.len(), &index.to_string());
+                SocketAddr::from_str(&socket)?
             }
-        }
+            None => SocketAddr::from_str(socket)?,
+        };
+        Ok(Endpoint::new(proto, socket_addr))
     }
 }
 
+/// Find the zone of a scoped IPv6 socket address, e.g. `eth0` in `[fe80::1%eth0]:9651`, if it is
+/// an interface name rather than a numeric scope id. Returns the position of the zone in the
+/// input, and the zone itself.
+fn interface_zone(socket: &str) -> Option<(usize, &str)> {
+    let start = socket.find('%')? + 1;
+    let end = start + socket[start..].find(']')?;
+    let zone = &socket[start..end];
+    if zone.is_empty() || zone.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((start, zone))
+}
+
+/// Get the index of the network interface with the given name, if it exists.
+fn interface_index(name: &str) -> Option<u32> {
+    network_interface::NetworkInterface::show()
+        .ok()?
+        .into_iter()
+        .find(|nic| nic.name == name)
+        .map(|nic| nic.index)
+}
+
 impl fmt::Display for Endpoint {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_fmt(format_args!("{} {}", self.proto, self.socket_addr))
+        let scheme = match self.proto {
+            Protocol::Tcp => "tcp",
+            Protocol::Quic => "quic",
+        };
+        f.write_fmt(format_args!("{scheme}://{}", self.socket_addr))
     }
 }
 
@@ -89,9 +161,11 @@ impl fmt::Display for Protocol {
 impl fmt::Display for EndpointParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::MissingProtocol => f.write_str("missing leading protocol identifier"),
             Self::UnknownProtocol => f.write_str("protocol for endpoint is not supported"),
             Self::Address(e) => f.write_fmt(format_args!("failed to parse address: {}", e)),
+            Self::UnknownInterface(name) => f.write_fmt(format_args!(
+                "network interface {name} in zone does not exist"
+            )),
         }
     }
 }
@@ -110,3 +184,102 @@ impl From<AddrParseError> for EndpointParseError {
         Self::Address(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, str::FromStr};
+
+    use network_interface::NetworkInterfaceConfig;
+
+    use super::{Endpoint, EndpointParseError};
+
+    #[test]
+    fn equivalent_endpoints_are_equal() {
+        let canonical = Endpoint::from_str("tcp://1.2.3.4:9651").unwrap();
+
+        for input in [
+            "1.2.3.4:9651",
+            "TCP://1.2.3.4:9651",
+            "Tcp://1.2.3.4:9651",
+            "tcp://[::ffff:1.2.3.4]:9651",
+        ] {
+            let endpoint = Endpoint::from_str(input).unwrap();
+            assert_eq!(endpoint, canonical, "{input} is not canonicalized");
+            assert_eq!(endpoint.to_string(), "tcp://1.2.3.4:9651");
+        }
+    }
+
+    #[test]
+    fn canonical_form_round_trips() {
+        for input in [
+            "tcp://1.2.3.4:9651",
+            "quic://1.2.3.4:9651",
+            "tcp://[2001:db8::1]:9651",
+            "quic://[fe80::1%2]:9651",
+        ] {
+            let endpoint = Endpoint::from_str(input).unwrap();
+            assert_eq!(endpoint.to_string(), input);
+            assert_eq!(Endpoint::from_str(&endpoint.to_string()).unwrap(), endpoint);
+        }
+    }
+
+    #[test]
+    fn deserialized_endpoint_is_canonical() {
+        let endpoint: Endpoint =
+            serde_json::from_str(r#"{"proto":"tcp","socketAddr":"[::ffff:1.2.3.4]:9651"}"#)
+                .unwrap();
+
+        assert_eq!(endpoint, Endpoint::from_str("tcp://1.2.3.4:9651").unwrap());
+        assert_eq!(
+            serde_json::to_string(&endpoint).unwrap(),
+            r#"{"proto":"tcp","socketAddr":"1.2.3.4:9651"}"#
+        );
+    }
+
+    #[test]
+    fn different_endpoints_are_not_equal() {
+        let tcp = Endpoint::from_str("tcp://1.2.3.4:9651").unwrap();
+
+        assert_ne!(tcp, Endpoint::from_str("quic://1.2.3.4:9651").unwrap());
+        assert_ne!(tcp, Endpoint::from_str("tcp://1.2.3.4:9652").unwrap());
+        assert_ne!(tcp, Endpoint::from_str("tcp://[::1.2.3.4]:9651").unwrap());
+    }
+
+    #[test]
+    fn interface_zone_is_resolved_and_round_trips() {
+        let nic = network_interface::NetworkInterface::show()
+            .expect("Can list network interfaces")
+            .into_iter()
+            .next()
+            .expect("Host has at least one network interface");
+
+        let endpoint = Endpoint::from_str(&format!("tcp://[fe80::1%{}]:9651", nic.name)).unwrap();
+        match endpoint.address() {
+            SocketAddr::V6(addr) => assert_eq!(addr.scope_id(), nic.index),
+            SocketAddr::V4(_) => panic!("Link local address is IPv6"),
+        }
+        assert_eq!(
+            endpoint.to_string(),
+            format!("tcp://[fe80::1%{}]:9651", nic.index)
+        );
+        assert_eq!(Endpoint::from_str(&endpoint.to_string()).unwrap(), endpoint);
+    }
+
+    #[test]
+    fn unknown_interface_zone() {
+        assert_eq!(
+            Endpoint::from_str("tcp://[fe80::1%doesnotexist0]:9651"),
+            Err(EndpointParseError::UnknownInterface(
+                "doesnotexist0".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn unknown_protocol() {
+        assert_eq!(
+            Endpoint::from_str("udp://1.2.3.4:9651"),
+            Err(EndpointParseError::UnknownProtocol)
+        );
+    }
+}
@@ -0,0 +1,465 @@
+//! In memory log of recent events, so operators can find out why something did not go through
+//! without having to dig through the logs.
+
+use core::fmt;
+use std::{
+    collections::VecDeque,
+    net::Ipv6Addr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info};
+use serde::Serialize;
+
+/// The maximum amount of drop events which are kept. Once this is reached, the oldest event is
+/// removed when a new one is recorded.
+const DROP_EVENTS_CAPACITY: usize = 100;
+
+/// Amount of seconds for which the amount of drop events is counted, regardless of how many of
+/// the events themselves are kept.
+const DROP_COUNTS_RETENTION_SECS: u64 = 60;
+
+/// The maximum amount of connection events which are kept per peer. Once this is reached, the
+/// oldest event is removed when a new one is recorded.
+const PEER_EVENTS_CAPACITY: usize = 20;
+
+/// Window in which at most the configured amount of dropped data packets is logged at info level.
+const PACKET_DROP_LOG_WINDOW: Duration = Duration::from_secs(1);
+
+/// The reason something was dropped or rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DropKind {
+    /// A message could not be sent because it exceeds a size limit.
+    OversizedMessage,
+    /// A route update was rejected by a filter or the import policy of a peer.
+    FilteredRoute,
+    /// A message was not received by the remote before its try duration expired.
+    ExpiredMessage,
+    /// A new route was not accepted because the routing table is full.
+    RouteTableFull,
+}
+
+/// A single drop event.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DropEvent {
+    /// Time the event happened, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// The reason of the drop.
+    pub kind: DropKind,
+    /// Short, human readable details about what was dropped.
+    pub detail: String,
+}
+
+/// Ring of the most recent [`DropEvent`]s. Next to the events themselves, the amount of events is
+/// counted per second, so the amount of recent events is known even if more events happened than
+/// fit in the ring. Cloning this gives a handle to the same ring.
+#[derive(Debug, Clone, Default)]
+pub struct DropEvents {
+    events: Arc<Mutex<VecDeque<DropEvent>>>,
+    /// Amount of events per second, oldest first, for the last [`DROP_COUNTS_RETENTION_SECS`].
+    counts: Arc<Mutex<VecDeque<(u64, usize)>>>,
+}
+
+impl DropEvents {
+    /// Create a new, empty `DropEvents` ring.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a new drop event. If the ring is full, the oldest event is removed.
+    pub fn record(&self, kind: DropKind, detail: impl Into<String>) {
+        self.record_at(unix_timestamp(), kind, detail.into());
+    }
+
+    /// Record a new drop event which happened at the given UNIX timestamp.
+    fn record_at(&self, timestamp: u64, kind: DropKind, detail: String) {
+        {
+            let mut counts = self.counts.lock().unwrap();
+            match counts.back_mut() {
+                Some((second, count)) if *second == timestamp => *count += 1,
+                _ => counts.push_back((timestamp, 1)),
+            }
+            let since = timestamp.saturating_sub(DROP_COUNTS_RETENTION_SECS);
+            while counts.front().is_some_and(|(second, _)| *second < since) {
+                counts.pop_front();
+            }
+        }
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= DROP_EVENTS_CAPACITY {
+            events.pop_front();
+        }
+        events.push_back(DropEvent {
+            timestamp,
+            kind,
+            detail,
+        });
+    }
+
+    /// Get all recorded events, oldest first.
+    pub fn recent(&self) -> Vec<DropEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Amount of recorded events which happened within the given window before now. Events are
+    /// only counted for the last [`DROP_COUNTS_RETENTION_SECS`], so larger windows are capped to
+    /// that.
+    pub fn count_within(&self, window: Duration) -> usize {
+        self.count_within_at(unix_timestamp(), window)
+    }
+
+    /// Amount of recorded events which happened within the given window before the given UNIX
+    /// timestamp.
+    fn count_within_at(&self, now: u64, window: Duration) -> usize {
+        let since = now.saturating_sub(window.as_secs());
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(second, _)| *second >= since)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// The reason a data packet was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketDropReason {
+    /// There is no route to the destination.
+    NoRoute,
+    /// The data queue of the next hop is full.
+    QueueFull,
+    /// The packet is too large to be handled.
+    Oversized,
+    /// The packet could not be decrypted.
+    DecryptionFailure,
+    /// The hop limit of the packet is exhausted.
+    HopLimitExceeded,
+}
+
+/// Amount of variants of [`PacketDropReason`].
+const PACKET_DROP_REASONS: usize = 5;
+
+impl fmt::Display for PacketDropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoRoute => "no route",
+            Self::QueueFull => "queue of next hop is full",
+            Self::Oversized => "packet is too large",
+            Self::DecryptionFailure => "decryption failed",
+            Self::HopLimitExceeded => "hop limit exceeded",
+        })
+    }
+}
+
+/// Log of dropped data packets. Every drop is counted per reason, and logged at debug level. If a
+/// rate is set, up to that many drops per second are logged at info level instead, so drops are
+/// visible without enabling debug logs, while a flood of drops does not flood the logs. Cloning
+/// this gives a handle to the same log.
+#[derive(Debug, Clone)]
+pub struct PacketDropLog {
+    /// Amount of dropped packets, indexed by reason.
+    counts: Arc<[AtomicU64; PACKET_DROP_REASONS]>,
+    /// Sampler of the drops logged at info level, if a rate is set.
+    window: Option<Arc<Mutex<SampleWindow>>>,
+}
+
+/// Fixed window sampler, which admits at most a given amount of events per window, e.g. to limit
+/// how many of them are logged.
+#[derive(Debug)]
+pub(crate) struct SampleWindow {
+    /// Length of a window.
+    length: Duration,
+    /// Maximum amount of events admitted per window.
+    rate: u32,
+    /// Start of the current window.
+    start: Instant,
+    /// Amount of events admitted in the current window.
+    admitted: u32,
+    /// Amount of events not admitted since the last event which was.
+    suppressed: u64,
+}
+
+impl SampleWindow {
+    /// Create a new `SampleWindow` which admits `rate` events per window of the given `length`.
+    /// The first window starts at `now`.
+    pub(crate) fn new(length: Duration, rate: u32, now: Instant) -> Self {
+        Self {
+            length,
+            rate,
+            start: now,
+            admitted: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Decide if an event at `now` is admitted. If it is, the amount of events which were not
+    /// admitted since the previous one is returned.
+    pub(crate) fn sample(&mut self, now: Instant) -> Option<u64> {
+        if now.duration_since(self.start) >= self.length {
+            self.start = now;
+            self.admitted = 0;
+        }
+        if self.admitted >= self.rate {
+            self.suppressed += 1;
+            return None;
+        }
+        self.admitted += 1;
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+impl PacketDropLog {
+    /// Create a new `PacketDropLog` which logs at most `rate` drops per second at info level, if
+    /// set.
+    pub fn new(rate: Option<u32>) -> Self {
+        Self {
+            counts: Arc::new(Default::default()),
+            window: rate.map(|rate| {
+                Arc::new(Mutex::new(SampleWindow::new(
+                    PACKET_DROP_LOG_WINDOW,
+                    rate,
+                    Instant::now(),
+                )))
+            }),
+        }
+    }
+
+    /// Record a dropped data packet for the given destination.
+    pub fn record(&self, reason: PacketDropReason, dst: Ipv6Addr) {
+        let total = self.counts[reason as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        match self.sample(Instant::now()) {
+            Some(0) => info!("Dropped data packet to {dst}: {reason} ({total} dropped for this reason)"),
+            Some(suppressed) => info!(
+                "Dropped data packet to {dst}: {reason} ({total} dropped for this reason, {suppressed} other drops not logged)"
+            ),
+            None => debug!("Dropped data packet to {dst}: {reason} ({total} dropped for this reason)"),
+        }
+    }
+
+    /// Amount of data packets dropped for the given reason.
+    pub fn count(&self, reason: PacketDropReason) -> u64 {
+        self.counts[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Decide if a drop at the given time is logged at info level. If it is, the amount of drops
+    /// which were not logged since the previous one is returned.
+    fn sample(&self, now: Instant) -> Option<u64> {
+        self.window.as_ref()?.lock().unwrap().sample(now)
+    }
+}
+
+/// The kind of a connection event of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PeerEventKind {
+    /// A connection to the peer was established.
+    Connected,
+    /// The first IHU was received from the peer, so the link works in both directions.
+    HandshakeOk,
+    /// Connecting to the peer failed.
+    ConnectError,
+    /// The connection to the peer was closed.
+    Disconnected,
+}
+
+/// A single connection event of a peer.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerEvent {
+    /// Time the event happened, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// The kind of event.
+    pub kind: PeerEventKind,
+    /// Short, human readable details about the event, e.g. the reason a connection failed.
+    pub detail: String,
+}
+
+/// Ring of the most recent [`PeerEvent`]s of a single peer.
+#[derive(Debug, Clone, Default)]
+pub struct PeerEvents {
+    events: VecDeque<PeerEvent>,
+}
+
+impl PeerEvents {
+    /// Create a new, empty `PeerEvents` ring.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a new event. If the ring is full, the oldest event is removed.
+    pub fn record(&mut self, kind: PeerEventKind, detail: impl Into<String>) {
+        if self.events.len() >= PEER_EVENTS_CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(PeerEvent {
+            timestamp: unix_timestamp(),
+            kind,
+            detail: detail.into(),
+        });
+    }
+
+    /// Amount of recorded events of the given kind which happened within the given window before
+    /// now.
+    pub fn count_within(&self, kind: PeerEventKind, window: Duration) -> usize {
+        let since = unix_timestamp().saturating_sub(window.as_secs());
+        self.events
+            .iter()
+            .filter(|event| event.kind == kind && event.timestamp >= since)
+            .count()
+    }
+
+    /// The kind of the most recent event, if any.
+    pub fn last_kind(&self) -> Option<PeerEventKind> {
+        self.events.back().map(|event| event.kind)
+    }
+
+    /// Check if the most recent event indicates the connection to the peer is open.
+    pub fn connected(&self) -> bool {
+        matches!(
+            self.last_kind(),
+            Some(PeerEventKind::Connected | PeerEventKind::HandshakeOk)
+        )
+    }
+
+    /// Get all recorded events, oldest first.
+    pub fn recent(&self) -> Vec<PeerEvent> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+/// The current time, in seconds since the UNIX epoch.
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time is after the UNIX epoch; qed")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        DropEvents, DropKind, PacketDropLog, PacketDropReason, PeerEventKind, PeerEvents,
+        DROP_COUNTS_RETENTION_SECS, DROP_EVENTS_CAPACITY, PACKET_DROP_LOG_WINDOW,
+        PEER_EVENTS_CAPACITY,
+    };
+
+    #[test]
+    fn packet_drop_logs_are_sampled() {
+        let log = PacketDropLog::new(Some(2));
+        let start = Instant::now();
+
+        assert_eq!(log.sample(start), Some(0));
+        assert_eq!(log.sample(start), Some(0));
+        assert_eq!(log.sample(start), None);
+        assert_eq!(log.sample(start), None);
+
+        // The next window reports the drops which were not logged.
+        let next = start + PACKET_DROP_LOG_WINDOW;
+        assert_eq!(log.sample(next), Some(2));
+        assert_eq!(log.sample(next), Some(0));
+
+        assert_eq!(PacketDropLog::new(None).sample(start), None);
+    }
+
+    #[test]
+    fn packet_drops_are_counted_per_reason() {
+        let log = PacketDropLog::new(None);
+        let dst = "400::1".parse().unwrap();
+        log.record(PacketDropReason::NoRoute, dst);
+        log.clone().record(PacketDropReason::NoRoute, dst);
+        log.record(PacketDropReason::QueueFull, dst);
+
+        assert_eq!(log.count(PacketDropReason::NoRoute), 2);
+        assert_eq!(log.count(PacketDropReason::QueueFull), 1);
+        assert_eq!(log.count(PacketDropReason::DecryptionFailure), 0);
+    }
+
+    #[test]
+    fn oldest_events_are_removed() {
+        let events = DropEvents::new();
+        for i in 0..DROP_EVENTS_CAPACITY + 5 {
+            events.record(DropKind::FilteredRoute, format!("event {i}"));
+        }
+
+        let recent = events.recent();
+        assert_eq!(recent.len(), DROP_EVENTS_CAPACITY);
+        assert_eq!(recent[0].detail, "event 5");
+        assert_eq!(
+            recent[DROP_EVENTS_CAPACITY - 1].detail,
+            format!("event {}", DROP_EVENTS_CAPACITY + 4)
+        );
+    }
+
+    #[test]
+    fn events_are_counted_beyond_capacity() {
+        let events = DropEvents::new();
+        let start = 1_000_000;
+        for i in 0..DROP_EVENTS_CAPACITY * 3 {
+            events.record_at(start, DropKind::RouteTableFull, format!("event {i}"));
+        }
+        events.record_at(start + 30, DropKind::FilteredRoute, "late".into());
+
+        assert_eq!(events.recent().len(), DROP_EVENTS_CAPACITY);
+        let minute = Duration::from_secs(60);
+        assert_eq!(
+            events.count_within_at(start + 30, minute),
+            DROP_EVENTS_CAPACITY * 3 + 1
+        );
+        assert_eq!(
+            events.count_within_at(start + 30, Duration::from_secs(10)),
+            1
+        );
+
+        // Counts older than the retention are forgotten once a new event is recorded.
+        let later = start + DROP_COUNTS_RETENTION_SECS + 1;
+        events.record_at(later, DropKind::FilteredRoute, "later".into());
+        assert_eq!(events.count_within_at(later, Duration::from_secs(3600)), 2);
+    }
+
+    #[test]
+    fn clones_share_events() {
+        let events = DropEvents::new();
+        events
+            .clone()
+            .record(DropKind::ExpiredMessage, "message 0123456789abcdef");
+
+        let recent = events.recent();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].kind, DropKind::ExpiredMessage);
+    }
+
+    #[test]
+    fn peer_events_are_capped() {
+        let mut events = PeerEvents::new();
+        assert_eq!(events.last_kind(), None);
+        for i in 0..PEER_EVENTS_CAPACITY + 3 {
+            events.record(PeerEventKind::ConnectError, format!("attempt {i}"));
+        }
+        events.record(PeerEventKind::Connected, "connected");
+
+        let recent = events.recent();
+        assert_eq!(recent.len(), PEER_EVENTS_CAPACITY);
+        assert_eq!(recent[0].detail, "attempt 4");
+        assert_eq!(events.last_kind(), Some(PeerEventKind::Connected));
+    }
+
+    #[test]
+    fn completed_handshake_is_still_connected() {
+        let mut events = PeerEvents::new();
+        assert!(!events.connected());
+        events.record(PeerEventKind::Connected, "connected");
+        events.record(PeerEventKind::HandshakeOk, "handshake ok");
+        assert!(events.connected());
+        events.record(PeerEventKind::Disconnected, "connection lost");
+        assert!(!events.connected());
+    }
+}
@@ -1,3 +1,8 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    RwLock,
+};
+
 use crate::{babel, subnet::Subnet};
 
 /// This trait is used to filter incoming updates from peers. Only updates which pass all
@@ -51,3 +56,101 @@ impl RouteUpdateFilter for RouterIdOwnsSubnet {
                 .contains_ip(update.router_id().to_pubkey().address().into())
     }
 }
+
+/// Import policy for routes learned from a single peer. Updates for subnets which are not allowed
+/// by the policy are rejected before they are processed.
+///
+/// A subnet is rejected if it is contained in any of the denied prefixes. Otherwise, if no allowed
+/// prefixes are configured, the subnet is accepted. If there are allowed prefixes, the subnet must
+/// be contained in at least one of them.
+#[derive(Debug, Default)]
+pub struct ImportPolicy {
+    prefixes: RwLock<PrefixLists>,
+    /// Amount of updates rejected by this policy.
+    rejected: AtomicU64,
+}
+
+/// The prefix lists of an [`ImportPolicy`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixLists {
+    /// Prefixes which routes are accepted for. If this is empty, all prefixes are accepted.
+    pub allowed: Vec<Subnet>,
+    /// Prefixes which routes are never accepted for.
+    pub denied: Vec<Subnet>,
+}
+
+impl ImportPolicy {
+    /// Checks if routes for the given `Subnet` are allowed by this policy.
+    pub fn allow(&self, subnet: &Subnet) -> bool {
+        let prefixes = self.prefixes.read().unwrap();
+        if prefixes.denied.iter().any(|d| d.contains_subnet(subnet)) {
+            return false;
+        }
+        prefixes.allowed.is_empty() || prefixes.allowed.iter().any(|a| a.contains_subnet(subnet))
+    }
+
+    /// Checks if an update for the given `Subnet` is allowed by this policy. If it is not, the
+    /// update is counted as rejected.
+    pub fn check(&self, subnet: &Subnet) -> bool {
+        let allowed = self.allow(subnet);
+        if !allowed {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Get the currently configured [`PrefixLists`].
+    pub fn prefixes(&self) -> PrefixLists {
+        self.prefixes.read().unwrap().clone()
+    }
+
+    /// Replace the configured [`PrefixLists`]. This only affects updates received afterwards,
+    /// routes which are already installed are not removed.
+    pub fn set_prefixes(&self, prefixes: PrefixLists) {
+        *self.prefixes.write().unwrap() = prefixes;
+    }
+
+    /// The amount of updates rejected by this policy.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use crate::subnet::Subnet;
+
+    use super::{ImportPolicy, PrefixLists};
+
+    fn subnet(addr: Ipv6Addr, prefix_len: u8) -> Subnet {
+        Subnet::new(addr.into(), prefix_len).expect("Valid subnet; qed")
+    }
+
+    #[test]
+    fn empty_policy_allows_everything() {
+        let policy = ImportPolicy::default();
+
+        assert!(policy.check(&subnet(Ipv6Addr::new(0x400, 1, 0, 0, 0, 0, 0, 0), 64)));
+        assert_eq!(policy.rejected(), 0);
+    }
+
+    #[test]
+    fn allowed_and_filtered_prefix() {
+        let policy = ImportPolicy::default();
+        policy.set_prefixes(PrefixLists {
+            allowed: vec![subnet(Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, 0), 7)],
+            denied: vec![subnet(Ipv6Addr::new(0x400, 0xdead, 0, 0, 0, 0, 0, 0), 32)],
+        });
+
+        // Contained in the allowed prefix.
+        assert!(policy.check(&subnet(Ipv6Addr::new(0x400, 1, 0, 0, 0, 0, 0, 0), 64)));
+        // Outside of the allowed prefix.
+        assert!(!policy.check(&subnet(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 64)));
+        // Allowed, but also contained in a denied prefix.
+        assert!(!policy.check(&subnet(Ipv6Addr::new(0x400, 0xdead, 1, 0, 0, 0, 0, 0), 64)));
+
+        assert_eq!(policy.rejected(), 2);
+    }
+}
@@ -1,4 +1,8 @@
+use std::fmt;
 use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[cfg(feature = "http-api")]
 use api::Http;
@@ -10,12 +14,14 @@ use log::{error, info, warn};
 use message::MessageStack;
 use subnet::Subnet;
 
+pub mod alerts;
 pub mod api;
 mod babel;
 mod connection;
 pub mod crypto;
 pub mod data;
 pub mod endpoint;
+pub mod events;
 pub mod filters;
 mod interval;
 #[cfg(feature = "message")]
@@ -24,12 +30,16 @@ mod metric;
 pub mod packet;
 mod peer;
 pub mod peer_manager;
+pub mod reconnect;
 pub mod router;
 mod router_id;
 mod routing_table;
 mod sequence_number;
+mod shaping;
 mod source_table;
 pub mod subnet;
+#[cfg(test)]
+mod testing;
 mod tun;
 
 /// The prefix of the global subnet used.
@@ -37,12 +47,27 @@ pub const GLOBAL_SUBNET_ADDRESS: IpAddr = IpAddr::V6(Ipv6Addr::new(0x400, 0, 0,
 /// The prefix length of the global subnet used.
 pub const GLOBAL_SUBNET_PREFIX_LEN: u8 = 7;
 
+/// Amount of time to wait after retracting our routes during a graceful shutdown, before peer
+/// connections are closed. This gives the retractions time to be sent.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Maximum amount of time to wait during a graceful shutdown for data packets which are queued
+/// for peers to be sent.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Config for a mycelium [`Stack`].
 pub struct Config {
     /// The secret key of the node.
     pub node_key: crypto::SecretKey,
     /// Statically configured peers.
     pub peers: Vec<Endpoint>,
+    /// Policy for reconnecting to the statically configured peers. Peers added later through the
+    /// API can set their own policy.
+    pub reconnect_policy: reconnect::ReconnectPolicy,
+    /// The prefix of the overlay network. Only routes to subnets in this prefix are accepted, and
+    /// it is routed to the TUN interface. The node refuses to start if the address derived from
+    /// its key is not in this prefix.
+    pub overlay_prefix: Subnet,
     /// Tun interface should be disabled.
     pub no_tun: bool,
     /// Listen port for TCP connections.
@@ -51,26 +76,117 @@ pub struct Config {
     pub quic_listen_port: u16,
     /// Udp port for peer discovery.
     pub peer_discovery_port: Option<u16>,
+    /// Multicast group used for link local peer discovery.
+    pub peer_discovery_group: Ipv6Addr,
+    /// Interfaces on which link local peer discovery is performed. If this is empty, all
+    /// interfaces with a link local IPv6 address are used.
+    pub peer_discovery_interfaces: Vec<String>,
+    /// Maximum amount of data packets queued to be sent to a single peer. Data packets for a peer
+    /// with a full queue are dropped.
+    pub peer_data_queue_size: NonZeroUsize,
+    /// Maximum amount of outbound connection attempts to peers running at the same time.
+    pub max_concurrent_dials: NonZeroUsize,
+    /// Disconnect inbound peers which don't exchange data packets for this long. If this is not
+    /// set, inbound peers are never disconnected for being idle.
+    pub inbound_idle_timeout: Option<Duration>,
+    /// Cap on the outbound bandwidth used for data packets to all peers combined, in bytes per
+    /// second. If this is not set, bandwidth is not limited.
+    pub bandwidth_cap: Option<u64>,
+    /// Compress data packets sent to peers which support it.
+    pub compression: bool,
+    /// Maximum amount of dropped data packets logged at info level per second. If this is not
+    /// set, dropped data packets are only logged at debug level.
+    pub drop_log_rate: Option<u32>,
     /// Name for the TUN device.
     pub tun_name: String,
     /// IP and port for the api address.
     pub api_addr: SocketAddr,
+    /// Only accept IPv6 connections if the api address is an IPv6 address. If this is false,
+    /// IPv4 connections are accepted on it as well.
+    pub api_v6_only: bool,
+    /// Subnets API requests are accepted from. If this is empty, requests from any address are
+    /// accepted.
+    pub api_allowed_sources: Vec<Subnet>,
+    /// Path the API is served under, in front of `/api/v1`. If this is not set, the API is served
+    /// under `/api/v1`.
+    pub api_base_path: Option<String>,
+    /// Allow clients of the API to report their own errors, which are logged by the node.
+    pub api_client_log: bool,
+    /// Labels added to every metric served by the API, e.g. to tell apart nodes scraped by the
+    /// same Prometheus server. A label name can only be used once.
+    pub metric_labels: Vec<api::MetricLabel>,
+    /// Maximum amount of time an API request can take, long polling requests excluded.
+    pub api_request_timeout: Duration,
+    /// Initial thresholds for the alerts reported by the API. These can be changed at runtime.
+    pub alert_thresholds: alerts::AlertThresholds,
+    /// Send and receive messages in order per remote and topic.
+    pub ordered_messages: bool,
+    /// Directory to persist outbound messages in, so their delivery is resumed after a restart.
+    /// If this is not set, outbound messages are only kept in memory.
+    pub message_store: Option<PathBuf>,
+    /// Maximum size in bytes of the payload of a message pushed through the HTTP API.
+    pub max_message_payload_size: usize,
+    /// Spread messages round robin over all equal routes to the receiver.
+    pub message_load_balancing: bool,
+    /// Only keep the selected route for every subnet, without fallback routes.
+    pub disable_fallback_routes: bool,
+    /// The maximum amount of routes kept in the routing table. If this is not set, the amount of
+    /// routes is not limited.
+    pub max_routes: Option<usize>,
+    /// The maximum metric of a route which can be selected. If this is not set, routes are
+    /// selected regardless of their metric.
+    pub max_metric: Option<u16>,
+    /// Amount of routes which need to be selected before the node reports it is ready.
+    pub readiness_min_routes: usize,
+    /// Amount of time after which the node reports it is ready, even if not enough routes are
+    /// selected.
+    pub readiness_timeout: Option<Duration>,
+    /// Percentage by which the intervals of periodic hellos and route updates are randomly
+    /// spread.
+    pub control_jitter: u8,
+}
+
+/// An error returned when the overlay address derived from the node key is not part of the
+/// expected overlay prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverlayPrefixMismatch {
+    /// The overlay address derived from the node key.
+    pub address: Ipv6Addr,
+    /// The expected overlay prefix.
+    pub prefix: Subnet,
+}
+
+/// Verify that the overlay address derived from the given key is part of the overlay prefix. If it
+/// is not, routes announced for the node would be rejected by other nodes in the same overlay,
+/// so routing would silently break.
+fn check_overlay_address(
+    key: &crypto::PublicKey,
+    prefix: Subnet,
+) -> Result<(), OverlayPrefixMismatch> {
+    let address = key.address();
+    if prefix.contains_ip(address.into()) {
+        Ok(())
+    } else {
+        Err(OverlayPrefixMismatch { address, prefix })
+    }
 }
 
 /// The Stack is the main structure in mycelium. It governs the entire data flow.
 pub struct Stack {
-    _router: router::Router,
-    _pm: peer_manager::PeerManager,
+    router: router::Router,
+    pm: peer_manager::PeerManager,
     #[cfg(feature = "message")]
     _ms: message::MessageStack,
     #[cfg(feature = "http-api")]
-    _api: api::Http,
+    api: api::Http,
 }
 
 impl Stack {
     /// Setup a new `Stack` with the provided [`Config`].
     pub async fn new(config: Config) -> Result<Self, Box<dyn std::error::Error>> {
         let node_pub_key = crypto::PublicKey::from(&config.node_key);
+        check_overlay_address(&node_pub_key, config.overlay_prefix)?;
+        api::check_metric_labels(&config.metric_labels)?;
         let node_addr = node_pub_key.address();
         let (tun_tx, tun_rx) = tokio::sync::mpsc::unbounded_channel();
 
@@ -84,6 +200,14 @@ impl Stack {
         )
         .expect("64 is a valid IPv6 prefix size; qed");
 
+        // Open the message store before the node key is handed to the router, so a store which
+        // can't be decrypted with the node key prevents startup.
+        #[cfg(feature = "message")]
+        let message_store = config
+            .message_store
+            .map(|dir| message::MessageStore::open(dir, &config.node_key))
+            .transpose()?;
+
         // Creating a new Router instance
         let router = match router::Router::new(
             tun_tx,
@@ -91,13 +215,20 @@ impl Stack {
             vec![node_subnet],
             (config.node_key, node_pub_key),
             vec![
-                Box::new(filters::AllowedSubnet::new(
-                    Subnet::new(GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN)
-                        .expect("Global subnet is properly defined; qed"),
-                )),
+                Box::new(filters::AllowedSubnet::new(config.overlay_prefix)),
                 Box::new(filters::MaxSubnetSize::<64>),
                 Box::new(filters::RouterIdOwnsSubnet),
             ],
+            config.message_load_balancing,
+            config.disable_fallback_routes,
+            config.max_routes,
+            config.max_metric.map(metric::Metric::new),
+            router::ReadinessConfig {
+                min_routes: config.readiness_min_routes,
+                timeout: config.readiness_timeout,
+            },
+            config.drop_log_rate,
+            config.control_jitter,
         ) {
             Ok(router) => {
                 info!(
@@ -116,14 +247,19 @@ impl Stack {
         let pm = peer_manager::PeerManager::new(
             router.clone(),
             config.peers,
+            config.reconnect_policy,
             config.tcp_listen_port,
             config.quic_listen_port,
-            if let Some(port) = config.peer_discovery_port {
-                port
-            } else {
-                0
+            peer_manager::PeerDiscoveryConfig {
+                port: config.peer_discovery_port,
+                multicast_group: config.peer_discovery_group,
+                interfaces: config.peer_discovery_interfaces,
             },
-            config.peer_discovery_port.is_none(),
+            config.peer_data_queue_size,
+            config.bandwidth_cap,
+            config.compression,
+            config.max_concurrent_dials,
+            config.inbound_idle_timeout,
         )?;
         info!("Started peer manager");
 
@@ -158,8 +294,7 @@ impl Stack {
                     &config.tun_name,
                     Subnet::new(node_addr.into(), 64)
                         .expect("64 is a valid subnet size for IPv6; qed"),
-                    Subnet::new(GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN)
-                        .expect("Static configured TUN route is valid; qed"),
+                    config.overlay_prefix,
                 )
                 .await?;
                 info!("Node overlay IP: {node_addr}");
@@ -168,7 +303,15 @@ impl Stack {
         };
 
         #[cfg(feature = "message")]
-        let ms = MessageStack::new(_data_plane, msg_receiver);
+        let ms = match message_store {
+            Some(store) => MessageStack::new_persistent(
+                _data_plane,
+                msg_receiver,
+                config.ordered_messages,
+                store,
+            ),
+            None => MessageStack::new(_data_plane, msg_receiver, config.ordered_messages),
+        };
 
         #[cfg(feature = "http-api")]
         let api = Http::spawn(
@@ -176,16 +319,90 @@ impl Stack {
             pm.clone(),
             #[cfg(feature = "message")]
             ms.clone(),
+            #[cfg(feature = "message")]
+            config.max_message_payload_size,
             config.api_addr,
+            config.api_v6_only,
+            config.api_request_timeout,
+            config.alert_thresholds,
+            config.api_allowed_sources,
+            config.api_base_path,
+            config.api_client_log,
+            config.metric_labels,
         );
 
         Ok(Stack {
-            _router: router,
-            _pm: pm,
+            router,
+            pm,
             #[cfg(feature = "message")]
             _ms: ms,
             #[cfg(feature = "http-api")]
-            _api: api,
+            api,
         })
     }
+
+    /// Gracefully shut down the `Stack`. Discovery and (re)connecting to peers is stopped, and the
+    /// API server is closed. Our own routes are then retracted, so neighbours converge
+    /// immediately instead of keeping stale routes until they expire. Finally, after a short grace
+    /// period for the retractions to be sent, and once the data packets queued for the peers are
+    /// sent, all peer connections are closed.
+    pub async fn shutdown(self) {
+        info!("Shutting down node");
+        self.pm.stop();
+        #[cfg(feature = "http-api")]
+        drop(self.api);
+
+        self.router.retract_static_routes();
+        tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+        self.pm.drain(SHUTDOWN_DRAIN_TIMEOUT).await;
+
+        self.pm.disconnect_all();
+    }
+}
+
+impl fmt::Display for OverlayPrefixMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Overlay address {} derived from the node key is not part of the overlay prefix {}, check the node key and configured overlay prefix",
+            self.address, self.prefix
+        )
+    }
+}
+
+impl std::error::Error for OverlayPrefixMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{crypto, subnet::Subnet};
+
+    use super::{check_overlay_address, OverlayPrefixMismatch};
+
+    #[test]
+    fn overlay_address_in_prefix_is_accepted() {
+        let pk = crypto::PublicKey::from(&crypto::SecretKey::new());
+        // Node addresses are either in 400::/8 or 500::/8.
+        let prefix = Subnet::new(pk.address().into(), 8).expect("Valid prefix size");
+
+        assert_eq!(check_overlay_address(&pk, prefix), Ok(()));
+    }
+
+    #[test]
+    fn overlay_address_outside_prefix_is_rejected() {
+        let pk = crypto::PublicKey::from(&crypto::SecretKey::new());
+        let other = if pk.address().octets()[0] == 0x04 {
+            "500::/8"
+        } else {
+            "400::/8"
+        };
+        let prefix: Subnet = other.parse().expect("Valid subnet");
+
+        assert_eq!(
+            check_overlay_address(&pk, prefix),
+            Err(OverlayPrefixMismatch {
+                address: pk.address(),
+                prefix,
+            })
+        );
+    }
 }
@@ -1,11 +1,18 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use crypto::PublicKey;
 use log::{debug, error, warn, LevelFilter};
+use mycelium::alerts::AlertThresholds;
+use mycelium::api::MetricLabel;
 use mycelium::endpoint::Endpoint;
-use mycelium::{crypto, Stack};
+use mycelium::reconnect::ReconnectPolicy;
+use mycelium::router::MAX_CONTROL_JITTER;
+use mycelium::subnet::Subnet;
+use mycelium::{crypto, Stack, GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN};
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::time::Duration;
 use std::{
     error::Error,
     net::{IpAddr, SocketAddr},
@@ -24,9 +31,24 @@ const DEFAULT_TCP_LISTEN_PORT: u16 = 9651;
 const DEFAULT_QUIC_LISTEN_PORT: u16 = 9651;
 /// The default port to use for IPv6 link local peer discovery (UDP).
 const DEFAULT_PEER_DISCOVERY_PORT: u16 = 9650;
+/// The default multicast group to use for IPv6 link local peer discovery.
+const DEFAULT_PEER_DISCOVERY_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xcafe);
 /// The default listening address for the HTTP API.
 const DEFAULT_HTTP_API_SERVER_ADDRESS: SocketAddr =
     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8989);
+/// The default maximum amount of seconds a request to the HTTP API can take.
+const DEFAULT_HTTP_API_REQUEST_TIMEOUT_SECS: u64 = 30;
+/// The default prefix of the overlay network, which is the global overlay subnet every node
+/// address is derived in.
+const DEFAULT_OVERLAY_PREFIX: (IpAddr, u8) = (GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN);
+/// The default maximum amount of data packets queued to be sent to a single peer.
+const DEFAULT_PEER_DATA_QUEUE_SIZE: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(1_000) };
+/// The default percentage by which the intervals of periodic control packets are randomly shortened.
+const DEFAULT_CONTROL_JITTER: u8 = 25;
+/// The default maximum amount of outbound connection attempts to peers running at the same time.
+const DEFAULT_MAX_CONCURRENT_DIALS: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(16) };
+/// The default maximum size in bytes of the payload of a message pushed through the HTTP API.
+const DEFAULT_MAX_MESSAGE_PAYLOAD_SIZE: usize = 1024 * 1024;
 
 const DEFAULT_KEY_FILE: &str = "priv_key.bin";
 
@@ -53,6 +75,23 @@ struct Cli {
     #[arg(long = "silent", default_value_t = false)]
     silent: bool,
 
+    /// The format of the logs.
+    #[arg(long = "log-format", value_enum, default_value_t = LogFormat::Human)]
+    log_format: LogFormat,
+
+    /// Amount of worker threads of the async runtime.
+    ///
+    /// If this is not set, the value of the TOKIO_WORKER_THREADS environment variable is used, or
+    /// the amount of CPU cores if that is not set either. Lowering this limits the resource usage
+    /// on small devices, while raising it can improve throughput under load on servers.
+    #[arg(long = "worker-threads", global = true)]
+    worker_threads: Option<NonZeroUsize>,
+
+    /// Maximum amount of threads in the pool of the async runtime for blocking operations, like
+    /// file IO. Defaults to 512.
+    #[arg(long = "max-blocking-threads", global = true)]
+    max_blocking_threads: Option<NonZeroUsize>,
+
     #[clap(flatten)]
     node_args: NodeArguments,
 
@@ -60,6 +99,15 @@ struct Cli {
     command: Option<Command>,
 }
 
+/// Output format of the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human readable logs.
+    Human,
+    /// One JSON object per log line, with timestamp, level, target and message fields.
+    Json,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Command {
     /// Inspect a public key provided in hex format, or export the local public key if no key is
@@ -127,9 +175,45 @@ pub enum MessageCommand {
 #[derive(Debug, Args)]
 pub struct NodeArguments {
     /// Peers to connect to.
+    ///
+    /// Peers are specified as `<protocol>://<address>`, where protocol is either tcp or quic. If
+    /// the protocol is omitted, tcp is used.
+    ///
+    /// Link local IPv6 peers need a zone, which is either the index or the name of the interface
+    /// to reach them on, e.g. `tcp://[fe80::1%eth0]:9651`.
     #[arg(long = "peers", num_args = 1..)]
     static_peers: Vec<Endpoint>,
 
+    /// Delay before the first attempt to reconnect to a peer, in milliseconds.
+    ///
+    /// After connecting to a peer set with `--peers` fails, or its connection is lost, the next
+    /// attempt is delayed. The delay is multiplied by `--reconnect-multiplier` after every
+    /// failure, up to `--reconnect-max-delay-ms`. These flags apply to all peers set with
+    /// `--peers`, a policy for a single peer can be set when adding it through the HTTP API.
+    /// Defaults to 5000.
+    #[arg(long = "reconnect-initial-delay-ms", value_parser = clap::value_parser!(u64).range(1..))]
+    reconnect_initial_delay_ms: Option<u64>,
+
+    /// Maximum delay between attempts to reconnect to a peer, in milliseconds.
+    ///
+    /// This must not be less than the initial delay. Defaults to 300000.
+    #[arg(long = "reconnect-max-delay-ms", value_parser = clap::value_parser!(u64).range(1..))]
+    reconnect_max_delay_ms: Option<u64>,
+
+    /// Factor by which the delay before reconnecting to a peer grows after every failure.
+    ///
+    /// This must be at least 1. Defaults to 2.
+    #[arg(long = "reconnect-multiplier")]
+    reconnect_multiplier: Option<f64>,
+
+    /// Fraction of the delay before reconnecting to a peer by which it is randomly made shorter or
+    /// longer.
+    ///
+    /// This keeps peers which lost their connection at the same time from all reconnecting at
+    /// once. This must be between 0 and 1. Defaults to 0.2.
+    #[arg(long = "reconnect-jitter")]
+    reconnect_jitter: Option<f64>,
+
     /// Port to listen on for tcp connections.
     #[arg(short = 't', long = "tcp-listen-port", default_value_t = DEFAULT_TCP_LISTEN_PORT)]
     tcp_listen_port: u16,
@@ -142,6 +226,17 @@ pub struct NodeArguments {
     #[arg(long = "peer-discovery-port", default_value_t = DEFAULT_PEER_DISCOVERY_PORT)]
     peer_discovery_port: u16,
 
+    /// Multicast group to use for link local peer discovery.
+    #[arg(long = "peer-discovery-group", default_value_t = DEFAULT_PEER_DISCOVERY_GROUP)]
+    peer_discovery_group: Ipv6Addr,
+
+    /// Interfaces on which link local peer discovery is performed.
+    ///
+    /// If this is not set, discovery is performed on every interface with a link local IPv6
+    /// address.
+    #[arg(long = "peer-discovery-interfaces", num_args = 1..)]
+    peer_discovery_interfaces: Vec<String>,
+
     /// Disable peer discovery.
     ///
     /// If this flag is passed, the automatic link local peer discovery will not be enabled, and
@@ -150,10 +245,140 @@ pub struct NodeArguments {
     #[arg(long = "disable-peer-discovery", default_value_t = false)]
     disable_peer_discovery: bool,
 
+    /// Maximum amount of data packets queued to be sent to a single peer.
+    ///
+    /// If a peer can't keep up, data packets are queued until this limit is reached. Once the
+    /// queue is full, new data packets for the peer are dropped (tail drop), and counted in the
+    /// stats of the peer. This keeps congestion of a single peer from affecting other peers.
+    #[arg(long = "peer-data-queue-size", default_value_t = DEFAULT_PEER_DATA_QUEUE_SIZE)]
+    peer_data_queue_size: NonZeroUsize,
+
+    /// Maximum amount of outbound connection attempts to peers running at the same time.
+    ///
+    /// Further connection attempts wait until a running one finishes. This avoids a burst of
+    /// connection attempts when many peers are added at once, for instance on startup or when
+    /// importing a peer list. Must be at least 1.
+    #[arg(long = "max-concurrent-dials", default_value_t = DEFAULT_MAX_CONCURRENT_DIALS)]
+    max_concurrent_dials: NonZeroUsize,
+
+    /// Disconnect inbound peers which don't exchange data for this many seconds.
+    ///
+    /// Only data packets count as activity, so an inbound peer which only exchanges control
+    /// traffic is disconnected as well. This reclaims the resources of connections which are not
+    /// used. Static peers and peers found through link local discovery are never disconnected for
+    /// being idle. If this is not set, idle inbound peers are kept connected.
+    #[arg(long = "inbound-idle-timeout", value_parser = clap::value_parser!(u64).range(1..))]
+    inbound_idle_timeout: Option<u64>,
+
+    /// Cap on the outbound bandwidth to all peers combined, in bytes per second.
+    ///
+    /// Data packets are only sent to peers at this rate. Packets which can't be sent yet wait in
+    /// the data queue of the peer, and are dropped once it is full. A cap for a single peer can be
+    /// set when adding it through the API. If this is not set, bandwidth is not limited.
+    #[arg(long = "bandwidth-cap")]
+    bandwidth_cap: Option<u64>,
+
+    /// Prefix of the overlay network.
+    ///
+    /// Only routes to subnets in this prefix are accepted from peers, and only this prefix is
+    /// routed to the TUN interface. Node addresses are always derived in `400::/7`, so a smaller
+    /// prefix in there restricts the overlay to the nodes of which the address is part of it. On
+    /// startup, the node checks that its own overlay address is part of this prefix, and refuses
+    /// to start if it is not.
+    #[arg(
+        long = "overlay-prefix",
+        default_value_t = Subnet::new(DEFAULT_OVERLAY_PREFIX.0, DEFAULT_OVERLAY_PREFIX.1)
+            .expect("Global subnet is properly defined; qed")
+    )]
+    overlay_prefix: Subnet,
+
+    /// Compress data packets sent to peers which support it.
+    ///
+    /// Compression is hop by hop and transparent to applications, it is only used with peers which
+    /// also enabled it. A data packet is only sent compressed if that makes it smaller. Note that
+    /// the payload of data packets is end to end encrypted, which generally does not compress
+    /// well. This costs CPU time for every data packet, so it is disabled by default.
+    #[arg(long = "compression", default_value_t = false)]
+    compression: bool,
+
+    /// Log dropped data packets at info level, at most this many times per second.
+    ///
+    /// Every dropped data packet is logged with the reason and destination at debug level. With
+    /// this set, up to the given amount of drops per second are logged at info level instead,
+    /// along with the total amount of drops for the reason and the amount of drops which were not
+    /// logged, so drops are visible without being flooded by them.
+    #[arg(long = "drop-log-rate")]
+    drop_log_rate: Option<u32>,
+
     /// Address of the HTTP API server.
     #[arg(long = "api-addr", default_value_t = DEFAULT_HTTP_API_SERVER_ADDRESS)]
     api_addr: SocketAddr,
 
+    /// Only accept IPv6 connections on the HTTP API, if its address is an IPv6 address.
+    ///
+    /// By default, an API bound to an IPv6 address like `[::]` accepts IPv4 connections as well,
+    /// regardless of the default of the operating system. With this flag set, only IPv6
+    /// connections are accepted. This has no effect if the API is bound to an IPv4 address.
+    #[arg(long = "api-v6-only", default_value_t = false)]
+    api_v6_only: bool,
+
+    /// Only accept HTTP API requests from addresses in these subnets.
+    ///
+    /// Subnets are specified in CIDR notation, e.g. `127.0.0.0/8`. Requests from other addresses
+    /// are rejected with `403 Forbidden`. If this is not set, requests from any address which can
+    /// reach the API are accepted.
+    #[arg(long = "api-allow-from", num_args = 1..)]
+    api_allow_from: Vec<Subnet>,
+
+    /// Serve the HTTP API under this path, in front of `/api/v1`.
+    ///
+    /// This allows exposing the API on a subpath through a reverse proxy, e.g. with
+    /// `--api-base-path /mycelium` the API is available under `/mycelium/api/v1`. Message
+    /// commands use this to reach the API as well.
+    #[arg(long = "api-base-path")]
+    api_base_path: Option<String>,
+
+    /// Allow clients of the HTTP API to report their own errors to the node.
+    ///
+    /// Reported errors are logged at warn level, marked with the address of the client, so
+    /// problems of for instance a dashboard can be diagnosed where its console is not accessible.
+    /// At most 30 reports are logged per minute. Since this lets clients write to the logs of the
+    /// node, it is disabled by default.
+    #[arg(long = "api-client-log", default_value_t = false)]
+    api_client_log: bool,
+
+    /// Add a label to every metric served on the metrics endpoint of the HTTP API.
+    ///
+    /// Labels are specified as `name=value`, e.g. `--metrics-label region=eu-west`, so nodes
+    /// scraped by the same Prometheus server can be told apart. The name must be a valid
+    /// Prometheus label name, can't start with `__`, and can't be a label the node sets itself
+    /// (`endpoint`, `type`, `state`, `kind` and `direction`). Every name can only be used once.
+    #[arg(long = "metrics-label", num_args = 1..)]
+    metrics_labels: Vec<MetricLabel>,
+
+    /// Maximum amount of seconds a request to the HTTP API can take.
+    ///
+    /// Requests which take longer are aborted with a `504 Gateway Timeout` response. Long polling
+    /// message requests are excluded, as they are expected to take long. The timeout must be at
+    /// least 1 second.
+    #[arg(long = "api-request-timeout", default_value_t = DEFAULT_HTTP_API_REQUEST_TIMEOUT_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+    api_request_timeout: u64,
+
+    /// Raise an alert once this many peer connections are lost within a minute.
+    ///
+    /// Alerts are listed by the HTTP API. Alert thresholds can also be changed at runtime through
+    /// the HTTP API.
+    #[arg(long = "alert-disconnects-per-minute")]
+    alert_disconnects_per_minute: Option<usize>,
+
+    /// Raise an alert once this many messages or routes are dropped within a minute.
+    #[arg(long = "alert-drops-per-minute")]
+    alert_drops_per_minute: Option<usize>,
+
+    /// Raise an alert once the routing table holds this many routes.
+    #[arg(long = "alert-route-table-size")]
+    alert_route_table_size: Option<usize>,
+
     /// Run without creating a TUN interface.
     ///
     /// The system will participate in the network as usual, but won't be able to send out L3
@@ -169,24 +394,127 @@ pub struct NodeArguments {
     /// the name must start with `utun` and be followed by digits.
     #[arg(long = "tun-name", default_value = TUN_NAME)]
     tun_name: String,
+
+    /// Send and receive messages in order.
+    ///
+    /// Messages sent to the same receiver with the same topic are tagged with a sequence number,
+    /// and received messages from the same sender with the same topic are delivered in the order
+    /// they were sent. Messages which arrive early are held back until the earlier messages
+    /// arrive, or until they have been waiting for the duration of the send window. This is best
+    /// effort, and both nodes need to enable this for ordering to happen.
+    #[arg(long = "ordered-messages", default_value_t = false)]
+    ordered_messages: bool,
+
+    /// Directory to persist outbound messages in.
+    ///
+    /// Messages are kept in this directory until they are received by the remote, or expire. When
+    /// the node starts, delivery of the messages left in the directory is resumed. If this is not
+    /// set, outbound messages are only kept in memory, and are lost when the node stops.
+    #[arg(long = "message-store")]
+    message_store: Option<PathBuf>,
+
+    /// Maximum size in bytes of the payload of a message pushed through the HTTP API.
+    ///
+    /// Messages with a larger payload are rejected with a `413 Payload Too Large` response.
+    #[arg(long = "max-message-payload-size", default_value_t = DEFAULT_MAX_MESSAGE_PAYLOAD_SIZE)]
+    max_message_payload_size: usize,
+
+    /// Spread messages over all equal routes to the receiver.
+    ///
+    /// If multiple routes to the receiver of a message have the same metric, packets of messages
+    /// sent by this node are spread round robin over them, instead of always using the selected
+    /// route.
+    #[arg(long = "message-load-balancing", default_value_t = false)]
+    message_load_balancing: bool,
+
+    /// Only keep the selected route for every subnet.
+    ///
+    /// Routes which are not selected are dropped instead of being kept as fallback routes.
+    /// Retracted routes are still kept until they expire, so the retraction propagates. This
+    /// makes routing more predictable, but recovery after the selected route is lost is slower,
+    /// since a new route only becomes available once a neighbour announces it again.
+    #[arg(long = "disable-fallback-routes", default_value_t = false)]
+    disable_fallback_routes: bool,
+
+    /// The maximum amount of routes kept in the routing table.
+    ///
+    /// Once this is reached, fallback routes with the highest metric are evicted to make room for
+    /// new routes, the oldest first if several have the same metric. Selected routes are never
+    /// evicted. If this is not set, the amount of routes is
+    /// not limited.
+    #[arg(long = "max-routes")]
+    max_routes: Option<usize>,
+
+    /// The maximum metric of a route which can be selected.
+    ///
+    /// Routes with a higher metric, including the link cost to the neighbour, are never selected,
+    /// even if they are the only route to a subnet. If this is not set, routes are selected
+    /// regardless of their metric.
+    #[arg(long = "max-metric")]
+    max_metric: Option<u16>,
+
+    /// Amount of routes which need to be selected before the node reports it is ready.
+    ///
+    /// Readiness is reported by the health endpoint of the HTTP API, and can be used by a load
+    /// balancer to only send traffic to the node once the initial route convergence is done.
+    #[arg(long = "readiness-min-routes", default_value_t = 1)]
+    readiness_min_routes: usize,
+
+    /// Amount of seconds after which the node reports it is ready, even if not enough routes have
+    /// been selected.
+    #[arg(long = "readiness-timeout")]
+    readiness_timeout: Option<u64>,
+
+    /// Percentage by which the intervals of periodic hellos and route updates are randomly
+    /// shortened, at most 50.
+    ///
+    /// Jitter prevents nodes from synchronizing their control traffic, which would otherwise
+    /// cause bursts of control packets on large networks. Intervals are never made longer, so
+    /// neighbours don't time out. Set this to 0 to disable jitter.
+    #[arg(long = "control-jitter", default_value_t = DEFAULT_CONTROL_JITTER, value_parser = clap::value_parser!(u8).range(0..=i64::from(MAX_CONTROL_JITTER)))]
+    control_jitter: u8,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
-    pretty_env_logger::formatted_timed_builder()
-        .filter_module(
-            "mycelium",
-            if cli.silent {
-                LevelFilter::Error
-            } else if cli.debug {
-                LevelFilter::Debug
-            } else {
-                LevelFilter::Info
-            },
-        )
-        .init();
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = cli.worker_threads {
+        runtime.worker_threads(worker_threads.get());
+    }
+    if let Some(max_blocking_threads) = cli.max_blocking_threads {
+        runtime.max_blocking_threads(max_blocking_threads.get());
+    }
+
+    runtime.build()?.block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let mut log_builder = pretty_env_logger::formatted_timed_builder();
+    log_builder.filter_module(
+        "mycelium",
+        if cli.silent {
+            LevelFilter::Error
+        } else if cli.debug {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        },
+    );
+    if cli.log_format == LogFormat::Json {
+        use std::io::Write;
+        log_builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    log_builder.init();
 
     let key_path = if let Some(path) = cli.key_file {
         path
@@ -243,6 +571,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         topic,
                         msg_path,
                         cli.node_args.api_addr,
+                        cli.node_args.api_base_path,
                     )
                     .await
                 }
@@ -252,8 +581,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     msg_path,
                     raw,
                 } => {
-                    return cli::recv_msg(timeout, topic, msg_path, raw, cli.node_args.api_addr)
-                        .await
+                    return cli::recv_msg(
+                        timeout,
+                        topic,
+                        msg_path,
+                        raw,
+                        cli.node_args.api_addr,
+                        cli.node_args.api_base_path,
+                    )
+                    .await
                 }
             },
         }
@@ -268,9 +604,29 @@ async fn main() -> Result<(), Box<dyn Error>> {
         secret_key
     };
 
+    let default_reconnect_policy = ReconnectPolicy::default();
     let config = mycelium::Config {
         node_key: node_secret_key,
         peers: cli.node_args.static_peers,
+        reconnect_policy: ReconnectPolicy {
+            initial_delay: cli.node_args.reconnect_initial_delay_ms.map_or(
+                default_reconnect_policy.initial_delay,
+                Duration::from_millis,
+            ),
+            max_delay: cli
+                .node_args
+                .reconnect_max_delay_ms
+                .map_or(default_reconnect_policy.max_delay, Duration::from_millis),
+            multiplier: cli
+                .node_args
+                .reconnect_multiplier
+                .unwrap_or(default_reconnect_policy.multiplier),
+            jitter: cli
+                .node_args
+                .reconnect_jitter
+                .unwrap_or(default_reconnect_policy.jitter),
+        },
+        overlay_prefix: cli.node_args.overlay_prefix,
         no_tun: cli.node_args.no_tun,
         tcp_listen_port: cli.node_args.tcp_listen_port,
         quic_listen_port: cli.node_args.quic_listen_port,
@@ -279,11 +635,40 @@ async fn main() -> Result<(), Box<dyn Error>> {
         } else {
             Some(cli.node_args.peer_discovery_port)
         },
+        peer_discovery_group: cli.node_args.peer_discovery_group,
+        peer_discovery_interfaces: cli.node_args.peer_discovery_interfaces,
+        peer_data_queue_size: cli.node_args.peer_data_queue_size,
+        max_concurrent_dials: cli.node_args.max_concurrent_dials,
+        inbound_idle_timeout: cli.node_args.inbound_idle_timeout.map(Duration::from_secs),
+        bandwidth_cap: cli.node_args.bandwidth_cap,
+        compression: cli.node_args.compression,
+        drop_log_rate: cli.node_args.drop_log_rate,
         tun_name: cli.node_args.tun_name,
         api_addr: cli.node_args.api_addr,
+        api_v6_only: cli.node_args.api_v6_only,
+        api_allowed_sources: cli.node_args.api_allow_from,
+        api_base_path: cli.node_args.api_base_path,
+        api_client_log: cli.node_args.api_client_log,
+        metric_labels: cli.node_args.metrics_labels,
+        api_request_timeout: Duration::from_secs(cli.node_args.api_request_timeout),
+        alert_thresholds: AlertThresholds {
+            disconnects_per_minute: cli.node_args.alert_disconnects_per_minute,
+            drops_per_minute: cli.node_args.alert_drops_per_minute,
+            route_table_size: cli.node_args.alert_route_table_size,
+        },
+        ordered_messages: cli.node_args.ordered_messages,
+        message_store: cli.node_args.message_store,
+        max_message_payload_size: cli.node_args.max_message_payload_size,
+        message_load_balancing: cli.node_args.message_load_balancing,
+        disable_fallback_routes: cli.node_args.disable_fallback_routes,
+        max_routes: cli.node_args.max_routes,
+        max_metric: cli.node_args.max_metric,
+        readiness_min_routes: cli.node_args.readiness_min_routes,
+        readiness_timeout: cli.node_args.readiness_timeout.map(Duration::from_secs),
+        control_jitter: cli.node_args.control_jitter,
     };
 
-    let _stack = Stack::new(config).await?;
+    let stack = Stack::new(config).await?;
 
     // TODO: put in dedicated file so we can only rely on certain signals on unix platforms
     #[cfg(target_family = "unix")]
@@ -305,6 +690,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    stack.shutdown().await;
+
     Ok(())
 }
 
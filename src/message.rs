@@ -11,25 +11,36 @@ use std::{
     marker::PhantomData,
     net::IpAddr,
     ops::{Deref, DerefMut},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{self, Duration},
 };
 
 use futures::{Stream, StreamExt};
-use log::{debug, error, trace, warn};
+use log::{debug, error, info, trace, warn};
 use rand::Fill;
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
-use tokio::sync::watch;
+use tokio::sync::{watch, Notify};
 
 use crate::{
     crypto::{PacketBuffer, PublicKey},
     data::DataPlane,
-    message::{chunk::MessageChunk, done::MessageDone, init::MessageInit},
+    events::DropKind,
+    message::{
+        chunk::MessageChunk, done::MessageDone, init::MessageInit, reorder::ReorderBuffer,
+        store::PersistedMessage,
+    },
 };
 
 mod chunk;
 mod done;
 mod init;
+mod reorder;
+mod store;
+
+pub use store::MessageStore;
 
 /// The amount of time to try and send messages before we give up.
 const MESSAGE_SEND_WINDOW: Duration = Duration::from_secs(60 * 5);
@@ -37,9 +48,35 @@ const MESSAGE_SEND_WINDOW: Duration = Duration::from_secs(60 * 5);
 /// The amount of time to wait before sending a chunk again if receipt is not acknowledged.
 const RETRANSMISSION_DELAY: Duration = Duration::from_secs(1);
 
+/// The minimum interval between retransmissions a [`RetrySchedule`] can set.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Amount of time between sweeps of the subscriber list to clear orphaned subscribers.
 const REPLY_SUBSCRIBER_CLEAR_DELAY: Duration = Duration::from_secs(60);
 
+/// The maximum amount of time an ordered message which arrives out of order is held back while
+/// waiting for earlier messages from the same sender and topic.
+const ORDERED_MESSAGE_HOLD_TIMEOUT: Duration = MESSAGE_SEND_WINDOW;
+
+/// The amount of time after which an ordered stream without new messages is considered to be
+/// finished. A sender starts a new stream afterwards, and a receiver forgets about the stream.
+const ORDERED_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 10);
+
+/// Amount of time between checks for ordered messages which have been held back for too long.
+const ORDERED_MESSAGE_EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum size of the topic of a message, in bytes. Topics are meant to be short labels, the
+/// size is also limited by the single length byte in the wire format.
+pub const MAX_TOPIC_SIZE: usize = 255;
+/// The maximum size of the correlation id of a message, in bytes.
+pub const MAX_CORRELATION_ID_SIZE: usize = 255;
+/// The maximum size of the content type of a message, in bytes.
+pub const MAX_CONTENT_TYPE_SIZE: usize = 255;
+
+/// The maximum amount of distinct topics of received messages which are tracked. Once this is
+/// reached, the topic which has not been seen for the longest time is forgotten.
+const MAX_SEEN_TOPICS: usize = 1_000;
+
 /// The average size of a single chunk. This is mainly intended to preallocate the chunk array on
 /// the receiver size. This value should allow reasonable overhead for standard MTU.
 const AVERAGE_CHUNK_SIZE: usize = 1_300;
@@ -71,9 +108,18 @@ const FLAG_MESSAGE_READ: u16 = 0b0000_1000_0000_0000;
 /// Flag indicating we are sending a reply to a received message. The message ID used is the same
 /// as the received message.
 const FLAG_MESSAGE_REPLY: u16 = 0b0000_0100_0000_0000;
+/// Flag indicating the message is part of an ordered stream. This is only set on the INIT packet,
+/// which then has a sequence number after the topic in the body.
+const FLAG_MESSAGE_ORDERED: u16 = 0b0000_0010_0000_0000;
 /// Flag acknowledging receipt of a packet. Once this has been received, the packet __should not__ be
 /// transmitted again by the sender.
 const FLAG_MESSAGE_ACK: u16 = 0b0000_0001_0000_0000;
+/// Flag indicating the message carries a correlation id set by the sender. This is only set on the
+/// INIT packet, which then has the correlation id at the end of the body.
+const FLAG_MESSAGE_CORRELATED: u16 = 0b0000_0000_1000_0000;
+/// Flag indicating the message carries a content type set by the sender. This is only set on the
+/// INIT packet, which then has the content type at the end of the body.
+const FLAG_MESSAGE_TYPED: u16 = 0b0000_0000_0100_0000;
 
 /// Length of a message checksum in bytes.
 const MESSAGE_CHECKSUM_LENGTH: usize = 32;
@@ -81,8 +127,13 @@ const MESSAGE_CHECKSUM_LENGTH: usize = 32;
 /// Checksum of a message used to verify received message integrity.
 pub type Checksum = [u8; MESSAGE_CHECKSUM_LENGTH];
 
-/// Response type when pushing a message.
-pub type MessagePushResponse = (MessageId, Option<watch::Receiver<Option<ReceivedMessage>>>);
+/// A subscription to the reply of a message we sent. This is only handed out if a reply is
+/// explicitly requested when pushing a message.
+pub struct ReplySubscription {
+    /// The watch channel only ever receives a `Some` value. The `Option` is only there to avoid
+    /// constructing a dummy initial value.
+    rx: watch::Receiver<Option<ReceivedMessage>>,
+}
 
 #[derive(Clone)]
 pub struct MessageStack {
@@ -97,6 +148,97 @@ pub struct MessageStack {
     /// This takes an Option as value to avoid the hassle of constructing a dummy value when
     /// creating the watch channel.
     reply_subscribers: Arc<Mutex<HashMap<MessageId, watch::Sender<Option<ReceivedMessage>>>>>,
+    /// Are messages sent and received in ordered mode?
+    ordered: bool,
+    /// Next sequence number and last time it was used for every ordered stream we send, keyed by
+    /// the /64 of the receiver and the topic.
+    outbound_sequences: Arc<Mutex<HashMap<(IpAddr, Vec<u8>), (u64, time::Instant)>>>,
+    /// Callers currently waiting for an inbound message.
+    consumers: Arc<Mutex<ConsumerRegistry>>,
+    /// Counter used to generate message ids. If this is not set, ids are random.
+    id_counter: Option<Arc<AtomicU64>>,
+    /// Store to persist outbound messages in, if persistence is enabled.
+    store: Option<Arc<MessageStore>>,
+}
+
+/// Registry of callers which are currently waiting for an inbound message.
+#[derive(Default)]
+struct ConsumerRegistry {
+    /// Id to assign to the next consumer.
+    next_id: u64,
+    /// Topic filter and start time of every waiting consumer. The filter is either an exact topic
+    /// or a topic prefix.
+    waiting: HashMap<u64, (Option<TopicFilter>, time::Instant)>,
+}
+
+/// Guard which keeps a consumer registered for as long as it is alive.
+struct ConsumerGuard {
+    id: u64,
+    registry: Arc<Mutex<ConsumerRegistry>>,
+}
+
+/// Aggregated info about the callers waiting for a message with the same topic filter.
+pub struct ConsumerInfo {
+    /// Topic the waiting callers wait for, if they wait for an exact topic.
+    pub topic: Option<Vec<u8>>,
+    /// Topic prefix the waiting callers wait for, if they wait for any topic with this prefix.
+    pub topic_prefix: Option<Vec<u8>>,
+    /// Amount of callers waiting with this topic filter.
+    pub waiting: usize,
+    /// Amount of time the longest waiting caller has been waiting.
+    pub oldest_wait: Duration,
+}
+
+/// Info about a topic on which messages have been received recently.
+pub struct SeenTopic {
+    /// The topic.
+    pub topic: Vec<u8>,
+    /// Amount of messages received on this topic.
+    pub messages: u64,
+    /// Amount of time since the last message on this topic was received.
+    pub last_seen: Duration,
+}
+
+/// Filter on the topic of inbound messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicFilter {
+    /// Only messages with exactly this topic match.
+    Exact(Vec<u8>),
+    /// Messages with a topic starting with these bytes match. This can be used for hierarchical
+    /// topics, e.g. the prefix `app/` matches both `app/a` and `app/b/c`.
+    Prefix(Vec<u8>),
+    /// Messages with exactly one of these topics match.
+    AnyOf(Vec<Vec<u8>>),
+}
+
+impl TopicFilter {
+    /// Checks if the given topic matches this filter.
+    pub fn matches(&self, topic: &[u8]) -> bool {
+        match self {
+            Self::Exact(filter) => filter == topic,
+            Self::Prefix(prefix) => topic.starts_with(prefix),
+            Self::AnyOf(topics) => topics.iter().any(|filter| filter == topic),
+        }
+    }
+
+    /// The topics or topic prefix used by this filter.
+    pub fn topics(&self) -> &[Vec<u8>] {
+        match self {
+            Self::Exact(topic) | Self::Prefix(topic) => std::slice::from_ref(topic),
+            Self::AnyOf(topics) => topics,
+        }
+    }
+}
+
+/// Settings to aggregate inbound messages on a topic into batches. A batch is delivered once
+/// `max_messages` messages are available, or once the oldest message in it has waited for
+/// `max_delay`, whichever happens first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aggregation {
+    /// The maximum amount of messages in a batch.
+    pub max_messages: usize,
+    /// The maximum amount of time a message waits for a batch to fill up.
+    pub max_delay: Duration,
 }
 
 struct MessageOutbox {
@@ -111,6 +253,17 @@ struct MessageInbox {
     complete_msges: VecDeque<ReceivedMessage>,
     /// Notification sender used to allert subscribed listeners.
     notify: watch::Sender<()>,
+    /// Completed ordered messages which are held back until earlier messages are received.
+    reorder: ReorderBuffer,
+    /// Aggregation settings of topics which are delivered in batches.
+    aggregations: HashMap<Vec<u8>, Aggregation>,
+    /// Completed messages on aggregated topics which are not part of a delivered batch yet, and
+    /// the time they were completed.
+    batches: HashMap<Vec<u8>, VecDeque<(ReceivedMessage, time::Instant)>>,
+    /// Topics of recently received messages, with the amount of messages received on them and
+    /// the last time a message was received on them. This holds at most [`MAX_SEEN_TOPICS`]
+    /// topics.
+    seen_topics: HashMap<Vec<u8>, (u64, time::Instant)>,
 }
 
 struct ReceivedMessageInfo {
@@ -122,6 +275,12 @@ struct ReceivedMessageInfo {
     len: u64,
     /// Optional topic of the message.
     topic: Vec<u8>,
+    /// Sequence number of the message, if it is part of an ordered stream.
+    seq: Option<u64>,
+    /// Optional correlation id set by the sender.
+    correlation_id: Vec<u8>,
+    /// Optional content type set by the sender.
+    content_type: String,
     chunks: Vec<Option<Chunk>>,
 }
 
@@ -141,10 +300,24 @@ pub struct ReceivedMessage {
     pub dst_pk: PublicKey,
     /// The possible topic of the message.
     pub topic: Vec<u8>,
+    /// The possible correlation id set by the sender of the message. This is not interpreted.
+    pub correlation_id: Vec<u8>,
+    /// The possible content type of the data, set by the sender of the message. This is not
+    /// interpreted.
+    pub content_type: String,
     /// Actual message.
     pub data: Vec<u8>,
 }
 
+impl ReceivedMessage {
+    /// Checks if this message matches the optional topic filter and the optional correlation id.
+    /// Filters which are not set match every message.
+    fn matches(&self, filter: Option<&TopicFilter>, correlation_id: Option<&[u8]>) -> bool {
+        filter.map_or(true, |filter| filter.matches(&self.topic))
+            && correlation_id.map_or(true, |id| self.correlation_id == id)
+    }
+}
+
 /// A chunk of a message. This represents individual data pieces on the receiver side.
 #[derive(Clone)]
 struct Chunk {
@@ -187,12 +360,130 @@ enum TransmissionState {
     Read,
     /// Transmission aborted by us. We indicated this by sending an abort flag to the receiver.
     Aborted,
+    /// Transmission cancelled on request of the sender. Like an aborted message, we indicated this
+    /// by sending an abort flag to the receiver.
+    Cancelled,
+}
+
+/// Schedule of the retransmissions of an outbound message. Packets which are not acknowledged are
+/// sent again after the initial interval, and every following interval is the previous one
+/// multiplied by the multiplier, up to the max interval. Durations are (de)serialized as an
+/// amount of milliseconds, fields which are not set take their default value.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RetrySchedule {
+    /// Interval before the first retransmission.
+    #[serde(
+        rename = "initialIntervalMs",
+        serialize_with = "serialize_millis",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub initial_interval: Duration,
+    /// Factor by which the interval grows after every retransmission.
+    pub multiplier: f64,
+    /// Maximum interval between retransmissions.
+    #[serde(
+        rename = "maxIntervalMs",
+        serialize_with = "serialize_millis",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub max_interval: Duration,
+}
+
+/// Marker error to indicate a [`RetrySchedule`] is not valid.
+#[derive(Debug)]
+pub struct InvalidRetrySchedule;
+
+impl RetrySchedule {
+    /// Check that the schedule can be used for a message which is tried for `try_duration`. The
+    /// intervals must be at least [`MIN_RETRY_INTERVAL`], so a message can't flood the network, and
+    /// the max interval can't exceed the try duration, as the message would never be retransmitted
+    /// otherwise. The interval may not shrink, i.e. the multiplier must be at least 1.
+    pub fn validate(&self, try_duration: Duration) -> Result<(), InvalidRetrySchedule> {
+        if self.initial_interval < MIN_RETRY_INTERVAL
+            || self.max_interval < MIN_RETRY_INTERVAL
+            || self.max_interval > try_duration
+            || !self.multiplier.is_finite()
+            || self.multiplier < 1.
+        {
+            return Err(InvalidRetrySchedule);
+        }
+        Ok(())
+    }
+
+    /// The interval before the retransmission with the given index, the first retransmission
+    /// having index 0.
+    pub fn interval(&self, retransmission: u32) -> Duration {
+        let interval = self.initial_interval.as_secs_f64()
+            * self
+                .multiplier
+                .powi(retransmission.min(i32::MAX as u32) as i32);
+        // If the interval overflows it becomes infinite, which is capped as well.
+        Duration::from_secs_f64(interval.min(self.max_interval.as_secs_f64()))
+    }
+}
+
+impl Default for RetrySchedule {
+    /// Retransmit at a fixed interval of [`RETRANSMISSION_DELAY`].
+    fn default() -> Self {
+        Self {
+            initial_interval: RETRANSMISSION_DELAY,
+            multiplier: 1.,
+            max_interval: RETRANSMISSION_DELAY,
+        }
+    }
+}
+
+impl fmt::Display for InvalidRetrySchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "retry intervals must be at least {} ms, the max interval can't exceed the try duration, and the multiplier must be at least 1",
+            MIN_RETRY_INTERVAL.as_millis()
+        )
+    }
+}
+
+/// Serialize a [`Duration`] as an amount of milliseconds.
+fn serialize_millis<S: serde::Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(duration.as_millis() as u64)
 }
 
+/// Deserialize a [`Duration`] from an amount of milliseconds.
+fn deserialize_millis<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    u64::deserialize(d).map(Duration::from_millis)
+}
+
+/// Marker error to indicate an outbound message is not known, or is not being sent anymore.
+#[derive(Debug)]
+pub struct MessageNotPending;
+
 #[derive(Debug, Clone, Copy)]
 pub enum PushMessageError {
     /// The topic set in the message is too large.
     TopicTooLarge,
+    /// The correlation id set in the message is too large.
+    CorrelationIdTooLarge,
+    /// The content type set in the message is too large.
+    ContentTypeTooLarge,
+}
+
+/// Check that the topic, correlation id and content type of a message to be pushed are not larger
+/// than their maximum size.
+pub fn check_message_fields(
+    topic: &[u8],
+    correlation_id: &[u8],
+    content_type: &str,
+) -> Result<(), PushMessageError> {
+    if topic.len() > MAX_TOPIC_SIZE {
+        Err(PushMessageError::TopicTooLarge)
+    } else if correlation_id.len() > MAX_CORRELATION_ID_SIZE {
+        Err(PushMessageError::CorrelationIdTooLarge)
+    } else if content_type.len() > MAX_CONTENT_TYPE_SIZE {
+        Err(PushMessageError::ContentTypeTooLarge)
+    } else {
+        Ok(())
+    }
 }
 
 impl MessageInbox {
@@ -201,7 +492,105 @@ impl MessageInbox {
             pending_msges: HashMap::new(),
             complete_msges: VecDeque::new(),
             notify,
+            reorder: ReorderBuffer::new(ORDERED_MESSAGE_HOLD_TIMEOUT, ORDERED_STREAM_IDLE_TIMEOUT),
+            aggregations: HashMap::new(),
+            batches: HashMap::new(),
+            seen_topics: HashMap::new(),
+        }
+    }
+
+    /// Make completed messages available to readers, and notify them. Messages on an aggregated
+    /// topic are added to the pending batch of that topic.
+    fn deliver(&mut self, msges: impl IntoIterator<Item = ReceivedMessage>) {
+        let now = time::Instant::now();
+        for msg in msges {
+            self.track_topic(&msg.topic, now);
+            if self.aggregations.contains_key(&msg.topic) {
+                self.batches
+                    .entry(msg.topic.clone())
+                    .or_default()
+                    .push_back((msg, now));
+            } else {
+                self.complete_msges.push_back(msg);
+            }
+        }
+        self.notify.send_replace(());
+    }
+
+    /// Amount of completed messages waiting to be read which match the given filter. Messages on
+    /// aggregated topics which are part of a pending batch are not included.
+    fn queued(&self, filter: Option<&TopicFilter>, correlation_id: Option<&[u8]>) -> usize {
+        self.complete_msges
+            .iter()
+            .filter(|m| m.matches(filter, correlation_id))
+            .count()
+    }
+
+    /// Count a received message on the given topic. If this is a new topic and the maximum amount
+    /// of topics is already tracked, the least recently seen topic is forgotten.
+    fn track_topic(&mut self, topic: &[u8], now: time::Instant) {
+        if let Some((count, last_seen)) = self.seen_topics.get_mut(topic) {
+            *count += 1;
+            *last_seen = now;
+            return;
+        }
+        if self.seen_topics.len() >= MAX_SEEN_TOPICS {
+            let oldest = self
+                .seen_topics
+                .iter()
+                .min_by_key(|(_, (_, last_seen))| *last_seen)
+                .map(|(topic, _)| topic.clone());
+            if let Some(oldest) = oldest {
+                self.seen_topics.remove(&oldest);
+            }
+        }
+        self.seen_topics.insert(topic.to_vec(), (1, now));
+    }
+
+    /// Get the next batch of messages on the given topic, if it is ready. If the batch is not
+    /// ready, the time at which it will be ready is returned, if there are any messages waiting.
+    ///
+    /// If the topic is not aggregated, this returns the first message on the topic as a batch of
+    /// one.
+    fn ready_batch(
+        &mut self,
+        topic: &[u8],
+        pop: bool,
+        now: time::Instant,
+    ) -> Result<Vec<ReceivedMessage>, Option<time::Instant>> {
+        let aggregation = match self.aggregations.get(topic) {
+            Some(aggregation) => aggregation,
+            None => {
+                let idx = self
+                    .complete_msges
+                    .iter()
+                    .position(|m| m.topic == topic)
+                    .ok_or(None)?;
+                return Ok(vec![if pop {
+                    self.complete_msges
+                        .remove(idx)
+                        .expect("Index is in bounds since we just found it; qed")
+                } else {
+                    self.complete_msges[idx].clone()
+                }]);
+            }
+        };
+        let batch = match self.batches.get_mut(topic) {
+            Some(batch) if !batch.is_empty() => batch,
+            _ => return Err(None),
+        };
+
+        let deadline = batch[0].1 + aggregation.max_delay;
+        if batch.len() < aggregation.max_messages && now < deadline {
+            return Err(Some(deadline));
         }
+
+        let len = batch.len().min(aggregation.max_messages);
+        Ok(if pop {
+            batch.drain(..len).map(|(msg, _)| msg).collect()
+        } else {
+            batch.iter().take(len).map(|(msg, _)| msg.clone()).collect()
+        })
     }
 }
 
@@ -223,7 +612,74 @@ impl MessageStack {
     /// Create a new `MessageStack`. This uses the provided [`DataPlane`] to inject message
     /// packets. Received packets must be injected into the `MessageStack` through the provided
     /// [`Stream`].
-    pub fn new<S>(data_plane: DataPlane, message_packet_stream: S) -> Self
+    ///
+    /// If `ordered` is set, messages we send are tagged with a sequence number per receiver and
+    /// topic, and received messages which are tagged are released per sender and topic in the
+    /// order they were sent. This is best effort: if an earlier message does not arrive within
+    /// the send window, later messages are released without it.
+    pub fn new<S>(data_plane: DataPlane, message_packet_stream: S, ordered: bool) -> Self
+    where
+        S: Stream<Item = (PacketBuffer, IpAddr, IpAddr)> + Send + Unpin + 'static,
+    {
+        Self::with_id_counter(data_plane, message_packet_stream, ordered, None, None)
+    }
+
+    /// Create a new `MessageStack` which persists outbound messages in the given
+    /// [`MessageStore`], so they survive a restart. Delivery of messages which are already in the
+    /// store is resumed immediately, unless they expired. See [`MessageStack::new`] for the other
+    /// arguments.
+    pub fn new_persistent<S>(
+        data_plane: DataPlane,
+        message_packet_stream: S,
+        ordered: bool,
+        store: MessageStore,
+    ) -> Self
+    where
+        S: Stream<Item = (PacketBuffer, IpAddr, IpAddr)> + Send + Unpin + 'static,
+    {
+        let ms = Self::with_id_counter(
+            data_plane,
+            message_packet_stream,
+            ordered,
+            None,
+            Some(Arc::new(store)),
+        );
+        ms.replay_persisted();
+        ms
+    }
+
+    /// Create a new `MessageStack` which generates sequential message ids, starting at
+    /// `first_id`, instead of random ones. This is only intended for tests which need to know the
+    /// id of a message up front, as ids are not unique across nodes and restarts. See
+    /// [`MessageStack::new`] for the other arguments.
+    #[cfg(any(test, feature = "deterministic-message-ids"))]
+    pub fn new_deterministic<S>(
+        data_plane: DataPlane,
+        message_packet_stream: S,
+        ordered: bool,
+        first_id: u64,
+    ) -> Self
+    where
+        S: Stream<Item = (PacketBuffer, IpAddr, IpAddr)> + Send + Unpin + 'static,
+    {
+        Self::with_id_counter(
+            data_plane,
+            message_packet_stream,
+            ordered,
+            Some(Arc::new(AtomicU64::new(first_id))),
+            None,
+        )
+    }
+
+    /// Create a new `MessageStack`, which generates message ids from the given counter if it is
+    /// set, and persists outbound messages in the given store if it is set.
+    fn with_id_counter<S>(
+        data_plane: DataPlane,
+        message_packet_stream: S,
+        ordered: bool,
+        id_counter: Option<Arc<AtomicU64>>,
+        store: Option<Arc<MessageStore>>,
+    ) -> Self
     where
         S: Stream<Item = (PacketBuffer, IpAddr, IpAddr)> + Send + Unpin + 'static,
     {
@@ -234,6 +690,11 @@ impl MessageStack {
             outbox: Arc::new(Mutex::new(MessageOutbox::new())),
             subscriber,
             reply_subscribers: Arc::new(Mutex::new(HashMap::new())),
+            ordered,
+            outbound_sequences: Arc::new(Mutex::new(HashMap::new())),
+            consumers: Arc::new(Mutex::new(ConsumerRegistry::default())),
+            id_counter,
+            store,
         };
 
         tokio::task::spawn(
@@ -263,6 +724,27 @@ impl MessageStack {
                 }
             });
         }
+
+        // task to periodically release ordered messages which have been held back for too long
+        if ordered {
+            let ms = ms.clone();
+            tokio::task::spawn(async move {
+                loop {
+                    tokio::time::sleep(ORDERED_MESSAGE_EXPIRE_INTERVAL).await;
+
+                    let mut inbox = ms.inbox.lock().unwrap();
+                    let released = inbox.reorder.expire(time::Instant::now());
+                    if !released.is_empty() {
+                        debug!(
+                            "Releasing {} ordered messages after hold timeout",
+                            released.len()
+                        );
+                        inbox.deliver(released);
+                    }
+                }
+            });
+        }
+
         ms
     }
 
@@ -289,6 +771,28 @@ impl MessageStack {
         warn!("Incoming message packet stream ended!");
     }
 
+    /// Hand a fully received message to the subscriber waiting for a reply with the same id. If
+    /// there is no such subscriber, or it quit before we could send the reply, the message is
+    /// returned.
+    fn notify_reply_subscriber(&self, message: ReceivedMessage) -> Result<(), ReceivedMessage> {
+        let mut subscribers = self.reply_subscribers.lock().unwrap();
+        // Use remove here since we are done with the subscriber
+        // TODO: only check this if the is_reply flag is set?
+        if let Some(sub) = subscribers.remove(&message.id) {
+            if let Err(e) = sub.send(Some(message)) {
+                debug!("Subscriber quit before we could send the reply");
+                Err(e
+                    .0
+                    .expect("We only ever send Some values to reply subscribers; qed"))
+            } else {
+                debug!("Informed subscriber of message reply");
+                Ok(())
+            }
+        } else {
+            Err(message)
+        }
+    }
+
     /// Handle an incoming message packet which is a reply to a message we previously sent.
     fn handle_message_reply(&self, mp: MessagePacket) {
         let header = mp.header();
@@ -302,6 +806,8 @@ impl MessageStack {
                     return;
                 }
                 message.state = TransmissionState::InProgress;
+                // The chunks are sent right away, not when the retransmission timer fires.
+                message.send_now.notify_one();
                 // Transform message into chunks.
                 let mut chunks =
                     Vec::with_capacity((message.len + AVERAGE_CHUNK_SIZE - 1) / AVERAGE_CHUNK_SIZE);
@@ -336,8 +842,19 @@ impl MessageStack {
                 // ACKs the right chunk. Additionally a malicious node could return a crafted input
                 // here anyway.
 
-                message.chunks[mc.chunk_idx() as usize].chunk_transmit_state =
-                    ChunkTransmitState::Acked;
+                let chunk = &mut message.chunks[mc.chunk_idx() as usize];
+                if matches!(chunk.chunk_transmit_state, ChunkTransmitState::Acked) {
+                    return;
+                }
+                chunk.chunk_transmit_state = ChunkTransmitState::Acked;
+                // Once every chunk is acknowledged, the done packet is sent right away.
+                if message
+                    .chunks
+                    .iter()
+                    .all(|chunk| matches!(chunk.chunk_transmit_state, ChunkTransmitState::Acked))
+                {
+                    message.send_now.notify_one();
+                }
             }
         } else if flags.done() {
             // ACK for full message.
@@ -347,7 +864,9 @@ impl MessageStack {
                     debug!("Dropping DONE ACK for message which is not being transmitted");
                     return;
                 }
-                message.state = TransmissionState::Received;
+                message.finish(TransmissionState::Received);
+                drop(outbox);
+                self.forget_persisted(message_id);
             }
         } else if flags.read() {
             // Ack for a read flag. Since the original read flag is sent by the receiver, this
@@ -372,7 +891,13 @@ impl MessageStack {
             // We receive a new message with an ID. If we already have a complete message, ignore
             // it.
             let mut inbox = self.inbox.lock().unwrap();
-            if inbox.complete_msges.iter().any(|m| m.id == message_id) {
+            if inbox.complete_msges.iter().any(|m| m.id == message_id)
+                || inbox
+                    .batches
+                    .values()
+                    .flatten()
+                    .any(|(m, _)| m.id == message_id)
+            {
                 debug!("Dropping INIT message as we already have a complete message with this ID");
                 return;
             }
@@ -389,6 +914,10 @@ impl MessageStack {
                 dst,
                 len: mi.length(),
                 topic: mi.topic().into(),
+                // Ignore the sequence number if we don't order messages ourselves.
+                seq: if self.ordered { mi.sequence() } else { None },
+                correlation_id: mi.correlation_id().into(),
+                content_type: String::from_utf8_lossy(mi.content_type()).into_owned(),
                 chunks,
             };
 
@@ -409,6 +938,11 @@ impl MessageStack {
             let mut inbox = self.inbox.lock().unwrap();
             if let Some(message) = inbox.pending_msges.get_mut(&message_id) {
                 let mc = MessageChunk::new(mp);
+                // A message without payload is completed without sending any chunk.
+                if message.len == 0 {
+                    debug!("Dropping CHUNK for message without payload");
+                    return;
+                }
                 // Make sure the data is within bounds of the message being sent.
                 if message.len < mc.chunk_offset() + mc.chunk_size() {
                     debug!("Dropping invalid message CHUNK for being out of bounds");
@@ -484,6 +1018,8 @@ impl MessageStack {
                     src: inbound_message.src,
                     dst: inbound_message.dst,
                     topic: inbound_message.topic.clone(),
+                    correlation_id: inbound_message.correlation_id.clone(),
+                    content_type: inbound_message.content_type.clone(),
                     data: message_data,
                 };
 
@@ -509,6 +1045,7 @@ impl MessageStack {
                 // This always is our own key as we are receiving.
                 let dst_pubkey = dp.router().node_public_key();
 
+                let seq = inbound_message.seq;
                 let message = ReceivedMessage {
                     id: message.id,
                     is_reply: inbound_message.is_reply,
@@ -517,30 +1054,26 @@ impl MessageStack {
                     dst_ip: message.dst,
                     dst_pk: dst_pubkey,
                     topic: message.topic,
+                    correlation_id: message.correlation_id,
+                    content_type: message.content_type,
                     data: message.data,
                 };
 
                 debug!("Message {} reception complete", message.id.as_hex());
 
                 // Check if we have any listeners and try to send the message to those first.
-                let mut subscribers = self.reply_subscribers.lock().unwrap();
-                // Use remove here since we are done with the subscriber
-                // TODO: only check this if the is_reply flag is set?
-                if let Some(sub) = subscribers.remove(&message.id) {
-                    if let Err(e) = sub.send(Some(message)) {
-                        debug!("Subscriber quit before we could send the reply");
-                        // Move message to be read if there were no subscribers.
-                        inbox.complete_msges.push_back(e.0.unwrap());
-                        // Notify subscribers we have a new message.
-                        inbox.notify.send_replace(());
+                if let Err(message) = self.notify_reply_subscriber(message) {
+                    // Ordered messages might need to wait for earlier messages first.
+                    let released = if let Some(seq) = seq {
+                        inbox.reorder.insert(seq, message, time::Instant::now())
                     } else {
-                        debug!("Informed subscriber of message reply");
+                        vec![message]
+                    };
+                    if !released.is_empty() {
+                        // Move message to be read if there were no subscribers, and notify
+                        // subscribers we have a new message.
+                        inbox.deliver(released);
                     }
-                } else {
-                    // Move message to be read if there were no subscribers.
-                    inbox.complete_msges.push_back(message);
-                    // Notify subscribers we have a new message.
-                    inbox.notify.send_replace(());
                 }
                 inbox.pending_msges.remove(&message_id);
 
@@ -589,58 +1122,144 @@ impl MessageStack {
 }
 
 impl MessageStack {
-    /// Push a new message to be transmitted, which will be tried for the given duration. A
-    /// [message id](MessageId) will be randomly generated, and returned.
+    /// Push a new message to be transmitted, which will be tried for the given duration, and
+    /// retransmitted according to the given [`RetrySchedule`]. A [message id](MessageId) will be
+    /// randomly generated, and returned. The correlation id and content type are passed to the
+    /// receiver as is, if they are empty they are not sent.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_message(
         &self,
         dst: IpAddr,
         data: Vec<u8>,
         topic: Vec<u8>,
+        correlation_id: Vec<u8>,
+        content_type: String,
         try_duration: Duration,
-        subscribe_reply: bool,
-    ) -> Result<MessagePushResponse, PushMessageError> {
-        self.push_message(None, dst, data, topic, try_duration, subscribe_reply)
+        retry_schedule: RetrySchedule,
+    ) -> Result<MessageId, PushMessageError> {
+        let id = self.next_message_id();
+        self.push_message(
+            id,
+            false,
+            dst,
+            data,
+            topic,
+            correlation_id,
+            content_type,
+            try_duration,
+            retry_schedule,
+        )?;
+        Ok(id)
+    }
+
+    /// Push a new message to be transmitted, which will be tried for the given duration, and
+    /// subscribe to a reply to it. A [message id](MessageId) will be randomly generated, and
+    /// returned alongside the [`ReplySubscription`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_message_with_reply(
+        &self,
+        dst: IpAddr,
+        data: Vec<u8>,
+        topic: Vec<u8>,
+        correlation_id: Vec<u8>,
+        content_type: String,
+        try_duration: Duration,
+        retry_schedule: RetrySchedule,
+    ) -> Result<(MessageId, ReplySubscription), PushMessageError> {
+        let id = self.next_message_id();
+        // Subscribe before the message is pushed, so we can't miss the reply.
+        let subscription = self.subscribe_id(id);
+        if let Err(e) = self.push_message(
+            id,
+            false,
+            dst,
+            data,
+            topic,
+            correlation_id,
+            content_type,
+            try_duration,
+            retry_schedule,
+        ) {
+            self.reply_subscribers.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        Ok((id, subscription))
     }
 
     /// Push a new message which is a reply to the message with [the provided id](MessageId).
+    #[allow(clippy::too_many_arguments)]
     pub fn reply_message(
         &self,
         reply_to: MessageId,
         dst: IpAddr,
         data: Vec<u8>,
+        correlation_id: Vec<u8>,
+        content_type: String,
         try_duration: Duration,
-    ) -> MessageId {
-        self.push_message(Some(reply_to), dst, data, vec![], try_duration, false)
-            .expect("Empty topic is never too large")
-            .0
+        retry_schedule: RetrySchedule,
+    ) -> Result<MessageId, PushMessageError> {
+        self.push_message(
+            reply_to,
+            true,
+            dst,
+            data,
+            vec![],
+            correlation_id,
+            content_type,
+            try_duration,
+            retry_schedule,
+        )?;
+        Ok(reply_to)
+    }
+
+    /// Generate the id for a new message.
+    fn next_message_id(&self) -> MessageId {
+        match self.id_counter {
+            Some(ref counter) => MessageId(counter.fetch_add(1, Ordering::Relaxed).to_be_bytes()),
+            None => MessageId::new(),
+        }
     }
 
     /// Subscribe to a new message with the given ID. In practice, this will be a reply.
-    pub fn subscribe_id(&self, id: MessageId) -> watch::Receiver<Option<ReceivedMessage>> {
+    pub fn subscribe_id(&self, id: MessageId) -> ReplySubscription {
         let mut subscribers = self.reply_subscribers.lock().unwrap();
-        if let Some(sub) = subscribers.get(&id) {
+        let rx = if let Some(sub) = subscribers.get(&id) {
             sub.subscribe()
         } else {
             // dummy initial value
             let (tx, rx) = watch::channel(None);
             subscribers.insert(id, tx);
             rx
-        }
+        };
+        ReplySubscription { rx }
     }
 
-    /// Push a new message. If id is set, it is considered a reply to that id. If not, a new id is
-    /// generated.
+    /// Push a new message with the given id. If reply is set, the message is considered a reply
+    /// to the message with that id.
+    #[allow(clippy::too_many_arguments)]
     fn push_message(
         &self,
-        id: Option<MessageId>,
+        id: MessageId,
+        reply: bool,
         dst: IpAddr,
         data: Vec<u8>,
         topic: Vec<u8>,
+        correlation_id: Vec<u8>,
+        content_type: String,
         try_duration: Duration,
-        subscribe: bool,
-    ) -> Result<MessagePushResponse, PushMessageError> {
-        if topic.len() > 255 {
-            return Err(PushMessageError::TopicTooLarge);
+        retry_schedule: RetrySchedule,
+    ) -> Result<(), PushMessageError> {
+        if let Err(err) = check_message_fields(&topic, &correlation_id, &content_type) {
+            self.data_plane
+                .lock()
+                .unwrap()
+                .router()
+                .drop_events()
+                .record(
+                    DropKind::OversizedMessage,
+                    format!("Message to {dst}: {err}"),
+                );
+            return Err(err);
         }
 
         let src = self
@@ -652,38 +1271,62 @@ impl MessageStack {
             .address()
             .into();
 
-        let (id, reply) = if let Some(id) = id {
-            (id, true)
-        } else {
-            (MessageId::new(), false)
-        };
-
         let len = data.len();
         let msg = Message {
             id,
             src,
             dst,
             topic,
+            correlation_id,
+            content_type,
             data,
         };
 
         let created = std::time::SystemTime::now();
         let deadline = created + try_duration;
 
+        if let Some(ref store) = self.store {
+            let persisted = PersistedMessage {
+                id,
+                reply,
+                dst,
+                topic: msg.topic.clone(),
+                correlation_id: msg.correlation_id.clone(),
+                content_type: msg.content_type.clone(),
+                payload: msg.data.clone(),
+                created_at: created
+                    .duration_since(time::UNIX_EPOCH)
+                    .expect("Time is after the UNIX epoch; qed")
+                    .as_secs(),
+                try_duration_secs: try_duration.as_secs(),
+            };
+            if let Err(e) = store.save(&persisted) {
+                warn!("Failed to persist message {}: {e}", id.as_hex());
+            }
+        }
+
+        // Replies are never part of an ordered stream.
+        let sequence = if self.ordered && !reply {
+            Some(self.next_sequence(dst, &msg.topic))
+        } else {
+            None
+        };
+
         let obmi = OutboundMessageInfo {
             state: TransmissionState::Init,
             created,
             deadline,
             len,
+            checksum: msg.checksum(),
             msg,
+            sequence,
+            // The init packet is sent right away.
+            attempts: 1,
+            retry_schedule,
             chunks: vec![], // leave Vec empty at start
+            send_now: Arc::new(Notify::new()),
         };
-
-        let subscription = if subscribe {
-            Some(self.subscribe_id(id))
-        } else {
-            None
-        };
+        let send_now = obmi.send_now.clone();
 
         // Already prepare the init packet for sending..
         let mut mp = MessagePacket::new(PacketBuffer::new());
@@ -695,6 +1338,15 @@ impl MessageStack {
         let mut mi = MessageInit::new(mp);
         mi.set_length(len as u64);
         mi.set_topic(&obmi.msg.topic);
+        if let Some(sequence) = sequence {
+            mi.set_sequence(sequence);
+        }
+        if !obmi.msg.correlation_id.is_empty() {
+            mi.set_correlation_id(&obmi.msg.correlation_id);
+        }
+        if !obmi.msg.content_type.is_empty() {
+            mi.set_content_type(obmi.msg.content_type.as_bytes());
+        }
 
         self.outbox
             .lock()
@@ -716,19 +1368,41 @@ impl MessageStack {
         // Clone message stack so it can be injected in the task.
         let message_stack = self.clone();
         tokio::task::spawn(async move {
-            let mut deadline = tokio::time::interval(MESSAGE_SEND_WINDOW);
-            let mut interval = tokio::time::interval(RETRANSMISSION_DELAY);
-            // Avoid a send burst if the system is slow.
-            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-            // intervals tick immediately, so consume one tick each
-            deadline.tick().await;
-            interval.tick().await;
+            // The first tick aborts the message once its try duration expired, the second one
+            // cleans it up.
+            let mut deadline = tokio::time::interval_at(
+                tokio::time::Instant::now() + try_duration,
+                MESSAGE_SEND_WINDOW,
+            );
+
+            // The retransmission timer is reset after it fires, so a slow system does not cause a
+            // send burst.
+            let mut retransmissions = 0;
+            let mut waited = retry_schedule.interval(retransmissions);
+            let retransmit = tokio::time::sleep(waited);
+            tokio::pin!(retransmit);
 
             let mut aborted = false;
+            // Set if packets which were not sent before must be sent right away.
+            let mut sending_new = false;
 
             loop {
                 tokio::select! {
-                    _ = interval.tick() => {
+                    _ = send_now.notified() => {
+                        sending_new = true;
+                        retransmit.as_mut().reset(tokio::time::Instant::now());
+                    },
+                    _ = &mut retransmit => {
+                        let last_interval = if std::mem::take(&mut sending_new) {
+                            // Sending new packets is not a retransmission, so it does not advance
+                            // the schedule.
+                            waited
+                        } else {
+                            retransmissions += 1;
+                            let next = retry_schedule.interval(retransmissions);
+                            std::mem::replace(&mut waited, next)
+                        };
+                        retransmit.as_mut().reset(tokio::time::Instant::now() + waited);
                         if aborted {
                             continue
                         }
@@ -745,6 +1419,16 @@ impl MessageStack {
                                     let mut mi = MessageInit::new(mp);
                                     mi.set_length(len as u64);
                                     mi.set_topic(&msg.msg.topic);
+                                    if let Some(sequence) = msg.sequence {
+                                        mi.set_sequence(sequence);
+                                    }
+                                    if !msg.msg.correlation_id.is_empty() {
+                                        mi.set_correlation_id(&msg.msg.correlation_id);
+                                    }
+                                    if !msg.msg.content_type.is_empty() {
+                                        mi.set_content_type(msg.msg.content_type.as_bytes());
+                                    }
+                                    msg.attempts += 1;
                                     match (msg.msg.src, msg.msg.dst) {
                                         (IpAddr::V6(src), IpAddr::V6(dst)) => {
                                             message_stack
@@ -763,6 +1447,7 @@ impl MessageStack {
                                 TransmissionState::InProgress => {
                                     // Send chunks which haven't been sent yet.
                                     let mut all_acked = true;
+                                    let mut sent = false;
                                     for chunk in msg.chunks.iter_mut() {
                                         if !matches!(chunk.chunk_transmit_state, ChunkTransmitState::Acked)
                                         {
@@ -802,9 +1487,10 @@ impl MessageStack {
                                                 }
                                                 chunk.chunk_transmit_state =
                                                     ChunkTransmitState::Sent(time::Instant::now());
+                                                sent = true;
                                             }
                                             ChunkTransmitState::Sent(t) => {
-                                                if t.elapsed().as_secs() >= 1 {
+                                                if t.elapsed() >= last_interval {
                                                     // retransmit
                                                     let mut mp = MessagePacket::new(PacketBuffer::new());
                                                     mp.header_mut().set_message_id(id);
@@ -837,6 +1523,7 @@ impl MessageStack {
                                                     }
                                                     chunk.chunk_transmit_state =
                                                         ChunkTransmitState::Sent(time::Instant::now());
+                                                    sent = true;
                                                 }
                                             }
                                             ChunkTransmitState::Acked => {
@@ -844,15 +1531,19 @@ impl MessageStack {
                                             }
                                         }
                                     }
+                                    if sent {
+                                        msg.attempts += 1;
+                                    }
 
                                     // If every chunk is acked, send the done packet.
                                     if all_acked {
+                                        msg.attempts += 1;
                                         let mut mp = MessagePacket::new(PacketBuffer::new());
                                         mp.header_mut().set_message_id(id);
 
                                         let mut md = MessageDone::new(mp);
                                         md.set_chunk_count(msg.chunks.len() as u64);
-                                        md.set_checksum(msg.msg.checksum());
+                                        md.set_checksum(msg.checksum);
 
                                         match (msg.msg.src, msg.msg.dst) {
                                             (IpAddr::V6(src), IpAddr::V6(dst)) => {
@@ -881,6 +1572,9 @@ impl MessageStack {
                                 TransmissionState::Aborted => {
                                     // Nothing to do if we aborted the message.
                                 }
+                                TransmissionState::Cancelled => {
+                                    // Nothing to do if the message was cancelled.
+                                }
                             };
                         } else {
                             // If the message is gone, just exit
@@ -895,13 +1589,27 @@ impl MessageStack {
                             aborted = true;
                             if let Some(msg) = message_stack.outbox.lock().unwrap().msges.get_mut(&id) {
                                 if matches!(msg.state, TransmissionState::Init | TransmissionState::InProgress) {
-                                    msg.state = TransmissionState::Aborted;
+                                    msg.finish(TransmissionState::Aborted);
 
                                     // Inform receiver of message abortion.
                                     let mut mp = MessagePacket::new(PacketBuffer::new());
                                     mp.header_mut().set_message_id(id);
                                     mp.header_mut().flags_mut().set_aborted();
 
+                                    message_stack
+                                        .data_plane
+                                        .lock()
+                                        .unwrap()
+                                        .router()
+                                        .drop_events()
+                                        .record(
+                                            DropKind::ExpiredMessage,
+                                            format!(
+                                                "Message {} to {} was not received before its deadline",
+                                                id.as_hex(),
+                                                msg.msg.dst
+                                            ),
+                                        );
 
                                     match (msg.msg.src, msg.msg.dst) {
                                         (IpAddr::V6(src), IpAddr::V6(dst)) => {
@@ -921,6 +1629,7 @@ impl MessageStack {
                                     };
                                 }
                             }
+                            message_stack.forget_persisted(id);
                             continue
                         }
 
@@ -932,7 +1641,87 @@ impl MessageStack {
             }
         });
 
-        Ok((id, subscription))
+        Ok(())
+    }
+
+    /// Resume delivery of all messages in the store, if messages are persisted. Messages which
+    /// expired while the node was not running are removed from the store.
+    fn replay_persisted(&self) {
+        let store = match self.store {
+            Some(ref store) => store,
+            None => return,
+        };
+        let messages = match store.load() {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Failed to load persisted messages: {e}");
+                return;
+            }
+        };
+
+        let now = time::SystemTime::now();
+        for msg in messages {
+            let id = msg.id;
+            let remaining = match msg.remaining_try_duration(now) {
+                Some(remaining) => remaining,
+                None => {
+                    debug!("Dropping expired persisted message {}", id.as_hex());
+                    self.forget_persisted(id);
+                    continue;
+                }
+            };
+            info!("Resuming delivery of persisted message {}", id.as_hex());
+            if let Err(e) = self.push_message(
+                id,
+                msg.reply,
+                msg.dst,
+                msg.payload,
+                msg.topic,
+                msg.correlation_id,
+                msg.content_type,
+                remaining,
+                // The schedule is not persisted, resumed messages use the default schedule.
+                RetrySchedule::default(),
+            ) {
+                warn!("Failed to resume persisted message {}: {e}", id.as_hex());
+                self.forget_persisted(id);
+            }
+        }
+    }
+
+    /// Remove a message from the store, if messages are persisted.
+    fn forget_persisted(&self, id: MessageId) {
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.remove(id) {
+                warn!("Failed to remove persisted message {}: {e}", id.as_hex());
+            }
+        }
+    }
+
+    /// Get the next sequence number for an ordered message to the given destination with the
+    /// given topic. Streams are tracked per /64, since that is the subnet owned by the receiving
+    /// node. If the stream has been idle for too long, a new stream is started.
+    fn next_sequence(&self, dst: IpAddr, topic: &[u8]) -> u64 {
+        let dst = match dst {
+            IpAddr::V6(ip) => {
+                let mut octets = ip.octets();
+                octets[8..].fill(0);
+                IpAddr::V6(octets.into())
+            }
+            ip => ip,
+        };
+
+        let now = time::Instant::now();
+        let mut sequences = self.outbound_sequences.lock().unwrap();
+        sequences.retain(|_, (_, last_used)| {
+            now.duration_since(*last_used) < ORDERED_STREAM_IDLE_TIMEOUT
+        });
+        let (next, last_used) = sequences.entry((dst, topic.to_vec())).or_insert((0, now));
+        let sequence = *next;
+        *next += 1;
+        *last_used = now;
+
+        sequence
     }
 
     /// Get information about the status of an outbound message.
@@ -963,6 +1752,7 @@ impl MessageStack {
                 TransmissionState::Received => TransmissionProgress::Received,
                 TransmissionState::Read => TransmissionProgress::Read,
                 TransmissionState::Aborted => TransmissionProgress::Aborted,
+                TransmissionState::Cancelled => TransmissionProgress::Cancelled,
             },
             created: mi
                 .created
@@ -975,16 +1765,161 @@ impl MessageStack {
                 .expect("Message expires after the epoch")
                 .as_secs() as i64,
             msg_len: mi.len,
+            payload_hash: mi.checksum.to_hex().to_string(),
+            retry_schedule: mi.retry_schedule,
         })
     }
 
+    /// Cancel sending an outbound message. The receiver is informed that the message is aborted,
+    /// and the message is no longer retried. The status of the message remains available until
+    /// its deadline, in the [`cancelled`](TransmissionProgress::Cancelled) state.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message is not known, or if it is not being sent anymore, i.e. it
+    /// is already received, expired or cancelled.
+    pub fn cancel_message(&self, id: MessageId) -> Result<(), MessageNotPending> {
+        {
+            let mut outbox = self.outbox.lock().unwrap();
+            let msg = outbox
+                .msges
+                .get_mut(&id)
+                .filter(|msg| {
+                    matches!(
+                        msg.state,
+                        TransmissionState::Init | TransmissionState::InProgress
+                    )
+                })
+                .ok_or(MessageNotPending)?;
+            debug!("Cancelling message {} to {}", id.as_hex(), msg.msg.dst);
+            msg.finish(TransmissionState::Cancelled);
+
+            // Inform receiver of message abortion.
+            let mut mp = MessagePacket::new(PacketBuffer::new());
+            mp.header_mut().set_message_id(id);
+            mp.header_mut().flags_mut().set_aborted();
+            match (msg.msg.src, msg.msg.dst) {
+                (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                    self.data_plane.lock().unwrap().inject_message_packet(
+                        src,
+                        dst,
+                        mp.into_inner(),
+                    );
+                }
+                _ => debug!("Can only send messages between two IPv6 addresses"),
+            }
+        }
+        self.forget_persisted(id);
+
+        Ok(())
+    }
+
+    /// Get the amount of outbound messages which are still being sent.
+    pub fn outbound_pending(&self) -> usize {
+        self.outbox
+            .lock()
+            .unwrap()
+            .msges
+            .values()
+            .filter(|mi| {
+                matches!(
+                    mi.state,
+                    TransmissionState::Init | TransmissionState::InProgress
+                )
+            })
+            .count()
+    }
+
+    /// Get the amount of inbound messages waiting to be read. Messages on aggregated topics which
+    /// are part of a pending batch are not included.
+    pub fn inbound_queued(&self) -> usize {
+        self.inbox.lock().unwrap().queued(None, None)
+    }
+
+    /// Get the outbound messages which are still being sent, i.e. which are not received by the
+    /// remote yet and have not expired. Messages are sorted by creation time, oldest first. The
+    /// payload of the messages is only included if `include_payload` is set.
+    pub fn pending_messages(&self, include_payload: bool) -> Vec<PendingMessage> {
+        let outbox = self.outbox.lock().unwrap();
+        let mut pending: Vec<PendingMessage> = outbox
+            .msges
+            .iter()
+            .filter(|(_, mi)| {
+                matches!(
+                    mi.state,
+                    TransmissionState::Init | TransmissionState::InProgress
+                )
+            })
+            .map(|(id, mi)| PendingMessage {
+                id: *id,
+                dst: mi.msg.dst,
+                topic: mi.msg.topic.clone(),
+                attempts: mi.attempts,
+                created: mi
+                    .created
+                    .duration_since(time::UNIX_EPOCH)
+                    .expect("Message was created after the epoch")
+                    .as_secs() as i64,
+                deadline: mi
+                    .deadline
+                    .duration_since(time::UNIX_EPOCH)
+                    .expect("Message expires after the epoch")
+                    .as_secs() as i64,
+                payload_len: mi.len,
+                payload_hash: mi.checksum.to_hex().to_string(),
+                payload: include_payload.then(|| mi.msg.data.clone()),
+            })
+            .collect();
+        pending.sort_by(|a, b| a.created.cmp(&b.created).then(a.id.cmp(&b.id)));
+        pending
+    }
+
     /// A future which eventually resolves to a new (inbound message)[`ReceivedMessage`], if new messages come in.
     ///
     /// If pop is false, the message is not removed and the next call of this method will return
     /// the same message.
     pub async fn message(&self, pop: bool, topic: Option<Vec<u8>>) -> ReceivedMessage {
+        self.message_matching(pop, topic.map(TopicFilter::Exact))
+            .await
+    }
+
+    /// A future which eventually resolves to a new (inbound message)[`ReceivedMessage`] with a
+    /// topic matching the given [`TopicFilter`]. If no filter is given, any message is returned.
+    ///
+    /// If pop is false, and no filter is given, the message is not removed and the next call of
+    /// this method will return the same message.
+    pub async fn message_matching(
+        &self,
+        pop: bool,
+        filter: Option<TopicFilter>,
+    ) -> ReceivedMessage {
+        self.message_with_remaining(pop, filter, None).await.0
+    }
+
+    /// Like [`MessageStack::message_matching`], but also returns the amount of messages matching
+    /// the filter which are still waiting to be read after the returned one. This allows a reader
+    /// to decide whether to read again immediately, without an extra empty read.
+    ///
+    /// If a correlation id is given, only messages carrying exactly that correlation id are
+    /// returned. If both a topic filter and a correlation id are given, a message must match
+    /// both.
+    pub async fn message_with_remaining(
+        &self,
+        pop: bool,
+        filter: Option<TopicFilter>,
+        correlation_id: Option<Vec<u8>>,
+    ) -> (ReceivedMessage, usize) {
         // Copy the subscriber since we need mutable access to it.
         let mut subscriber = self.subscriber.clone();
+        // Keep track of us waiting until the future completes or is dropped. If we wait for
+        // multiple topics, we are registered as a consumer of every one of them.
+        let _consumers = match filter {
+            Some(TopicFilter::AnyOf(ref topics)) => topics
+                .iter()
+                .map(|topic| self.register_consumer(Some(TopicFilter::Exact(topic.clone()))))
+                .collect(),
+            ref filter => vec![self.register_consumer(filter.clone())],
+        };
 
         loop {
             // Scope to ensure we drop the lock after we checked for a message and don't hold
@@ -992,14 +1927,17 @@ impl MessageStack {
             'check: {
                 let mut inbox = self.inbox.lock().unwrap();
                 // If a filter is set only check for those messages.
-                if let Some(ref topic) = topic {
+                if filter.is_some() || correlation_id.is_some() {
+                    let filter = filter.as_ref();
+                    let correlation_id = correlation_id.as_deref();
                     if let Some((idx, _)) = inbox
                         .complete_msges
                         .iter()
                         .enumerate()
-                        .find(|(_, v)| &v.topic == topic)
+                        .find(|(_, v)| v.matches(filter, correlation_id))
                     {
-                        return inbox.complete_msges.remove(idx).unwrap();
+                        let msg = inbox.complete_msges.remove(idx).unwrap();
+                        return (msg, inbox.queued(filter, correlation_id));
                     } else {
                         break 'check;
                     }
@@ -1010,7 +1948,9 @@ impl MessageStack {
                     inbox.complete_msges.front().cloned()
                 } {
                     self.notify_read(&msg);
-                    return msg;
+                    // When peeking, the returned message is still queued.
+                    let remaining = inbox.complete_msges.len() - usize::from(!pop);
+                    return (msg, remaining);
                 };
             }
 
@@ -1020,16 +1960,241 @@ impl MessageStack {
         }
     }
 
-    /// Notify the sender of a message that it has been read.
-    fn notify_read(&self, msg: &ReceivedMessage) {
-        let mut mp = MessagePacket::new(PacketBuffer::new());
-        let mut header = mp.header_mut();
-        header.set_message_id(msg.id);
-        header.flags_mut().set_read();
+    /// A [`Stream`] of inbound messages on the given topic. Every message is removed once it is
+    /// yielded. While the stream is waiting for a message, it is registered as a consumer of the
+    /// topic, and dropping the stream removes that registration.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use mycelium::message::MessageStack;
+    ///
+    /// async fn print_two(stack: MessageStack) {
+    ///     let mut messages = stack.subscribe(b"chat".to_vec());
+    ///     for _ in 0..2 {
+    ///         let msg = messages.next().await.expect("Message stream never ends");
+    ///         println!("{} from {}", String::from_utf8_lossy(&msg.data), msg.src_ip);
+    ///     }
+    ///     // The subscription is cleaned up once `messages` is dropped here.
+    /// }
+    /// ```
+    pub fn subscribe(
+        &self,
+        topic: Vec<u8>,
+    ) -> impl Stream<Item = ReceivedMessage> + Send + Unpin + 'static {
+        futures::stream::unfold((self.clone(), topic), |(stack, topic)| async move {
+            let msg = stack.message(true, Some(topic.clone())).await;
+            Some((msg, (stack, topic)))
+        })
+        .boxed()
+    }
 
-        debug!("Notify sender we read message {}", msg.id.as_hex());
+    /// A future which eventually resolves to a batch of inbound messages on the given topic.
+    ///
+    /// If the topic is aggregated, the batch is returned once it is full, or once the oldest
+    /// message in it has waited for the configured delay. Otherwise, this returns a batch with the
+    /// first message on the topic.
+    ///
+    /// If pop is false, the messages are not removed and the next call of this method will return
+    /// the same messages.
+    pub async fn message_batch(&self, pop: bool, topic: Vec<u8>) -> Vec<ReceivedMessage> {
+        self.message_batch_with_remaining(pop, topic).await.0
+    }
 
-        match (msg.src_ip, msg.dst_ip) {
+    /// Like [`MessageStack::message_batch`], but also returns the amount of messages on the topic
+    /// which are still waiting to be read after the returned batch.
+    pub async fn message_batch_with_remaining(
+        &self,
+        pop: bool,
+        topic: Vec<u8>,
+    ) -> (Vec<ReceivedMessage>, usize) {
+        // Copy the subscriber since we need mutable access to it.
+        let mut subscriber = self.subscriber.clone();
+        // Keep track of us waiting until the future completes or is dropped.
+        let _consumer = self.register_consumer(Some(TopicFilter::Exact(topic.clone())));
+
+        loop {
+            let ready = {
+                let mut inbox = self.inbox.lock().unwrap();
+                inbox
+                    .ready_batch(&topic, pop, time::Instant::now())
+                    .map(|batch| {
+                        // When peeking, the returned messages are still queued.
+                        let queued = if inbox.aggregations.contains_key(&topic) {
+                            inbox.batches.get(&topic).map(VecDeque::len).unwrap_or(0)
+                        } else {
+                            inbox.queued(Some(&TopicFilter::Exact(topic.clone())), None)
+                        };
+                        let remaining = if pop {
+                            queued
+                        } else {
+                            queued.saturating_sub(batch.len())
+                        };
+                        (batch, remaining)
+                    })
+            };
+            match ready {
+                Ok((batch, remaining)) => {
+                    for msg in &batch {
+                        self.notify_read(msg);
+                    }
+                    return (batch, remaining);
+                }
+                Err(Some(deadline)) => {
+                    tokio::select! {
+                        _ = subscriber.changed() => {},
+                        _ = tokio::time::sleep_until(deadline.into()) => {},
+                    }
+                }
+                // Sender can never be dropped since we hold a reference to self which contains
+                // the inbox.
+                Err(None) => {
+                    let _ = subscriber.changed().await;
+                }
+            }
+        }
+    }
+
+    /// Aggregate inbound messages on the given topic into batches, or stop doing so if
+    /// `aggregation` is `None`. Aggregated messages are only returned by
+    /// [`MessageStack::message_batch`], they are not returned by [`MessageStack::message`].
+    ///
+    /// Messages on the topic which are already waiting to be read become part of the first batch
+    /// when aggregation is enabled. When it is disabled, messages in the pending batch become
+    /// available as individual messages again.
+    pub fn set_aggregation(&self, topic: Vec<u8>, aggregation: Option<Aggregation>) {
+        let mut inbox = self.inbox.lock().unwrap();
+        match aggregation {
+            Some(aggregation) => {
+                let now = time::Instant::now();
+                let (batched, rest): (VecDeque<_>, VecDeque<_>) =
+                    std::mem::take(&mut inbox.complete_msges)
+                        .into_iter()
+                        .partition(|m| m.topic == topic);
+                inbox.complete_msges = rest;
+                inbox
+                    .batches
+                    .entry(topic.clone())
+                    .or_default()
+                    .extend(batched.into_iter().map(|m| (m, now)));
+                inbox.aggregations.insert(topic, aggregation);
+            }
+            None => {
+                inbox.aggregations.remove(&topic);
+                if let Some(batch) = inbox.batches.remove(&topic) {
+                    inbox
+                        .complete_msges
+                        .extend(batch.into_iter().map(|(m, _)| m));
+                }
+            }
+        }
+        // Wake up waiting callers, so they can pick up the changes.
+        inbox.notify.send_replace(());
+    }
+
+    /// Get the aggregation settings of all aggregated topics.
+    pub fn aggregations(&self) -> Vec<(Vec<u8>, Aggregation)> {
+        self.inbox
+            .lock()
+            .unwrap()
+            .aggregations
+            .iter()
+            .map(|(topic, aggregation)| (topic.clone(), *aggregation))
+            .collect()
+    }
+
+    /// Get the aggregation settings of the given topic, if it is aggregated.
+    pub fn aggregation(&self, topic: &[u8]) -> Option<Aggregation> {
+        self.inbox.lock().unwrap().aggregations.get(topic).copied()
+    }
+
+    /// Put a message which was popped from the inbox, but could not be handed to the reader, back
+    /// in front of the inbox, so it is the first message read next.
+    pub fn requeue_message(&self, msg: ReceivedMessage) {
+        let mut inbox = self.inbox.lock().unwrap();
+        inbox.complete_msges.push_front(msg);
+        inbox.notify.send_replace(());
+    }
+
+    /// Get the distinct topics of recently received messages, with the amount of messages received
+    /// on each of them. The topics are sorted by the amount of messages, most used first.
+    pub fn seen_topics(&self) -> Vec<SeenTopic> {
+        let now = time::Instant::now();
+        let mut topics: Vec<SeenTopic> = self
+            .inbox
+            .lock()
+            .unwrap()
+            .seen_topics
+            .iter()
+            .map(|(topic, (messages, last_seen))| SeenTopic {
+                topic: topic.clone(),
+                messages: *messages,
+                last_seen: now.duration_since(*last_seen),
+            })
+            .collect();
+        topics.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.topic.cmp(&b.topic)));
+        topics
+    }
+
+    /// Get info about the callers currently waiting for an inbound message, grouped by the topic
+    /// filter they use. Callers waiting for a topic prefix are listed separately from callers
+    /// waiting for the exact same topic.
+    pub fn consumers(&self) -> Vec<ConsumerInfo> {
+        let now = time::Instant::now();
+        let mut consumers: Vec<(Option<&TopicFilter>, ConsumerInfo)> = Vec::new();
+        let registry = self.consumers.lock().unwrap();
+        for (filter, since) in registry.waiting.values() {
+            let wait = now.duration_since(*since);
+            match consumers.iter_mut().find(|(f, _)| *f == filter.as_ref()) {
+                Some((_, ci)) => {
+                    ci.waiting += 1;
+                    ci.oldest_wait = ci.oldest_wait.max(wait);
+                }
+                None => consumers.push((
+                    filter.as_ref(),
+                    ConsumerInfo {
+                        topic: match filter {
+                            Some(TopicFilter::Exact(topic)) => Some(topic.clone()),
+                            _ => None,
+                        },
+                        topic_prefix: match filter {
+                            Some(TopicFilter::Prefix(prefix)) => Some(prefix.clone()),
+                            _ => None,
+                        },
+                        waiting: 1,
+                        oldest_wait: wait,
+                    },
+                )),
+            }
+        }
+        consumers.into_iter().map(|(_, ci)| ci).collect()
+    }
+
+    /// Register a new caller waiting for a message with an optional topic filter, which is an
+    /// exact topic or a topic prefix. The caller is unregistered once the returned
+    /// [`ConsumerGuard`] is dropped.
+    fn register_consumer(&self, filter: Option<TopicFilter>) -> ConsumerGuard {
+        let mut registry = self.consumers.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry.waiting.insert(id, (filter, time::Instant::now()));
+        ConsumerGuard {
+            id,
+            registry: self.consumers.clone(),
+        }
+    }
+
+    /// Notify the sender of a message that it has been read.
+    fn notify_read(&self, msg: &ReceivedMessage) {
+        let mut mp = MessagePacket::new(PacketBuffer::new());
+        let mut header = mp.header_mut();
+        header.set_message_id(msg.id);
+        header.flags_mut().set_read();
+
+        debug!("Notify sender we read message {}", msg.id.as_hex());
+
+        match (msg.src_ip, msg.dst_ip) {
             (IpAddr::V6(src), IpAddr::V6(dst)) => {
                 self.data_plane
                     .lock()
@@ -1045,6 +2210,34 @@ impl MessageStack {
     }
 }
 
+impl ReplySubscription {
+    /// Wait for the reply to arrive.
+    ///
+    /// A subscription is only cleared once the reply is sent to it, or once nobody holds it any
+    /// longer. As such, if the subscription would somehow be closed without a reply, no reply can
+    /// arrive anymore, and this future never resolves. Callers should apply their own timeout.
+    pub async fn reply(&mut self) -> ReceivedMessage {
+        loop {
+            if let Some(msg) = self.rx.borrow_and_update().as_ref() {
+                return msg.clone();
+            }
+            if self.rx.changed().await.is_err() {
+                // Sender is gone, check if it managed to set a value before that.
+                if let Some(msg) = self.rx.borrow().as_ref() {
+                    return msg.clone();
+                }
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Drop for ConsumerGuard {
+    fn drop(&mut self) {
+        self.registry.lock().unwrap().waiting.remove(&self.id);
+    }
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageInfo {
@@ -1058,6 +2251,32 @@ pub struct MessageInfo {
     pub deadline: i64,
     /// Size of the message in bytes.
     pub msg_len: usize,
+    /// Hex encoded blake3 hash of the payload. This is kept after the payload itself is dropped.
+    pub payload_hash: String,
+    /// The schedule used to retransmit the message.
+    pub retry_schedule: RetrySchedule,
+}
+
+/// Info about an outbound message which is still being sent.
+pub struct PendingMessage {
+    /// Id of the message.
+    pub id: MessageId,
+    /// The receiver of the message.
+    pub dst: IpAddr,
+    /// The topic of the message, this is empty if the message has no topic.
+    pub topic: Vec<u8>,
+    /// Amount of times packets of the message have been sent, the first transmission included.
+    pub attempts: u64,
+    /// Time the message was created (received) by the system.
+    pub created: i64,
+    /// Time at which point we will give up sending the message.
+    pub deadline: i64,
+    /// Size of the payload in bytes.
+    pub payload_len: usize,
+    /// Hex encoded blake3 hash of the payload.
+    pub payload_hash: String,
+    /// The payload of the message, if it was requested.
+    pub payload: Option<Vec<u8>>,
 }
 
 #[derive(Serialize)]
@@ -1081,6 +2300,9 @@ pub enum TransmissionProgress {
     /// We aborted sending this message, the remote __might__ have a full message and process it,
     /// but that generally won't be the case.
     Aborted,
+    /// Sending this message was cancelled on request. Like an aborted message, the remote
+    /// __might__ have a full message, but that generally won't be the case.
+    Cancelled,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -1247,10 +2469,25 @@ impl<'a> Flags<'a> {
         self.flags & FLAG_MESSAGE_REPLY != 0
     }
 
+    /// Check if the MESSAGE_ORDERED flag is set on the header.
+    fn ordered(&self) -> bool {
+        self.flags & FLAG_MESSAGE_ORDERED != 0
+    }
+
     /// Check if the MESSAGE_ACK flag is set on the header.
     fn ack(&self) -> bool {
         self.flags & FLAG_MESSAGE_ACK != 0
     }
+
+    /// Check if the MESSAGE_CORRELATED flag is set on the header.
+    fn correlated(&self) -> bool {
+        self.flags & FLAG_MESSAGE_CORRELATED != 0
+    }
+
+    /// Check if the MESSAGE_TYPED flag is set on the header.
+    fn typed(&self) -> bool {
+        self.flags & FLAG_MESSAGE_TYPED != 0
+    }
 }
 
 impl fmt::Binary for Flags<'_> {
@@ -1306,10 +2543,25 @@ impl FlagsMut<'_, '_> {
         self.flags |= FLAG_MESSAGE_REPLY;
     }
 
+    /// Sets the MESSAGE_ORDERED flag on the header.
+    fn set_ordered(&mut self) {
+        self.flags |= FLAG_MESSAGE_ORDERED;
+    }
+
     /// Sets the MESSAGE_ACK flag on the header.
     fn set_ack(&mut self) {
         self.flags |= FLAG_MESSAGE_ACK;
     }
+
+    /// Sets the MESSAGE_CORRELATED flag on the header.
+    fn set_correlated(&mut self) {
+        self.flags |= FLAG_MESSAGE_CORRELATED;
+    }
+
+    /// Sets the MESSAGE_TYPED flag on the header.
+    fn set_typed(&mut self) {
+        self.flags |= FLAG_MESSAGE_TYPED;
+    }
 }
 
 // Header layout:
@@ -1414,6 +2666,10 @@ pub struct Message {
     dst: IpAddr,
     /// An optional topic of the message, usefull to differentiate messages before reading.
     topic: Vec<u8>,
+    /// An optional correlation id, which is passed to the receiver as is.
+    correlation_id: Vec<u8>,
+    /// An optional content type of the data, which is passed to the receiver as is.
+    content_type: String,
     /// Data of the message
     data: Vec<u8>,
 }
@@ -1427,15 +2683,37 @@ pub struct OutboundMessageInfo {
     deadline: time::SystemTime,
     /// Length of the message.
     len: usize,
-    /// The message to send.
+    /// Checksum of the payload of the message. This is kept after the payload itself is dropped.
+    checksum: MessageChecksum,
+    /// The message to send. The payload is dropped once the message is not being sent anymore.
     msg: Message,
+    /// Sequence number of the message if it is sent in ordered mode.
+    sequence: Option<u64>,
+    /// Amount of times packets of the message have been sent, the first transmission included.
+    attempts: u64,
+    /// Schedule of the retransmissions of the message.
+    retry_schedule: RetrySchedule,
     /// Chunks of the message.
     chunks: Vec<ChunkState>,
+    /// Notified when packets of the message which were not sent before are ready, so they are
+    /// sent without waiting for the retransmission timer.
+    send_now: Arc<Notify>,
 }
 
 /// A message checksum. In practice this is a 32 byte blake3 digest of the entire message.
 pub type MessageChecksum = blake3::Hash;
 
+impl OutboundMessageInfo {
+    /// Mark the transmission of the message as finished with the given state. The payload is only
+    /// needed to (re)transmit chunks, so it is dropped to free the memory. The length and checksum
+    /// of the payload are kept, so the status of the message can still report them.
+    fn finish(&mut self, state: TransmissionState) {
+        self.state = state;
+        self.msg.data = Vec::new();
+        self.chunks = Vec::new();
+    }
+}
+
 impl Message {
     /// Calculates the [`MessageChecksum`] of the message.
     ///
@@ -1448,7 +2726,18 @@ impl Message {
 impl fmt::Display for PushMessageError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::TopicTooLarge => f.write_str("topic too large, topic is limitted to 255 bytes"),
+            Self::TopicTooLarge => write!(
+                f,
+                "topic too large, topic is limitted to {MAX_TOPIC_SIZE} bytes"
+            ),
+            Self::CorrelationIdTooLarge => write!(
+                f,
+                "correlation id too large, correlation id is limitted to {MAX_CORRELATION_ID_SIZE} bytes"
+            ),
+            Self::ContentTypeTooLarge => write!(
+                f,
+                "content type too large, content type is limitted to {MAX_CONTENT_TYPE_SIZE} bytes"
+            ),
         }
     }
 }
@@ -1457,8 +2746,466 @@ impl std::error::Error for PushMessageError {}
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use futures::StreamExt;
+
+    use crate::{
+        crypto::{PublicKey, SecretKey},
+        testing,
+    };
+
+    use super::{
+        store::PersistedMessage, Aggregation, MessageId, MessagePacketHeaderMut, MessageStack,
+        MessageStore, ReceivedMessage, RetrySchedule, TopicFilter, TransmissionProgress,
+        MAX_SEEN_TOPICS, MESSAGE_HEADER_SIZE,
+    };
+
+    /// Create a new [`MessageStack`] which is not connected to any peer.
+    fn message_stack() -> MessageStack {
+        MessageStack::new(testing::data_plane(), tokio_stream::pending(), false)
+    }
+
+    /// Create a message as it would be received from a remote in reply to the message with the
+    /// given id.
+    fn reply_for(id: MessageId) -> ReceivedMessage {
+        let src_pk = PublicKey::from(&SecretKey::new());
+        let dst_pk = PublicKey::from(&SecretKey::new());
+        ReceivedMessage {
+            id,
+            is_reply: true,
+            src_ip: src_pk.address().into(),
+            src_pk,
+            dst_ip: dst_pk.address().into(),
+            dst_pk,
+            topic: vec![],
+            correlation_id: vec![],
+            content_type: String::new(),
+            data: b"pong".to_vec(),
+        }
+    }
+
+    /// Overlay IP of a random remote node.
+    fn remote_ip() -> IpAddr {
+        PublicKey::from(&SecretKey::new()).address().into()
+    }
+
+    /// Create a message as it would be received from a remote on the given topic.
+    fn message_on(topic: &[u8]) -> ReceivedMessage {
+        ReceivedMessage {
+            is_reply: false,
+            topic: topic.to_vec(),
+            ..reply_for(MessageId::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn await_reply_receives_reply() {
+        let ms = message_stack();
+
+        let (id, mut sub) = ms
+            .new_message_with_reply(
+                remote_ip(),
+                b"ping".to_vec(),
+                vec![],
+                vec![],
+                String::new(),
+                Duration::from_secs(5),
+                RetrySchedule::default(),
+            )
+            .expect("Can push message");
+
+        assert!(ms.notify_reply_subscriber(reply_for(id)).is_ok());
+
+        let reply = tokio::time::timeout(Duration::from_secs(1), sub.reply())
+            .await
+            .expect("Reply is delivered to the subscription");
+        assert!(reply.id == id);
+        assert_eq!(reply.data, b"pong");
+    }
 
-    use super::{MessagePacketHeaderMut, MESSAGE_HEADER_SIZE};
+    #[tokio::test]
+    async fn pending_messages_are_listed() {
+        let ms = message_stack();
+        let dst = remote_ip();
+
+        let id = ms
+            .new_message(
+                dst,
+                b"ping".to_vec(),
+                b"outbox".to_vec(),
+                vec![],
+                String::new(),
+                Duration::from_secs(5),
+                RetrySchedule::default(),
+            )
+            .expect("Can push message");
+
+        let pending = ms.pending_messages(false);
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].id == id);
+        assert_eq!(pending[0].dst, dst);
+        assert_eq!(pending[0].topic, b"outbox");
+        assert_eq!(pending[0].attempts, 1);
+        assert!(pending[0].deadline > pending[0].created);
+        assert_eq!(pending[0].payload_len, 4);
+        assert_eq!(
+            pending[0].payload_hash,
+            blake3::hash(b"ping").to_hex().to_string()
+        );
+        assert!(pending[0].payload.is_none());
+
+        let pending = ms.pending_messages(true);
+        assert_eq!(pending[0].payload.as_deref(), Some(&b"ping"[..]));
+
+        // Aborted messages are no longer being sent.
+        ms.outbox.lock().unwrap().msges.get_mut(&id).unwrap().state =
+            super::TransmissionState::Aborted;
+        assert!(ms.pending_messages(false).is_empty());
+    }
+
+    #[tokio::test]
+    async fn pending_message_can_be_cancelled() {
+        let ms = message_stack();
+
+        let id = ms
+            .new_message(
+                remote_ip(),
+                b"ping".to_vec(),
+                vec![],
+                vec![],
+                String::new(),
+                Duration::from_secs(5),
+                RetrySchedule::default(),
+            )
+            .expect("Can push message");
+
+        assert!(ms.cancel_message(id).is_ok());
+        assert!(ms.pending_messages(false).is_empty());
+        assert!(matches!(
+            ms.message_info(id)
+                .expect("Cancelled message is still known")
+                .state,
+            TransmissionProgress::Cancelled
+        ));
+
+        // The payload is dropped, but its size and hash are still known.
+        assert!(ms.outbox.lock().unwrap().msges[&id].msg.data.is_empty());
+        let info = ms
+            .message_info(id)
+            .expect("Cancelled message is still known");
+        assert_eq!(info.msg_len, 4);
+        assert_eq!(
+            info.payload_hash,
+            blake3::hash(b"ping").to_hex().to_string()
+        );
+
+        // A cancelled message can't be cancelled again, and unknown messages can't be cancelled.
+        assert!(ms.cancel_message(id).is_err());
+        assert!(ms.cancel_message(MessageId::new()).is_err());
+    }
+
+    #[test]
+    fn retry_interval_grows_up_to_max() {
+        let schedule = RetrySchedule {
+            initial_interval: Duration::from_millis(100),
+            multiplier: 2.,
+            max_interval: Duration::from_secs(1),
+        };
+
+        let intervals = (0..6).map(|i| schedule.interval(i)).collect::<Vec<_>>();
+        assert_eq!(
+            intervals,
+            [100, 200, 400, 800, 1_000, 1_000].map(Duration::from_millis)
+        );
+        // Large amounts of retransmissions don't overflow.
+        assert_eq!(schedule.interval(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn default_retry_schedule_is_constant() {
+        let schedule = RetrySchedule::default();
+
+        assert!(schedule.validate(Duration::from_secs(1)).is_ok());
+        for i in 0..5 {
+            assert_eq!(schedule.interval(i), Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn invalid_retry_schedules_are_rejected() {
+        let try_duration = Duration::from_secs(60);
+        let too_short = RetrySchedule {
+            initial_interval: Duration::from_millis(99),
+            ..RetrySchedule::default()
+        };
+        let too_long = RetrySchedule {
+            max_interval: try_duration + Duration::from_secs(1),
+            ..RetrySchedule::default()
+        };
+        let shrinking = RetrySchedule {
+            multiplier: 0.5,
+            ..RetrySchedule::default()
+        };
+        let infinite = RetrySchedule {
+            multiplier: f64::INFINITY,
+            ..RetrySchedule::default()
+        };
+
+        assert!(too_short.validate(try_duration).is_err());
+        assert!(too_long.validate(try_duration).is_err());
+        assert!(shrinking.validate(try_duration).is_err());
+        assert!(infinite.validate(try_duration).is_err());
+    }
+
+    #[test]
+    fn retry_schedule_uses_defaults_for_missing_fields() {
+        let schedule: RetrySchedule =
+            serde_json::from_str(r#"{"initialIntervalMs":250,"multiplier":1.5}"#)
+                .expect("Can parse retry schedule");
+
+        assert_eq!(schedule.initial_interval, Duration::from_millis(250));
+        assert_eq!(schedule.multiplier, 1.5);
+        assert_eq!(schedule.max_interval, RetrySchedule::default().max_interval);
+    }
+
+    #[tokio::test]
+    async fn message_info_reports_retry_schedule() {
+        let ms = message_stack();
+        let schedule = RetrySchedule {
+            initial_interval: Duration::from_millis(500),
+            multiplier: 2.,
+            max_interval: Duration::from_secs(8),
+        };
+
+        let id = ms
+            .new_message(
+                remote_ip(),
+                b"ping".to_vec(),
+                vec![],
+                vec![],
+                String::new(),
+                Duration::from_secs(5),
+                schedule,
+            )
+            .expect("Can push message");
+
+        assert_eq!(
+            ms.message_info(id)
+                .expect("Message is known")
+                .retry_schedule,
+            schedule
+        );
+    }
+
+    #[tokio::test]
+    async fn no_reply_does_not_subscribe() {
+        let ms = message_stack();
+
+        let id = ms
+            .new_message(
+                remote_ip(),
+                b"ping".to_vec(),
+                vec![],
+                vec![],
+                String::new(),
+                Duration::from_secs(5),
+                RetrySchedule::default(),
+            )
+            .expect("Can push message");
+
+        assert!(!ms.reply_subscribers.lock().unwrap().contains_key(&id));
+        // Without a subscriber, the reply is handed back so it can go to the inbox.
+        assert!(ms.notify_reply_subscriber(reply_for(id)).is_err());
+    }
+
+    #[tokio::test]
+    async fn await_reply_topic_too_large_clears_subscription() {
+        let ms = message_stack();
+
+        assert!(ms
+            .new_message_with_reply(
+                remote_ip(),
+                b"ping".to_vec(),
+                vec![0; 256],
+                vec![],
+                String::new(),
+                Duration::from_secs(5),
+                RetrySchedule::default(),
+            )
+            .is_err());
+        assert!(ms.reply_subscribers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_packets_are_sent_without_waiting_for_retransmission() {
+        let (ms, own_ip) = testing::loopback_message_stack();
+        // The first retransmission is only after a minute, so the message is only delivered in
+        // time if the chunks and the done packet are sent as soon as the previous step is
+        // acknowledged.
+        let slow = RetrySchedule {
+            initial_interval: Duration::from_secs(60),
+            multiplier: 1.,
+            max_interval: Duration::from_secs(60),
+        };
+
+        ms.new_message(
+            own_ip,
+            vec![7; 10_000],
+            b"slow".to_vec(),
+            vec![],
+            String::new(),
+            Duration::from_secs(120),
+            slow,
+        )
+        .expect("Can push message");
+
+        let msg = tokio::time::timeout(
+            Duration::from_secs(5),
+            ms.message(true, Some(b"slow".to_vec())),
+        )
+        .await
+        .expect("Message is delivered before the first retransmission");
+        assert_eq!(msg.data, vec![7; 10_000]);
+    }
+
+    #[tokio::test]
+    async fn empty_payload_is_delivered() {
+        let (ms, own_ip) = testing::loopback_message_stack();
+
+        ms.new_message(
+            own_ip,
+            vec![],
+            b"signal".to_vec(),
+            vec![],
+            String::new(),
+            Duration::from_secs(5),
+            RetrySchedule::default(),
+        )
+        .expect("Can push message without payload");
+
+        let msg = tokio::time::timeout(
+            Duration::from_secs(5),
+            ms.message(true, Some(b"signal".to_vec())),
+        )
+        .await
+        .expect("Message without payload is delivered");
+        assert_eq!(msg.topic, b"signal");
+        assert!(msg.data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn deterministic_message_ids() {
+        let ms = MessageStack::new_deterministic(
+            testing::data_plane(),
+            tokio_stream::pending(),
+            false,
+            1,
+        );
+
+        let first = ms
+            .new_message(
+                remote_ip(),
+                b"ping".to_vec(),
+                vec![],
+                vec![],
+                String::new(),
+                Duration::from_secs(5),
+                RetrySchedule::default(),
+            )
+            .expect("Can push message");
+        let (second, _) = ms
+            .new_message_with_reply(
+                remote_ip(),
+                b"ping".to_vec(),
+                vec![],
+                vec![],
+                String::new(),
+                Duration::from_secs(5),
+                RetrySchedule::default(),
+            )
+            .expect("Can push message");
+
+        assert_eq!(first.as_hex(), "0000000000000001");
+        assert_eq!(second.as_hex(), "0000000000000002");
+    }
+
+    #[tokio::test]
+    async fn waiting_consumers_are_tracked() {
+        let ms = message_stack();
+
+        let consumer = tokio::spawn({
+            let ms = ms.clone();
+            async move { ms.message(true, Some(b"topic".to_vec())).await }
+        });
+        // Let the consumer start waiting.
+        tokio::task::yield_now().await;
+
+        let consumers = ms.consumers();
+        assert_eq!(consumers.len(), 1);
+        assert_eq!(consumers[0].topic, Some(b"topic".to_vec()));
+        assert_eq!(consumers[0].topic_prefix, None);
+        assert_eq!(consumers[0].waiting, 1);
+
+        consumer.abort();
+        assert!(consumer.await.is_err());
+        assert!(ms.consumers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn prefix_consumers_are_not_listed_as_topic() {
+        let ms = message_stack();
+
+        let consumer = tokio::spawn({
+            let ms = ms.clone();
+            async move {
+                ms.message_matching(true, Some(TopicFilter::Prefix(b"app/".to_vec())))
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+
+        let consumers = ms.consumers();
+        assert_eq!(consumers.len(), 1);
+        assert_eq!(consumers[0].topic, None);
+        assert_eq!(consumers[0].topic_prefix, Some(b"app/".to_vec()));
+        assert_eq!(consumers[0].waiting, 1);
+
+        consumer.abort();
+        assert!(consumer.await.is_err());
+        assert!(ms.consumers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscription_yields_messages_and_cleans_up() {
+        let ms = message_stack();
+        ms.inbox.lock().unwrap().deliver([
+            message_on(b"telemetry"),
+            message_on(b"other"),
+            message_on(b"telemetry"),
+        ]);
+
+        let mut messages = ms.subscribe(b"telemetry".to_vec());
+        for _ in 0..2 {
+            let msg = messages.next().await.expect("Stream yields messages");
+            assert_eq!(msg.topic, b"telemetry");
+        }
+
+        // No message left, so the stream is now waiting as a consumer.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), messages.next())
+                .await
+                .is_err()
+        );
+        assert_eq!(ms.consumers().len(), 1);
+
+        drop(messages);
+        assert!(ms.consumers().is_empty());
+        assert_eq!(ms.inbox.lock().unwrap().complete_msges.len(), 1);
+    }
 
     #[test]
     fn set_init_flag() {
@@ -1520,6 +3267,16 @@ mod tests {
         assert_eq!(buf_mut.header[8], 0b0000_0100);
     }
 
+    #[test]
+    fn set_ordered_flag() {
+        let mut buf = [0; MESSAGE_HEADER_SIZE];
+        let mut buf_mut = MessagePacketHeaderMut { header: &mut buf };
+        buf_mut.flags_mut().set_ordered();
+
+        assert!(buf_mut.flags().ordered());
+        assert_eq!(buf_mut.header[8], 0b0000_0010);
+    }
+
     #[test]
     fn set_ack_flag() {
         let mut buf = [0; MESSAGE_HEADER_SIZE];
@@ -1530,6 +3287,26 @@ mod tests {
         assert_eq!(buf_mut.header[8], 0b0000_0001);
     }
 
+    #[test]
+    fn set_correlated_flag() {
+        let mut buf = [0; MESSAGE_HEADER_SIZE];
+        let mut buf_mut = MessagePacketHeaderMut { header: &mut buf };
+        buf_mut.flags_mut().set_correlated();
+
+        assert!(buf_mut.flags().correlated());
+        assert_eq!(buf_mut.header[9], 0b1000_0000);
+    }
+
+    #[test]
+    fn set_typed_flag() {
+        let mut buf = [0; MESSAGE_HEADER_SIZE];
+        let mut buf_mut = MessagePacketHeaderMut { header: &mut buf };
+        buf_mut.flags_mut().set_typed();
+
+        assert!(buf_mut.flags().typed());
+        assert_eq!(buf_mut.header[9], 0b0100_0000);
+    }
+
     #[test]
     fn set_mutli_flag() {
         let mut buf = [0; MESSAGE_HEADER_SIZE];
@@ -1540,4 +3317,357 @@ mod tests {
         assert!(buf_mut.flags().ack() && buf_mut.flags().init());
         assert_eq!(buf_mut.header[8], 0b1000_0001);
     }
+
+    #[tokio::test]
+    async fn aggregated_topic_is_delivered_in_batches() {
+        let ms = message_stack();
+        ms.set_aggregation(
+            b"telemetry".to_vec(),
+            Some(Aggregation {
+                max_messages: 3,
+                max_delay: Duration::from_secs(60),
+            }),
+        );
+
+        ms.inbox.lock().unwrap().deliver([
+            message_on(b"telemetry"),
+            message_on(b"other"),
+            message_on(b"telemetry"),
+            message_on(b"telemetry"),
+            message_on(b"telemetry"),
+        ]);
+
+        let batch = tokio::time::timeout(
+            Duration::from_secs(1),
+            ms.message_batch(true, b"telemetry".to_vec()),
+        )
+        .await
+        .expect("Full batch is delivered immediately");
+        assert_eq!(batch.len(), 3);
+
+        // Only one message left, which waits for the batch to fill up.
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            ms.message_batch(true, b"telemetry".to_vec())
+        )
+        .await
+        .is_err());
+
+        // Other topics are not affected.
+        let msg = tokio::time::timeout(
+            Duration::from_secs(1),
+            ms.message(true, Some(b"other".to_vec())),
+        )
+        .await
+        .expect("Message on other topic is delivered");
+        assert_eq!(msg.topic, b"other");
+    }
+
+    #[tokio::test]
+    async fn aggregated_batch_is_delivered_after_delay() {
+        let ms = message_stack();
+        ms.set_aggregation(
+            b"telemetry".to_vec(),
+            Some(Aggregation {
+                max_messages: 10,
+                max_delay: Duration::from_millis(50),
+            }),
+        );
+
+        ms.inbox
+            .lock()
+            .unwrap()
+            .deliver([message_on(b"telemetry"), message_on(b"telemetry")]);
+
+        let batch = tokio::time::timeout(
+            Duration::from_secs(1),
+            ms.message_batch(true, b"telemetry".to_vec()),
+        )
+        .await
+        .expect("Batch is delivered once the delay expires");
+        assert_eq!(batch.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn disabling_aggregation_releases_batch() {
+        let ms = message_stack();
+        ms.set_aggregation(
+            b"telemetry".to_vec(),
+            Some(Aggregation {
+                max_messages: 10,
+                max_delay: Duration::from_secs(60),
+            }),
+        );
+        ms.inbox.lock().unwrap().deliver([message_on(b"telemetry")]);
+
+        ms.set_aggregation(b"telemetry".to_vec(), None);
+
+        let msg = tokio::time::timeout(Duration::from_secs(1), ms.message(true, None))
+            .await
+            .expect("Message is released when aggregation is disabled");
+        assert_eq!(msg.topic, b"telemetry");
+    }
+
+    #[tokio::test]
+    async fn remaining_messages_are_reported() {
+        let ms = message_stack();
+        ms.inbox.lock().unwrap().deliver([
+            message_on(b"telemetry"),
+            message_on(b"other"),
+            message_on(b"telemetry"),
+        ]);
+
+        let (msg, remaining) = ms.message_with_remaining(false, None, None).await;
+        assert_eq!(msg.topic, b"telemetry");
+        assert_eq!(remaining, 2);
+
+        let (msg, remaining) = ms
+            .message_with_remaining(true, Some(TopicFilter::Exact(b"telemetry".to_vec())), None)
+            .await;
+        assert_eq!(msg.topic, b"telemetry");
+        assert_eq!(remaining, 1);
+
+        let (_, remaining) = ms.message_with_remaining(true, None, None).await;
+        assert_eq!(remaining, 1);
+        let (_, remaining) = ms.message_with_remaining(true, None, None).await;
+        assert_eq!(remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn requeued_message_is_read_first() {
+        let ms = message_stack();
+        ms.inbox
+            .lock()
+            .unwrap()
+            .deliver([message_on(b"first"), message_on(b"second")]);
+
+        let msg = ms.message_matching(true, None).await;
+        assert_eq!(msg.topic, b"first");
+        ms.requeue_message(msg);
+
+        let (msg, remaining) = ms.message_with_remaining(true, None, None).await;
+        assert_eq!(msg.topic, b"first");
+        assert_eq!(remaining, 1);
+        assert_eq!(ms.message_matching(true, None).await.topic, b"second");
+        // Requeueing does not count the message as seen again.
+        assert_eq!(ms.seen_topics()[0].messages, 1);
+    }
+
+    #[tokio::test]
+    async fn seen_topics_are_counted_and_bounded() {
+        let ms = message_stack();
+        ms.inbox.lock().unwrap().deliver([
+            message_on(b"telemetry"),
+            message_on(b"other"),
+            message_on(b"telemetry"),
+        ]);
+
+        let topics = ms.seen_topics();
+        assert_eq!(topics.len(), 2);
+        assert_eq!(topics[0].topic, b"telemetry");
+        assert_eq!(topics[0].messages, 2);
+        assert_eq!(topics[1].topic, b"other");
+        assert_eq!(topics[1].messages, 1);
+
+        let mut inbox = ms.inbox.lock().unwrap();
+        for i in 0..MAX_SEEN_TOPICS {
+            inbox.track_topic(format!("topic-{i}").as_bytes(), Instant::now());
+        }
+        assert_eq!(inbox.seen_topics.len(), MAX_SEEN_TOPICS);
+        // The first topics have been seen the longest time ago, so they are forgotten first.
+        assert!(!inbox.seen_topics.contains_key(b"telemetry".as_slice()));
+        assert!(!inbox.seen_topics.contains_key(b"other".as_slice()));
+        assert!(inbox.seen_topics.contains_key(b"topic-0".as_slice()));
+    }
+
+    #[test]
+    fn topic_filter_matching() {
+        let exact = TopicFilter::Exact(b"app/sub".to_vec());
+        assert!(exact.matches(b"app/sub"));
+        assert!(!exact.matches(b"app/sub/leaf"));
+        assert!(!exact.matches(b"app"));
+
+        let prefix = TopicFilter::Prefix(b"app/".to_vec());
+        assert!(prefix.matches(b"app/"));
+        assert!(prefix.matches(b"app/sub"));
+        assert!(prefix.matches(b"app/sub/leaf"));
+        assert!(!prefix.matches(b"app"));
+        assert!(!prefix.matches(b"other/app/sub"));
+        assert!(!prefix.matches(b""));
+
+        let any_of = TopicFilter::AnyOf(vec![b"app/a".to_vec(), b"app/b".to_vec()]);
+        assert!(any_of.matches(b"app/a"));
+        assert!(any_of.matches(b"app/b"));
+        assert!(!any_of.matches(b"app/"));
+        assert!(!any_of.matches(b"app/b/c"));
+        assert!(!TopicFilter::AnyOf(vec![]).matches(b""));
+    }
+
+    #[tokio::test]
+    async fn message_with_any_of_topics() {
+        let ms = message_stack();
+        ms.inbox
+            .lock()
+            .unwrap()
+            .deliver([message_on(b"other"), message_on(b"second")]);
+        let filter = TopicFilter::AnyOf(vec![b"first".to_vec(), b"second".to_vec()]);
+
+        let msg = tokio::time::timeout(
+            Duration::from_secs(1),
+            ms.message_matching(true, Some(filter.clone())),
+        )
+        .await
+        .expect("Message on the second topic is returned");
+        assert_eq!(msg.topic, b"second");
+
+        // The only message left is on neither topic.
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            ms.message_matching(true, Some(filter)),
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn message_with_correlation_id() {
+        let ms = message_stack();
+        ms.inbox.lock().unwrap().deliver([
+            ReceivedMessage {
+                correlation_id: b"other".to_vec(),
+                ..message_on(b"rpc")
+            },
+            ReceivedMessage {
+                correlation_id: b"request-1".to_vec(),
+                ..message_on(b"events")
+            },
+            ReceivedMessage {
+                correlation_id: b"request-1".to_vec(),
+                ..message_on(b"rpc")
+            },
+        ]);
+
+        // Topic and correlation id must both match.
+        let (msg, remaining) = tokio::time::timeout(
+            Duration::from_secs(1),
+            ms.message_with_remaining(
+                true,
+                Some(TopicFilter::Exact(b"rpc".to_vec())),
+                Some(b"request-1".to_vec()),
+            ),
+        )
+        .await
+        .expect("Message with matching correlation id is returned");
+        assert_eq!(msg.topic, b"rpc");
+        assert_eq!(msg.correlation_id, b"request-1");
+        assert_eq!(remaining, 0);
+
+        // Without a topic filter any topic matches.
+        let (msg, _) = tokio::time::timeout(
+            Duration::from_secs(1),
+            ms.message_with_remaining(true, None, Some(b"request-1".to_vec())),
+        )
+        .await
+        .expect("Message with matching correlation id is returned");
+        assert_eq!(msg.topic, b"events");
+
+        // The only message left has a different correlation id.
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            ms.message_with_remaining(true, None, Some(b"request-1".to_vec())),
+        )
+        .await
+        .is_err());
+        assert_eq!(ms.inbox.lock().unwrap().complete_msges.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn message_with_topic_prefix() {
+        let ms = message_stack();
+        ms.inbox
+            .lock()
+            .unwrap()
+            .deliver([message_on(b"other/sub"), message_on(b"app/sub/leaf")]);
+
+        let msg = tokio::time::timeout(
+            Duration::from_secs(1),
+            ms.message_matching(true, Some(TopicFilter::Prefix(b"app/".to_vec()))),
+        )
+        .await
+        .expect("Message with matching topic prefix is returned");
+        assert_eq!(msg.topic, b"app/sub/leaf");
+
+        // The only message left does not match the prefix.
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            ms.message_matching(true, Some(TopicFilter::Prefix(b"app/".to_vec()))),
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn persisted_messages_are_replayed_after_restart() {
+        let dir = std::env::temp_dir().join(format!(
+            "mycelium-message-replay-{}",
+            MessageId::new().as_hex()
+        ));
+        let key = SecretKey::new();
+
+        let ms = MessageStack::new_persistent(
+            testing::data_plane(),
+            tokio_stream::pending(),
+            false,
+            MessageStore::open(&dir, &key).expect("Can open message store"),
+        );
+        let id = ms
+            .new_message(
+                remote_ip(),
+                b"ping".to_vec(),
+                b"topic".to_vec(),
+                vec![],
+                String::new(),
+                Duration::from_secs(60),
+                RetrySchedule::default(),
+            )
+            .expect("Can push message");
+        // Expired while the node was not running.
+        let expired = PersistedMessage {
+            id: MessageId::new(),
+            reply: false,
+            dst: remote_ip(),
+            topic: vec![],
+            correlation_id: vec![],
+            content_type: String::new(),
+            payload: b"ping".to_vec(),
+            created_at: 1,
+            try_duration_secs: 60,
+        };
+        MessageStore::open(&dir, &key)
+            .expect("Can open message store")
+            .save(&expired)
+            .expect("Can persist message");
+        drop(ms);
+
+        // "Restart" the node with the same store.
+        let ms = MessageStack::new_persistent(
+            testing::data_plane(),
+            tokio_stream::pending(),
+            false,
+            MessageStore::open(&dir, &key).expect("Can open message store"),
+        );
+
+        assert!(ms.message_info(id).is_some());
+        assert!(ms.message_info(expired.id).is_none());
+        let persisted = MessageStore::open(&dir, &key)
+            .expect("Can open message store")
+            .load()
+            .expect("Can load persisted messages");
+        assert_eq!(persisted.len(), 1);
+        assert!(persisted[0].id == id);
+        assert_eq!(persisted[0].topic, b"topic");
+        assert_eq!(persisted[0].payload, b"ping");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -4,6 +4,13 @@ use super::MessagePacket;
 ///
 /// The body of an init message has the following structure:
 ///   - 8 bytes size
+///   - 1 byte topic length
+///   - topic
+///   - 8 bytes sequence number, only present if the MESSAGE_ORDERED flag is set
+///   - 1 byte correlation id length, only present if the MESSAGE_CORRELATED flag is set
+///   - correlation id, only present if the MESSAGE_CORRELATED flag is set
+///   - 1 byte content type length, only present if the MESSAGE_TYPED flag is set
+///   - content type, only present if the MESSAGE_TYPED flag is set
 pub struct MessageInit {
     buffer: MessagePacket,
 }
@@ -31,6 +38,42 @@ impl MessageInit {
         &self.buffer.buffer()[9..9 + topic_len]
     }
 
+    /// Return the sequence number of the message, as written in the body. This is only present
+    /// for ordered messages.
+    pub fn sequence(&self) -> Option<u64> {
+        if !self.buffer.header().flags().ordered() {
+            return None;
+        }
+        let offset = 9 + self.buffer.buffer()[8] as usize;
+        Some(u64::from_be_bytes(
+            self.buffer.buffer()[offset..offset + 8]
+                .try_into()
+                .expect("Buffer contains a sequence field of valid length; qed"),
+        ))
+    }
+
+    /// Return the correlation id of the message, as written in the body. This is empty if the
+    /// sender did not set a correlation id.
+    pub fn correlation_id(&self) -> &[u8] {
+        if !self.buffer.header().flags().correlated() {
+            return &[];
+        }
+        let offset = self.correlation_id_offset();
+        let correlation_id_len = self.buffer.buffer()[offset] as usize;
+        &self.buffer.buffer()[offset + 1..offset + 1 + correlation_id_len]
+    }
+
+    /// Return the content type of the message, as written in the body. This is empty if the
+    /// sender did not set a content type.
+    pub fn content_type(&self) -> &[u8] {
+        if !self.buffer.header().flags().typed() {
+            return &[];
+        }
+        let offset = self.content_type_offset();
+        let content_type_len = self.buffer.buffer()[offset] as usize;
+        &self.buffer.buffer()[offset + 1..offset + 1 + content_type_len]
+    }
+
     /// Set the length field of the message body.
     pub fn set_length(&mut self, length: u64) {
         self.buffer.buffer_mut()[..8].copy_from_slice(&length.to_be_bytes())
@@ -51,6 +94,77 @@ impl MessageInit {
         self.buffer.buffer_mut()[9..9 + topic.len()].copy_from_slice(topic);
     }
 
+    /// Set the sequence number in the message body, and mark the message as ordered. This must
+    /// be called after the topic is set, as the sequence number is written after the topic.
+    pub fn set_sequence(&mut self, sequence: u64) {
+        let offset = 9 + self.buffer.buffer()[8] as usize;
+        self.buffer.set_used_buffer_size(offset + 8);
+        self.buffer.buffer_mut()[offset..offset + 8].copy_from_slice(&sequence.to_be_bytes());
+        self.buffer.header_mut().flags_mut().set_ordered();
+    }
+
+    /// Set the correlation id in the message body. This must be called after the topic and
+    /// sequence number are set, as the correlation id is written after them.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the correlation id is longer than 255 bytes.
+    pub fn set_correlation_id(&mut self, correlation_id: &[u8]) {
+        assert!(
+            correlation_id.len() <= u8::MAX as usize,
+            "Correlation id can be 255 bytes long at most"
+        );
+        let offset = self.correlation_id_offset();
+        self.buffer
+            .set_used_buffer_size(offset + 1 + correlation_id.len());
+        self.buffer.buffer_mut()[offset] = correlation_id.len() as u8;
+        self.buffer.buffer_mut()[offset + 1..offset + 1 + correlation_id.len()]
+            .copy_from_slice(correlation_id);
+        self.buffer.header_mut().flags_mut().set_correlated();
+    }
+
+    /// Set the content type in the message body. This must be called after the topic, sequence
+    /// number and correlation id are set, as the content type is written after them.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the content type is longer than 255 bytes.
+    pub fn set_content_type(&mut self, content_type: &[u8]) {
+        assert!(
+            content_type.len() <= u8::MAX as usize,
+            "Content type can be 255 bytes long at most"
+        );
+        let offset = self.content_type_offset();
+        self.buffer
+            .set_used_buffer_size(offset + 1 + content_type.len());
+        self.buffer.buffer_mut()[offset] = content_type.len() as u8;
+        self.buffer.buffer_mut()[offset + 1..offset + 1 + content_type.len()]
+            .copy_from_slice(content_type);
+        self.buffer.header_mut().flags_mut().set_typed();
+    }
+
+    /// Offset in the body where the content type starts, i.e. the end of the optional correlation
+    /// id.
+    fn content_type_offset(&self) -> usize {
+        let offset = self.correlation_id_offset();
+        if self.buffer.header().flags().correlated() {
+            offset + 1 + self.buffer.buffer()[offset] as usize
+        } else {
+            offset
+        }
+    }
+
+    /// Offset in the body where the correlation id starts, i.e. the end of the topic and optional
+    /// sequence number.
+    fn correlation_id_offset(&self) -> usize {
+        let offset = 9 + self.buffer.buffer()[8] as usize;
+        if self.buffer.header().flags().ordered() {
+            offset + 8
+        } else {
+            offset
+        }
+    }
+
     /// Convert the `MessageInit` into a reply. This does nothing if it is already a reply.
     pub fn into_reply(mut self) -> Self {
         self.buffer.header_mut().flags_mut().set_ack();
@@ -98,4 +212,88 @@ mod tests {
         assert_eq!(&ms.buffer.buffer()[..8], &[0, 0, 0, 0, 204, 153, 217, 8]);
         assert_eq!(ms.length(), 3_432_634_632);
     }
+
+    #[test]
+    fn no_sequence_if_not_ordered() {
+        let mut ms = MessageInit::new(MessagePacket::new(PacketBuffer::new()));
+
+        ms.set_topic(b"topic");
+
+        assert_eq!(ms.sequence(), None);
+    }
+
+    #[test]
+    fn write_sequence_after_topic() {
+        let mut ms = MessageInit::new(MessagePacket::new(PacketBuffer::new()));
+
+        ms.set_topic(b"topic");
+        ms.set_sequence(258);
+
+        assert!(ms.buffer.header().flags().ordered());
+        assert_eq!(ms.topic(), b"topic");
+        assert_eq!(&ms.buffer.buffer()[14..22], &[0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(ms.sequence(), Some(258));
+    }
+
+    #[test]
+    fn no_correlation_id_if_not_set() {
+        let mut ms = MessageInit::new(MessagePacket::new(PacketBuffer::new()));
+
+        ms.set_topic(b"topic");
+
+        assert_eq!(ms.correlation_id(), b"");
+    }
+
+    #[test]
+    fn write_correlation_id_after_topic() {
+        let mut ms = MessageInit::new(MessagePacket::new(PacketBuffer::new()));
+
+        ms.set_topic(b"topic");
+        ms.set_correlation_id(b"rpc-1");
+
+        assert!(ms.buffer.header().flags().correlated());
+        assert_eq!(ms.topic(), b"topic");
+        assert_eq!(&ms.buffer.buffer()[14..20], b"\x05rpc-1");
+        assert_eq!(ms.correlation_id(), b"rpc-1");
+    }
+
+    #[test]
+    fn write_correlation_id_after_sequence() {
+        let mut ms = MessageInit::new(MessagePacket::new(PacketBuffer::new()));
+
+        ms.set_topic(b"topic");
+        ms.set_sequence(258);
+        ms.set_correlation_id(b"rpc-1");
+
+        assert_eq!(ms.sequence(), Some(258));
+        assert_eq!(&ms.buffer.buffer()[22..28], b"\x05rpc-1");
+        assert_eq!(ms.correlation_id(), b"rpc-1");
+    }
+
+    #[test]
+    fn write_content_type_after_topic() {
+        let mut ms = MessageInit::new(MessagePacket::new(PacketBuffer::new()));
+
+        ms.set_topic(b"topic");
+        ms.set_content_type(b"text/plain");
+
+        assert!(ms.buffer.header().flags().typed());
+        assert_eq!(ms.correlation_id(), b"");
+        assert_eq!(&ms.buffer.buffer()[14..25], b"\x0atext/plain");
+        assert_eq!(ms.content_type(), b"text/plain");
+    }
+
+    #[test]
+    fn write_content_type_after_correlation_id() {
+        let mut ms = MessageInit::new(MessagePacket::new(PacketBuffer::new()));
+
+        ms.set_topic(b"topic");
+        ms.set_sequence(258);
+        ms.set_correlation_id(b"rpc-1");
+        ms.set_content_type(b"application/json");
+
+        assert_eq!(ms.sequence(), Some(258));
+        assert_eq!(ms.correlation_id(), b"rpc-1");
+        assert_eq!(ms.content_type(), b"application/json");
+    }
 }
@@ -0,0 +1,263 @@
+//! Reordering of inbound messages which are part of an ordered stream.
+//!
+//! A sender in ordered mode tags every message with a sequence number, which is tracked
+//! separately for every receiver and topic. The first message of a stream always has sequence
+//! number 0. On the receiving side, messages are released per (sender, topic) in the order of
+//! these sequence numbers. If a message is missing, later messages are held back until it arrives,
+//! or until they have been held for longer than the configured timeout. This is best effort: a
+//! message which is not received within the try duration of the sender never arrives, and the
+//! messages after it are released once their hold timeout expires.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::crypto::PublicKey;
+
+use super::ReceivedMessage;
+
+/// Buffer which releases inbound messages per (sender, topic) in the order they were sent.
+pub struct ReorderBuffer {
+    /// Maximum amount of time a message is held back while waiting for earlier messages.
+    hold_timeout: Duration,
+    /// Amount of time after which an idle stream is forgotten.
+    idle_timeout: Duration,
+    streams: HashMap<(PublicKey, Vec<u8>), OrderedStream>,
+}
+
+/// State of a single ordered stream of messages, i.e. messages from a single sender with the same
+/// topic.
+struct OrderedStream {
+    /// Sequence number of the next message to release.
+    next_seq: u64,
+    /// Messages which arrived before earlier messages in the stream, and the time they arrived.
+    held: BTreeMap<u64, (ReceivedMessage, Instant)>,
+    /// Last time a message was received on this stream.
+    last_activity: Instant,
+}
+
+impl ReorderBuffer {
+    /// Create a new, empty `ReorderBuffer`. Messages are held back for at most `hold_timeout`,
+    /// and streams which did not receive a message for `idle_timeout` are forgotten.
+    pub fn new(hold_timeout: Duration, idle_timeout: Duration) -> Self {
+        Self {
+            hold_timeout,
+            idle_timeout,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Insert a received message with the given sequence number. All messages which can be
+    /// released as a result are returned, in order.
+    pub fn insert(&mut self, seq: u64, msg: ReceivedMessage, now: Instant) -> Vec<ReceivedMessage> {
+        let stream = self
+            .streams
+            .entry((msg.src_pk, msg.topic.clone()))
+            .or_insert_with(|| OrderedStream {
+                next_seq: 0,
+                held: BTreeMap::new(),
+                last_activity: now,
+            });
+        stream.last_activity = now;
+
+        let mut released = Vec::new();
+
+        // A sender restarts its sequence at 0, so this is a new stream. Release whatever is left
+        // of the old one first.
+        if seq == 0 && stream.next_seq != 0 {
+            released.extend(
+                std::mem::take(&mut stream.held)
+                    .into_values()
+                    .map(|(msg, _)| msg),
+            );
+            stream.next_seq = 0;
+        }
+
+        // Messages after this one have already been released, so there is no point in holding it.
+        if seq < stream.next_seq {
+            released.push(msg);
+            return released;
+        }
+
+        stream.held.insert(seq, (msg, now));
+        stream.release_in_order(&mut released);
+
+        released
+    }
+
+    /// Release messages which have been held back for longer than the hold timeout, skipping
+    /// over the missing messages in front of them. Streams which have been idle for longer than
+    /// the idle timeout are removed.
+    pub fn expire(&mut self, now: Instant) -> Vec<ReceivedMessage> {
+        let mut released = Vec::new();
+
+        for stream in self.streams.values_mut() {
+            while stream
+                .held
+                .values()
+                .any(|(_, held_since)| now.duration_since(*held_since) >= self.hold_timeout)
+            {
+                // Give up on the missing messages in front of the first held message.
+                if let Some(&seq) = stream.held.keys().next() {
+                    stream.next_seq = seq;
+                }
+                stream.release_in_order(&mut released);
+            }
+        }
+
+        self.streams.retain(|_, stream| {
+            !stream.held.is_empty() || now.duration_since(stream.last_activity) < self.idle_timeout
+        });
+
+        released
+    }
+}
+
+impl OrderedStream {
+    /// Release all held messages which directly follow the last released message.
+    fn release_in_order(&mut self, released: &mut Vec<ReceivedMessage>) {
+        while let Some((msg, _)) = self.held.remove(&self.next_seq) {
+            released.push(msg);
+            self.next_seq += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        crypto::{PublicKey, SecretKey},
+        message::{MessageId, ReceivedMessage},
+    };
+
+    use super::ReorderBuffer;
+
+    const HOLD_TIMEOUT: Duration = Duration::from_secs(10);
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Create a new message from the given sender on the given topic. The payload is set to the
+    /// provided sequence number, so the order can be verified.
+    fn message(src_pk: PublicKey, topic: &[u8], seq: u64) -> ReceivedMessage {
+        let dst_pk = PublicKey::from(&SecretKey::new());
+        ReceivedMessage {
+            id: MessageId::new(),
+            is_reply: false,
+            src_ip: src_pk.address().into(),
+            src_pk,
+            dst_ip: dst_pk.address().into(),
+            dst_pk,
+            topic: topic.to_vec(),
+            correlation_id: vec![],
+            content_type: String::new(),
+            data: seq.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Extract the sequence numbers from released messages.
+    fn seqs(msgs: &[ReceivedMessage]) -> Vec<u64> {
+        msgs.iter()
+            .map(|m| u64::from_be_bytes(m.data[..].try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn in_order_messages_are_released_immediately() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let mut rb = ReorderBuffer::new(HOLD_TIMEOUT, IDLE_TIMEOUT);
+        let now = Instant::now();
+
+        for seq in 0..3 {
+            assert_eq!(
+                seqs(&rb.insert(seq, message(pk, b"t", seq), now)),
+                vec![seq]
+            );
+        }
+    }
+
+    #[test]
+    fn out_of_order_messages_are_reordered() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let mut rb = ReorderBuffer::new(HOLD_TIMEOUT, IDLE_TIMEOUT);
+        let now = Instant::now();
+
+        assert!(rb.insert(2, message(pk, b"t", 2), now).is_empty());
+        assert!(rb.insert(1, message(pk, b"t", 1), now).is_empty());
+        assert_eq!(
+            seqs(&rb.insert(0, message(pk, b"t", 0), now)),
+            vec![0, 1, 2]
+        );
+        assert_eq!(seqs(&rb.insert(3, message(pk, b"t", 3), now)), vec![3]);
+    }
+
+    #[test]
+    fn streams_are_ordered_independently() {
+        let pk1 = PublicKey::from(&SecretKey::new());
+        let pk2 = PublicKey::from(&SecretKey::new());
+        let mut rb = ReorderBuffer::new(HOLD_TIMEOUT, IDLE_TIMEOUT);
+        let now = Instant::now();
+
+        assert!(rb.insert(1, message(pk1, b"t", 1), now).is_empty());
+        // Different topic from the same sender.
+        assert_eq!(seqs(&rb.insert(0, message(pk1, b"u", 0), now)), vec![0]);
+        // Same topic from a different sender.
+        assert_eq!(seqs(&rb.insert(0, message(pk2, b"t", 0), now)), vec![0]);
+        assert_eq!(seqs(&rb.insert(0, message(pk1, b"t", 0), now)), vec![0, 1]);
+    }
+
+    #[test]
+    fn held_messages_are_released_after_timeout() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let mut rb = ReorderBuffer::new(HOLD_TIMEOUT, IDLE_TIMEOUT);
+        let now = Instant::now();
+
+        assert!(rb.insert(0, message(pk, b"t", 0), now).len() == 1);
+        // Message 1 is lost.
+        assert!(rb.insert(2, message(pk, b"t", 2), now).is_empty());
+        assert!(rb
+            .insert(3, message(pk, b"t", 3), now + Duration::from_secs(5))
+            .is_empty());
+
+        assert!(rb.expire(now + Duration::from_secs(5)).is_empty());
+        assert_eq!(seqs(&rb.expire(now + HOLD_TIMEOUT)), vec![2, 3]);
+
+        // The lost message arriving late is released immediately.
+        assert_eq!(
+            seqs(&rb.insert(1, message(pk, b"t", 1), now + HOLD_TIMEOUT)),
+            vec![1]
+        );
+        assert_eq!(
+            seqs(&rb.insert(4, message(pk, b"t", 4), now + HOLD_TIMEOUT)),
+            vec![4]
+        );
+    }
+
+    #[test]
+    fn sequence_restart_starts_new_stream() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let mut rb = ReorderBuffer::new(HOLD_TIMEOUT, IDLE_TIMEOUT);
+        let now = Instant::now();
+
+        assert_eq!(seqs(&rb.insert(0, message(pk, b"t", 0), now)), vec![0]);
+        assert_eq!(seqs(&rb.insert(1, message(pk, b"t", 1), now)), vec![1]);
+        assert!(rb.insert(3, message(pk, b"t", 3), now).is_empty());
+
+        // Sender restarted, the held message of the old stream is released first.
+        assert_eq!(seqs(&rb.insert(0, message(pk, b"t", 0), now)), vec![3, 0]);
+        assert!(rb.insert(2, message(pk, b"t", 2), now).is_empty());
+        assert_eq!(seqs(&rb.insert(1, message(pk, b"t", 1), now)), vec![1, 2]);
+    }
+
+    #[test]
+    fn idle_streams_are_forgotten() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let mut rb = ReorderBuffer::new(HOLD_TIMEOUT, IDLE_TIMEOUT);
+        let now = Instant::now();
+
+        assert_eq!(seqs(&rb.insert(0, message(pk, b"t", 0), now)), vec![0]);
+        assert!(rb.expire(now + IDLE_TIMEOUT).is_empty());
+        assert!(rb.streams.is_empty());
+    }
+}
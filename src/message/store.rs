@@ -0,0 +1,334 @@
+//! Optional persistence of outbound messages on disk.
+//!
+//! Every outbound message is written to its own file in the store directory when it is pushed,
+//! and the file is removed once the message is received by the remote, or expires. When the node
+//! starts, the messages left in the store are loaded, and delivery is resumed for those which did
+//! not expire yet.
+//!
+//! Messages are encrypted with AES-GCM before they are written, using a key derived from the
+//! private key of the node, so their content can't be read from the filesystem. The store also
+//! holds a small, encrypted check file, which is used to verify that the store is opened with the
+//! same key it was created with.
+
+use std::{
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{aead::Aead, aead::OsRng, AeadCore, Aes256Gcm, Key, KeyInit, Nonce};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use super::MessageId;
+use crate::crypto::SecretKey;
+
+/// Extension of the files holding a persisted message.
+const MESSAGE_FILE_EXTENSION: &str = "msg";
+
+/// Name of the file used to verify the store is opened with the right key.
+const KEY_CHECK_FILE: &str = "key-check";
+
+/// Plaintext of the key check file.
+const KEY_CHECK_PLAINTEXT: &[u8] = b"mycelium message store";
+
+/// Context used to derive the encryption key of the store from the private key of the node.
+const KEY_DERIVATION_CONTEXT: &str = "mycelium 2024-05 message store encryption key";
+
+/// Size of an AES-GCM nonce in bytes.
+const NONCE_SIZE: usize = 12;
+
+/// Directory holding persisted outbound messages.
+pub struct MessageStore {
+    dir: PathBuf,
+    cipher: Aes256Gcm,
+}
+
+/// An outbound message as it is persisted on disk, with all info needed to resume delivery.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistedMessage {
+    /// Id of the message.
+    pub id: MessageId,
+    /// Whether the message is a reply to the message with the same id.
+    pub reply: bool,
+    /// Overlay IP of the receiver.
+    pub dst: IpAddr,
+    /// Topic of the message, empty if not set.
+    pub topic: Vec<u8>,
+    /// Correlation id of the message, empty if not set.
+    pub correlation_id: Vec<u8>,
+    /// Content type of the message, empty if not set.
+    pub content_type: String,
+    /// The actual data of the message.
+    pub payload: Vec<u8>,
+    /// Time the message was pushed, in seconds since the UNIX epoch.
+    pub created_at: u64,
+    /// Amount of seconds delivery of the message is tried, starting at `created_at`.
+    pub try_duration_secs: u64,
+}
+
+impl PersistedMessage {
+    /// The amount of time delivery of this message should still be tried at the given time, or
+    /// `None` if the message expired.
+    pub fn remaining_try_duration(&self, now: SystemTime) -> Option<Duration> {
+        let deadline = UNIX_EPOCH + Duration::from_secs(self.created_at + self.try_duration_secs);
+        deadline
+            .duration_since(now)
+            .ok()
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+impl MessageStore {
+    /// Open the `MessageStore` in the given directory, creating the directory if it does not
+    /// exist yet. Messages are encrypted with a key derived from the given node key.
+    ///
+    /// This fails if the store was created with a different node key, or if it holds messages
+    /// which were persisted without encryption, since those messages could not be loaded.
+    pub fn open(dir: impl Into<PathBuf>, node_key: &SecretKey) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let key: Key<Aes256Gcm> =
+            blake3::derive_key(KEY_DERIVATION_CONTEXT, node_key.as_bytes()).into();
+        let store = Self {
+            dir,
+            cipher: Aes256Gcm::new(&key),
+        };
+        store.check_key()?;
+
+        Ok(store)
+    }
+
+    /// Verify the store is opened with the key it was created with. If the store has no key
+    /// check file yet, it is created, as long as there are no messages in the store.
+    fn check_key(&self) -> io::Result<()> {
+        let path = self.dir.join(KEY_CHECK_FILE);
+        match fs::read(&path) {
+            Ok(data) => {
+                if self.decrypt(&data).as_deref() != Some(KEY_CHECK_PLAINTEXT) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "message store {} was created with a different node key, its messages can't be decrypted",
+                            self.dir.display()
+                        ),
+                    ));
+                }
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if self.message_files()?.next().is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "message store {} contains messages which are not encrypted by this node",
+                            self.dir.display()
+                        ),
+                    ));
+                }
+                self.write_atomic(&path, &self.encrypt(KEY_CHECK_PLAINTEXT))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Persist a message. If a message with the same id is already persisted, it is replaced.
+    pub fn save(&self, msg: &PersistedMessage) -> io::Result<()> {
+        let data = self.encrypt(&serde_json::to_vec(msg)?);
+        self.write_atomic(&self.message_path(msg.id), &data)
+    }
+
+    /// Write data to a temporary file first and rename it to the given path, so a crash never
+    /// leaves a partially written file behind.
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Encrypt data with the key of the store. The random nonce is prepended to the encrypted
+    /// data.
+    fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(
+            self.cipher
+                .encrypt(&nonce, data)
+                .expect("Encryption can't fail; qed"),
+        );
+        encrypted
+    }
+
+    /// Decrypt data previously encrypted by [`MessageStore::encrypt`] with the same key. Returns
+    /// `None` if the data was not encrypted with the key of the store, or is malformed.
+    fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, data) = data.split_at(NONCE_SIZE);
+        self.cipher.decrypt(Nonce::from_slice(nonce), data).ok()
+    }
+
+    /// Remove a persisted message. This does nothing if the message is not persisted.
+    pub fn remove(&self, id: MessageId) -> io::Result<()> {
+        match fs::remove_file(self.message_path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Load all persisted messages. Files which can't be read or parsed are skipped.
+    pub fn load(&self) -> io::Result<Vec<PersistedMessage>> {
+        let mut messages = Vec::new();
+        for path in self.message_files()? {
+            let path = path?;
+            match self.load_message(&path) {
+                Ok(msg) => messages.push(msg),
+                Err(e) => warn!("Skipping persisted message {}: {e}", path.display()),
+            }
+        }
+        debug!("Loaded {} persisted messages", messages.len());
+
+        Ok(messages)
+    }
+
+    /// Paths of all files holding a persisted message.
+    fn message_files(&self) -> io::Result<impl Iterator<Item = io::Result<PathBuf>>> {
+        Ok(fs::read_dir(&self.dir)?.filter_map(|entry| match entry {
+            Ok(entry) => {
+                let path = entry.path();
+                (path.extension().and_then(|ext| ext.to_str()) == Some(MESSAGE_FILE_EXTENSION))
+                    .then_some(Ok(path))
+            }
+            Err(e) => Some(Err(e)),
+        }))
+    }
+
+    /// Load and decrypt a single persisted message from the given file.
+    fn load_message(&self, path: &Path) -> io::Result<PersistedMessage> {
+        let data = self.decrypt(&fs::read(path)?).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "message can't be decrypted")
+        })?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Path of the file holding the message with the given id.
+    fn message_path(&self, id: MessageId) -> PathBuf {
+        self.dir
+            .join(format!("{}.{MESSAGE_FILE_EXTENSION}", id.as_hex()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::{crypto::SecretKey, message::MessageId};
+
+    use super::{MessageStore, PersistedMessage, MESSAGE_FILE_EXTENSION};
+
+    /// Create a new, empty store in a unique temporary directory.
+    fn temp_store(key: &SecretKey) -> (MessageStore, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "mycelium-message-store-{}",
+            MessageId::new().as_hex()
+        ));
+        (
+            MessageStore::open(&dir, key).expect("Can create store"),
+            dir,
+        )
+    }
+
+    fn message(created_at: u64) -> PersistedMessage {
+        PersistedMessage {
+            id: MessageId::new(),
+            reply: false,
+            dst: "400::1".parse().unwrap(),
+            topic: b"topic".to_vec(),
+            correlation_id: vec![],
+            content_type: "text/plain".to_string(),
+            payload: b"payload".to_vec(),
+            created_at,
+            try_duration_secs: 60,
+        }
+    }
+
+    #[test]
+    fn messages_survive_reopening() {
+        let key = SecretKey::new();
+        let (store, dir) = temp_store(&key);
+        let kept = message(1);
+        let removed = message(2);
+        store.save(&kept).expect("Can save message");
+        store.save(&removed).expect("Can save message");
+        store.remove(removed.id).expect("Can remove message");
+        drop(store);
+
+        let store = MessageStore::open(&dir, &key).expect("Can reopen store");
+        let loaded = store.load().expect("Can load messages");
+        assert!(loaded == vec![kept]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn messages_are_encrypted() {
+        let key = SecretKey::new();
+        let (store, dir) = temp_store(&key);
+        let msg = message(1);
+        store.save(&msg).expect("Can save message");
+
+        let data = std::fs::read(dir.join(format!("{}.{MESSAGE_FILE_EXTENSION}", msg.id.as_hex())))
+            .expect("Message is written to a file");
+        assert!(!data.windows(msg.payload.len()).any(|w| w == msg.payload));
+        assert!(!data.windows(msg.topic.len()).any(|w| w == msg.topic));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn opening_with_wrong_key_fails() {
+        let (store, dir) = temp_store(&SecretKey::new());
+        store.save(&message(1)).expect("Can save message");
+        drop(store);
+
+        assert!(MessageStore::open(&dir, &SecretKey::new()).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn opening_with_unencrypted_messages_fails() {
+        let dir = std::env::temp_dir().join(format!(
+            "mycelium-message-store-{}",
+            MessageId::new().as_hex()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(format!("0123456789abcdef.{MESSAGE_FILE_EXTENSION}")),
+            b"{}",
+        )
+        .unwrap();
+
+        assert!(MessageStore::open(&dir, &SecretKey::new()).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn remaining_try_duration() {
+        let msg = message(1_000);
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert_eq!(
+            msg.remaining_try_duration(at(1_010)),
+            Some(Duration::from_secs(50))
+        );
+        assert_eq!(msg.remaining_try_duration(at(1_060)), None);
+        assert_eq!(msg.remaining_try_duration(at(2_000)), None);
+        assert!(msg.remaining_try_duration(SystemTime::now()).is_none());
+    }
+}
@@ -1,3 +1,8 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
 use bytes::{Buf, BufMut, BytesMut};
 pub use control::ControlPacket;
 pub use data::DataPacket;
@@ -12,6 +17,21 @@ const PROTOCOL_VERSION: u8 = 1;
 /// The size of a `Packet` header on the wire, in bytes.
 const PACKET_HEADER_SIZE: usize = 4;
 
+/// Flag in the flags byte of the `Packet` header, indicating the sender can decompress data
+/// packets. Peers which don't know about compression ignore the flags byte, and never set it.
+const FLAG_COMPRESSION_SUPPORTED: u8 = 0b1000_0000;
+/// Flag in the flags byte of the `Packet` header, indicating the payload of the data packet is
+/// compressed.
+const FLAG_COMPRESSED: u8 = 0b0100_0000;
+
+/// Compression level used for data packets. Compression happens for every packet on the hot path,
+/// so this favours speed over ratio.
+const COMPRESSION_LEVEL: u8 = 1;
+
+/// Maximum size of a decompressed data packet payload, which is the maximum size the payload
+/// length field allows.
+const MAX_DECOMPRESSED_SIZE: usize = u16::MAX as usize;
+
 #[derive(Debug, Clone)]
 pub enum Packet {
     DataPacket(DataPacket),
@@ -27,6 +47,13 @@ pub enum PacketType {
 
 pub struct Codec {
     packet_type: Option<PacketType>,
+    /// The packet currently being decoded has a compressed payload.
+    compressed: bool,
+    /// Amount of bytes saved by compressing data packets. Compression is only enabled if this is
+    /// set.
+    compression: Option<Arc<AtomicU64>>,
+    /// The remote indicated it can decompress data packets.
+    remote_decompresses: bool,
     data_packet_codec: data::Codec,
     control_packet_codec: control::Codec,
 }
@@ -35,10 +62,51 @@ impl Codec {
     pub fn new() -> Self {
         Codec {
             packet_type: None,
+            compressed: false,
+            compression: None,
+            remote_decompresses: false,
             data_packet_codec: data::Codec::new(),
             control_packet_codec: control::Codec::new(),
         }
     }
+
+    /// Create a new `Codec` which compresses the payload of data packets, once the remote
+    /// indicated it can decompress them. The amount of bytes saved by compression is added to
+    /// `saved_bytes`.
+    pub fn with_compression(saved_bytes: Arc<AtomicU64>) -> Self {
+        Codec {
+            compression: Some(saved_bytes),
+            ..Self::new()
+        }
+    }
+
+    /// The flags byte to set in the header of packets we send.
+    fn flags(&self) -> u8 {
+        if self.compression.is_some() {
+            FLAG_COMPRESSION_SUPPORTED
+        } else {
+            0
+        }
+    }
+
+    /// Compress the payload of a data packet if compression is enabled, the remote can decompress
+    /// it, and it actually gets smaller. Returns true if the payload is compressed.
+    fn compress(&self, packet: &mut DataPacket) -> bool {
+        let saved_bytes = match &self.compression {
+            Some(saved_bytes) if self.remote_decompresses => saved_bytes,
+            _ => return false,
+        };
+        let compressed = miniz_oxide::deflate::compress_to_vec(&packet.raw_data, COMPRESSION_LEVEL);
+        if compressed.len() >= packet.raw_data.len() {
+            return false;
+        }
+        saved_bytes.fetch_add(
+            (packet.raw_data.len() - compressed.len()) as u64,
+            Ordering::Relaxed,
+        );
+        packet.raw_data = compressed;
+        true
+    }
 }
 
 impl Decoder for Codec {
@@ -67,6 +135,11 @@ impl Decoder for Codec {
                 ));
             };
 
+            if header[2] & FLAG_COMPRESSION_SUPPORTED != 0 {
+                self.remote_decompresses = true;
+            }
+            self.compressed = header[2] & FLAG_COMPRESSED != 0;
+
             let packet_type_byte = header[1];
             let packet_type = match packet_type_byte {
                 0 => PacketType::DataPacket,
@@ -88,8 +161,20 @@ impl Decoder for Codec {
         match packet_type {
             PacketType::DataPacket => {
                 match self.data_packet_codec.decode(src) {
-                    Ok(Some(p)) => {
+                    Ok(Some(mut p)) => {
                         self.packet_type = None; // Reset state
+                        if self.compressed {
+                            p.raw_data = miniz_oxide::inflate::decompress_to_vec_with_limit(
+                                &p.raw_data,
+                                MAX_DECOMPRESSED_SIZE,
+                            )
+                            .map_err(|e| {
+                                std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!("Invalid compressed data packet: {e}"),
+                                )
+                            })?;
+                        }
                         Ok(Some(Packet::DataPacket(p)))
                     }
                     Ok(None) => Ok(None),
@@ -115,12 +200,16 @@ impl Encoder<Packet> for Codec {
 
     fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
         match item {
-            Packet::DataPacket(datapacket) => {
-                dst.put_slice(&[PROTOCOL_VERSION, 0, 0, 0]);
+            Packet::DataPacket(mut datapacket) => {
+                let mut flags = self.flags();
+                if self.compress(&mut datapacket) {
+                    flags |= FLAG_COMPRESSED;
+                }
+                dst.put_slice(&[PROTOCOL_VERSION, 0, flags, 0]);
                 self.data_packet_codec.encode(datapacket, dst)
             }
             Packet::ControlPacket(controlpacket) => {
-                dst.put_slice(&[PROTOCOL_VERSION, 1, 0, 0]);
+                dst.put_slice(&[PROTOCOL_VERSION, 1, self.flags(), 0]);
                 self.control_packet_codec.encode(controlpacket, dst)
             }
         }
@@ -132,3 +221,73 @@ impl Default for Codec {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{Codec, DataPacket, Packet};
+
+    fn data_packet(raw_data: Vec<u8>) -> Packet {
+        Packet::DataPacket(DataPacket {
+            raw_data,
+            hop_limit: 64,
+            src_ip: "400::1".parse().unwrap(),
+            dst_ip: "400::2".parse().unwrap(),
+        })
+    }
+
+    /// Encode a packet with one codec, and decode it with the other.
+    fn transfer(sender: &mut Codec, receiver: &mut Codec, packet: Packet) -> (DataPacket, usize) {
+        let mut buf = BytesMut::new();
+        sender.encode(packet, &mut buf).expect("Can encode packet");
+        let size = buf.len();
+        match receiver.decode(&mut buf).expect("Can decode packet") {
+            Some(Packet::DataPacket(dp)) => (dp, size),
+            _ => panic!("Expected a data packet"),
+        }
+    }
+
+    #[test]
+    fn compressed_data_packet_round_trip() {
+        let saved_a = Arc::new(AtomicU64::new(0));
+        let saved_b = Arc::new(AtomicU64::new(0));
+        let mut a = Codec::with_compression(saved_a.clone());
+        let mut b = Codec::with_compression(saved_b.clone());
+        let payload = b"compressible ".repeat(50);
+
+        // Nothing is compressed until the remote indicated it can decompress.
+        let (_, size) = transfer(&mut a, &mut b, data_packet(payload.clone()));
+        assert!(size > payload.len());
+        assert_eq!(saved_a.load(Ordering::Relaxed), 0);
+
+        let (dp, size) = transfer(&mut b, &mut a, data_packet(payload.clone()));
+        assert_eq!(dp.raw_data, payload);
+        assert!(size < payload.len());
+        assert!(saved_b.load(Ordering::Relaxed) > 0);
+        assert_eq!(dp.hop_limit, 64);
+    }
+
+    #[test]
+    fn no_compression_without_support() {
+        let saved = Arc::new(AtomicU64::new(0));
+        let mut a = Codec::with_compression(saved.clone());
+        let mut b = Codec::new();
+        let payload = b"compressible ".repeat(50);
+
+        let (_, size) = transfer(&mut b, &mut a, data_packet(payload.clone()));
+        assert!(size > payload.len());
+
+        // The peer without compression never indicates support, so we never compress.
+        let (dp, size) = transfer(&mut a, &mut b, data_packet(payload.clone()));
+        assert_eq!(dp.raw_data, payload);
+        assert!(size > payload.len());
+        assert_eq!(saved.load(Ordering::Relaxed), 0);
+    }
+}
@@ -3,20 +3,25 @@ use log::{debug, error, info, trace};
 use std::{
     error::Error,
     io,
+    num::NonZeroUsize,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc, RwLock, Weak,
+        Arc, Mutex, RwLock, Weak,
     },
+    time::{Duration, Instant},
 };
 use tokio::{
     select,
-    sync::{mpsc, Notify},
+    sync::{futures::Notified, mpsc, Notify},
 };
 use tokio_util::codec::Framed;
 
 use crate::{
     connection::{self, Connection},
+    events::unix_timestamp,
+    filters::ImportPolicy,
     packet::{self, Packet},
+    shaping::BandwidthLimit,
 };
 use crate::{
     packet::{ControlPacket, DataPacket},
@@ -52,19 +57,47 @@ pub struct PeerRef {
 }
 
 impl Peer {
+    /// Create a new `Peer` on the given connection. At most `data_queue_size` data packets are
+    /// queued to be sent to the peer, data packets sent while the queue is full are dropped and
+    /// counted in `dropped_data_packets`. Data packets are only sent as fast as the
+    /// `bandwidth_limit` allows, so a throttled peer fills up its queue. If `compression` is set,
+    /// data packets are compressed if the peer supports it, and the amount of bytes saved is
+    /// added to it. Data packets received from the peer which can't be decrypted are counted in
+    /// `decryption_failures`.
+    #[allow(clippy::too_many_arguments)]
     pub fn new<C: Connection + Unpin + Send + 'static>(
-        router_data_tx: mpsc::Sender<DataPacket>,
+        router_data_tx: mpsc::Sender<(DataPacket, Peer)>,
         router_control_tx: mpsc::UnboundedSender<(ControlPacket, Peer)>,
         connection: C,
         dead_peer_sink: mpsc::Sender<Peer>,
         bytes_written: Arc<AtomicU64>,
         bytes_read: Arc<AtomicU64>,
+        dropped_data_packets: Arc<AtomicU64>,
+        decryption_failures: Arc<AtomicU64>,
+        data_queue_size: NonZeroUsize,
+        bandwidth_limit: BandwidthLimit,
+        compression: Option<Arc<AtomicU64>>,
+        import_policy: Arc<ImportPolicy>,
     ) -> Result<Self, io::Error> {
         // Wrap connection so we can get access to the counters.
         let connection = connection::Tracked::new(bytes_read, bytes_written, connection);
 
-        // Data channel for peer
-        let (to_peer_data, mut from_routing_data) = mpsc::unbounded_channel::<DataPacket>();
+        // Data channel for peer. This is bounded, so a slow peer can't make us buffer an unbounded
+        // amount of packets.
+        let (to_peer_data, from_routing_data) = mpsc::channel::<DataPacket>(data_queue_size.get());
+        // If there is a bandwidth cap, packets are taken from the queue at the allowed rate in a
+        // separate task, so waiting for the cap does not block receiving from the connection.
+        let mut from_routing_data = if bandwidth_limit.cap().is_some() {
+            let (shaped_tx, shaped_rx) = mpsc::channel(PACKET_COALESCE_WINDOW);
+            tokio::spawn(shape_data_packets(
+                from_routing_data,
+                shaped_tx,
+                bandwidth_limit.clone(),
+            ));
+            shaped_rx
+        } else {
+            from_routing_data
+        };
         // Control channel for peer
         let (to_peer_control, mut from_routing_control) =
             mpsc::unbounded_channel::<ControlPacket>();
@@ -74,17 +107,31 @@ impl Peer {
             inner: Arc::new(PeerInner {
                 state: RwLock::new(PeerState::new()),
                 to_peer_data,
+                dropped_data_packets,
+                decryption_failures,
+                bandwidth_limit,
                 to_peer_control,
                 connection_identifier: connection.identifier()?,
                 static_link_cost: connection.static_link_cost()?,
                 death_notifier,
                 alive: AtomicBool::new(true),
+                connected_since: Instant::now(),
+                connected_at: unix_timestamp(),
+                last_data_activity: Mutex::new(tokio::time::Instant::now()),
+                hellos_sent: AtomicU64::new(0),
+                ihus_received: AtomicU64::new(0),
+                ihu_notify: Notify::new(),
+                import_policy,
             }),
         };
 
         // Framed for peer
         // Used to send and receive packets from a TCP stream
-        let mut framed = Framed::new(connection, packet::Codec::new());
+        let codec = match compression {
+            Some(saved_bytes) => packet::Codec::with_compression(saved_bytes),
+            None => packet::Codec::new(),
+        };
+        let mut framed = Framed::new(connection, codec);
 
         {
             let peer = peer.clone();
@@ -98,7 +145,8 @@ impl Peer {
                                 Some(Ok(packet)) => {
                                     match packet {
                                         Packet::DataPacket(packet) => {
-                                            if let Err(error) = router_data_tx.send(packet).await{
+                                            peer.record_data_activity();
+                                            if let Err(error) = router_data_tx.send((packet, peer.clone())).await{
                                                 error!("Error sending to to_routing_data: {}", error);
                                             }
                                         }
@@ -194,8 +242,55 @@ impl Peer {
     /// For sending data packets towards a peer instance on this node.
     /// It's send over the to_peer_data channel and read from the corresponding receiver.
     /// The receiver sends the packet over the TCP stream towards the destined peer instance on another node
-    pub fn send_data_packet(&self, data_packet: DataPacket) -> Result<(), Box<dyn Error>> {
-        Ok(self.inner.to_peer_data.send(data_packet)?)
+    ///
+    /// If the queue of data packets for the peer is full, the packet is dropped (tail drop) and
+    /// counted in the dropped data packets of the peer. This is not considered an error, instead
+    /// false is returned.
+    pub fn send_data_packet(&self, data_packet: DataPacket) -> Result<bool, Box<dyn Error>> {
+        match self.inner.to_peer_data.try_send(data_packet) {
+            Ok(()) => {
+                self.record_data_activity();
+                Ok(true)
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                trace!(
+                    "Dropping data packet for {}, data queue is full",
+                    self.connection_identifier()
+                );
+                self.inner
+                    .dropped_data_packets
+                    .fetch_add(1, Ordering::Relaxed);
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record that a data packet received from the peer could not be decrypted.
+    pub fn decryption_failed(&self) {
+        self.inner
+            .decryption_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Amount of data packets received from the peer which could not be decrypted.
+    pub fn decryption_failures(&self) -> u64 {
+        self.inner.decryption_failures.load(Ordering::Relaxed)
+    }
+
+    /// Amount of data packets in the data queue of the peer, which are not sent yet.
+    pub fn queued_data_packets(&self) -> usize {
+        self.inner.to_peer_data.max_capacity() - self.inner.to_peer_data.capacity()
+    }
+
+    /// Amount of data packets which were dropped because the data queue of the peer was full.
+    pub fn dropped_data_packets(&self) -> u64 {
+        self.inner.dropped_data_packets.load(Ordering::Relaxed)
+    }
+
+    /// The limit on the outbound bandwidth to this peer.
+    pub fn bandwidth_limit(&self) -> &BandwidthLimit {
+        &self.inner.bandwidth_limit
     }
 
     /// For sending control packets towards a peer instance on this node.
@@ -227,6 +322,93 @@ impl Peer {
             / TOTAL_METRIC_DIVISOR) as u16;
     }
 
+    /// Record the round trip time between sending a Hello to this `Peer` and receiving the IHU
+    /// answering it.
+    pub fn record_latency(&self, rtt: Duration) {
+        let mut inner = self.inner.state.write().unwrap();
+        inner.latency = Some(smooth_latency(inner.latency, rtt));
+    }
+
+    /// The smoothed round trip time to this `Peer`, if an IHU has been received from it yet.
+    ///
+    /// Unlike the [link cost](Self::link_cost), this does not include a default or static cost.
+    pub fn latency(&self) -> Option<Duration> {
+        self.inner.state.read().unwrap().latency
+    }
+
+    /// Record that a Hello was sent to this `Peer`.
+    pub fn hello_sent(&self) {
+        self.inner.hellos_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that an IHU was received from this `Peer`.
+    pub fn ihu_received(&self) {
+        self.inner.ihus_received.fetch_add(1, Ordering::Relaxed);
+        self.inner.ihu_notify.notify_waiters();
+    }
+
+    /// Wait for the next IHU from this `Peer`. The returned future completes for IHU's received
+    /// after it is created, even if it is not polled yet.
+    pub fn ihu_notified(&self) -> Notified<'_> {
+        self.inner.ihu_notify.notified()
+    }
+
+    /// Check if at least one IHU was received from this `Peer`, i.e. it confirmed it can hear us.
+    pub fn handshake_completed(&self) -> bool {
+        self.inner.ihus_received.load(Ordering::Relaxed) > 0
+    }
+
+    /// The amount of Hello's sent to this `Peer` which are considered in the
+    /// [`hello_loss`](Self::hello_loss).
+    pub fn hello_samples(&self) -> u64 {
+        self.inner
+            .hellos_sent
+            .load(Ordering::Relaxed)
+            .saturating_sub(1)
+    }
+
+    /// The fraction of Hello's sent to this `Peer` which have not been answered with an IHU, as a
+    /// value between 0 and 1.
+    ///
+    /// The last Hello is not considered, as the IHU for it might still be on its way.
+    pub fn hello_loss(&self) -> f64 {
+        let sent = self.hello_samples();
+        if sent == 0 {
+            return 0.;
+        }
+        let received = self.inner.ihus_received.load(Ordering::Relaxed).min(sent);
+
+        (sent - received) as f64 / sent as f64
+    }
+
+    /// The time at which the connection to this `Peer` was established, in seconds since the UNIX
+    /// epoch.
+    pub fn connected_at(&self) -> u64 {
+        self.inner.connected_at
+    }
+
+    /// The amount of time the connection to this `Peer` has been established.
+    pub fn uptime(&self) -> Duration {
+        self.inner.connected_since.elapsed()
+    }
+
+    /// The amount of time since a data packet was last sent to or received from this `Peer`, or
+    /// since the connection was established if no data packet was exchanged yet. Control packets
+    /// are not considered.
+    pub fn data_idle_time(&self) -> Duration {
+        self.inner.last_data_activity.lock().unwrap().elapsed()
+    }
+
+    /// Mark that a data packet was sent to or received from this `Peer`.
+    fn record_data_activity(&self) {
+        *self.inner.last_data_activity.lock().unwrap() = tokio::time::Instant::now();
+    }
+
+    /// The [`ImportPolicy`] applied to route updates received from this `Peer`.
+    pub fn import_policy(&self) -> &ImportPolicy {
+        &self.inner.import_policy
+    }
+
     /// Identifier for the connection to the `Peer`.
     pub fn connection_identifier(&self) -> &String {
         &self.inner.connection_identifier
@@ -295,7 +477,13 @@ impl PartialEq for Peer {
 #[derive(Debug)]
 struct PeerInner {
     state: RwLock<PeerState>,
-    to_peer_data: mpsc::UnboundedSender<DataPacket>,
+    to_peer_data: mpsc::Sender<DataPacket>,
+    /// Amount of data packets dropped because `to_peer_data` was full.
+    dropped_data_packets: Arc<AtomicU64>,
+    /// Amount of data packets received from this peer which could not be decrypted.
+    decryption_failures: Arc<AtomicU64>,
+    /// Limit on the outbound bandwidth used for data packets.
+    bandwidth_limit: BandwidthLimit,
     to_peer_control: mpsc::UnboundedSender<ControlPacket>,
     /// Used to identify peer based on its connection params.
     connection_identifier: String,
@@ -306,6 +494,49 @@ struct PeerInner {
     death_notifier: Arc<Notify>,
     /// Keep track if the connection is alive.
     alive: AtomicBool,
+    /// Time at which the connection was established.
+    connected_since: Instant,
+    /// Time at which the connection was established, in seconds since the UNIX epoch. This is
+    /// saved once, since deriving it from the uptime gives a slightly different time every time.
+    connected_at: u64,
+    /// Last time a data packet was sent to or received from the peer.
+    last_data_activity: Mutex<tokio::time::Instant>,
+    /// Amount of Hello's sent to this peer.
+    hellos_sent: AtomicU64,
+    /// Amount of IHU's received from this peer.
+    ihus_received: AtomicU64,
+    /// Notifies waiters every time an IHU is received from this peer.
+    ihu_notify: Notify,
+    /// Policy for routes announced by this peer.
+    import_policy: Arc<ImportPolicy>,
+}
+
+/// Forward data packets from `queue` to `shaped` as fast as the [`BandwidthLimit`] allows. This
+/// stops once either side of the forwarding is closed.
+async fn shape_data_packets(
+    mut queue: mpsc::Receiver<DataPacket>,
+    shaped: mpsc::Sender<DataPacket>,
+    bandwidth_limit: BandwidthLimit,
+) {
+    while let Some(packet) = queue.recv().await {
+        bandwidth_limit.acquire(packet.raw_data.len()).await;
+        if shaped.send(packet).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Smooth a new round trip time sample into the existing smoothed latency, the same way as the
+/// link cost. The first sample is used as is.
+fn smooth_latency(latency: Option<Duration>, rtt: Duration) -> Duration {
+    match latency {
+        None => rtt,
+        Some(latency) => {
+            (latency * EXISTING_METRIC_FACTOR
+                + rtt * (TOTAL_METRIC_DIVISOR - EXISTING_METRIC_FACTOR))
+                / TOTAL_METRIC_DIVISOR
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -314,6 +545,8 @@ struct PeerState {
     time_last_received_hello: tokio::time::Instant,
     link_cost: u16,
     time_last_received_ihu: tokio::time::Instant,
+    /// Smoothed round trip time, if it has been measured yet.
+    latency: Option<Duration>,
 }
 
 impl PeerState {
@@ -332,6 +565,106 @@ impl PeerState {
             link_cost,
             time_last_received_ihu,
             time_last_received_hello,
+            latency: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv6Addr,
+        num::NonZeroUsize,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc;
+
+    use crate::{
+        events::unix_timestamp, filters::ImportPolicy, packet::DataPacket, shaping::BandwidthLimit,
+        testing,
+    };
+
+    use super::{smooth_latency, Peer};
+
+    #[tokio::test]
+    async fn full_data_queue_drops_packets() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Can bind local listener");
+        let addr = listener.local_addr().expect("Listener has a local address");
+        // The remote side of the connection is kept open, but never read.
+        let (stream, _remote) =
+            tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+        let (router_data_tx, _router_data_rx) = mpsc::channel(1);
+        let (router_control_tx, _router_control_rx) = mpsc::unbounded_channel();
+        let (dead_peer_sink, _dead_peer_stream) = mpsc::channel(1);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let peer = Peer::new(
+            router_data_tx,
+            router_control_tx,
+            stream.expect("Can connect to local listener"),
+            dead_peer_sink,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicU64::new(0)),
+            dropped.clone(),
+            Arc::new(AtomicU64::new(0)),
+            NonZeroUsize::new(10).unwrap(),
+            BandwidthLimit::new(None, None),
+            None,
+            Arc::new(ImportPolicy::default()),
+        )
+        .expect("Can create peer");
+
+        // The connection task does not run while we don't yield, so only the first packets fit in
+        // the queue, and the rest is dropped instead of buffered.
+        for _ in 0..100 {
+            peer.send_data_packet(DataPacket {
+                raw_data: vec![0; 1_000],
+                hop_limit: 64,
+                src_ip: Ipv6Addr::LOCALHOST,
+                dst_ip: Ipv6Addr::LOCALHOST,
+            })
+            .expect("Dropping a packet is not an error");
         }
+
+        assert_eq!(peer.dropped_data_packets(), 90);
+        assert_eq!(dropped.load(Ordering::Relaxed), 90);
+        assert_eq!(peer.queued_data_packets(), 10);
+
+        // Once the connection task runs, the queue is drained.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while peer.queued_data_packets() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Data queue is drained");
+    }
+
+    #[tokio::test]
+    async fn connection_time_is_saved() {
+        let before = unix_timestamp();
+        let (router, _, _tun_rx) = testing::router(None);
+        let (stream, _remote) = testing::tcp_pair().await;
+        let peer = testing::router_peer(&router, stream);
+
+        let connected_at = peer.connected_at();
+        assert!(connected_at >= before && connected_at <= unix_timestamp());
+        assert_eq!(peer.connected_at(), connected_at);
+    }
+
+    #[test]
+    fn latency_is_smoothed() {
+        let first = smooth_latency(None, Duration::from_millis(100));
+        assert_eq!(first, Duration::from_millis(100));
+
+        // A single spike only moves the latency by a tenth of the difference.
+        let spiked = smooth_latency(Some(first), Duration::from_millis(200));
+        assert_eq!(spiked, Duration::from_millis(110));
     }
 }
@@ -1,8 +1,13 @@
 use crate::connection::Quic;
+use crate::crypto::PublicKey;
 use crate::endpoint::{Endpoint, Protocol};
+use crate::events::{unix_timestamp, PeerEvent, PeerEventKind, PeerEvents};
+use crate::filters::{ImportPolicy, PrefixLists};
 use crate::peer::{Peer, PeerRef};
+use crate::reconnect::{Backoff, ReconnectPolicy, RECONNECT_GRACE_PERIOD};
 use crate::router::Router;
 use crate::router_id::RouterId;
+use crate::shaping::{BandwidthLimit, TokenBucket};
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use log::{debug, error, info, trace, warn};
@@ -12,31 +17,66 @@ use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::net::{IpAddr, SocketAddr};
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{broadcast, Semaphore};
 use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
 
 /// Magic bytes to identify a multicast UDP packet used in link local peer discovery.
 const MYCELIUM_MULTICAST_DISCOVERY_MAGIC: &[u8; 8] = b"mycelium";
 /// Size of a peer discovery beacon.
 const PEER_DISCOVERY_BEACON_SIZE: usize = 8 + 2 + 40;
-/// Link local peer discovery group joined by the UDP listener.
-const LL_PEER_DISCOVERY_GROUP: &str = "ff02::cafe";
 /// The time between sending consecutive link local discovery beacons.
 const LL_PEER_DISCOVERY_BEACON_INTERVAL: Duration = Duration::from_secs(60);
-/// The time between checking known peer liveness and trying to reconnect.
-const PEER_CONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// The time between checking known peer liveness and trying to reconnect. Reconnect attempts are
+/// delayed by the [`Backoff`] of the peer, which is checked at this interval.
+const PEER_CONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// The maximum amount of time to wait for a TCP connection to a peer to be established, so an
+/// unresponsive address does not hold on to a dial permit until the OS gives up.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 /// The maximum amount of successive failures allowed when connecting to a local discovered peer,
 /// before it is forgotten.
 const MAX_FAILED_LOCAL_PEER_CONNECTION_ATTEMPTS: usize = 3;
+/// The maximum time between checking inbound peers for inactivity.
+const IDLE_PEER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Link cost at or above which a peer does not get any latency points in its quality score.
+const QUALITY_MAX_LINK_COST: u16 = 1000;
+/// Amount of reconnects at or above which a peer does not get any stability points in its quality
+/// score.
+const QUALITY_MAX_RECONNECTS: usize = 5;
+/// Uptime of a connection after which a peer gets all uptime points in its quality score.
+const QUALITY_FULL_UPTIME: Duration = Duration::from_secs(60 * 60);
+/// Amount of Hello's which must have been sent to a peer before its packet loss is reported.
+const MIN_PACKET_LOSS_SAMPLES: u64 = 3;
+/// Amount of peer changes buffered for a subscriber. A subscriber which falls further behind
+/// misses changes.
+const PEER_CHANGES_CAPACITY: usize = 128;
+/// Interval at which the data queues of the peers are checked while draining them.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
 
 /// The PeerManager creates new peers by connecting to configured addresses, and setting up the
 /// connection. Once a connection is established, the created [`Peer`] is handed over to the
 /// [`Router`].
+///
+/// Every connected peer is assigned a quality score between 0 and 100, which is the sum of:
+///
+///  - Latency (40 points): 40 for a link cost of 0, decreasing linearly to 0 at a link cost of
+///    1000. The link cost is the round trip time of the last Hello/IHU exchange in milliseconds.
+///  - Loss (30 points): 30 times the fraction of Hello's sent to the peer which have been answered
+///    with an IHU.
+///  - Stability (15 points): 15 if the connection never had to be reestablished, decreasing
+///    linearly to 0 at 5 reconnects.
+///  - Uptime (15 points): 0 for a new connection, increasing linearly to 15 once the connection
+///    has been up for an hour.
+///
+/// Peers which are not connected have a quality score of 0.
 #[derive(Clone)]
 pub struct PeerManager {
     inner: Arc<Inner>,
@@ -54,8 +94,24 @@ pub enum PeerType {
     Inbound,
 }
 
+/// Configuration of the link local peer discovery.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerDiscoveryConfig {
+    /// UDP port used to send and receive discovery beacons. If this is not set, link local peer
+    /// discovery is disabled.
+    pub port: Option<u16>,
+    /// Multicast group joined by the UDP listener.
+    pub multicast_group: Ipv6Addr,
+    /// Names of the interfaces on which the multicast group is joined. If this is empty, the
+    /// group is joined on every interface with a link local IPv6 address.
+    pub interfaces: Vec<String>,
+}
+
 /// Local info about a peer.
 struct PeerInfo {
+    /// Short identifier of this peer, which is stable as long as the peer is known.
+    id: u64,
     /// Details how we found out about this peer.
     pt: PeerType,
     /// Are we currently connecting to this peer?
@@ -65,21 +121,38 @@ struct PeerInfo {
     /// Amount of failed times we tried to connect to this peer. This is reset after a successful
     /// connection.
     connection_attempts: usize,
+    /// Amount of times a connection to this peer has been established.
+    connections: usize,
     /// Keep track of the amount of bytes we've sent to and received from this peer.
     con_traffic: ConnectionTraffic,
+    /// Policy for routes announced by this peer. This is shared with every connection to the
+    /// peer, so it is kept across reconnects.
+    import_policy: Arc<ImportPolicy>,
+    /// Recent connection events of this peer.
+    events: PeerEvents,
+    /// Outbound bandwidth cap of this peer in bytes per second, if any.
+    bandwidth_cap: Option<u64>,
+    /// Backoff of reconnecting to this peer.
+    backoff: Backoff,
 }
 
 /// Counters for the amount of traffic written to and received from a [`Peer`].
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 struct ConnectionTraffic {
     /// Amount of bytes transmitted to this peer.
     tx_bytes: Arc<AtomicU64>,
     /// Amount of bytes received from this peer.
     rx_bytes: Arc<AtomicU64>,
+    /// Amount of data packets dropped because the data queue of this peer was full.
+    dropped_data_packets: Arc<AtomicU64>,
+    /// Amount of bytes saved by compressing data packets sent to this peer.
+    compression_saved_bytes: Arc<AtomicU64>,
+    /// Amount of data packets received from this peer which could not be decrypted.
+    decryption_failures: Arc<AtomicU64>,
 }
 
 /// General state about a connection to a [`Peer`].
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum ConnectionState {
     /// There is a working connection to the [`Peer`].
@@ -94,6 +167,9 @@ pub enum ConnectionState {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PeerStats {
+    /// Short identifier of the [`Peer`], which can be used instead of the endpoint to refer to
+    /// it. This is stable as long as the peer is known.
+    pub id: u64,
     /// The endpoint of the [`Peer`].
     pub endpoint: Endpoint,
     /// The (Type)[`PeerType`] of the [`Peer`].
@@ -105,9 +181,89 @@ pub struct PeerStats {
     pub tx_bytes: u64,
     /// Amount of bytes received from this [`Peer`].
     pub rx_bytes: u64,
+    /// Amount of data packets dropped because the data queue of this [`Peer`] was full.
+    pub dropped_data_packets: u64,
+    /// Amount of bytes saved by compressing data packets sent to this [`Peer`].
+    #[serde(default)]
+    pub compression_saved_bytes: u64,
+    /// Effective outbound bandwidth cap of this [`Peer`] in bytes per second, the lowest of the
+    /// cap of the peer and the cap of the node, if any.
+    pub bandwidth_cap: Option<u64>,
+    /// Percentage of the bandwidth cap used in the last second. This is only set if the [`Peer`]
+    /// is connected and there is a cap.
+    pub bandwidth_utilization: Option<f64>,
+    /// Quality score of the connection to this [`Peer`], between 0 (worst) and 100 (best).
+    pub quality: u8,
+    /// Smoothed round trip time to this [`Peer`] in milliseconds, measured between sending a
+    /// Hello and receiving the IHU answering it. This is only set if the peer is connected and
+    /// answered a Hello.
+    #[serde(default)]
+    pub latency_ms: Option<f64>,
+    /// Percentage of Hello's sent to this [`Peer`] over the current connection which have not
+    /// been answered. This is only set if the peer is connected and enough Hello's have been
+    /// sent.
+    #[serde(default)]
+    pub packet_loss_percent: Option<f64>,
+    /// Time the current connection to this [`Peer`] was established, in seconds since the UNIX
+    /// epoch. This is reset on reconnect, and only set if the peer is connected.
+    #[serde(default)]
+    pub connected_since: Option<u64>,
+    /// Time a data packet was last sent to or received from this [`Peer`], in seconds since the
+    /// UNIX epoch. This is the time the connection was established if no data packet was
+    /// exchanged yet, and only set if the peer is connected.
+    #[serde(default)]
+    pub last_data_activity: Option<u64>,
+    /// Fingerprint of the public key of this [`Peer`]. This is only set once the peer is
+    /// connected and announced its own subnet, since its key is not known before.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// The full public key of this [`Peer`], if it is known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PublicKey>,
+    /// Amount of data packets for this node received from this [`Peer`] which could not be
+    /// decrypted.
+    #[serde(default)]
+    pub decryption_failures: u64,
+    /// Amount of successive failed connection attempts and lost connections to this [`Peer`].
+    /// This is reset once a connection stays up for a while.
+    #[serde(default)]
+    pub reconnect_failures: u32,
+    /// Amount of seconds until the next attempt to reconnect to this [`Peer`]. This is only set
+    /// if the peer is not connected, and reconnecting is delayed.
+    #[serde(default)]
+    pub reconnect_in_secs: Option<u64>,
+}
+
+/// Aggregated statistics of all known [`Peer`]s.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerSummary {
+    /// Amount of known peers.
+    pub total: usize,
+    /// Amount of peers with a working connection.
+    pub connected: usize,
+    /// Amount of peers we are currently connecting to.
+    pub connecting: usize,
+    /// Amount of peers without a working connection.
+    pub dead: usize,
+    /// Total amount of bytes transmitted to all peers.
+    pub total_tx: u64,
+    /// Total amount of bytes received from all peers.
+    pub total_rx: u64,
 }
 
 impl PeerInfo {
+    /// Return the current state of the connection to this peer.
+    fn connection_state(&self) -> ConnectionState {
+        if self.connecting {
+            ConnectionState::Connecting
+        } else if self.pr.alive() {
+            ConnectionState::Alive
+        } else {
+            ConnectionState::Dead
+        }
+    }
+
     /// Return the amount of bytes read from this peer.
     #[inline]
     fn read(&self) -> u64 {
@@ -117,7 +273,13 @@ impl PeerInfo {
     /// Return the amount of bytes written to this peer.
     #[inline]
     fn written(&self) -> u64 {
-        self.con_traffic.rx_bytes.load(Ordering::Relaxed)
+        self.con_traffic.tx_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Return the amount of times the connection to this peer had to be reestablished.
+    #[inline]
+    fn reconnects(&self) -> usize {
+        self.connections.saturating_sub(1)
     }
 }
 
@@ -125,29 +287,92 @@ impl PeerInfo {
 #[derive(Debug)]
 pub struct PeerExists;
 
+/// A change to the known peers, see [`PeerManager::subscribe_changes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerChange {
+    /// A peer was added, or a connection event of the peer was recorded.
+    Updated(Endpoint),
+    /// A peer was removed.
+    Removed(Endpoint),
+}
+
 /// Marker error to indicate a [`peer`](Endpoint) is not known.
 #[derive(Debug)]
 pub struct PeerNotFound;
 
+/// Error returned when deleting a [`peer`](Endpoint) fails.
+#[derive(Debug)]
+pub enum DeletePeerError {
+    /// The peer is not known.
+    NotFound,
+    /// The peer is not a [`PeerType::Static`] peer. Other peers are not managed by the operator,
+    /// so they can't be removed.
+    NotStatic(PeerType),
+}
+
 struct Inner {
     /// Router is unfortunately wrapped in a Mutex, because router is not Sync.
     router: Mutex<Router>,
     peers: Mutex<HashMap<Endpoint, PeerInfo>>,
+    /// Import policies set for inbound peers, by remote address. Inbound peers typically connect
+    /// from a different port every time, so the policy is kept by address to apply it again when
+    /// the peer reconnects.
+    inbound_import_policies: Mutex<HashMap<IpAddr, Arc<ImportPolicy>>>,
     /// Listen port for new peer connections
     tcp_listen_port: u16,
     quic_socket: quinn::Endpoint,
+    /// Configuration of the link local peer discovery.
+    discovery_config: PeerDiscoveryConfig,
+    /// Maximum amount of data packets queued for a single peer.
+    data_queue_size: NonZeroUsize,
+    /// Outbound bandwidth cap shared by all peers, if any.
+    node_bandwidth: Option<TokenBucket>,
+    /// Compress data packets sent to peers which support it.
+    compression: bool,
+    /// Identifier assigned to the next new peer.
+    next_peer_id: AtomicU64,
+    /// Maximum amount of outbound connection attempts running at the same time.
+    max_concurrent_dials: NonZeroUsize,
+    /// Permits for outbound connection attempts, which limit how many of them run at the same
+    /// time.
+    dial_permits: Semaphore,
+    /// Inbound peers which don't exchange data packets for this long are disconnected, if set.
+    inbound_idle_timeout: Option<Duration>,
+    /// Token to stop the background tasks (listeners, reconnecting and discovery) on shutdown.
+    cancel: CancellationToken,
+    /// Publishes changes to the known peers.
+    changes: broadcast::Sender<PeerChange>,
 }
 
 impl PeerManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         router: Router,
         static_peers_sockets: Vec<Endpoint>,
+        static_reconnect_policy: ReconnectPolicy,
         tcp_listen_port: u16,
         quic_listen_port: u16,
-        peer_discovery_port: u16,
-        disable_peer_discovery: bool,
+        discovery_config: PeerDiscoveryConfig,
+        data_queue_size: NonZeroUsize,
+        bandwidth_cap: Option<u64>,
+        compression: bool,
+        max_concurrent_dials: NonZeroUsize,
+        inbound_idle_timeout: Option<Duration>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
+        if !discovery_config.multicast_group.is_multicast() {
+            return Err(format!(
+                "Peer discovery group {} is not a multicast address",
+                discovery_config.multicast_group
+            )
+            .into());
+        }
+        if inbound_idle_timeout == Some(Duration::ZERO) {
+            return Err("Inbound idle timeout must be larger than 0".into());
+        }
+        static_reconnect_policy.validate()?;
+
         let quic_socket = make_quic_endpoint(router.router_id(), quic_listen_port)?;
+        let static_peer_count = static_peers_sockets.len() as u64;
 
         let peer_manager = PeerManager {
             inner: Arc::new(Inner {
@@ -158,39 +383,67 @@ impl PeerManager {
                         // These peers are not alive, but we say they are because the reconnect
                         // loop will perform the actual check and figure out they are dead, then
                         // (re)connect.
-                        .map(|s| {
+                        .enumerate()
+                        .map(|(id, s)| {
                             (
                                 s,
                                 PeerInfo {
+                                    id: id as u64,
                                     pt: PeerType::Static,
                                     connecting: false,
                                     pr: PeerRef::new(),
                                     connection_attempts: 0,
-                                    con_traffic: ConnectionTraffic {
-                                        tx_bytes: Arc::new(AtomicU64::new(0)),
-                                        rx_bytes: Arc::new(AtomicU64::new(0)),
-                                    },
+                                    connections: 0,
+                                    con_traffic: ConnectionTraffic::default(),
+                                    import_policy: Arc::default(),
+                                    events: PeerEvents::new(),
+                                    bandwidth_cap: None,
+                                    backoff: Backoff::new(static_reconnect_policy),
                                 },
                             )
                         })
                         .collect(),
                 ),
+                inbound_import_policies: Mutex::new(HashMap::new()),
                 tcp_listen_port,
                 quic_socket,
+                discovery_config,
+                data_queue_size,
+                node_bandwidth: bandwidth_cap.map(TokenBucket::new),
+                compression,
+                next_peer_id: AtomicU64::new(static_peer_count),
+                max_concurrent_dials,
+                dial_permits: Semaphore::new(max_concurrent_dials.get()),
+                inbound_idle_timeout,
+                cancel: CancellationToken::new(),
+                changes: broadcast::channel(PEER_CHANGES_CAPACITY).0,
             }),
         };
+        let cancel = &peer_manager.inner.cancel;
 
         // Start listeners for inbound connections.
-        tokio::spawn(peer_manager.inner.clone().tcp_listener());
-        tokio::spawn(peer_manager.inner.clone().quic_listener());
+        spawn_cancellable(cancel.clone(), peer_manager.inner.clone().tcp_listener());
+        spawn_cancellable(cancel.clone(), peer_manager.inner.clone().quic_listener());
 
         // Start (re)connecting to outbound/local peers
-        tokio::spawn(peer_manager.inner.clone().connect_to_peers());
+        spawn_cancellable(
+            cancel.clone(),
+            peer_manager.inner.clone().connect_to_peers(),
+        );
+
+        // Disconnect inbound peers which stopped exchanging data.
+        if let Some(idle_timeout) = inbound_idle_timeout {
+            spawn_cancellable(
+                cancel.clone(),
+                peer_manager.inner.clone().reap_idle_peers(idle_timeout),
+            );
+        }
 
         // Discover local peers, this does not actually connect to them. That is handle by the
         // connect_to_peers task.
-        if !disable_peer_discovery {
-            tokio::spawn(
+        if let Some(peer_discovery_port) = peer_manager.inner.discovery_config.port {
+            spawn_cancellable(
+                cancel.clone(),
                 peer_manager
                     .inner
                     .clone()
@@ -201,14 +454,68 @@ impl PeerManager {
         Ok(peer_manager)
     }
 
-    /// Add a new peer to the system.
+    /// Stop discovering, accepting and (re)connecting to peers. Existing connections are kept
+    /// open until [`PeerManager::disconnect_all`] is called.
+    pub fn stop(&self) {
+        info!("Stopping peer manager");
+        self.inner.cancel.cancel();
+    }
+
+    /// Wait until the data queues of all connected peers are empty, so data packets which are
+    /// already routed are sent before the connections are closed. Waiting stops after `timeout`,
+    /// in which case false is returned.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let queued: usize = self
+                .inner
+                .peers
+                .lock()
+                .unwrap()
+                .values()
+                .filter_map(|pi| pi.pr.upgrade())
+                .map(|peer| peer.queued_data_packets())
+                .sum();
+            if queued == 0 {
+                return true;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                warn!("Closing peer connections with {queued} data packets still queued");
+                return false;
+            }
+            tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Close the connection to all currently connected peers. Unless [`PeerManager::stop`] was
+    /// called first, peers will be reconnected by the reconnect loop.
+    pub fn disconnect_all(&self) {
+        for (endpoint, pi) in self.inner.peers.lock().unwrap().iter_mut() {
+            if let Some(peer) = pi.pr.upgrade() {
+                debug!("Disconnecting peer {endpoint}");
+                peer.died();
+                pi.events
+                    .record(PeerEventKind::Disconnected, "Disconnected by this node");
+                self.inner.peer_changed(PeerChange::Updated(*endpoint));
+            }
+        }
+    }
+
+    /// Add a new peer to the system. Outbound traffic to the peer is limited to `bandwidth_cap`
+    /// bytes per second, if set. Reconnecting to the peer is delayed according to
+    /// `reconnect_policy`.
     ///
     /// The peer starts of as a dead peer, and connecting is handled in the reconnect loop.
     ///
     /// # Errors
     ///
     /// This function returns an error if the [`Endpoint`] is already known.
-    pub fn add_peer(&self, peer: Endpoint) -> Result<(), PeerExists> {
+    pub fn add_peer(
+        &self,
+        peer: Endpoint,
+        bandwidth_cap: Option<u64>,
+        reconnect_policy: ReconnectPolicy,
+    ) -> Result<(), PeerExists> {
         let mut peer_map = self.inner.peers.lock().unwrap();
         if peer_map.contains_key(&peer) {
             return Err(PeerExists);
@@ -216,62 +523,304 @@ impl PeerManager {
         peer_map.insert(
             peer,
             PeerInfo {
+                id: self.inner.next_peer_id(),
                 pt: PeerType::Static,
                 connecting: false,
                 pr: PeerRef::new(),
                 connection_attempts: 0,
-                con_traffic: ConnectionTraffic {
-                    tx_bytes: Arc::new(AtomicU64::new(0)),
-                    rx_bytes: Arc::new(AtomicU64::new(0)),
-                },
+                connections: 0,
+                con_traffic: ConnectionTraffic::default(),
+                import_policy: Arc::default(),
+                events: PeerEvents::new(),
+                bandwidth_cap,
+                backoff: Backoff::new(reconnect_policy),
             },
         );
+        self.inner.peer_changed(PeerChange::Updated(peer));
 
         Ok(())
     }
 
-    /// Delete a peer from the system.
-    ///
-    /// The peer will be disconnected if it is currently connected.
+    /// Get the [`Endpoint`] of the peer with the given short identifier, if it is known.
+    pub fn endpoint_by_id(&self, id: u64) -> Option<Endpoint> {
+        self.inner
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, pi)| pi.id == id)
+            .map(|(endpoint, _)| *endpoint)
+    }
+
+    /// Get the current connection to the peer identified by the given [`Endpoint`], if it is
+    /// connected.
     ///
     /// # Errors
     ///
     /// Returns an error if there is no peer identified by the given [`Endpoint`].
-    pub fn delete_peer(&self, endpoint: &Endpoint) -> Result<(), PeerNotFound> {
+    pub fn connected_peer(&self, endpoint: &Endpoint) -> Result<Option<Peer>, PeerNotFound> {
+        self.inner
+            .peers
+            .lock()
+            .unwrap()
+            .get(endpoint)
+            .map(|pi| pi.pr.upgrade())
+            .ok_or(PeerNotFound)
+    }
+
+    /// Delete a static peer from the system.
+    ///
+    /// The peer will be disconnected if it is currently connected, and is not reconnected to
+    /// afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no peer identified by the given [`Endpoint`], or if the peer
+    /// is not a [`PeerType::Static`] peer.
+    pub fn delete_peer(&self, endpoint: &Endpoint) -> Result<(), DeletePeerError> {
         let mut peer_map = self.inner.peers.lock().unwrap();
-        peer_map.remove(endpoint).ok_or(PeerNotFound).map(|pi| {
-            // Make sure we kill the peer connection if one exists
-            if let Some(peer) = pi.pr.upgrade() {
-                peer.died();
-            }
-        })
+        let Entry::Occupied(entry) = peer_map.entry(*endpoint) else {
+            return Err(DeletePeerError::NotFound);
+        };
+        if entry.get().pt != PeerType::Static {
+            return Err(DeletePeerError::NotStatic(entry.get().pt.clone()));
+        }
+        // Make sure we kill the peer connection if one exists
+        if let Some(peer) = entry.remove().pr.upgrade() {
+            peer.died();
+        }
+        self.inner.peer_changed(PeerChange::Removed(*endpoint));
+        Ok(())
+    }
+
+    /// Get the [`ImportPolicy`] applied to routes announced by the peer identified by the given
+    /// [`Endpoint`]. Changes to the policy apply to all updates received afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no peer identified by the given [`Endpoint`].
+    pub fn import_policy(&self, endpoint: &Endpoint) -> Result<Arc<ImportPolicy>, PeerNotFound> {
+        self.inner
+            .peers
+            .lock()
+            .unwrap()
+            .get(endpoint)
+            .map(|pi| pi.import_policy.clone())
+            .ok_or(PeerNotFound)
+    }
+
+    /// Replace the prefix lists of the [`ImportPolicy`] of the peer identified by the given
+    /// [`Endpoint`]. The policy of an inbound peer is kept for its address, so it also applies when
+    /// the peer reconnects from a different port.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no peer identified by the given [`Endpoint`].
+    pub fn set_import_policy(
+        &self,
+        endpoint: &Endpoint,
+        prefixes: PrefixLists,
+    ) -> Result<(), PeerNotFound> {
+        let peers = self.inner.peers.lock().unwrap();
+        let pi = peers.get(endpoint).ok_or(PeerNotFound)?;
+        pi.import_policy.set_prefixes(prefixes);
+        if pi.pt == PeerType::Inbound {
+            self.inner
+                .inbound_import_policies
+                .lock()
+                .unwrap()
+                .insert(endpoint.address().ip(), pi.import_policy.clone());
+        }
+        Ok(())
+    }
+
+    /// Get the recent connection events of the peer identified by the given [`Endpoint`], oldest
+    /// first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no peer identified by the given [`Endpoint`].
+    pub fn peer_events(&self, endpoint: &Endpoint) -> Result<Vec<PeerEvent>, PeerNotFound> {
+        self.inner
+            .peers
+            .lock()
+            .unwrap()
+            .get(endpoint)
+            .map(|pi| pi.events.recent())
+            .ok_or(PeerNotFound)
+    }
+
+    /// Get the amount of connections to peers which have been lost within the given window before
+    /// now.
+    pub fn disconnects_within(&self, window: Duration) -> usize {
+        self.inner
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|pi| pi.events.count_within(PeerEventKind::Disconnected, window))
+            .sum()
+    }
+
+    /// Get the effective configuration of the link local peer discovery.
+    pub fn discovery_config(&self) -> PeerDiscoveryConfig {
+        self.inner.discovery_config.clone()
+    }
+
+    /// Get the maximum amount of data packets queued to be sent to a single peer.
+    pub fn data_queue_size(&self) -> NonZeroUsize {
+        self.inner.data_queue_size
+    }
+
+    /// Get the maximum amount of outbound connection attempts running at the same time.
+    pub fn max_concurrent_dials(&self) -> NonZeroUsize {
+        self.inner.max_concurrent_dials
+    }
+
+    /// Get the amount of time after which inbound peers which don't exchange data packets are
+    /// disconnected, if set.
+    pub fn inbound_idle_timeout(&self) -> Option<Duration> {
+        self.inner.inbound_idle_timeout
+    }
+
+    /// Subscribe to changes of the known peers. Only changes after subscribing are received. If
+    /// the subscriber falls behind too far, it misses changes and receives a
+    /// [`Lagged`](broadcast::error::RecvError::Lagged) error instead.
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<PeerChange> {
+        self.inner.changes.subscribe()
     }
 
     /// Get a view of all known peers and their stats.
     pub fn peers(&self) -> Vec<PeerStats> {
+        let neighbour_keys: HashMap<String, PublicKey> = self
+            .inner
+            .router
+            .lock()
+            .unwrap()
+            .neighbour_keys()
+            .into_iter()
+            .collect();
         let peer_map = self.inner.peers.lock().unwrap();
         let mut pi = Vec::with_capacity(peer_map.len());
         for (endpoint, peer_info) in peer_map.iter() {
-            let connection_state = if peer_info.connecting {
-                ConnectionState::Connecting
-            } else if peer_info.pr.alive() {
-                ConnectionState::Alive
-            } else {
-                ConnectionState::Dead
+            let connection_state = peer_info.connection_state();
+            let peer = peer_info
+                .pr
+                .upgrade()
+                .filter(|_| matches!(connection_state, ConnectionState::Alive));
+            let quality = match &peer {
+                Some(peer) => quality_score(
+                    peer.link_cost(),
+                    peer.hello_loss(),
+                    peer_info.reconnects(),
+                    peer.uptime(),
+                ),
+                None => 0,
+            };
+            let (bandwidth_cap, bandwidth_utilization) = match &peer {
+                Some(peer) => (
+                    peer.bandwidth_limit().cap(),
+                    peer.bandwidth_limit().utilization(),
+                ),
+                None => (
+                    self.inner.bandwidth_limit(peer_info.bandwidth_cap).cap(),
+                    None,
+                ),
             };
+            let public_key = peer
+                .as_ref()
+                .and_then(|peer| neighbour_keys.get(peer.connection_identifier()))
+                .copied();
             pi.push(PeerStats {
+                id: peer_info.id,
                 endpoint: *endpoint,
                 pt: peer_info.pt.clone(),
                 connection_state,
                 tx_bytes: peer_info.written(),
                 rx_bytes: peer_info.read(),
+                dropped_data_packets: peer_info
+                    .con_traffic
+                    .dropped_data_packets
+                    .load(Ordering::Relaxed),
+                compression_saved_bytes: peer_info
+                    .con_traffic
+                    .compression_saved_bytes
+                    .load(Ordering::Relaxed),
+                bandwidth_cap,
+                bandwidth_utilization,
+                quality,
+                latency_ms: peer
+                    .as_ref()
+                    .and_then(Peer::latency)
+                    .map(|latency| latency.as_secs_f64() * 1000.),
+                packet_loss_percent: peer
+                    .as_ref()
+                    .filter(|peer| peer.hello_samples() >= MIN_PACKET_LOSS_SAMPLES)
+                    .map(|peer| peer.hello_loss() * 100.),
+                connected_since: peer.as_ref().map(Peer::connected_at),
+                last_data_activity: peer
+                    .as_ref()
+                    .map(|peer| unix_timestamp().saturating_sub(peer.data_idle_time().as_secs())),
+                fingerprint: public_key.map(|pk| pk.fingerprint()),
+                decryption_failures: peer_info
+                    .con_traffic
+                    .decryption_failures
+                    .load(Ordering::Relaxed),
+                public_key,
+                reconnect_failures: peer_info.backoff.failures(),
+                reconnect_in_secs: match connection_state {
+                    ConnectionState::Dead => peer_info
+                        .backoff
+                        .remaining(Instant::now())
+                        .map(|remaining| remaining.as_secs_f64().ceil() as u64),
+                    _ => None,
+                },
             });
         }
         pi
     }
+
+    /// Get the stats of the peer with the given [`PublicKey`], if it is a directly connected
+    /// neighbour. The key of a peer is only known once it announced its own subnet.
+    pub fn neighbour(&self, public_key: &PublicKey) -> Option<PeerStats> {
+        self.peers()
+            .into_iter()
+            .find(|peer| peer.public_key.as_ref() == Some(public_key))
+    }
+
+    /// Get aggregated statistics of all known peers.
+    pub fn peer_summary(&self) -> PeerSummary {
+        let peer_map = self.inner.peers.lock().unwrap();
+        let mut summary = PeerSummary {
+            total: peer_map.len(),
+            ..Default::default()
+        };
+        for peer_info in peer_map.values() {
+            match peer_info.connection_state() {
+                ConnectionState::Alive => summary.connected += 1,
+                ConnectionState::Connecting => summary.connecting += 1,
+                ConnectionState::Dead => summary.dead += 1,
+            }
+            summary.total_tx += peer_info.written();
+            summary.total_rx += peer_info.read();
+        }
+        summary
+    }
 }
 
 impl Inner {
+    /// Periodically disconnect inbound peers which did not exchange data packets for
+    /// `idle_timeout`.
+    async fn reap_idle_peers(self: Arc<Self>, idle_timeout: Duration) {
+        let mut check_interval = tokio::time::interval(idle_timeout.min(IDLE_PEER_CHECK_INTERVAL));
+        check_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            check_interval.tick().await;
+            disconnect_idle_peers(&self.peers.lock().unwrap(), idle_timeout);
+        }
+    }
+
     /// Connect and if needed reconnect to known peers.
     async fn connect_to_peers(self: Arc<Self>) {
         let mut peer_check_interval = tokio::time::interval(PEER_CONNECT_INTERVAL);
@@ -293,38 +842,87 @@ impl Inner {
                     if let Some(pi) = peers.get_mut(&endpoint) {
                         // Regardless of what happened, we are no longer connecting.
                         pi.connecting = false;
-                        if let Some(peer) = maybe_new_peer {
-                            // We did find a new Peer, insert into router and keep track of it
-                            // Use fully qualified call to aid compiler in type inference.
-                            pi.pr = Peer::refer(&peer);
-                            self.router.lock().unwrap().add_peer_interface(peer);
-                        } else {
-                            // Connection failed, add a failed attempt and forget about the peer if
-                            // needed.
-                            pi.connection_attempts += 1;
-                            if pi.pt == PeerType::LinkLocalDiscovery
-                                && pi.connection_attempts >= MAX_FAILED_LOCAL_PEER_CONNECTION_ATTEMPTS {
-                                info!("Forgetting about locally discovered peer {endpoint} after failing to connect to it");
-                                peers.remove(&endpoint);
+                        match maybe_new_peer {
+                            Ok(peer) => {
+                                // We did find a new Peer, insert into router and keep track of it
+                                // Use fully qualified call to aid compiler in type inference. If
+                                // this is a reconnect, the route table dump requested by the router
+                                // restores the routes learned from the peer before.
+                                pi.pr = Peer::refer(&peer);
+                                pi.connections += 1;
+                                pi.events.record(PeerEventKind::Connected, "Connected to peer");
+                                self.peer_changed(PeerChange::Updated(endpoint));
+                                self.router.lock().unwrap().add_peer_interface(peer);
+                            }
+                            Err(e) => {
+                                // Connection failed, add a failed attempt and forget about the peer if
+                                // needed.
+                                pi.connection_attempts += 1;
+                                pi.backoff.failed(Instant::now());
+                                pi.events.record(PeerEventKind::ConnectError, e);
+                                if pi.pt == PeerType::LinkLocalDiscovery
+                                    && pi.connection_attempts >= MAX_FAILED_LOCAL_PEER_CONNECTION_ATTEMPTS {
+                                    info!("Forgetting about locally discovered peer {endpoint} after failing to connect to it");
+                                    peers.remove(&endpoint);
+                                    self.peer_changed(PeerChange::Removed(endpoint));
+                                } else {
+                                    self.peer_changed(PeerChange::Updated(endpoint));
+                                }
                             }
                         }
+                    } else if let Ok(peer) = maybe_new_peer {
+                        // The peer was deleted while we were connecting to it.
+                        debug!("Closing new connection to deleted peer {endpoint}");
+                        peer.died();
                     }
                 }
                 _ = peer_check_interval.tick() => {
                     // Remove dead inbound peers
-                    self.peers.lock().unwrap().retain(|_, v| v.pt != PeerType::Inbound || v.pr.alive());
+                    self.peers.lock().unwrap().retain(|endpoint, v| {
+                        let keep = v.pt != PeerType::Inbound || v.pr.alive();
+                        if !keep {
+                            self.peer_changed(PeerChange::Removed(*endpoint));
+                        }
+                        keep
+                    });
                     debug!("Looking for dead peers");
+                    let now = Instant::now();
                     // check if there is an entry for the peer in the router's peer list
                     for (endpoint, pi) in self.peers.lock().unwrap().iter_mut() {
+                        // Start over once the connection proved to be stable.
+                        if pi.backoff.failures() > 0
+                            && pi.pr.upgrade().is_some_and(|peer| peer.uptime() >= RECONNECT_GRACE_PERIOD) {
+                            debug!("Connection to {endpoint} is stable, resetting reconnect backoff");
+                            pi.backoff.reset();
+                        }
+                        if pi.events.last_kind() == Some(PeerEventKind::Connected)
+                            && pi.pr.upgrade().is_some_and(|peer| peer.handshake_completed()) {
+                            pi.events.record(PeerEventKind::HandshakeOk, "Received first IHU from peer");
+                            self.peer_changed(PeerChange::Updated(*endpoint));
+                        }
                         if !pi.connecting && !pi.pr.alive() {
                             debug!("Found dead peer {endpoint}");
+                            let lost = pi.events.connected();
+                            if lost {
+                                pi.events.record(PeerEventKind::Disconnected, "Connection lost");
+                                self.peer_changed(PeerChange::Updated(*endpoint));
+                            }
                             if pi.pt == PeerType::Inbound {
                                 debug!("Refusing to reconnect to inbound peer");
                                 continue
                             }
+                            // Don't reconnect right away after the connection is lost, so peers
+                            // which lost their connection at the same time don't all reconnect
+                            // at once.
+                            if lost {
+                                pi.backoff.failed(now);
+                            }
+                            if !pi.backoff.ready(now) {
+                                continue
+                            }
                             // Mark that we are connecting to the peer.
                             pi.connecting = true;
-                            connection_futures.push(self.clone().connect_peer(*endpoint, pi.con_traffic.clone()));
+                            connection_futures.push(self.clone().connect_peer(*endpoint, pi.con_traffic.clone(), pi.import_policy.clone(), pi.bandwidth_cap));
                         }
                     }
                 }
@@ -332,31 +930,62 @@ impl Inner {
         }
     }
 
-    /// Create a new connection to a remote peer
+    /// Create a new connection to a remote peer. If the maximum amount of connection attempts is
+    /// already running, this waits until one of them finishes, so adding many peers at once does
+    /// not cause a connection storm.
     async fn connect_peer(
         self: Arc<Self>,
         endpoint: Endpoint,
         ct: ConnectionTraffic,
-    ) -> (Endpoint, Option<Peer>) {
-        debug!("Connecting to {endpoint}");
-        match endpoint.proto() {
-            Protocol::Tcp => self.connect_tcp_peer(endpoint, ct).await,
-            Protocol::Quic => self.connect_quic_peer(endpoint, ct).await,
-        }
+        import_policy: Arc<ImportPolicy>,
+        bandwidth_cap: Option<u64>,
+    ) -> (Endpoint, Result<Peer, String>) {
+        let bandwidth_limit = self.bandwidth_limit(bandwidth_cap);
+        let inner = self.clone();
+        limit_dial(&self.dial_permits, async move {
+            debug!("Connecting to {endpoint}");
+            match endpoint.proto() {
+                Protocol::Tcp => {
+                    inner
+                        .connect_tcp_peer(endpoint, ct, import_policy, bandwidth_limit)
+                        .await
+                }
+                Protocol::Quic => {
+                    inner
+                        .connect_quic_peer(endpoint, ct, import_policy, bandwidth_limit)
+                        .await
+                }
+            }
+        })
+        .await
     }
 
     async fn connect_tcp_peer(
         self: Arc<Self>,
         endpoint: Endpoint,
         ct: ConnectionTraffic,
-    ) -> (Endpoint, Option<Peer>) {
-        match TcpStream::connect(endpoint.address()).await {
+        import_policy: Arc<ImportPolicy>,
+        bandwidth_limit: BandwidthLimit,
+    ) -> (Endpoint, Result<Peer, String>) {
+        let connect =
+            tokio::time::timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(endpoint.address()))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "connection attempt timed out",
+                    ))
+                });
+        match connect {
             Ok(peer_stream) => {
                 debug!("Opened connection to {endpoint}");
                 // Make sure Nagle's algorithm is disabeld as it can cause latency spikes.
                 if let Err(e) = peer_stream.set_nodelay(true) {
                     error!("Couldn't disable Nagle's algorithm on stream {e}");
-                    return (endpoint, None);
+                    return (
+                        endpoint,
+                        Err(format!("Couldn't disable Nagle's algorithm: {e}")),
+                    );
                 }
 
                 // Scope the MutexGuard, if we don't do this the future won't be Send
@@ -373,22 +1002,28 @@ impl Inner {
                         dead_peer_sink,
                         ct.tx_bytes,
                         ct.rx_bytes,
+                        ct.dropped_data_packets,
+                        ct.decryption_failures,
+                        self.data_queue_size,
+                        bandwidth_limit,
+                        self.compression(&ct.compression_saved_bytes),
+                        import_policy,
                     )
                 };
                 match res {
                     Ok(new_peer) => {
                         info!("Connected to new peer {}", endpoint);
-                        (endpoint, Some(new_peer))
+                        (endpoint, Ok(new_peer))
                     }
                     Err(e) => {
                         error!("Failed to spawn peer {endpoint}: {e}");
-                        (endpoint, None)
+                        (endpoint, Err(format!("Failed to spawn peer: {e}")))
                     }
                 }
             }
             Err(e) => {
                 error!("Couldn't connect to {endpoint}: {e}");
-                (endpoint, None)
+                (endpoint, Err(format!("Couldn't connect: {e}")))
             }
         }
     }
@@ -397,7 +1032,9 @@ impl Inner {
         self: Arc<Self>,
         endpoint: Endpoint,
         ct: ConnectionTraffic,
-    ) -> (Endpoint, Option<Peer>) {
+        import_policy: Arc<ImportPolicy>,
+        bandwidth_limit: BandwidthLimit,
+    ) -> (Endpoint, Result<Peer, String>) {
         let mut config = quinn::ClientConfig::new(Arc::new(
             rustls::ClientConfig::builder()
                 .with_safe_defaults()
@@ -440,32 +1077,44 @@ impl Inner {
                                 dead_peer_sink,
                                 ct.tx_bytes,
                                 ct.rx_bytes,
+                                ct.dropped_data_packets,
+                                ct.decryption_failures,
+                                self.data_queue_size,
+                                bandwidth_limit,
+                                self.compression(&ct.compression_saved_bytes),
+                                import_policy,
                             )
                         };
                         match res {
                             Ok(new_peer) => {
                                 info!("Connected to new peer {}", endpoint);
-                                (endpoint, Some(new_peer))
+                                (endpoint, Ok(new_peer))
                             }
                             Err(e) => {
                                 error!("Failed to spawn peer {endpoint}: {e}");
-                                (endpoint, None)
+                                (endpoint, Err(format!("Failed to spawn peer: {e}")))
                             }
                         }
                     }
                     Err(e) => {
                         error!("Couldn't open bidirectional quic stream to {endpoint}: {e}");
-                        (endpoint, None)
+                        (
+                            endpoint,
+                            Err(format!("Couldn't open bidirectional quic stream: {e}")),
+                        )
                     }
                 },
                 Err(e) => {
                     error!("Couldn't complete quic connection to {endpoint}: {e}");
-                    (endpoint, None)
+                    (
+                        endpoint,
+                        Err(format!("Couldn't complete quic connection: {e}")),
+                    )
                 }
             },
             Err(e) => {
                 error!("Couldn't initiate connection to {endpoint}: {e}");
-                (endpoint, None)
+                (endpoint, Err(format!("Couldn't initiate connection: {e}")))
             }
         }
     }
@@ -480,15 +1129,22 @@ impl Inner {
             Ok(listener) => loop {
                 match listener.accept().await {
                     Ok((stream, remote)) => {
-                        let tx_bytes = Arc::new(AtomicU64::new(0));
-                        let rx_bytes = Arc::new(AtomicU64::new(0));
+                        let endpoint = Endpoint::new(Protocol::Tcp, remote);
+                        let ct = ConnectionTraffic::default();
+                        let import_policy = self.inbound_import_policy(&endpoint);
                         let new_peer = match Peer::new(
                             router_data_tx.clone(),
                             router_control_tx.clone(),
                             stream,
                             dead_peer_sink.clone(),
-                            tx_bytes.clone(),
-                            rx_bytes.clone(),
+                            ct.tx_bytes.clone(),
+                            ct.rx_bytes.clone(),
+                            ct.dropped_data_packets.clone(),
+                            ct.decryption_failures.clone(),
+                            self.data_queue_size,
+                            self.bandwidth_limit(None),
+                            self.compression(&ct.compression_saved_bytes),
+                            import_policy.clone(),
                         ) {
                             Ok(peer) => peer,
                             Err(e) => {
@@ -498,9 +1154,10 @@ impl Inner {
                         };
                         info!("Accepted new inbound peer {}", remote);
                         self.add_peer(
-                            Endpoint::new(Protocol::Tcp, remote),
+                            endpoint,
                             PeerType::Inbound,
-                            ConnectionTraffic { tx_bytes, rx_bytes },
+                            ct,
+                            import_policy,
                             Some(new_peer),
                         );
                     }
@@ -544,15 +1201,22 @@ impl Inner {
                 }
             };
 
-            let tx_bytes = Arc::new(AtomicU64::new(0));
-            let rx_bytes = Arc::new(AtomicU64::new(0));
+            let endpoint = Endpoint::new(Protocol::Quic, con.remote_address());
+            let ct = ConnectionTraffic::default();
+            let import_policy = self.inbound_import_policy(&endpoint);
             let new_peer = match Peer::new(
                 router_data_tx.clone(),
                 router_control_tx.clone(),
                 q,
                 dead_peer_sink.clone(),
-                tx_bytes.clone(),
-                rx_bytes.clone(),
+                ct.tx_bytes.clone(),
+                ct.rx_bytes.clone(),
+                ct.dropped_data_packets.clone(),
+                ct.decryption_failures.clone(),
+                self.data_queue_size,
+                self.bandwidth_limit(None),
+                self.compression(&ct.compression_saved_bytes),
+                import_policy.clone(),
             ) {
                 Ok(peer) => peer,
                 Err(e) => {
@@ -562,26 +1226,79 @@ impl Inner {
             };
             info!("Accepted new inbound quic peer {}", con.remote_address());
             self.add_peer(
-                Endpoint::new(Protocol::Quic, con.remote_address()),
+                endpoint,
                 PeerType::Inbound,
-                ConnectionTraffic { tx_bytes, rx_bytes },
+                ct,
+                import_policy,
                 Some(new_peer),
             )
         }
     }
 
+    /// Get the [`ImportPolicy`] for a new inbound connection from the given [`Endpoint`]. If a
+    /// policy was set for an inbound peer with the same address, that policy is used. Otherwise, if
+    /// the connection replaces an existing one, the policy of the existing connection is kept.
+    fn inbound_import_policy(&self, endpoint: &Endpoint) -> Arc<ImportPolicy> {
+        if let Some(policy) = self
+            .inbound_import_policies
+            .lock()
+            .unwrap()
+            .get(&endpoint.address().ip())
+        {
+            return policy.clone();
+        }
+        self.peers
+            .lock()
+            .unwrap()
+            .get(endpoint)
+            .map(|pi| pi.import_policy.clone())
+            .unwrap_or_default()
+    }
+
+    /// Get the counter of bytes saved by compression to pass to a new connection, if compression
+    /// is enabled.
+    fn compression(&self, saved_bytes: &Arc<AtomicU64>) -> Option<Arc<AtomicU64>> {
+        self.compression.then(|| saved_bytes.clone())
+    }
+
+    /// Publish a change to the known peers to all subscribers.
+    fn peer_changed(&self, change: PeerChange) {
+        // Sending only fails if there are no subscribers, in which case nobody needs to know.
+        let _ = self.changes.send(change);
+    }
+
+    /// Get a new identifier for a peer which is not known yet.
+    fn next_peer_id(&self) -> u64 {
+        self.next_peer_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Create the [`BandwidthLimit`] for a new connection to a peer with the given cap. The cap
+    /// of the node is shared with all other connections.
+    fn bandwidth_limit(&self, bandwidth_cap: Option<u64>) -> BandwidthLimit {
+        BandwidthLimit::new(
+            bandwidth_cap.map(TokenBucket::new),
+            self.node_bandwidth.clone(),
+        )
+    }
+
     /// Add a new peer identifier we discovered.
     fn add_peer(
         &self,
         endpoint: Endpoint,
         discovery_type: PeerType,
         con_traffic: ConnectionTraffic,
+        import_policy: Arc<ImportPolicy>,
         peer: Option<Peer>,
     ) {
         let mut peers = self.peers.lock().unwrap();
         // Only if we don't know it yet.
         if let Entry::Vacant(e) = peers.entry(endpoint) {
+            let mut events = PeerEvents::new();
+            if peer.is_some() {
+                events.record(PeerEventKind::Connected, "Accepted inbound connection");
+            }
             e.insert(PeerInfo {
+                id: self.next_peer_id(),
                 pt: discovery_type,
                 connecting: false,
                 pr: if let Some(p) = &peer {
@@ -590,19 +1307,50 @@ impl Inner {
                     PeerRef::new()
                 },
                 connection_attempts: 0,
+                connections: usize::from(peer.is_some()),
                 con_traffic,
+                import_policy,
+                events,
+                bandwidth_cap: None,
+                backoff: Backoff::new(ReconnectPolicy::default()),
             });
             if let Some(p) = peer {
                 self.router.lock().unwrap().add_peer_interface(p);
             }
+            self.peer_changed(PeerChange::Updated(endpoint));
             info!("Added new peer {endpoint}");
         } else if discovery_type == PeerType::Inbound {
             // We got an inbound peer with a duplicate entry. This is possible if the sending port
             // is the same as the previous one, which generally happens with our Quic setup. In
             // this case, the old connection needs to be replaced.
+            let connections = peers
+                .get(&endpoint)
+                .map(|pi| pi.connections)
+                .unwrap_or_default()
+                + usize::from(peer.is_some());
+            // The new connection is the same peer, so it keeps its identifier.
+            let id = peers
+                .get(&endpoint)
+                .map(|pi| pi.id)
+                .unwrap_or_else(|| self.next_peer_id());
+            // Keep the history of the connection which is replaced.
+            let mut events = peers
+                .get_mut(&endpoint)
+                .map(|pi| std::mem::take(&mut pi.events))
+                .unwrap_or_default();
+            if events.connected() {
+                events.record(
+                    PeerEventKind::Disconnected,
+                    "Replaced by a new inbound connection",
+                );
+            }
+            if peer.is_some() {
+                events.record(PeerEventKind::Connected, "Accepted inbound connection");
+            }
             let old_peer_info = peers.insert(
                 endpoint,
                 PeerInfo {
+                    id,
                     pt: discovery_type,
                     connecting: false,
                     pr: if let Some(p) = &peer {
@@ -611,9 +1359,15 @@ impl Inner {
                         PeerRef::new()
                     },
                     connection_attempts: 0,
+                    connections,
                     con_traffic,
+                    import_policy,
+                    events,
+                    bandwidth_cap: None,
+                    backoff: Backoff::new(ReconnectPolicy::default()),
                 },
             );
+            self.peer_changed(PeerChange::Updated(endpoint));
             // If we have a new peer notify insert the new one in the router, then notify it that
             // the old one is dead.
             if let Some(p) = peer {
@@ -637,9 +1391,7 @@ impl Inner {
     async fn local_discovery(self: Arc<Self>, peer_discovery_port: u16) {
         let rid = self.router.lock().unwrap().router_id();
 
-        let multicast_destination = LL_PEER_DISCOVERY_GROUP
-            .parse()
-            .expect("Link local discovery group address is properly defined");
+        let multicast_destination = self.discovery_config.multicast_group;
         let sock = match UdpSocket::bind(SocketAddr::new(
             "::".parse().expect("Valid all interface IPv6 designator"),
             peer_discovery_port,
@@ -664,7 +1416,7 @@ impl Inner {
         let mut joined_interfaces = HashSet::new();
         // Join the multicast discovery group on newly detected interfaces.
         let mut join_new_interfaces = || {
-            let ipv6_nics = list_ipv6_interface_ids()?;
+            let ipv6_nics = list_ipv6_interface_ids(&self.discovery_config.interfaces)?;
             // Keep the existing interfaces, removing interface ids we previously joined but are no
             // longer found when listing ids. We simply discard unknown ids, and assume if the
             // interface is gone (or it's IPv6), that we also implicitly left the group (i.e. no
@@ -791,15 +1543,58 @@ impl Inner {
         self.add_peer(
             Endpoint::new(Protocol::Tcp, remote),
             PeerType::LinkLocalDiscovery,
-            ConnectionTraffic {
-                tx_bytes: Arc::new(AtomicU64::new(0)),
-                rx_bytes: Arc::new(AtomicU64::new(0)),
-            },
+            ConnectionTraffic::default(),
+            Arc::default(),
             None,
         );
     }
 }
 
+/// Disconnect all connected inbound peers which did not send or receive a data packet for at least
+/// `idle_timeout`. Other peers are never disconnected for being idle, since they are reconnected
+/// to anyway. Returns the amount of disconnected peers.
+fn disconnect_idle_peers(peers: &HashMap<Endpoint, PeerInfo>, idle_timeout: Duration) -> usize {
+    let mut disconnected = 0;
+    for (endpoint, pi) in peers {
+        if pi.pt != PeerType::Inbound || !pi.pr.alive() {
+            continue;
+        }
+        let Some(peer) = pi.pr.upgrade() else {
+            continue;
+        };
+        let idle = peer.data_idle_time();
+        if idle >= idle_timeout {
+            info!("Disconnecting inbound peer {endpoint}, no data exchanged for {idle:?}");
+            peer.died();
+            disconnected += 1;
+        }
+    }
+    disconnected
+}
+
+/// Run a connection attempt while holding one of the permits, so at most as many connection
+/// attempts as there are permits run at the same time.
+async fn limit_dial<F: Future>(permits: &Semaphore, dial: F) -> F::Output {
+    let _permit = permits
+        .acquire()
+        .await
+        .expect("Dial permits are never closed; qed");
+    dial.await
+}
+
+/// Spawn a background task which is stopped once the given [`CancellationToken`] is cancelled.
+fn spawn_cancellable<F>(cancel: CancellationToken, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cancel.cancelled() => {}
+            _ = task => {}
+        }
+    });
+}
+
 /// Spawn a quic socket which can be used to both receive quic connections and initiate new quic
 /// connections to remotes.
 fn make_quic_endpoint(
@@ -869,10 +1664,16 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
     }
 }
 
-/// Get a list of the interface identifiers of every network interface with a local IPv6 IP.
-fn list_ipv6_interface_ids() -> Result<HashSet<u32>, Box<dyn std::error::Error>> {
+/// Get a list of the interface identifiers of every network interface with a local IPv6 IP. If
+/// `allowed_names` is not empty, only interfaces with one of the given names are considered.
+fn list_ipv6_interface_ids(
+    allowed_names: &[String],
+) -> Result<HashSet<u32>, Box<dyn std::error::Error>> {
     let mut nics = HashSet::new();
     for nic in network_interface::NetworkInterface::show()? {
+        if !allowed_names.is_empty() && !allowed_names.contains(&nic.name) {
+            continue;
+        }
         for addr in nic.addr {
             if let network_interface::Addr::V6(addr) = addr {
                 // Check if the address is part of fe80::/64
@@ -886,6 +1687,20 @@ fn list_ipv6_interface_ids() -> Result<HashSet<u32>, Box<dyn std::error::Error>>
     Ok(nics)
 }
 
+/// Calculate the quality score of a connected peer, see [`PeerManager`] for how the score is
+/// composed.
+fn quality_score(link_cost: u16, hello_loss: f64, reconnects: usize, uptime: Duration) -> u8 {
+    let latency = 40.
+        * (1. - f64::from(link_cost.min(QUALITY_MAX_LINK_COST)) / f64::from(QUALITY_MAX_LINK_COST));
+    let loss = 30. * (1. - hello_loss.clamp(0., 1.));
+    let stability =
+        15. * (1. - reconnects.min(QUALITY_MAX_RECONNECTS) as f64 / QUALITY_MAX_RECONNECTS as f64);
+    let uptime =
+        15. * (uptime.min(QUALITY_FULL_UPTIME).as_secs_f64() / QUALITY_FULL_UPTIME.as_secs_f64());
+
+    (latency + loss + stability + uptime).round() as u8
+}
+
 impl fmt::Display for PeerExists {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("Peer identified by endpoint already exists")
@@ -901,3 +1716,218 @@ impl fmt::Display for PeerNotFound {
 }
 
 impl std::error::Error for PeerNotFound {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::Ipv6Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::stream::{FuturesUnordered, StreamExt};
+    use tokio::sync::Semaphore;
+
+    use super::{
+        disconnect_idle_peers, limit_dial, quality_score, ConnectionTraffic, PeerInfo, PeerType,
+        QUALITY_FULL_UPTIME,
+    };
+    use crate::endpoint::{Endpoint, Protocol};
+    use crate::events::PeerEvents;
+    use crate::filters::PrefixLists;
+    use crate::packet::DataPacket;
+    use crate::peer::Peer;
+    use crate::reconnect::{Backoff, ReconnectPolicy};
+    use crate::router::Router;
+    use crate::testing;
+
+    /// Create a peer of `router` on a local TCP connection. The remote side of the connection is
+    /// returned as well, so it stays open.
+    async fn connected_peer(router: &Router) -> (Peer, tokio::net::TcpStream) {
+        let (stream, remote) = testing::tcp_pair().await;
+        (testing::router_peer(router, stream), remote)
+    }
+
+    /// Create the info of a known peer of the given type, connected as `peer`.
+    fn peer_info(pt: PeerType, peer: &Peer) -> PeerInfo {
+        PeerInfo {
+            id: 0,
+            pt,
+            connecting: false,
+            pr: peer.refer(),
+            connection_attempts: 0,
+            connections: 1,
+            con_traffic: ConnectionTraffic::default(),
+            import_policy: Arc::default(),
+            events: PeerEvents::new(),
+            bandwidth_cap: None,
+            backoff: Backoff::new(ReconnectPolicy::default()),
+        }
+    }
+
+    #[test]
+    fn quality_score_bounds() {
+        assert_eq!(quality_score(0, 0., 0, QUALITY_FULL_UPTIME), 100);
+        assert_eq!(quality_score(0, 0., 0, QUALITY_FULL_UPTIME * 2), 100);
+        assert_eq!(quality_score(u16::MAX, 1., 100, Duration::ZERO), 0);
+    }
+
+    #[test]
+    fn quality_score_components() {
+        // Half the max link cost, a quarter of hellos lost, 1 reconnect and 30 minutes uptime
+        // gives 20 + 22.5 + 12 + 7.5 points.
+        assert_eq!(quality_score(500, 0.25, 1, QUALITY_FULL_UPTIME / 2), 62);
+    }
+
+    #[tokio::test]
+    async fn dials_respect_concurrency_cap() {
+        const CAP: usize = 2;
+        let permits = Semaphore::new(CAP);
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+
+        let mut dials = (0..10)
+            .map(|_| {
+                limit_dial(&permits, async {
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect::<FuturesUnordered<_>>();
+        let mut finished = 0;
+        while dials.next().await.is_some() {
+            finished += 1;
+        }
+
+        assert_eq!(finished, 10);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), CAP);
+    }
+
+    #[tokio::test]
+    async fn inbound_import_policy_is_kept_for_address() {
+        let (router, _, _tun_rx) = testing::router(None);
+        let pm = testing::peer_manager(router);
+        let endpoint = |addr: &str| Endpoint::new(Protocol::Tcp, addr.parse().unwrap());
+        let first = endpoint("[2001:db8::1]:50000");
+        pm.inner.add_peer(
+            first,
+            PeerType::Inbound,
+            ConnectionTraffic::default(),
+            pm.inner.inbound_import_policy(&first),
+            None,
+        );
+        let prefixes = PrefixLists {
+            allowed: vec!["400::/8".parse().unwrap()],
+            denied: vec![],
+        };
+
+        pm.set_import_policy(&first, prefixes.clone())
+            .expect("Peer exists");
+
+        // A reconnect from a different port gets the same policy, other peers don't.
+        let reconnect = pm
+            .inner
+            .inbound_import_policy(&endpoint("[2001:db8::1]:50001"));
+        assert_eq!(reconnect.prefixes(), prefixes);
+        assert!(Arc::ptr_eq(
+            &reconnect,
+            &pm.import_policy(&first).expect("Peer exists")
+        ));
+        assert_eq!(
+            pm.inner
+                .inbound_import_policy(&endpoint("[2001:db8::2]:50000"))
+                .prefixes(),
+            PrefixLists::default()
+        );
+        assert!(pm
+            .set_import_policy(&endpoint("[2001:db8::3]:50000"), prefixes)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn peer_ids_are_stable_across_reconnects() {
+        let (router, _, _tun_rx) = testing::router(None);
+        let pm = testing::peer_manager(router.clone());
+        let static_peer = Endpoint::new(Protocol::Tcp, "[2001:db8::1]:9651".parse().unwrap());
+        let inbound = Endpoint::new(Protocol::Tcp, "[2001:db8::2]:50000".parse().unwrap());
+        let inbound_connection = |peer| {
+            pm.inner.add_peer(
+                inbound,
+                PeerType::Inbound,
+                ConnectionTraffic::default(),
+                pm.inner.inbound_import_policy(&inbound),
+                Some(peer),
+            )
+        };
+
+        pm.add_peer(static_peer, None, ReconnectPolicy::default())
+            .expect("Peer is new");
+        let (first, _first_conn) = connected_peer(&router).await;
+        inbound_connection(first);
+        let ids = || {
+            let mut ids = pm
+                .peers()
+                .into_iter()
+                .map(|ps| (ps.endpoint, ps.id))
+                .collect::<Vec<_>>();
+            ids.sort_by_key(|(_, id)| *id);
+            ids
+        };
+        let assigned = ids();
+        assert_eq!(assigned.len(), 2);
+        assert_ne!(assigned[0].1, assigned[1].1);
+
+        // The inbound peer reconnects from the same port, which replaces its connection.
+        let (second, _second_conn) = connected_peer(&router).await;
+        inbound_connection(second);
+        assert_eq!(ids(), assigned);
+
+        for (endpoint, id) in assigned {
+            assert_eq!(pm.endpoint_by_id(id), Some(endpoint));
+        }
+        assert_eq!(pm.endpoint_by_id(u64::MAX), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_inbound_peers_are_disconnected() {
+        const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+        let (router, _, _tun_rx) = testing::router(None);
+        let (idle_inbound, _idle_inbound_conn) = connected_peer(&router).await;
+        let (active_inbound, _active_inbound_conn) = connected_peer(&router).await;
+        let (idle_static, _idle_static_conn) = connected_peer(&router).await;
+        let peers = HashMap::from([
+            (
+                Endpoint::new(Protocol::Tcp, "[2001:db8::1]:9651".parse().unwrap()),
+                peer_info(PeerType::Inbound, &idle_inbound),
+            ),
+            (
+                Endpoint::new(Protocol::Tcp, "[2001:db8::2]:9651".parse().unwrap()),
+                peer_info(PeerType::Inbound, &active_inbound),
+            ),
+            (
+                Endpoint::new(Protocol::Tcp, "[2001:db8::3]:9651".parse().unwrap()),
+                peer_info(PeerType::Static, &idle_static),
+            ),
+        ]);
+
+        // Nothing is idle for long enough yet.
+        assert_eq!(disconnect_idle_peers(&peers, IDLE_TIMEOUT), 0);
+
+        tokio::time::advance(IDLE_TIMEOUT).await;
+        active_inbound
+            .send_data_packet(DataPacket {
+                raw_data: vec![0; 100],
+                hop_limit: 64,
+                src_ip: Ipv6Addr::LOCALHOST,
+                dst_ip: Ipv6Addr::LOCALHOST,
+            })
+            .expect("Can queue data packet");
+
+        assert_eq!(disconnect_idle_peers(&peers, IDLE_TIMEOUT), 1);
+        assert!(!idle_inbound.refer().alive());
+        assert!(active_inbound.refer().alive());
+        assert!(idle_static.refer().alive());
+    }
+}
@@ -0,0 +1,267 @@
+//! Backoff of reconnect attempts to peers.
+//!
+//! After connecting to a peer fails, or an existing connection is lost, the next attempt is
+//! delayed. The delay grows exponentially with every successive failure, up to a maximum, and is
+//! randomized a bit so peers which lost their connection at the same time, e.g. after a network
+//! blip, don't all reconnect at the same moment. Once a connection stays up for
+//! [`RECONNECT_GRACE_PERIOD`], the delay starts over.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Amount of time a connection must stay up before the backoff of a peer is reset.
+pub const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Default delay before the first reconnect attempt.
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(5);
+/// Default maximum delay between reconnect attempts.
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Default factor by which the delay grows after every failure.
+const DEFAULT_MULTIPLIER: f64 = 2.;
+/// Default fraction by which the delay is randomly made shorter or longer.
+const DEFAULT_JITTER: f64 = 0.2;
+
+/// Policy for reconnecting to a peer. The first attempt is made after the initial delay, and every
+/// following delay is the previous one multiplied by the multiplier, up to the max delay. Every
+/// delay is randomly changed by up to the jitter fraction of it. Durations are (de)serialized as
+/// an amount of milliseconds, fields which are not set take their default value.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnect attempt.
+    #[serde(
+        rename = "initialDelayMs",
+        serialize_with = "serialize_millis",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub initial_delay: Duration,
+    /// Maximum delay between reconnect attempts.
+    #[serde(
+        rename = "maxDelayMs",
+        serialize_with = "serialize_millis",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub max_delay: Duration,
+    /// Factor by which the delay grows after every failure.
+    pub multiplier: f64,
+    /// Fraction of the delay, between 0 and 1, by which it is randomly made shorter or longer.
+    pub jitter: f64,
+}
+
+/// Marker error to indicate a [`ReconnectPolicy`] is not valid.
+#[derive(Debug)]
+pub struct InvalidReconnectPolicy;
+
+impl ReconnectPolicy {
+    /// Check that the policy can be used. The delays must not be zero, the delay may not shrink,
+    /// i.e. the multiplier must be at least 1 and the max delay must not be less than the initial
+    /// delay, and the jitter must be between 0 and 1.
+    pub fn validate(&self) -> Result<(), InvalidReconnectPolicy> {
+        if self.initial_delay.is_zero()
+            || self.max_delay < self.initial_delay
+            || !self.multiplier.is_finite()
+            || self.multiplier < 1.
+            || !(0. ..=1.).contains(&self.jitter)
+        {
+            return Err(InvalidReconnectPolicy);
+        }
+        Ok(())
+    }
+
+    /// The delay before the reconnect attempt after the given amount of successive failures,
+    /// without jitter.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let delay = self.initial_delay.as_secs_f64()
+            * self
+                .multiplier
+                .powi(failures.saturating_sub(1).min(i32::MAX as u32) as i32);
+        // If the delay overflows it becomes infinite, which is capped as well.
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+
+    /// Randomly make `delay` shorter or longer, by at most the jitter fraction of it.
+    fn jittered(&self, delay: Duration) -> Duration {
+        let jitter = self.jitter.clamp(0., 1.);
+        delay.mul_f64(1. + rand::thread_rng().gen_range(-jitter..=jitter))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            multiplier: DEFAULT_MULTIPLIER,
+            jitter: DEFAULT_JITTER,
+        }
+    }
+}
+
+impl fmt::Display for InvalidReconnectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            "invalid reconnect policy, delays must be larger than 0, the max delay must be at least the initial delay, the multiplier must be at least 1, and the jitter must be between 0 and 1",
+        )
+    }
+}
+
+impl std::error::Error for InvalidReconnectPolicy {}
+
+/// Serialize a [`Duration`] as an amount of milliseconds.
+fn serialize_millis<S: serde::Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(duration.as_millis() as u64)
+}
+
+/// Deserialize a [`Duration`] from an amount of milliseconds.
+fn deserialize_millis<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    u64::deserialize(d).map(Duration::from_millis)
+}
+
+/// Backoff state of reconnecting to a single peer.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Policy used to compute the delays.
+    policy: ReconnectPolicy,
+    /// Amount of successive failed connection attempts and lost connections.
+    failures: u32,
+    /// Earliest time of the next connection attempt, if it is delayed.
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    /// Create a new `Backoff`, which allows connecting right away.
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+            next_attempt: None,
+        }
+    }
+
+    /// Amount of successive failed connection attempts and lost connections since the backoff
+    /// was last reset.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Record that connecting failed, or a connection was lost, at `now`. The next attempt is
+    /// delayed according to the policy.
+    pub fn failed(&mut self, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        let delay = self.policy.jittered(self.policy.delay(self.failures));
+        self.next_attempt = Some(now + delay);
+    }
+
+    /// Start over with the initial delay, and allow connecting right away.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.next_attempt = None;
+    }
+
+    /// Check if a connection attempt is allowed at `now`.
+    pub fn ready(&self, now: Instant) -> bool {
+        self.next_attempt.map_or(true, |next| next <= now)
+    }
+
+    /// Amount of time from `now` until the next connection attempt is allowed, if it is delayed.
+    pub fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.next_attempt
+            .filter(|next| *next > now)
+            .map(|next| next - now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Backoff, ReconnectPolicy};
+
+    fn policy(jitter: f64) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn delay_grows_up_to_max() {
+        let policy = policy(0.);
+
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(4), Duration::from_secs(8));
+        assert_eq!(policy.delay(5), Duration::from_secs(10));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn policy_is_validated() {
+        assert!(ReconnectPolicy::default().validate().is_ok());
+        assert!(policy(1.).validate().is_ok());
+        assert!(policy(1.5).validate().is_err());
+        assert!(policy(-0.1).validate().is_err());
+        assert!(ReconnectPolicy {
+            multiplier: 0.5,
+            ..policy(0.)
+        }
+        .validate()
+        .is_err());
+        assert!(ReconnectPolicy {
+            initial_delay: Duration::ZERO,
+            ..policy(0.)
+        }
+        .validate()
+        .is_err());
+        assert!(ReconnectPolicy {
+            max_delay: Duration::from_millis(999),
+            ..policy(0.)
+        }
+        .validate()
+        .is_err());
+        assert!(ReconnectPolicy {
+            max_delay: Duration::from_secs(1),
+            ..policy(0.)
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn backoff_delays_attempts_until_reset() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(policy(0.));
+        assert!(backoff.ready(now));
+        assert_eq!(backoff.remaining(now), None);
+
+        backoff.failed(now);
+        backoff.failed(now);
+        assert_eq!(backoff.failures(), 2);
+        assert!(!backoff.ready(now));
+        assert_eq!(backoff.remaining(now), Some(Duration::from_secs(2)));
+        assert!(backoff.ready(now + Duration::from_secs(2)));
+
+        backoff.reset();
+        assert_eq!(backoff.failures(), 0);
+        assert!(backoff.ready(now));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(policy(0.5));
+        for _ in 0..100 {
+            backoff.reset();
+            backoff.failed(now);
+            let delay = backoff.remaining(now).unwrap_or_default();
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1500));
+        }
+    }
+}
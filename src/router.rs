@@ -1,6 +1,7 @@
 use crate::{
     babel::{self, RouteRequest, SeqNoRequest},
     crypto::{PacketBuffer, PublicKey, SecretKey, SharedSecret},
+    events::{DropEvents, DropKind, PacketDropLog, PacketDropReason},
     filters::RouteUpdateFilter,
     metric::Metric,
     packet::{ControlPacket, DataPacket},
@@ -17,10 +18,16 @@ use etherparse::{
 };
 use left_right::{ReadHandle, WriteHandle};
 use log::{debug, error, info, trace, warn};
+use rand::Rng;
 use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BTreeSet, HashMap},
     error::Error,
     net::IpAddr,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
     time::{Duration, Instant},
 };
 use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
@@ -44,6 +51,11 @@ const DEAD_PEER_THRESHOLD: Duration = Duration::from_secs(HELLO_INTERVAL * 2 + 3
 /// time since the last IHU exceeds DEAD_PEER_THRESHOLD.
 const DEAD_PEER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
+/// Amount of hellos sent to a peer when it is probed.
+const PROBE_COUNT: u32 = 3;
+/// Maximum amount of time to wait for the IHU in reply to a hello sent to probe a peer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Amount of time to wait between consecutive seqno bumps of the local router seqno.
 const SEQNO_BUMP_TIMEOUT: Duration = Duration::from_secs(4);
 
@@ -53,6 +65,9 @@ const BIG_METRIC_CHANGE_TRESHOLD: Metric = Metric::new(10);
 /// The amount a metric of a route needs to improve before we will consider switching to it.
 const SIGNIFICANT_METRIC_IMPROVEMENT: Metric = Metric::new(10);
 
+/// The maximum percentage by which the intervals of periodic control packets are shortened.
+pub const MAX_CONTROL_JITTER: u8 = 50;
+
 #[derive(Clone)]
 pub struct Router {
     inner_w: Arc<Mutex<WriteHandle<RouterInner, RouterOpLogEntry>>>,
@@ -64,29 +79,181 @@ pub struct Router {
     static_routes: Vec<Subnet>,
     router_id: RouterId,
     node_keypair: (SecretKey, PublicKey),
-    router_data_tx: Sender<DataPacket>,
+    router_data_tx: Sender<(DataPacket, Peer)>,
     router_control_tx: UnboundedSender<(ControlPacket, Peer)>,
-    node_tun: UnboundedSender<DataPacket>,
+    /// Channel for data packets for this node, along with the peer they were received from, if
+    /// they did not originate from this node.
+    node_tun: UnboundedSender<(DataPacket, Option<Peer>)>,
     node_tun_subnet: Subnet,
     update_filters: Arc<Vec<Box<dyn RouteUpdateFilter + Send + Sync>>>,
     /// Channel injected into peers, so they can notify the router if they exit.
     dead_peer_sink: mpsc::Sender<Peer>,
     /// Channel to notify the router of expired SourceKey's.
     expired_source_key_sink: mpsc::Sender<SourceKey>,
+    /// Counter used to spread message packets originating from this node round robin over all
+    /// equal routes to the destination. If this is not set, the selected route is always used.
+    message_round_robin: Option<Arc<AtomicUsize>>,
+    /// Only keep the selected route for a subnet, and drop all other routes.
+    disable_fallback_routes: bool,
+    /// Recent events of things which have been dropped or rejected by the node.
+    drop_events: DropEvents,
+    /// Counts and logs dropped data packets.
+    packet_drop_log: PacketDropLog,
+    /// The maximum amount of routes kept in the routing table, if limited.
+    max_routes: Option<usize>,
+    /// Amount of fallback routes evicted to make room for new routes.
+    route_evictions: Arc<AtomicU64>,
+    /// The maximum metric of a route which can be selected, if limited.
+    max_metric: Option<Metric>,
+    /// Percentage by which the intervals of periodic control packets are randomly shortened.
+    control_jitter: u8,
+    /// Uptime and readiness of the router.
+    status: RouterStatus,
+}
+
+/// Uptime and readiness of a [`Router`]. This can be checked without access to the router
+/// itself, so a busy router does not delay health checks. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct RouterStatus {
+    /// Settings which determine when the router is ready.
+    readiness: ReadinessConfig,
+    /// Time at which the router was created.
+    created: Instant,
+    /// Set once the router is ready. A router stays ready once it is ready.
+    ready: Arc<AtomicBool>,
+}
+
+impl RouterStatus {
+    /// Create the status of a new router, which becomes ready according to `readiness`.
+    fn new(readiness: ReadinessConfig) -> Self {
+        Self {
+            readiness,
+            created: Instant::now(),
+            ready: Arc::new(AtomicBool::new(readiness.min_routes == 0)),
+        }
+    }
+
+    /// Get the amount of time since the router was created.
+    pub fn uptime(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Checks if the router is ready. The router becomes ready the first time the configured
+    /// minimum amount of routes is selected, or once the configured timeout expired. This is
+    /// distinct from liveness: once ready, the router stays ready, even if routes are lost later.
+    pub fn ready(&self) -> bool {
+        if self.ready.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        match self.readiness.timeout {
+            Some(timeout) if self.created.elapsed() >= timeout => {
+                info!("Router is ready since the readiness timeout expired");
+                self.ready.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Result of actively probing a [`Peer`].
+#[derive(Debug, Clone, Copy)]
+pub struct PeerProbe {
+    /// Average round trip time of the probes which were answered, if any.
+    pub rtt: Option<Duration>,
+    /// Fraction of the probes which were not answered, as a value between 0 and 1.
+    pub loss: f64,
+}
+
+/// Settings which determine when a [`Router`] is ready, i.e. when the initial route convergence
+/// is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadinessConfig {
+    /// Amount of routes which need to be selected before the router is ready.
+    pub min_routes: usize,
+    /// Amount of time after which the router is ready, even if not enough routes have been
+    /// selected. If this is not set, the router only becomes ready once enough routes are
+    /// selected.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            min_routes: 1,
+            timeout: None,
+        }
+    }
+}
+
+/// Statistics about the routing table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteStats {
+    /// Total amount of routes in the routing table.
+    pub total: usize,
+    /// Amount of selected routes.
+    pub selected: usize,
+    /// Amount of fallback routes.
+    pub fallback: usize,
+    /// The maximum amount of routes kept in the routing table, if limited.
+    pub max_routes: Option<usize>,
+    /// Amount of fallback routes evicted to make room for new routes.
+    pub evictions: u64,
 }
 
 impl Router {
+    /// Create a new `Router`.
+    ///
+    /// If `balance_messages` is set, message packets originating from this node are spread round
+    /// robin over the selected route and all feasible routes with the same metric, instead of
+    /// always using the selected route.
+    ///
+    /// If `disable_fallback_routes` is set, only the selected route is kept for every subnet.
+    /// Routes which are not selected are removed immediately, instead of being retained as
+    /// fallback. Retracted routes are still kept until they expire, and if no route is selected
+    /// for a subnet, its routes are kept as well. This makes the routing table predictable, at the
+    /// cost of slower recovery: if the selected route is lost, a new route can only be selected
+    /// once a neighbour announces one again, rather than switching to a known fallback route right
+    /// away.
+    ///
+    /// If `max_routes` is set, the routing table holds at most this many routes. When a new route
+    /// is learned while the table is full, the fallback route with the highest metric is evicted
+    /// to make room for it, or the oldest of them if several have the same metric. Selected routes are never evicted, so if there are no fallback routes
+    /// left, the new route is not accepted.
+    ///
+    /// If `max_metric` is set, routes with a higher metric, including the link cost to the
+    /// neighbour, are never selected, even if they are the only route to a subnet. Such routes
+    /// are still kept as fallback, so they can be selected if their metric improves.
+    ///
+    /// The router becomes ready once it selected routes as specified by `readiness`, see
+    /// [`Router::ready`].
+    ///
+    /// Dropped data packets are logged at info level at most `drop_log_rate` times per second if
+    /// it is set, and at debug level otherwise, see [`PacketDropLog`].
+    ///
+    /// The intervals between periodic hellos and route updates are randomly shortened by up to
+    /// `control_jitter` percent, capped at [`MAX_CONTROL_JITTER`], so nodes don't synchronize
+    /// their control traffic.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        node_tun: UnboundedSender<DataPacket>,
+        node_tun: UnboundedSender<(DataPacket, Option<Peer>)>,
         node_tun_subnet: Subnet,
         static_routes: Vec<Subnet>,
         node_keypair: (SecretKey, PublicKey),
         update_filters: Vec<Box<dyn RouteUpdateFilter + Send + Sync>>,
+        balance_messages: bool,
+        disable_fallback_routes: bool,
+        max_routes: Option<usize>,
+        max_metric: Option<Metric>,
+        readiness: ReadinessConfig,
+        drop_log_rate: Option<u32>,
+        control_jitter: u8,
     ) -> Result<Self, Box<dyn Error>> {
         // Tx is passed onto each new peer instance. This enables peers to send control packets to the router.
         let (router_control_tx, router_control_rx) = mpsc::unbounded_channel();
         // Tx is passed onto each new peer instance. This enables peers to send data packets to the router.
-        let (router_data_tx, router_data_rx) = mpsc::channel::<DataPacket>(1000);
+        let (router_data_tx, router_data_rx) = mpsc::channel::<(DataPacket, Peer)>(1000);
         let (expired_source_key_sink, expired_source_key_stream) = mpsc::channel(1);
         let (expired_route_entry_sink, expired_route_entry_stream) = mpsc::channel(1);
         let (dead_peer_sink, dead_peer_stream) = mpsc::channel(1);
@@ -112,6 +279,19 @@ impl Router {
             dead_peer_sink,
             expired_source_key_sink,
             update_filters: Arc::new(update_filters),
+            message_round_robin: if balance_messages {
+                Some(Arc::new(AtomicUsize::new(0)))
+            } else {
+                None
+            },
+            disable_fallback_routes,
+            drop_events: DropEvents::new(),
+            packet_drop_log: PacketDropLog::new(drop_log_rate),
+            max_routes,
+            route_evictions: Arc::new(AtomicU64::new(0)),
+            max_metric,
+            control_jitter,
+            status: RouterStatus::new(readiness),
         };
 
         tokio::spawn(Router::start_periodic_hello_sender(router.clone()));
@@ -147,7 +327,7 @@ impl Router {
         self.router_control_tx.clone()
     }
 
-    pub fn router_data_tx(&self) -> Sender<DataPacket> {
+    pub fn router_data_tx(&self) -> Sender<(DataPacket, Peer)> {
         self.router_data_tx.clone()
     }
 
@@ -155,7 +335,7 @@ impl Router {
         self.node_tun_subnet
     }
 
-    pub fn node_tun(&self) -> UnboundedSender<DataPacket> {
+    pub fn node_tun(&self) -> UnboundedSender<(DataPacket, Option<Peer>)> {
         self.node_tun.clone()
     }
 
@@ -165,6 +345,12 @@ impl Router {
     }
 
     /// Add a peer interface to the router.
+    ///
+    /// A full route table dump is requested from the peer, so routes through it are installed
+    /// without waiting for the next periodic update. For a peer which reconnects, this restores
+    /// the routes which were retracted when the previous connection died. Those routes typically
+    /// come back with the same seqno and metric, which the source table, as it is kept across the
+    /// reconnect, still considers feasible.
     pub fn add_peer_interface(&self, peer: Peer) {
         debug!("Adding peer {} to router", peer.connection_identifier());
         self.peer_interfaces.write().unwrap().push(peer.clone());
@@ -192,8 +378,12 @@ impl Router {
         self.router_id
     }
 
-    /// Get the [`PublicKey`] for an [`IpAddr`] if a route exists to the IP.
+    /// Get the [`PublicKey`] for an [`IpAddr`] if a route exists to the IP. For an IP in our own
+    /// subnet, this is our own key.
     pub fn get_pubkey(&self, ip: IpAddr) -> Option<PublicKey> {
+        if self.node_tun_subnet.contains_ip(ip) {
+            return Some(self.node_keypair.1);
+        }
         self.inner_r
             .enter()
             .expect("Write handle is saved on router so it is not dropped before the read handles")
@@ -203,8 +393,12 @@ impl Router {
             .copied()
     }
 
-    /// Gets the cached [`SharedSecret`] for the remote.
+    /// Gets the cached [`SharedSecret`] for the remote. If the destination is in our own subnet,
+    /// the secret shared with ourselves is returned, so packets can be looped back.
     pub fn get_shared_secret_from_dest(&self, dest: IpAddr) -> Option<SharedSecret> {
+        if self.node_tun_subnet.contains_ip(dest) {
+            return Some(self.node_keypair.0.shared_secret(&self.node_keypair.1));
+        }
         self.inner_r
             .enter()
             .expect("Write handle is saved on router so it is not dropped before the read handles")
@@ -237,6 +431,126 @@ impl Router {
         self.peer_interfaces.write().unwrap().retain(|p| p != peer);
     }
 
+    /// Get the recent events of things which have been dropped or rejected by the node.
+    pub fn drop_events(&self) -> &DropEvents {
+        &self.drop_events
+    }
+
+    /// Get the log of dropped data packets.
+    pub fn packet_drop_log(&self) -> &PacketDropLog {
+        &self.packet_drop_log
+    }
+
+    /// Get statistics about the routing table.
+    pub fn route_stats(&self) -> RouteStats {
+        let inner = self
+            .inner_r
+            .enter()
+            .expect("Write handle is saved on router so it is not dropped before the read handles");
+
+        let total = inner.routing_table.len();
+        let selected = inner
+            .routing_table
+            .iter()
+            .filter(|(_, _, re)| re.selected())
+            .count();
+
+        RouteStats {
+            total,
+            selected,
+            fallback: total - selected,
+            max_routes: self.max_routes,
+            evictions: self.route_evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Get the maximum amount of routes kept in the routing table, if it is limited.
+    pub fn max_routes(&self) -> Option<usize> {
+        self.max_routes
+    }
+
+    /// Get the maximum metric of a route which can be selected, if it is limited.
+    pub fn max_metric(&self) -> Option<Metric> {
+        self.max_metric
+    }
+
+    /// Get the amount of time since the router was created.
+    pub fn uptime(&self) -> Duration {
+        self.status.uptime()
+    }
+
+    /// Get the percentage by which the intervals of periodic control packets are randomly shortened.
+    pub fn control_jitter(&self) -> u8 {
+        self.control_jitter
+    }
+
+    /// Checks if the router is ready, see [`RouterStatus::ready`].
+    pub fn ready(&self) -> bool {
+        self.status.ready()
+    }
+
+    /// Get the uptime and readiness of the router, which can be checked without access to the
+    /// router.
+    pub fn status(&self) -> RouterStatus {
+        self.status.clone()
+    }
+
+    /// Mark the router as ready if enough routes are selected.
+    fn update_readiness(&self) {
+        if self.status.ready.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let selected = self
+            .inner_r
+            .enter()
+            .expect("Write handle is saved on router so it is not dropped before the read handles")
+            .routing_table
+            .iter()
+            .filter(|(_, _, re)| re.selected() && !re.metric().is_infinite())
+            .count();
+        if selected >= self.status.readiness.min_routes {
+            info!("Router is ready after selecting {selected} routes");
+            self.status.ready.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the [`PublicKey`] of all directly connected neighbours, alongside the connection
+    /// identifier of the neighbour. The key of a neighbour is only known once it announced its own
+    /// subnet to us.
+    pub fn neighbour_keys(&self) -> Vec<(String, PublicKey)> {
+        let peers = self.peer_interfaces.read().unwrap();
+        let inner = self
+            .inner_r
+            .enter()
+            .expect("Write handle is saved on router so it is not dropped before the read handles");
+
+        inner
+            .routing_table
+            .iter()
+            // A neighbour announces its own subnet without any hop costs.
+            .filter(|(_, _, re)| re.metric() == Metric::from(0) && peers.contains(re.neighbour()))
+            .map(|(_, _, re)| {
+                (
+                    re.neighbour().connection_identifier().clone(),
+                    re.source().router_id().to_pubkey(),
+                )
+            })
+            .collect()
+    }
+
+    /// Get the amount of times the selected next hop for the given [`Subnet`] changed since a
+    /// route to the subnet first appeared. A high amount indicates an unstable path.
+    pub fn reselect_count(&self, subnet: Subnet) -> u64 {
+        self.inner_r
+            .enter()
+            .expect("Write handle is saved on router so it is not dropped before the read handles")
+            .next_hop_changes
+            .get(&subnet)
+            .map(|(_, changes)| *changes)
+            .unwrap_or(0)
+    }
+
     /// Get a list of all selected route entries.
     pub fn load_selected_routes(&self) -> Vec<RouteEntry> {
         let inner = self
@@ -252,7 +566,27 @@ impl Router {
             .collect()
     }
 
-    /// Get a list of all fallback route entries.
+    /// Get the selected route entry for exactly the given subnet, if there is one. Routes for
+    /// larger subnets which contain the given subnet are not considered.
+    pub fn load_selected_route(&self, subnet: Subnet) -> Option<RouteEntry> {
+        // The routing table only holds IPv6 subnets.
+        if !subnet.network().is_ipv6() {
+            return None;
+        }
+        let inner = self
+            .inner_r
+            .enter()
+            .expect("Write handle is saved on router so it is not dropped before the read handles");
+
+        inner
+            .routing_table
+            .entries(subnet)
+            .into_iter()
+            .find(|re| re.selected())
+    }
+
+    /// Get a list of all fallback route entries. This is always empty if fallback routes are
+    /// disabled.
     pub fn load_fallback_routes(&self) -> Vec<RouteEntry> {
         let inner = self
             .inner_r
@@ -339,8 +673,66 @@ impl Router {
         }
     }
 
-    /// Run route selection for a given subnet
-    fn route_selection(&self, subnet: Subnet) {
+    /// Run route selection for every subnet in the routing table, and return the amount of
+    /// subnets for which a different route is selected as a result.
+    pub fn reselect_routes(&self) -> usize {
+        let subnets = self
+            .inner_r
+            .enter()
+            .expect("Write handle is saved on router so it is not dropped before the read handles")
+            .routing_table
+            .iter()
+            .map(|(rk, _, _)| rk.subnet())
+            .collect::<BTreeSet<_>>();
+
+        info!("Forcing route selection for {} subnets", subnets.len());
+        subnets
+            .into_iter()
+            .filter(|subnet| self.route_selection(*subnet))
+            .count()
+    }
+
+    /// Compute the selected routes as they would be if the given peer died, without changing
+    /// anything. Like [`Router::handle_dead_peer`], the routes through the peer are retracted, and
+    /// route selection runs for the subnets they lead to. Subnets without any other route keep
+    /// the retracted route, so they are returned with an infinite metric.
+    pub fn simulate_peer_removal(&self, peer: &Peer) -> Vec<RouteEntry> {
+        let inner = self
+            .inner_r
+            .enter()
+            .expect("Write handle is saved on router so it is not dropped before the read handles");
+
+        let subnets = inner
+            .routing_table
+            .iter()
+            .map(|(rk, _, _)| rk.subnet())
+            .collect::<BTreeSet<_>>();
+
+        subnets
+            .into_iter()
+            .filter_map(|subnet| {
+                let mut routes = inner.routing_table.entries(subnet);
+                let current = routes.iter().find(|re| re.selected()).cloned();
+                if !routes.iter().any(|re| re.neighbour() == peer) {
+                    return current;
+                }
+                for re in routes.iter_mut().filter(|re| re.neighbour() == peer) {
+                    re.update_metric(Metric::infinite());
+                }
+                let current = current.and_then(|current| {
+                    routes.iter().find(|re| {
+                        re.source() == current.source() && re.neighbour() == current.neighbour()
+                    })
+                });
+                let mut best = self.find_best_route(&routes, current)?.clone();
+                best.set_selected(true);
+                Some(best)
+            })
+            .collect()
+    }
+
+    /// Run route selection for a given subnet. Returns true if a different route is selected.
+    fn route_selection(&self, subnet: Subnet) -> bool {
         debug!("Running route selection for {subnet}");
         let mut inner_w = self.inner_w.lock().unwrap();
 
@@ -352,7 +744,7 @@ impl Router {
 
         // No routes for subnet, nothing to do here.
         if routes.is_empty() {
-            return;
+            return false;
         }
 
         // If there is no selected route there is nothing to do here. We keep expired routes in the
@@ -369,7 +761,7 @@ impl Router {
                 debug!(
                     "New selected route for {subnet} is the same as the route alreayd installed"
                 );
-                return;
+                return false;
             }
 
             if new_selected.metric().is_infinite()
@@ -377,7 +769,7 @@ impl Router {
                 && routes[0].selected()
             {
                 debug!("New selected route for {subnet} is retracted, like the previously selected route");
-                return;
+                return false;
             }
 
             inner_w.append(RouterOpLogEntry::SelectRoute(RouteKey::new(
@@ -385,9 +777,14 @@ impl Router {
                 new_selected.neighbour().clone(),
             )));
             inner_w.publish();
+            drop(inner_w);
 
+            self.update_readiness();
             self.trigger_update(subnet);
+            return true;
         }
+
+        false
     }
 
     /// Remove expired source keys from the router state.
@@ -468,6 +865,7 @@ impl Router {
                             r.neighbour().clone(),
                         )))
                         .publish();
+                    self.update_readiness();
                     // If the entry wasn't retracted yet, notify our peers.
                     if !entry.metric().is_infinite() {
                         self.trigger_update(subnet);
@@ -525,11 +923,12 @@ impl Router {
     fn handle_incoming_ihu(&self, _: babel::Ihu, source_peer: Peer) {
         // reset the IHU timer associated with the peer
         // measure time between Hello and and IHU and set the link cost
-        let time_diff = tokio::time::Instant::now()
-            .duration_since(source_peer.time_last_received_hello())
-            .as_millis();
+        let rtt =
+            tokio::time::Instant::now().duration_since(source_peer.time_last_received_hello());
 
-        source_peer.set_link_cost(time_diff as u16);
+        source_peer.set_link_cost(rtt.as_millis() as u16);
+        source_peer.record_latency(rtt);
+        source_peer.ihu_received();
 
         // set the last_received_ihu for this peer
         source_peer.set_time_last_received_ihu(tokio::time::Instant::now());
@@ -765,13 +1164,15 @@ impl Router {
         let source_table = self.source_table.read().unwrap();
         let best = routes
             .iter()
-            .filter(|re| source_table.route_feasible(re))
+            .filter(|re| source_table.route_feasible(re) && self.metric_acceptable(re))
             .min_by_key(|re| re.metric() + Metric::from(re.neighbour().link_cost()));
 
         if let (Some(best), Some(current)) = (best, current) {
             // If we swap to an actually different route, only do so if the metric is
-            // significantly better OR if it is directly connected (metric 0).
+            // significantly better OR if it is directly connected (metric 0). A current route
+            // which exceeds the maximum metric is always replaced.
             if (best.source() != current.source() || best.neighbour() != current.neighbour())
+                && self.metric_acceptable(current)
                 && !(best.metric() + Metric::from(best.neighbour().link_cost())
                     < current.metric() + Metric::from(current.neighbour().link_cost())
                         - SIGNIFICANT_METRIC_IMPROVEMENT
@@ -785,16 +1186,56 @@ impl Router {
         best
     }
 
+    /// Checks if the metric of a route, including the link cost to the neighbour, does not exceed
+    /// the configured maximum metric. Retracted routes are always acceptable, so retractions are
+    /// still propagated.
+    fn metric_acceptable(&self, re: &RouteEntry) -> bool {
+        match self.max_metric {
+            Some(max) => {
+                re.metric().is_infinite()
+                    || re.metric() + Metric::from(re.neighbour().link_cost()) <= max
+            }
+            None => true,
+        }
+    }
+
     /// Handle a received update TLV
     fn handle_incoming_update(&self, update: babel::Update, source_peer: Peer) {
         // Check if we actually allow this update based on filters.
         for filter in &*self.update_filters {
             if !filter.allow(&update) {
                 debug!("Update denied by filter");
+                self.drop_events.record(
+                    DropKind::FilteredRoute,
+                    format!(
+                        "Update for {} from {} denied by filter",
+                        update.subnet(),
+                        source_peer.connection_identifier()
+                    ),
+                );
                 return;
             }
         }
 
+        // Retractions are always accepted, so routes installed before the import policy of the
+        // peer changed can still be removed.
+        if !update.metric().is_infinite() && !source_peer.import_policy().check(&update.subnet()) {
+            debug!(
+                "Update for {} denied by import policy of peer {}",
+                update.subnet(),
+                source_peer.connection_identifier()
+            );
+            self.drop_events.record(
+                DropKind::FilteredRoute,
+                format!(
+                    "Update for {} from {} denied by import policy",
+                    update.subnet(),
+                    source_peer.connection_identifier()
+                ),
+            );
+            return;
+        }
+
         let metric = update.metric();
         let router_id = update.router_id();
         let seqno = update.seqno();
@@ -897,8 +1338,11 @@ impl Router {
                 metric,
                 router_id,
             ));
-            // If the update is unfeasible the route must be unselected.
-            if existing_entry.selected() && !update_feasible {
+            // If the update is unfeasible, or the new metric is too high, the route must be
+            // unselected.
+            if existing_entry.selected()
+                && (!update_feasible || !self.metric_acceptable(existing_entry))
+            {
                 existing_entry.set_selected(false);
                 inner_w.append(RouterOpLogEntry::UnselectRoute(rk));
             }
@@ -909,6 +1353,47 @@ impl Router {
                 return;
             }
 
+            // Make room for the new route if the routing table is full.
+            if let Some(max_routes) = self.max_routes {
+                let victim = {
+                    let inner = inner_w
+                        .enter()
+                        .expect("We deref through a write handle so this enter never fails");
+                    if inner.routing_table.len() < max_routes {
+                        None
+                    } else {
+                        // Routes for the subnet of the update are not considered, as route
+                        // selection for it still needs to run on the loaded entries. The route
+                        // with the highest metric is the least likely to ever be selected, so it
+                        // goes first regardless of its age. Of equally bad routes, the oldest is
+                        // evicted.
+                        let victim = inner
+                            .routing_table
+                            .iter()
+                            .filter(|(rk, _, re)| !re.selected() && rk.subnet() != subnet)
+                            .max_by_key(|(_, _, re)| (re.metric(), Reverse(re.learned())))
+                            .map(|(rk, _, _)| rk);
+                        if victim.is_none() {
+                            debug!("Routing table is full, ignoring new route to {subnet}");
+                            self.drop_events.record(
+                                DropKind::RouteTableFull,
+                                format!(
+                                    "New route to {subnet} via {} rejected since the routing table is full",
+                                    source_peer.connection_identifier()
+                                ),
+                            );
+                            return;
+                        }
+                        victim
+                    }
+                };
+                if let Some(victim) = victim {
+                    debug!("Routing table is full, evicting fallback route {victim}");
+                    self.route_evictions.fetch_add(1, Ordering::Relaxed);
+                    inner_w.append(RouterOpLogEntry::RemoveRoute(victim));
+                }
+            }
+
             // Create new entry in the route table
             let re = RouteEntry::new(
                 SourceKey::new(subnet, router_id),
@@ -942,8 +1427,34 @@ impl Router {
             )));
         }
 
+        // Without fallback routes, every route other than the selected one is dropped. Retracted
+        // routes are kept until they expire, like they are with fallback routes, so the retraction
+        // is not forgotten while it is still being propagated. If no route is selected, nothing is
+        // dropped, as the previously selected route is still needed to retract it, and it can be
+        // selected again if its metric improves.
+        if let (true, Some(selected)) = (self.disable_fallback_routes, new_selected_route) {
+            for re in routing_table_entries
+                .iter()
+                .filter(|re| re.neighbour() != selected.neighbour() && !re.metric().is_infinite())
+            {
+                debug!(
+                    "Removing fallback route to {subnet} via {}",
+                    re.neighbour().connection_identifier()
+                );
+                inner_w.append(RouterOpLogEntry::RemoveRoute(RouteKey::new(
+                    subnet,
+                    re.neighbour().clone(),
+                )));
+            }
+        }
+
         // Already publish here, we won't make any other adjustments to the routing table.
         inner_w.publish();
+        drop(inner_w);
+
+        if new_selected_route.is_some() {
+            self.update_readiness();
+        }
 
         // At this point we are done, though we would like to understand if we need to send a
         // triggered update to our peers. This is done if there is a sufficiently large change. We
@@ -1009,7 +1520,27 @@ impl Router {
         false
     }
 
-    pub fn route_packet(&self, mut data_packet: DataPacket) {
+    pub fn route_packet(&self, data_packet: DataPacket) {
+        self.forward_packet(data_packet, None, None)
+    }
+
+    /// Route a message packet which originates from this node. If message load balancing is
+    /// enabled, successive packets are spread round robin over all equal routes to the
+    /// destination.
+    pub fn route_message_packet(&self, data_packet: DataPacket) {
+        self.forward_packet(data_packet, self.message_round_robin.as_deref(), None)
+    }
+
+    /// Forward a packet to the next hop, or to the TUN interface if it is for us. If a round robin
+    /// counter is given, the next hop is selected round robin from all equal routes to the
+    /// destination, otherwise the selected route is used. Packets for us are passed on along with
+    /// the peer they were received `from`, if any.
+    fn forward_packet(
+        &self,
+        mut data_packet: DataPacket,
+        round_robin: Option<&AtomicUsize>,
+        from: Option<Peer>,
+    ) {
         let node_tun_subnet = self.node_tun_subnet();
 
         trace!(
@@ -1025,18 +1556,30 @@ impl Router {
         data_packet.hop_limit -= 1;
 
         if node_tun_subnet.contains_ip(data_packet.dst_ip.into()) {
-            if let Err(e) = self.node_tun().send(data_packet) {
+            if let Err(e) = self.node_tun().send((data_packet, from)) {
                 error!("Error sending data packet to TUN interface: {:?}", e);
             }
         } else {
-            match self.select_best_route(IpAddr::V6(data_packet.dst_ip)) {
+            let dst = IpAddr::V6(data_packet.dst_ip);
+            let route = match round_robin {
+                Some(counter) => round_robin_pick(&self.select_equal_routes(dst), counter).cloned(),
+                None => self.select_best_route(dst),
+            };
+            match route {
                 Some(route_entry) => {
-                    if let Err(e) = route_entry.neighbour().send_data_packet(data_packet) {
-                        error!(
-                            "Error sending data packet to peer {}: {:?}",
-                            route_entry.neighbour().connection_identifier(),
-                            e
-                        );
+                    let dst_ip = data_packet.dst_ip;
+                    match route_entry.neighbour().send_data_packet(data_packet) {
+                        Ok(true) => {}
+                        Ok(false) => self
+                            .packet_drop_log
+                            .record(PacketDropReason::QueueFull, dst_ip),
+                        Err(e) => {
+                            error!(
+                                "Error sending data packet to peer {}: {:?}",
+                                route_entry.neighbour().connection_identifier(),
+                                e
+                            );
+                        }
                     }
                 }
                 None => {
@@ -1047,16 +1590,17 @@ impl Router {
     }
 
     /// Handle a received data packet.
-    async fn handle_incoming_data_packet(self, mut router_data_rx: Receiver<DataPacket>) {
-        while let Some(data_packet) = router_data_rx.recv().await {
-            self.route_packet(data_packet);
+    async fn handle_incoming_data_packet(self, mut router_data_rx: Receiver<(DataPacket, Peer)>) {
+        while let Some((data_packet, peer)) = router_data_rx.recv().await {
+            self.forward_packet(data_packet, None, Some(peer));
         }
         warn!("Router data receiver stream ended");
     }
 
     /// Handle a packet who's TTL is too low.
     fn time_exceeded(&self, data_packet: DataPacket) {
-        trace!("Refusing to forward expired packet");
+        self.packet_drop_log
+            .record(PacketDropReason::HopLimitExceeded, data_packet.dst_ip);
         self.oob_icmp(
             Icmpv6Type::TimeExceeded(TimeExceededCode::HopLimitExceeded),
             data_packet,
@@ -1065,10 +1609,8 @@ impl Router {
 
     /// Handle a packet if we have no route for the destination address.
     fn no_route_to_host(&self, data_packet: DataPacket) {
-        trace!(
-            "Could not forward data packet, no route found for {}",
-            data_packet.dst_ip
-        );
+        self.packet_drop_log
+            .record(PacketDropReason::NoRoute, data_packet.dst_ip);
 
         self.oob_icmp(
             Icmpv6Type::DestinationUnreachable(DestUnreachableCode::NoRoute),
@@ -1151,10 +1693,36 @@ impl Router {
             })
     }
 
+    /// Get the selected route for a destination IP, followed by all other feasible routes to the
+    /// same subnet which have the same metric, including the link cost to the neighbour.
+    pub fn select_equal_routes(&self, dest_ip: IpAddr) -> Vec<RouteEntry> {
+        let Some(selected) = self.select_best_route(dest_ip) else {
+            return vec![];
+        };
+        let total_metric = selected.metric() + Metric::from(selected.neighbour().link_cost());
+
+        let entries = self
+            .inner_r
+            .enter()
+            .expect("Write handle is saved on router so it is not dropped before the read handles")
+            .routing_table
+            .entries(selected.source().subnet());
+        let source_table = self.source_table.read().unwrap();
+
+        let mut routes = vec![selected];
+        routes.extend(entries.into_iter().filter(|re| {
+            !re.selected()
+                && !re.metric().is_infinite()
+                && source_table.route_feasible(re)
+                && re.metric() + Metric::from(re.neighbour().link_cost()) == total_metric
+        }));
+        routes
+    }
+
     /// Task to propagete the static routes periodically
     async fn propagate_static_routes(self) {
         loop {
-            tokio::time::sleep(ROUTE_PROPAGATION_INTERVAL).await;
+            tokio::time::sleep(jittered(ROUTE_PROPAGATION_INTERVAL, self.control_jitter)).await;
 
             trace!("Propagating static routes");
 
@@ -1167,7 +1735,7 @@ impl Router {
     /// Task to propagate selected routes periodically
     async fn propagate_selected_routes(self) {
         loop {
-            tokio::time::sleep(ROUTE_PROPAGATION_INTERVAL).await;
+            tokio::time::sleep(jittered(ROUTE_PROPAGATION_INTERVAL, self.control_jitter)).await;
 
             trace!("Propagating selected routes");
 
@@ -1175,15 +1743,18 @@ impl Router {
         }
     }
 
-    /// Task which periodically sends a Hello TLV to all known peers
+    /// Task which periodically sends a Hello TLV to all known peers. Since IHU's are sent in
+    /// response to hellos, jittering the hellos spreads the IHU's as well.
     async fn start_periodic_hello_sender(self) {
         let hello_interval = Duration::from_secs(HELLO_INTERVAL);
         loop {
-            tokio::time::sleep(hello_interval).await;
+            // The hello still announces the nominal interval.
+            tokio::time::sleep(jittered(hello_interval, self.control_jitter)).await;
 
             for peer in self.peer_interfaces.read().unwrap().iter() {
                 let hello = ControlPacket::new_hello(peer, hello_interval);
                 peer.set_time_last_received_hello(tokio::time::Instant::now());
+                peer.hello_sent();
 
                 if let Err(error) = peer.send_control_packet(hello) {
                     error!("Error sending hello to peer: {}", error);
@@ -1192,6 +1763,40 @@ impl Router {
         }
     }
 
+    /// Probe a peer by sending it hellos one at a time, and measuring the time until the IHU in
+    /// reply arrives. Probes are regular hellos, so they update the link cost of the peer like
+    /// the periodic hellos do.
+    ///
+    /// This does not need the router itself, so the router does not have to be held while waiting
+    /// for the replies.
+    pub async fn probe_peer(peer: &Peer) -> PeerProbe {
+        let hello_interval = Duration::from_secs(HELLO_INTERVAL);
+        let mut rtts = Vec::with_capacity(PROBE_COUNT as usize);
+        for _ in 0..PROBE_COUNT {
+            // Start waiting for the IHU before sending the hello, so the reply can't be missed.
+            let ihu = peer.ihu_notified();
+            let start = tokio::time::Instant::now();
+            peer.set_time_last_received_hello(start);
+            peer.hello_sent();
+            if let Err(e) = peer.send_control_packet(ControlPacket::new_hello(peer, hello_interval))
+            {
+                debug!(
+                    "Failed to send probe to peer {}: {e}",
+                    peer.connection_identifier()
+                );
+                continue;
+            }
+            if tokio::time::timeout(PROBE_TIMEOUT, ihu).await.is_ok() {
+                rtts.push(start.elapsed());
+            }
+        }
+
+        PeerProbe {
+            rtt: (!rtts.is_empty()).then(|| rtts.iter().sum::<Duration>() / rtts.len() as u32),
+            loss: (PROBE_COUNT - rtts.len() as u32) as f64 / PROBE_COUNT as f64,
+        }
+    }
+
     /// Propagates the static routes to all known peers.
     fn propagate_static_route(&self) {
         for peer in self.peer_interfaces.read().unwrap().iter() {
@@ -1199,6 +1804,25 @@ impl Router {
         }
     }
 
+    /// Send a retraction for all static routes to all peers. This is used when the node shuts
+    /// down, so neighbours immediately stop using routes to it, instead of waiting for them to
+    /// expire.
+    pub fn retract_static_routes(&self) {
+        for peer in self.peer_interfaces.read().unwrap().iter() {
+            for sr in self.static_routes.iter() {
+                info!("Retracting static route {sr}");
+                let update = babel::Update::new(
+                    UPDATE_INTERVAL,
+                    self.router_seqno.read().unwrap().0, // Retractions receive the seqno of the router
+                    Metric::infinite(),
+                    *sr,
+                    self.router_id,
+                );
+                self.send_update(peer, update);
+            }
+        }
+    }
+
     /// Propagate the static routes to a single peer
     fn propagate_static_route_to_peer(&self, peer: &Peer) {
         for sr in self.static_routes.iter() {
@@ -1351,6 +1975,9 @@ impl Router {
 
 pub struct RouterInner {
     routing_table: RoutingTable<(PublicKey, SharedSecret)>,
+    /// The last selected next hop for every subnet in the routing table, and the amount of times
+    /// the selected next hop changed since a route to the subnet first appeared.
+    next_hop_changes: HashMap<Subnet, (String, u64)>,
     expired_route_entry_sink: mpsc::Sender<(RouteKey, RouteExpirationType)>,
 }
 
@@ -1360,11 +1987,30 @@ impl RouterInner {
     ) -> Result<Self, Box<dyn Error>> {
         let router_inner = RouterInner {
             routing_table: RoutingTable::new(),
+            next_hop_changes: HashMap::new(),
             expired_route_entry_sink,
         };
 
         Ok(router_inner)
     }
+
+    /// Keep track of the next hop selected by the given [`RouteKey`], counting a change if it
+    /// differs from the previously selected next hop of the subnet.
+    fn track_selected_next_hop(&mut self, rk: &RouteKey) {
+        let next_hop = rk.neighbour().connection_identifier();
+        match self.next_hop_changes.entry(rk.subnet()) {
+            Entry::Occupied(mut entry) => {
+                let (last_next_hop, changes) = entry.get_mut();
+                if last_next_hop != next_hop {
+                    *last_next_hop = next_hop.clone();
+                    *changes += 1;
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((next_hop.clone(), 0));
+            }
+        }
+    }
 }
 
 enum RouterOpLogEntry {
@@ -1394,12 +2040,17 @@ impl left_right::Absorb<RouterOpLogEntry> for RouterInner {
             }
             RouterOpLogEntry::RemoveRoute(rk) => {
                 self.routing_table.remove(rk);
+                // Forget the subnet once the last route to it is gone.
+                if self.routing_table.entries(rk.subnet()).is_empty() {
+                    self.next_hop_changes.remove(&rk.subnet());
+                }
             }
             RouterOpLogEntry::UnselectRoute(rk) => {
                 self.routing_table.unselect_route(rk);
             }
             RouterOpLogEntry::SelectRoute(rk) => {
                 self.routing_table.select_route(rk);
+                self.track_selected_next_hop(rk);
             }
             RouterOpLogEntry::UpdateRouteEntry(rk, seqno, metric, pk) => {
                 if let Some(re) = self.routing_table.get_mut(rk) {
@@ -1422,6 +2073,7 @@ impl Clone for RouterInner {
     fn clone(&self) -> Self {
         let RouterInner {
             routing_table,
+            next_hop_changes,
             expired_route_entry_sink,
         } = self;
         let mut new_routing_table = RoutingTable::new();
@@ -1430,7 +2082,518 @@ impl Clone for RouterInner {
         }
         RouterInner {
             routing_table: new_routing_table,
+            next_hop_changes: next_hop_changes.clone(),
             expired_route_entry_sink: expired_route_entry_sink.clone(),
         }
     }
 }
+
+/// Pick the next candidate round robin, using the given counter. Returns [`None`] if there are no
+/// candidates.
+fn round_robin_pick<'a, T>(candidates: &'a [T], counter: &AtomicUsize) -> Option<&'a T> {
+    if candidates.is_empty() {
+        return None;
+    }
+    candidates.get(counter.fetch_add(1, Ordering::Relaxed) % candidates.len())
+}
+
+/// Randomly shorten `interval` by up to `jitter` percent, as recommended for periodic
+/// transmissions by RFC 8966. The interval is never made longer, so neighbours which time out
+/// hellos and routes based on the nominal interval don't consider us dead.
+fn jittered(interval: Duration, jitter: u8) -> Duration {
+    if jitter == 0 {
+        return interval;
+    }
+    let jitter = f64::from(jitter.min(MAX_CONTROL_JITTER)) / 100.;
+    interval.mul_f64(1. - rand::thread_rng().gen_range(0.0..=jitter))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv6Addr,
+        sync::{atomic::AtomicUsize, Arc},
+        time::Duration,
+    };
+
+    use crate::{
+        babel,
+        crypto::{PublicKey, SecretKey},
+        metric::Metric,
+        packet::DataPacket,
+        peer::Peer,
+        router_id::RouterId,
+        sequence_number::SeqNo,
+        subnet::Subnet,
+        testing,
+    };
+
+    use super::{jittered, round_robin_pick, Router, UPDATE_INTERVAL};
+
+    /// Create a new router which does not select routes with a metric above `max_metric`.
+    fn router(max_metric: Option<Metric>) -> Router {
+        testing::router(max_metric).0
+    }
+
+    /// Create a new peer of the router, connected over a local TCP connection.
+    async fn peer(router: &Router) -> Peer {
+        let (stream, _) = testing::tcp_pair().await;
+        testing::router_peer(router, stream)
+    }
+
+    /// Connect two routers to each other, returning the peer on either side of the connection.
+    async fn link(a: &Router, b: &Router) -> (Peer, Peer) {
+        let (a_stream, b_stream) = testing::tcp_pair().await;
+        (
+            testing::router_peer(a, a_stream),
+            testing::router_peer(b, b_stream),
+        )
+    }
+
+    /// Checks if a route is selected for the given subnet.
+    fn has_selected_route(router: &Router, subnet: Subnet) -> bool {
+        router.load_selected_route(subnet).is_some()
+    }
+
+    /// Wait until a usable route through the given peer is selected for the subnet. Panics if
+    /// this takes longer than a fraction of the update interval.
+    async fn wait_for_selected_route(router: &Router, subnet: Subnet, via: &Peer) {
+        let route_selected = || {
+            router
+                .inner_r
+                .enter()
+                .expect(
+                    "Write handle is saved on router so it is not dropped before the read handles",
+                )
+                .routing_table
+                .entries(subnet)
+                .iter()
+                .any(|re| re.selected() && re.neighbour() == via && !re.metric().is_infinite())
+        };
+        tokio::time::timeout(UPDATE_INTERVAL / 10, async {
+            while !route_selected() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Route is selected well within the update interval");
+    }
+
+    #[tokio::test]
+    async fn route_above_max_metric_is_not_selected() {
+        let router = router(Some(Metric::new(100)));
+        let peer = peer(&router).await;
+        let router_id = RouterId::new(PublicKey::from(&SecretKey::new()));
+        let good = Subnet::new("4aa:bb:cc:dd::".parse().unwrap(), 64).unwrap();
+        let bad = Subnet::new("4aa:bb:cc:ee::".parse().unwrap(), 64).unwrap();
+
+        // The link cost of the peer (50) is added to the metric of the update.
+        for (subnet, metric) in [(good, 20), (bad, 80)] {
+            router.handle_incoming_update(
+                babel::Update::new(
+                    Duration::from_secs(60),
+                    SeqNo::new(),
+                    Metric::new(metric),
+                    subnet,
+                    router_id,
+                ),
+                peer.clone(),
+            );
+        }
+
+        assert!(has_selected_route(&router, good));
+        // The route is the only option, but it is still not selected.
+        assert!(!has_selected_route(&router, bad));
+    }
+
+    #[tokio::test]
+    async fn next_hop_changes_are_counted() {
+        let router = router(None);
+        let first = peer(&router).await;
+        let second = peer(&router).await;
+        let router_id = RouterId::new(PublicKey::from(&SecretKey::new()));
+        let subnet = Subnet::new("4aa:bb:cc:dd::".parse().unwrap(), 64).unwrap();
+        let update = |seqno: SeqNo, metric| {
+            babel::Update::new(
+                Duration::from_secs(60),
+                seqno,
+                Metric::new(metric),
+                subnet,
+                router_id,
+            )
+        };
+
+        router.handle_incoming_update(update(SeqNo::new(), 50), first.clone());
+        assert_eq!(router.reselect_count(subnet), 0);
+
+        // A better route through another neighbour changes the next hop.
+        router.handle_incoming_update(update(SeqNo::new(), 20), second.clone());
+        assert_eq!(router.reselect_count(subnet), 1);
+
+        // Updates through the selected next hop don't change it.
+        router.handle_incoming_update(update(SeqNo::new(), 15), second);
+        assert_eq!(router.reselect_count(subnet), 1);
+
+        router.handle_incoming_update(update(SeqNo::new() + 1, 0), first);
+        assert_eq!(router.reselect_count(subnet), 2);
+    }
+
+    #[tokio::test]
+    async fn forced_reselection_follows_link_costs() {
+        let router = router(None);
+        let first = peer(&router).await;
+        let second = peer(&router).await;
+        let router_id = RouterId::new(PublicKey::from(&SecretKey::new()));
+        let subnet = Subnet::new("4aa:bb:cc:dd::".parse().unwrap(), 64).unwrap();
+        for (peer, metric) in [(&first, 10), (&second, 30)] {
+            router.handle_incoming_update(
+                babel::Update::new(
+                    Duration::from_secs(60),
+                    SeqNo::new(),
+                    Metric::new(metric),
+                    subnet,
+                    router_id,
+                ),
+                peer.clone(),
+            );
+        }
+        assert_eq!(
+            router
+                .load_selected_route(subnet)
+                .map(|re| re.neighbour().clone()),
+            Some(first.clone())
+        );
+        assert_eq!(router.reselect_routes(), 0);
+
+        // A link cost change on its own does not trigger route selection.
+        first.set_link_cost(10_000);
+        assert_eq!(
+            router
+                .load_selected_route(subnet)
+                .map(|re| re.neighbour().clone()),
+            Some(first)
+        );
+
+        assert_eq!(router.reselect_routes(), 1);
+        assert_eq!(
+            router
+                .load_selected_route(subnet)
+                .map(|re| re.neighbour().clone()),
+            Some(second)
+        );
+        assert_eq!(router.reselect_routes(), 0);
+    }
+
+    #[tokio::test]
+    async fn routes_return_quickly_after_reconnect() {
+        let a = router(None);
+        let b = router(None);
+        let b_subnet = b.node_tun_subnet();
+
+        let (a_peer, b_peer) = link(&a, &b).await;
+        a.add_peer_interface(a_peer.clone());
+        b.add_peer_interface(b_peer.clone());
+        wait_for_selected_route(&a, b_subnet, &a_peer).await;
+
+        // Disconnect, which retracts the routes learned over the connection.
+        a_peer.died();
+        b_peer.died();
+        a.handle_dead_peer(a_peer);
+        b.handle_dead_peer(b_peer);
+
+        // After reconnecting, the route table dump requested from the peer restores the route
+        // well before the next periodic update.
+        let (a_peer, b_peer) = link(&a, &b).await;
+        a.add_peer_interface(a_peer.clone());
+        b.add_peer_interface(b_peer);
+        wait_for_selected_route(&a, b_subnet, &a_peer).await;
+    }
+
+    #[tokio::test]
+    async fn neighbour_keys_are_learned_from_own_subnets() {
+        let router = router(None);
+        let neighbour = peer(&router).await;
+        let gone = peer(&router).await;
+        router.add_peer_interface(neighbour.clone());
+        let neighbour_key = PublicKey::from(&SecretKey::new());
+        let remote_key = PublicKey::from(&SecretKey::new());
+        let update = |subnet: &str, metric, pk| {
+            babel::Update::new(
+                Duration::from_secs(60),
+                SeqNo::new(),
+                Metric::new(metric),
+                Subnet::new(subnet.parse().unwrap(), 64).unwrap(),
+                RouterId::new(pk),
+            )
+        };
+
+        // The neighbour announces its own subnet, and relays the subnet of a node further away.
+        router.handle_incoming_update(
+            update("4aa:bb:cc:dd::", 0, neighbour_key),
+            neighbour.clone(),
+        );
+        router.handle_incoming_update(update("4aa:bb:cc:ee::", 10, remote_key), neighbour.clone());
+        // Routes learned from a peer which is not connected as an interface are ignored.
+        router.handle_incoming_update(update("4aa:bb:cc:ff::", 0, remote_key), gone);
+
+        assert_eq!(
+            router.neighbour_keys(),
+            [(neighbour.connection_identifier().clone(), neighbour_key)]
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_measures_connected_peer() {
+        let a = router(None);
+        let b = router(None);
+        let (a_peer, _b_peer) = link(&a, &b).await;
+
+        let probe = Router::probe_peer(&a_peer).await;
+        assert!(probe.rtt.is_some());
+        assert_eq!(probe.loss, 0.);
+    }
+
+    #[tokio::test]
+    async fn probe_of_unresponsive_peer_is_lost() {
+        let a = router(None);
+        // Nothing answers on the other end of the connection.
+        let (stream, _other) = testing::tcp_pair().await;
+        let peer = testing::router_peer(&a, stream);
+
+        let probe = Router::probe_peer(&peer).await;
+        assert!(probe.rtt.is_none());
+        assert_eq!(probe.loss, 1.);
+    }
+
+    #[tokio::test]
+    async fn peer_removal_is_simulated_without_changes() {
+        let router = router(None);
+        let first = peer(&router).await;
+        let second = peer(&router).await;
+        let router_id = RouterId::new(PublicKey::from(&SecretKey::new()));
+        let redundant = Subnet::new("4aa:bb:cc:dd::".parse().unwrap(), 64).unwrap();
+        let single = Subnet::new("4aa:bb:cc:ee::".parse().unwrap(), 64).unwrap();
+        let update = |subnet, metric| {
+            babel::Update::new(
+                Duration::from_secs(60),
+                SeqNo::new(),
+                Metric::new(metric),
+                subnet,
+                router_id,
+            )
+        };
+
+        router.handle_incoming_update(update(redundant, 10), first.clone());
+        router.handle_incoming_update(update(redundant, 30), second.clone());
+        router.handle_incoming_update(update(single, 10), first.clone());
+
+        let simulated = router.simulate_peer_removal(&first);
+        assert_eq!(simulated.len(), 2);
+        let redundant_route = simulated
+            .iter()
+            .find(|re| re.source().subnet() == redundant)
+            .expect("Redundant subnet has a route");
+        assert_eq!(redundant_route.neighbour(), &second);
+        assert!(!redundant_route.metric().is_infinite());
+        let single_route = simulated
+            .iter()
+            .find(|re| re.source().subnet() == single)
+            .expect("Single subnet keeps the retracted route");
+        assert!(single_route.metric().is_infinite());
+
+        // Nothing actually changed.
+        let selected = router.load_selected_routes();
+        assert_eq!(selected.len(), 2);
+        assert!(selected
+            .iter()
+            .all(|re| re.neighbour() == &first && !re.metric().is_infinite()));
+    }
+
+    #[tokio::test]
+    async fn fallback_routes_are_dropped_but_retractions_kept() {
+        let mut router = router(None);
+        router.disable_fallback_routes = true;
+        let first = peer(&router).await;
+        let second = peer(&router).await;
+        let router_id = RouterId::new(PublicKey::from(&SecretKey::new()));
+        let subnet = Subnet::new("4aa:bb:cc:dd::".parse().unwrap(), 64).unwrap();
+        let update = |seqno, metric| {
+            babel::Update::new(Duration::from_secs(60), seqno, metric, subnet, router_id)
+        };
+        let seqno = SeqNo::new();
+
+        // Only the selected route is kept.
+        router.handle_incoming_update(update(seqno, Metric::new(10)), first.clone());
+        router.handle_incoming_update(update(seqno, Metric::new(5)), second.clone());
+        let selected = router
+            .load_selected_route(subnet)
+            .expect("Route is selected");
+        assert_eq!(selected.neighbour(), &first);
+        assert!(router.load_fallback_routes().is_empty());
+
+        // The retracted route stays selected, so the retraction is propagated with its source.
+        router.handle_incoming_update(update(seqno, Metric::infinite()), first.clone());
+        let selected = router
+            .load_selected_route(subnet)
+            .expect("Retraction is kept");
+        assert_eq!(selected.neighbour(), &first);
+        assert!(selected.metric().is_infinite());
+
+        // A new route replaces the retracted one, which is kept until it expires.
+        router.handle_incoming_update(update(seqno + 1, Metric::new(20)), second.clone());
+        let selected = router
+            .load_selected_route(subnet)
+            .expect("Route is selected");
+        assert_eq!(selected.neighbour(), &second);
+        let fallback = router.load_fallback_routes();
+        assert_eq!(fallback.len(), 1);
+        assert_eq!(fallback[0].neighbour(), &first);
+        assert!(fallback[0].metric().is_infinite());
+    }
+
+    #[tokio::test]
+    async fn worst_and_oldest_fallback_routes_are_evicted() {
+        let mut router = router(None);
+        router.max_routes = Some(6);
+        let first = peer(&router).await;
+        let second = peer(&router).await;
+        let router_id = RouterId::new(PublicKey::from(&SecretKey::new()));
+        let subnet =
+            |i: u16| Subnet::new(format!("4aa:bb:cc:{i:x}::").parse().unwrap(), 64).unwrap();
+        let update = |subnet, seqno, metric| {
+            babel::Update::new(
+                Duration::from_secs(60),
+                seqno,
+                Metric::new(metric),
+                subnet,
+                router_id,
+            )
+        };
+        let seqno = SeqNo::new();
+
+        for i in 1..=3 {
+            router.handle_incoming_update(update(subnet(i), seqno, 10), first.clone());
+        }
+        // Fallback routes, learned one after the other.
+        for (i, metric) in [(1, 40), (2, 50), (3, 50)] {
+            router.handle_incoming_update(update(subnet(i), seqno + 1, metric), second.clone());
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let fallback = || {
+            let mut subnets = router
+                .load_fallback_routes()
+                .iter()
+                .map(|re| re.source().subnet())
+                .collect::<Vec<_>>();
+            subnets.sort();
+            subnets
+        };
+        assert_eq!(fallback(), [subnet(1), subnet(2), subnet(3)]);
+
+        // The highest metric goes first, the oldest of equal metrics before the newer one.
+        router.handle_incoming_update(update(subnet(4), seqno, 10), first.clone());
+        assert_eq!(fallback(), [subnet(1), subnet(3)]);
+        router.handle_incoming_update(update(subnet(5), seqno, 10), first.clone());
+        assert_eq!(fallback(), [subnet(1)]);
+        router.handle_incoming_update(update(subnet(6), seqno, 10), first.clone());
+        assert!(fallback().is_empty());
+
+        // Selected routes are never evicted.
+        router.handle_incoming_update(update(subnet(7), seqno, 10), first.clone());
+        assert!(router.load_selected_route(subnet(7)).is_none());
+        assert_eq!(router.load_selected_routes().len(), 6);
+    }
+
+    #[test]
+    fn round_robin_spreads_over_candidates() {
+        let counter = AtomicUsize::new(0);
+        let mut picked = [0; 3];
+
+        for _ in 0..300 {
+            let next_hop = *round_robin_pick(&[0, 1, 2], &counter).expect("Candidates exist");
+            picked[next_hop] += 1;
+        }
+
+        assert_eq!(picked, [100, 100, 100]);
+    }
+
+    #[test]
+    fn round_robin_without_candidates() {
+        let counter = AtomicUsize::new(0);
+
+        assert_eq!(round_robin_pick::<usize>(&[], &counter), None);
+    }
+
+    #[tokio::test]
+    async fn balanced_message_packets_alternate_over_equal_routes() {
+        let mut router = router(None);
+        router.message_round_robin = Some(Arc::new(AtomicUsize::new(0)));
+        let first = peer(&router).await;
+        let second = peer(&router).await;
+        let router_id = RouterId::new(PublicKey::from(&SecretKey::new()));
+        let subnet = Subnet::new("4aa:bb:cc:dd::".parse().unwrap(), 64).unwrap();
+        for peer in [&first, &second] {
+            router.handle_incoming_update(
+                babel::Update::new(
+                    Duration::from_secs(60),
+                    SeqNo::new(),
+                    Metric::new(10),
+                    subnet,
+                    router_id,
+                ),
+                peer.clone(),
+            );
+        }
+        let packet = || DataPacket {
+            raw_data: vec![0; 100],
+            hop_limit: 64,
+            src_ip: Ipv6Addr::LOCALHOST,
+            dst_ip: "4aa:bb:cc:dd::1".parse().unwrap(),
+        };
+
+        // The connection tasks of the peers don't run while we don't yield, so every packet stays
+        // in the data queue of the peer it was routed to.
+        let mut queued = Vec::new();
+        for _ in 0..4 {
+            router.route_message_packet(packet());
+            queued.push((first.queued_data_packets(), second.queued_data_packets()));
+        }
+
+        let selected_first = queued[0] == (1, 0);
+        let expected = if selected_first {
+            [(1, 0), (1, 1), (2, 1), (2, 2)]
+        } else {
+            [(0, 1), (1, 1), (1, 2), (2, 2)]
+        };
+        assert_eq!(queued, expected);
+    }
+
+    #[test]
+    fn jitter_stays_within_range() {
+        let interval = Duration::from_secs(20);
+        let times = (0..1_000)
+            .map(|_| jittered(interval, 25))
+            .collect::<Vec<_>>();
+
+        assert!(times
+            .iter()
+            .all(|t| *t >= Duration::from_secs(15) && *t <= interval));
+        // The times are actually spread over the range.
+        assert!(times.iter().any(|t| *t < Duration::from_secs(16)));
+        assert!(times.iter().any(|t| *t > Duration::from_secs(19)));
+    }
+
+    #[test]
+    fn jitter_is_capped() {
+        let interval = Duration::from_secs(20);
+
+        assert!((0..1_000).all(|_| jittered(interval, 100) >= Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn no_jitter_keeps_interval() {
+        let interval = Duration::from_secs(20);
+
+        assert!((0..100).all(|_| jittered(interval, 0) == interval));
+    }
+}
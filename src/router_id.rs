@@ -32,6 +32,12 @@ impl RouterId {
         out
     }
 
+    /// Hex encode this `RouterId`. This encodes exactly the bytes of the `RouterId` as they are
+    /// sent in updates.
+    pub fn as_hex(&self) -> String {
+        faster_hex::hex_string(&self.as_bytes())
+    }
+
     /// Converts this `RouterId` to a [`PublicKey`].
     pub fn to_pubkey(self) -> PublicKey {
         self.pk
@@ -9,7 +9,7 @@ use crate::{
 use core::fmt;
 use std::{
     net::{IpAddr, Ipv6Addr},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 /// Default time before a route expires.
@@ -48,6 +48,8 @@ pub struct RouteEntry {
     metric: Metric,
     seqno: SeqNo,
     selected: bool,
+    /// Time at which the route was learned.
+    learned: Instant,
 }
 
 impl RouteKey {
@@ -71,8 +73,8 @@ impl RouteKey {
 }
 
 impl RouteEntry {
-    /// Create a new `RouteEntry`.
-    pub const fn new(
+    /// Create a new `RouteEntry`, which is learned now.
+    pub fn new(
         source: SourceKey,
         neighbor: Peer,
         metric: Metric,
@@ -85,6 +87,7 @@ impl RouteEntry {
             metric,
             seqno,
             selected,
+            learned: Instant::now(),
         }
     }
 
@@ -103,6 +106,11 @@ impl RouteEntry {
         self.metric
     }
 
+    /// Returns the time at which this `RouteEntry` was learned.
+    pub const fn learned(&self) -> Instant {
+        self.learned
+    }
+
     /// Return the (neighbour)[`Peer`] associated with this `RouteEntry`.
     pub fn neighbour(&self) -> &Peer {
         &self.neighbor
@@ -141,6 +149,8 @@ impl RouteEntry {
 /// [`RouteEntries`](RouteEntry) for the given [`RouteKey`].
 pub struct RoutingTable<T> {
     table: IpLookupTable<Ipv6Addr, TableEntry<T>>,
+    /// Total amount of [`RouteEntries`](RouteEntry) in the table.
+    len: usize,
 }
 
 /// An entry in the RoutingTable.
@@ -159,9 +169,15 @@ impl<T> RoutingTable<T> {
     pub fn new() -> Self {
         Self {
             table: IpLookupTable::new(),
+            len: 0,
         }
     }
 
+    /// Returns the total amount of [`RouteEntries`](RouteEntry) in the table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
     /// Get a  reference to the [`RouteEntry`] associated with the [`RouteKey`] if one is
     /// present in the table.
     pub fn get(&self, key: &RouteKey) -> Option<&RouteEntry> {
@@ -246,6 +262,7 @@ impl<T> RoutingTable<T> {
                     idx
                 } else {
                     entries.push((entry, expiration));
+                    self.len += 1;
                     entries.len() - 1
                 };
                 // In debug mode, verify that we only have 1 selected route at most. We do this by
@@ -271,6 +288,7 @@ impl<T> RoutingTable<T> {
                         entries: vec![(entry, expiration)],
                     },
                 );
+                self.len += 1;
             }
         };
     }
@@ -301,6 +319,7 @@ impl<T> RoutingTable<T> {
                     self.table.remove(addr, key.subnet.prefix_len() as u32);
                 }
                 elem.map(|(entry, expiration)| {
+                    self.len -= 1;
                     expiration.abort();
                     entry
                 })
@@ -0,0 +1,264 @@
+//! Shaping of outbound traffic to peers, so the overlay does not saturate the underlay.
+//!
+//! Bandwidth caps are enforced with token buckets. A bucket refills at the capped rate, and
+//! sending data takes tokens out of it. Sending is allowed to put the bucket in debt, after which
+//! the next send waits until the debt is paid back. This way, packets larger than the burst size
+//! can still be sent, while the long term rate is held at the cap.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Amount of time worth of traffic which can be sent in a single burst.
+const BURST_DURATION: Duration = Duration::from_millis(100);
+
+/// Window over which the utilization of a cap is measured.
+const UTILIZATION_WINDOW: Duration = Duration::from_secs(1);
+
+/// A token bucket limiting traffic to a fixed amount of bytes per second. Clones share the same
+/// bucket.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Rate at which the bucket refills, in bytes per second.
+    rate: u64,
+    state: Arc<Mutex<BucketState>>,
+}
+
+/// Mutable state of a [`TokenBucket`].
+#[derive(Debug)]
+struct BucketState {
+    /// Amount of tokens currently in the bucket, this is negative if the bucket is in debt.
+    tokens: f64,
+    /// Last time tokens were added to the bucket.
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new, full `TokenBucket` which allows `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        let bucket = Self {
+            rate,
+            state: Arc::new(Mutex::new(BucketState {
+                tokens: 0.,
+                last_refill: Instant::now(),
+            })),
+        };
+        bucket.state.lock().unwrap().tokens = bucket.capacity();
+        bucket
+    }
+
+    /// The rate of the bucket, in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// The maximum amount of tokens in the bucket.
+    fn capacity(&self) -> f64 {
+        self.rate as f64 * BURST_DURATION.as_secs_f64()
+    }
+
+    /// Take tokens for `amount` bytes out of the bucket, and return how long to wait before they
+    /// can be sent.
+    fn reserve(&self, amount: usize) -> Duration {
+        if self.rate == 0 {
+            // Nothing can ever be sent, wait for the longest time we reasonably can.
+            return Duration::from_secs(u32::MAX as u64);
+        }
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.last_refill).as_secs_f64() * self.rate as f64;
+        state.tokens = (state.tokens + refill).min(self.capacity());
+        state.last_refill = now;
+
+        // Only wait if the bucket is already in debt, so a single large reservation can pass.
+        let wait = if state.tokens < 0. {
+            Duration::from_secs_f64(-state.tokens / self.rate as f64)
+        } else {
+            Duration::ZERO
+        };
+        state.tokens -= amount as f64;
+        wait
+    }
+}
+
+/// The outbound bandwidth limits of a single peer. This combines an optional cap for the peer
+/// itself, and an optional cap shared by all peers of the node. Clones share the same limits.
+#[derive(Debug, Clone)]
+pub struct BandwidthLimit {
+    /// Cap of this peer only.
+    peer: Option<TokenBucket>,
+    /// Cap shared with all peers of the node.
+    node: Option<TokenBucket>,
+    /// Traffic sent through this limit, to compute the utilization of the cap.
+    meter: Arc<Mutex<Meter>>,
+}
+
+/// Amount of bytes sent in the current and previous [`UTILIZATION_WINDOW`].
+#[derive(Debug)]
+struct Meter {
+    window_start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl Meter {
+    /// Move to a new window if the current one is over.
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < UTILIZATION_WINDOW {
+            return;
+        }
+        // If more than one window passed, nothing was sent in the previous one.
+        self.previous = if elapsed < 2 * UTILIZATION_WINDOW {
+            self.current
+        } else {
+            0
+        };
+        self.current = 0;
+        self.window_start = now;
+    }
+}
+
+impl BandwidthLimit {
+    /// Create a new `BandwidthLimit` from a cap for the peer, and a cap shared by all peers.
+    pub fn new(peer: Option<TokenBucket>, node: Option<TokenBucket>) -> Self {
+        Self {
+            peer,
+            node,
+            meter: Arc::new(Mutex::new(Meter {
+                window_start: Instant::now(),
+                current: 0,
+                previous: 0,
+            })),
+        }
+    }
+
+    /// The effective cap in bytes per second, i.e. the lowest of the peer and node caps, if any.
+    pub fn cap(&self) -> Option<u64> {
+        match (&self.peer, &self.node) {
+            (Some(peer), Some(node)) => Some(peer.rate().min(node.rate())),
+            (Some(bucket), None) | (None, Some(bucket)) => Some(bucket.rate()),
+            (None, None) => None,
+        }
+    }
+
+    /// Wait until `amount` bytes can be sent without exceeding any cap.
+    pub async fn acquire(&self, amount: usize) {
+        {
+            let mut meter = self.meter.lock().unwrap();
+            meter.advance(Instant::now());
+            meter.current += amount as u64;
+        }
+        let wait = [&self.peer, &self.node]
+            .into_iter()
+            .flatten()
+            .map(|bucket| bucket.reserve(amount))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Percentage of the effective cap used in the last second, if there is a cap.
+    pub fn utilization(&self) -> Option<f64> {
+        let cap = self.cap()?;
+        let mut meter = self.meter.lock().unwrap();
+        meter.advance(Instant::now());
+        Some(if cap == 0 {
+            100.
+        } else {
+            meter.previous as f64 * 100. / cap as f64
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{BandwidthLimit, TokenBucket};
+
+    /// Check that `wait` is `expected`, give or take rounding of the token math.
+    fn assert_wait(wait: Duration, expected: Duration) {
+        assert!(
+            wait.abs_diff(expected) < Duration::from_micros(1),
+            "waited {wait:?} instead of {expected:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_allows_burst_and_waits_for_debt() {
+        // Refills 100 bytes per millisecond, and holds 10_000 bytes.
+        let bucket = TokenBucket::new(100_000);
+
+        // A full bucket allows a burst of its capacity, and one more send into debt.
+        for _ in 0..10 {
+            assert_wait(bucket.reserve(1_000), Duration::ZERO);
+        }
+        assert_wait(bucket.reserve(5_000), Duration::ZERO);
+        // The debt of 5_000 bytes is paid back in 50 milliseconds.
+        assert_wait(bucket.reserve(1_000), Duration::from_millis(50));
+        // 6_000 bytes of debt are paid back after 60 milliseconds, 1_000 bytes later there are
+        // tokens again.
+        tokio::time::advance(Duration::from_millis(70)).await;
+        assert_wait(bucket.reserve(1_000), Duration::ZERO);
+        assert_wait(bucket.reserve(1_000), Duration::ZERO);
+
+        // Refilling stops at the capacity, so an idle bucket still only allows a single burst.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        for _ in 0..10 {
+            assert_wait(bucket.reserve(1_000), Duration::ZERO);
+        }
+        assert_wait(bucket.reserve(1_000), Duration::ZERO);
+        assert_wait(bucket.reserve(1_000), Duration::from_millis(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throughput_is_held_at_cap() {
+        const CAP: u64 = 100_000;
+        let limit = BandwidthLimit::new(Some(TokenBucket::new(CAP)), None);
+
+        let start = Instant::now();
+        let mut sent = 0;
+        while sent < CAP as usize / 2 {
+            limit.acquire(1_000).await;
+            sent += 1_000;
+        }
+
+        // The burst of 10_000 bytes, and the send which put the bucket in debt, pass right away.
+        // Every other byte is sent at the cap. Sleeps are rounded up to the millisecond resolution
+        // of the timer, which the next refill makes up for, except for the last sleep.
+        let expected = Duration::from_secs_f64((sent - 10_000 - 1_000) as f64 / CAP as f64);
+        assert!(
+            start.elapsed() >= expected - Duration::from_micros(1)
+                && start.elapsed() <= expected + Duration::from_millis(1),
+            "sending took {:?} instead of {expected:?}",
+            start.elapsed()
+        );
+        assert_eq!(limit.utilization(), Some(0.));
+        tokio::time::advance(Duration::from_secs(1) - start.elapsed()).await;
+        assert_eq!(limit.utilization(), Some(50.));
+    }
+
+    #[test]
+    fn effective_cap_is_lowest_cap() {
+        let node = TokenBucket::new(1_000);
+
+        assert_eq!(BandwidthLimit::new(None, None).cap(), None);
+        assert_eq!(
+            BandwidthLimit::new(Some(TokenBucket::new(500)), Some(node.clone())).cap(),
+            Some(500)
+        );
+        assert_eq!(
+            BandwidthLimit::new(Some(TokenBucket::new(5_000)), Some(node.clone())).cap(),
+            Some(1_000)
+        );
+        assert_eq!(BandwidthLimit::new(None, Some(node)).cap(), Some(1_000));
+    }
+}
@@ -9,7 +9,7 @@
 //! might not be optimal for other uses.
 
 use core::fmt;
-use std::net::IpAddr;
+use std::{net::IpAddr, str::FromStr};
 
 use ipnet::IpNet;
 
@@ -25,6 +25,10 @@ pub struct Subnet {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PrefixLenError;
 
+/// An error returned when parsing a [`Subnet`] from a string fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubnetParseError;
+
 impl Subnet {
     /// Create a new `Subnet` from the given [`IpAddr`] and prefix length.
     pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Subnet, PrefixLenError> {
@@ -89,6 +93,17 @@ impl fmt::Display for Subnet {
     }
 }
 
+impl FromStr for Subnet {
+    type Err = SubnetParseError;
+
+    /// Parse a `Subnet` in CIDR notation, e.g. `400::/7`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            inner: s.parse().map_err(|_| SubnetParseError)?,
+        })
+    }
+}
+
 impl fmt::Display for PrefixLenError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("Invalid prefix length for this address")
@@ -96,3 +111,11 @@ impl fmt::Display for PrefixLenError {
 }
 
 impl std::error::Error for PrefixLenError {}
+
+impl fmt::Display for SubnetParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid subnet, expected an address and prefix length (addr/len)")
+    }
+}
+
+impl std::error::Error for SubnetParseError {}
@@ -0,0 +1,182 @@
+//! Fixtures shared by the tests of the different modules.
+
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroUsize,
+    sync::{atomic::AtomicU64, Arc},
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+use crate::{
+    crypto::{PublicKey, SecretKey},
+    data::DataPlane,
+    filters::ImportPolicy,
+    metric::Metric,
+    packet::DataPacket,
+    peer::Peer,
+    peer_manager::{ConnectionState, PeerDiscoveryConfig, PeerManager, PeerStats, PeerType},
+    reconnect::ReconnectPolicy,
+    router::Router,
+    shaping::BandwidthLimit,
+    subnet::Subnet,
+};
+
+#[cfg(feature = "message")]
+use crate::message::MessageStack;
+
+/// Create a new [`Router`] with a fresh identity, which is not connected to any peer and does not
+/// select routes with a metric above `max_metric`. Packets for the node are sent to the returned
+/// receiver, the returned sender can be used to inject packets for the node.
+pub fn router(
+    max_metric: Option<Metric>,
+) -> (
+    Router,
+    UnboundedSender<(DataPacket, Option<Peer>)>,
+    UnboundedReceiver<(DataPacket, Option<Peer>)>,
+) {
+    let sk = SecretKey::new();
+    let pk = PublicKey::from(&sk);
+    let subnet = Subnet::new(pk.address().into(), 64).expect("64 is a valid IPv6 prefix size");
+    let (tun_tx, tun_rx) = mpsc::unbounded_channel();
+    let router = Router::new(
+        tun_tx.clone(),
+        subnet,
+        vec![subnet],
+        (sk, pk),
+        vec![],
+        false,
+        false,
+        None,
+        max_metric,
+        Default::default(),
+        None,
+        0,
+    )
+    .expect("Can create a router");
+    (router, tun_tx, tun_rx)
+}
+
+/// Create both ends of a local TCP connection.
+pub async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Can bind local listener");
+    let addr = listener.local_addr().expect("Listener has a local address");
+    let (stream, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (
+        stream.expect("Can connect to local listener"),
+        accepted.expect("Can accept local connection").0,
+    )
+}
+
+/// Create a [`Peer`] of the router on an existing TCP connection.
+pub fn router_peer(router: &Router, stream: TcpStream) -> Peer {
+    Peer::new(
+        router.router_data_tx(),
+        router.router_control_tx(),
+        stream,
+        router.dead_peer_sink().clone(),
+        Arc::new(AtomicU64::new(0)),
+        Arc::new(AtomicU64::new(0)),
+        Arc::new(AtomicU64::new(0)),
+        Arc::new(AtomicU64::new(0)),
+        NonZeroUsize::new(1_000).expect("1000 is not zero; qed"),
+        BandwidthLimit::new(None, None),
+        None,
+        Arc::new(ImportPolicy::default()),
+    )
+    .expect("Can create peer")
+}
+
+/// Create a new [`PeerManager`] for the router, which listens on random ports, does not discover
+/// local peers and has no static peers.
+pub fn peer_manager(router: Router) -> PeerManager {
+    PeerManager::new(
+        router,
+        vec![],
+        ReconnectPolicy::default(),
+        0,
+        0,
+        PeerDiscoveryConfig {
+            port: None,
+            multicast_group: Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xcafe),
+            interfaces: vec![],
+        },
+        NonZeroUsize::new(1_000).expect("1000 is not zero; qed"),
+        None,
+        false,
+        NonZeroUsize::new(1).expect("1 is not zero; qed"),
+        None,
+    )
+    .expect("Can create peer manager")
+}
+
+/// Create a new [`DataPlane`] for a fresh [`Router`], which is not connected to any peer and drops
+/// all message packets.
+pub fn data_plane() -> DataPlane {
+    let (router, _, tun_rx) = router(None);
+    DataPlane::new(
+        router,
+        tokio_stream::pending(),
+        futures::sink::drain(),
+        futures::sink::drain(),
+        tun_rx,
+    )
+}
+
+/// Create a new [`MessageStack`] which is not connected to any peer, but which receives the
+/// messages it sends to its own overlay IP. The overlay IP is returned alongside the stack.
+#[cfg(feature = "message")]
+pub fn loopback_message_stack() -> (MessageStack, IpAddr) {
+    let (router, _, tun_rx) = router(None);
+    let address = router.node_public_key().address().into();
+    let (msg_tx, msg_rx) = mpsc::channel(100);
+    let data_plane = DataPlane::new(
+        router,
+        tokio_stream::pending(),
+        futures::sink::drain(),
+        tokio_util::sync::PollSender::new(msg_tx),
+        tun_rx,
+    );
+    (
+        MessageStack::new(
+            data_plane,
+            tokio_stream::wrappers::ReceiverStream::new(msg_rx),
+            false,
+        ),
+        address,
+    )
+}
+
+/// Stats of a peer with the given `id`, which is reachable on `tcp://10.0.0.<id>:9651` and did not
+/// exchange any traffic yet.
+pub fn peer_stats(id: u8, pt: PeerType, connection_state: ConnectionState) -> PeerStats {
+    PeerStats {
+        id: id.into(),
+        endpoint: format!("tcp://10.0.0.{id}:9651")
+            .parse()
+            .expect("Valid endpoint; qed"),
+        pt,
+        connection_state,
+        tx_bytes: 0,
+        rx_bytes: 0,
+        dropped_data_packets: 0,
+        compression_saved_bytes: 0,
+        bandwidth_cap: None,
+        bandwidth_utilization: None,
+        quality: 100,
+        latency_ms: None,
+        packet_loss_percent: None,
+        connected_since: None,
+        last_data_activity: None,
+        fingerprint: None,
+        public_key: None,
+        decryption_failures: 0,
+        reconnect_failures: 0,
+        reconnect_in_secs: None,
+    }
+}